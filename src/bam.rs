@@ -0,0 +1,127 @@
+// Copyright (c) 2018 10x Genomics, Inc. All rights reserved.
+
+//! Pseudoalignment of the reads of a BAM file, e.g. to re-quantify reads
+//! another aligner has aligned.
+use std::collections::HashMap;
+use std::cmp;
+use std::path::Path;
+
+use bio::alphabets::dna;
+use debruijn::Kmer;
+use failure::Error;
+use rayon::prelude::*;
+use rayon::{ThreadPool, ThreadPoolBuilder};
+use rust_htslib::bam::{self, Read};
+
+use pseudoaligner::Pseudoaligner;
+
+/// Reads mapped together by the worker threads.
+const BAM_BATCH_READS: usize = 10_000;
+
+impl<K: Kmer + Sync + Send> Pseudoaligner<K> {
+    /// Count the reads of the BAM file `bam_path` per equivalence class,
+    /// like `count_reads`, on `threads` threads. Only primary records are
+    /// mapped, so that secondary and supplementary alignments do not count
+    /// a read again; mates of a pair are counted on their own, and records
+    /// of reads shorter than k are skipped. Reads aligned to the reverse
+    /// strand are mapped as they were sequenced, and the k-mers overlapping
+    /// N are skipped as `map_read_bases_outcome` skips them.
+    pub fn map_bam(&self, bam_path: &Path, threads: usize) -> Result<HashMap<Vec<u32>, u64>, Error> {
+        let threads = cmp::max(threads, 1);
+        let mut reader = bam::Reader::from_path(bam_path)?;
+        reader.set_threads(threads)?;
+        let pool = ThreadPoolBuilder::new().num_threads(threads).build()?;
+
+        let mut counts = HashMap::new();
+        let mut batch = Vec::with_capacity(BAM_BATCH_READS);
+        for result in reader.records() {
+            let record = result?;
+            if record.is_secondary() || record.is_supplementary() {
+                continue;
+            }
+
+            let bases = record.seq().as_bytes();
+            if bases.len() < K::k() {
+                continue;
+            }
+            // BAM stores reverse strand alignments reverse complemented
+            batch.push(if record.is_reverse() { dna::revcomp(&bases) } else { bases });
+
+            if batch.len() == BAM_BATCH_READS {
+                self.count_batch(&pool, &batch, &mut counts);
+                batch.clear();
+            }
+        }
+        self.count_batch(&pool, &batch, &mut counts);
+
+        Ok(counts)
+    }
+
+    fn count_batch(&self, pool: &ThreadPool, batch: &[Vec<u8>], counts: &mut HashMap<Vec<u32>, u64>) {
+        let eq_classes: Vec<Vec<u32>> = pool.install(|| {
+            batch
+                .par_iter()
+                .filter_map(|bases| self.map_read_bases_outcome(bases).mapped_eq_class())
+                .collect()
+        });
+        for eq_class in eq_classes {
+            *counts.entry(eq_class).or_insert(0) += 1;
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use config::KmerType;
+    use rust_htslib::bam::record::Record;
+    use test_utils::{build_test_index, random_seq, TempDir};
+
+    const FLAG_UNMAPPED: u16 = 0x4;
+    const FLAG_REVERSE: u16 = 0x10;
+    const FLAG_SECONDARY: u16 = 0x100;
+    const FLAG_SUPPLEMENTARY: u16 = 0x800;
+
+    fn write_bam(path: &Path, records: &[(&str, &str, u16)]) {
+        let header = bam::Header::new();
+        let mut writer = bam::Writer::from_path(path, &header, bam::Format::Bam).unwrap();
+        for &(qname, seq, flags) in records {
+            let mut record = Record::new();
+            record.set(qname.as_bytes(), None, seq.as_bytes(), &vec![40; seq.len()]);
+            record.set_tid(-1);
+            record.set_pos(-1);
+            record.set_mtid(-1);
+            record.set_mpos(-1);
+            record.set_flags(flags);
+            writer.write(&record).unwrap();
+        }
+    }
+
+    #[test]
+    fn only_primary_records_are_counted() {
+        let (tx1, tx2) = (random_seq(300, 1), random_seq(300, 2));
+        let index: Pseudoaligner<KmerType> = build_test_index(&[("tx1", &tx1), ("tx2", &tx2)]);
+        let reverse = String::from_utf8(dna::revcomp(tx1[100..160].as_bytes())).unwrap();
+        let with_n = tx2[100..130].to_string() + "N" + &tx2[131..160];
+
+        let dir = TempDir::new();
+        let path = dir.path().join("reads.bam");
+        write_bam(&path, &[
+            ("read1", &tx1[0..60], FLAG_UNMAPPED),
+            ("read1", &tx1[0..60], FLAG_UNMAPPED | FLAG_SECONDARY),
+            ("read1", &tx1[0..60], FLAG_UNMAPPED | FLAG_SUPPLEMENTARY),
+            // stored reverse complemented, as aligners write reverse strand reads
+            ("read2", &reverse, FLAG_UNMAPPED | FLAG_REVERSE),
+            ("read3", &tx2[200..260], FLAG_UNMAPPED),
+            ("read4", &with_n, FLAG_UNMAPPED),
+            ("short", &tx2[0..20], FLAG_UNMAPPED),
+        ]);
+
+        for &threads in &[1, 3] {
+            let counts = index.map_bam(&path, threads).unwrap();
+            assert_eq!(counts.len(), 2);
+            assert_eq!(counts[&vec![0]], 2);
+            assert_eq!(counts[&vec![1]], 2);
+        }
+    }
+}
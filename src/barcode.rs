@@ -0,0 +1,323 @@
+// Copyright (c) 2018 10x Genomics, Inc. All rights reserved.
+
+//! Single-cell reads of 10x-style libraries: the first mate holds a cell
+//! barcode followed by a UMI, and only the second mate is mapped. Barcodes
+//! can be corrected against a whitelist, and the mapped reads are counted
+//! by cell and equivalence class, as molecules by UMI.
+use std::cmp;
+use std::collections::{BTreeMap, HashMap, HashSet};
+use std::fs::File;
+use std::io::{BufRead, BufReader, Write};
+use std::path::Path;
+
+use bio::io::fastq;
+use debruijn::Kmer;
+use failure::{self, Error};
+use rayon::prelude::*;
+use rayon::ThreadPoolBuilder;
+use serde_json;
+
+use bus::BusWriter;
+use config::MAPPING_CHUNK_READS;
+use pseudoaligner::{MappingOutcome, Pseudoaligner};
+use umi::UmiCounter;
+
+/// Cell barcodes a library was made with, one per line of a whitelist file.
+pub struct Whitelist {
+    barcodes: HashSet<Vec<u8>>,
+}
+
+impl Whitelist {
+    pub fn read<P: AsRef<Path>>(path: P) -> Result<Whitelist, Error> {
+        let mut barcodes = HashSet::new();
+        for line in BufReader::new(File::open(path)?).lines() {
+            let line = line?;
+            let barcode = line.trim();
+            if !barcode.is_empty() {
+                barcodes.insert(barcode.as_bytes().to_vec());
+            }
+        }
+        Ok(Whitelist { barcodes })
+    }
+
+    pub fn len(&self) -> usize {
+        self.barcodes.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.barcodes.is_empty()
+    }
+
+    /// `barcode` if it is whitelisted, else the only whitelisted barcode
+    /// one substitution away from it. `None` if there is none, or several.
+    pub fn correct(&self, barcode: &[u8]) -> Option<Vec<u8>> {
+        if self.barcodes.contains(barcode) {
+            return Some(barcode.to_vec());
+        }
+
+        let mut corrected = None;
+        let mut candidate = barcode.to_vec();
+        for pos in 0..barcode.len() {
+            for &base in b"ACGT" {
+                if base == barcode[pos] {
+                    continue;
+                }
+                candidate[pos] = base;
+                if self.barcodes.contains(&candidate) {
+                    if corrected.is_some() {
+                        return None;
+                    }
+                    corrected = Some(candidate.clone());
+                }
+            }
+            candidate[pos] = barcode[pos];
+        }
+        corrected
+    }
+}
+
+/// Lengths of the barcode and UMI at the start of the first mates.
+#[derive(Clone, Copy, Debug, Serialize)]
+pub struct BarcodeOptions {
+    pub barcode_len: usize,
+    pub umi_len: usize,
+    /// Merge UMIs one mismatch apart when counting molecules
+    pub merge_umis: bool,
+}
+
+/// Reads of a barcoded run, by what became of their barcode and mapping.
+#[derive(Serialize, Debug, Clone, Default, PartialEq)]
+pub struct CellSummary {
+    /// Read pairs read
+    pub reads: usize,
+    /// Pairs whose second mate mapped, with a barcode that is, or was
+    /// corrected to, a whitelisted one
+    pub mapped_reads: usize,
+    /// Pairs whose barcode was one mismatch from a whitelisted one
+    pub corrected_barcodes: usize,
+    /// Pairs without a barcode they could be assigned to: a first mate
+    /// too short for the barcode and UMI, or a barcode not in the
+    /// whitelist and not one mismatch from exactly one barcode of it
+    pub unassigned_reads: usize,
+    /// Barcodes with mapped reads
+    pub cells: usize,
+    /// Molecules, counted once per UMI of every cell and equivalence class
+    pub molecules: u64,
+}
+
+impl CellSummary {
+    pub fn to_json(&self) -> Result<String, Error> {
+        Ok(serde_json::to_string_pretty(self)?)
+    }
+}
+
+/// Mapped reads of every cell, with a `UmiCounter` of their UMIs by
+/// equivalence class.
+pub struct CellCounts {
+    pub summary: CellSummary,
+    cells: BTreeMap<Vec<u8>, UmiCounter>,
+}
+
+impl CellCounts {
+    /// Molecules of every cell and equivalence class.
+    pub fn molecules(&self) -> BTreeMap<&[u8], HashMap<Vec<u32>, u64>> {
+        self.cells
+            .iter()
+            .map(|(barcode, umis)| (barcode.as_slice(), umis.eq_class_counts()))
+            .collect()
+    }
+
+    /// Write the molecules as a sparse counts matrix: a TSV of `barcode
+    /// transcripts molecules` lines, by barcode and then class.
+    pub fn write_matrix<K: Kmer + Sync + Send, W: Write>(&self, mut writer: W, index: &Pseudoaligner<K>) -> Result<(), Error> {
+        writeln!(writer, "barcode\ttranscripts\tmolecules")?;
+        for (barcode, counts) in self.molecules() {
+            let mut counts: Vec<(Vec<u32>, u64)> = counts.into_iter().collect();
+            counts.sort();
+            for (eq_class, count) in counts {
+                let tx_names: Vec<&str> = eq_class.iter().map(|&tx_id| index.tx_names[tx_id as usize].as_str()).collect();
+                writeln!(writer, "{}\t{}\t{}", String::from_utf8_lossy(barcode), tx_names.join(","), count)?;
+            }
+        }
+        writer.flush()?;
+        Ok(())
+    }
+}
+
+/// What became of the barcode of a read pair: the whitelisted barcode or
+/// the barcode as read, whether it was corrected, and the class of the
+/// second mate if it mapped.
+type BarcodedRead = (Option<Vec<u8>>, bool, Option<Vec<u32>>);
+
+/// Map the second mates of the barcoded `pairs` on `threads` threads and
+/// count their molecules by cell. Barcodes not in `whitelist`, if given,
+/// are corrected to the only whitelisted barcode one mismatch away, and
+/// reads that can not be are unassigned. Mapped reads are also added to
+/// `bus`, if given, by barcode and UMI.
+pub fn map_barcoded_reads<K, I>(
+    index: &Pseudoaligner<K>,
+    pairs: I,
+    options: BarcodeOptions,
+    whitelist: Option<&Whitelist>,
+    threads: usize,
+    mut bus: Option<&mut BusWriter>,
+) -> Result<CellCounts, Error>
+where
+    K: Kmer + Sync + Send,
+    I: Iterator<Item = Result<(fastq::Record, fastq::Record), Error>>,
+{
+    if options.barcode_len == 0 || options.umi_len == 0 {
+        return Err(failure::err_msg("Barcodes and UMIs need at least one base"));
+    }
+    let pool = ThreadPoolBuilder::new().num_threads(cmp::max(threads, 1)).build()?;
+    let tag_len = options.barcode_len + options.umi_len;
+
+    let mut summary = CellSummary::default();
+    let mut cells: BTreeMap<Vec<u8>, UmiCounter> = BTreeMap::new();
+    let mut batch = Vec::with_capacity(MAPPING_CHUNK_READS);
+    let mut pairs = pairs.peekable();
+    while pairs.peek().is_some() {
+        batch.clear();
+        while batch.len() < MAPPING_CHUNK_READS {
+            match pairs.next() {
+                Some(pair) => batch.push(pair?),
+                None => break,
+            }
+        }
+
+        let barcoded_reads: Vec<BarcodedRead> = pool.install(|| {
+            batch
+                .par_iter()
+                .map(|(mate1, mate2)| {
+                    if mate1.seq().len() < tag_len {
+                        return (None, false, None);
+                    }
+                    let barcode = &mate1.seq()[..options.barcode_len];
+                    let (barcode, corrected) = match whitelist {
+                        Some(whitelist) => match whitelist.correct(barcode) {
+                            Some(whitelisted) => {
+                                let corrected = whitelisted != barcode;
+                                (Some(whitelisted), corrected)
+                            }
+                            None => (None, false),
+                        },
+                        None => (Some(barcode.to_vec()), false),
+                    };
+                    // reads without a barcode are not counted, so not mapped either
+                    let eq_class = match barcode {
+                        Some(_) => match index.map_read_bases_outcome(mate2.seq()) {
+                            MappingOutcome::Mapped(eq_class, _) | MappingOutcome::Rescued(eq_class, _) => Some(eq_class),
+                            _ => None,
+                        },
+                        None => None,
+                    };
+                    (barcode, corrected, eq_class)
+                })
+                .collect()
+        });
+
+        for ((mate1, _), (barcode, corrected, eq_class)) in batch.iter().zip(barcoded_reads) {
+            summary.reads += 1;
+            if corrected {
+                summary.corrected_barcodes += 1;
+            }
+            let barcode = match barcode {
+                Some(barcode) => barcode,
+                None => {
+                    summary.unassigned_reads += 1;
+                    continue;
+                }
+            };
+            let eq_class = match eq_class {
+                Some(eq_class) => eq_class,
+                None => continue,
+            };
+
+            summary.mapped_reads += 1;
+            let umi = &mate1.seq()[options.barcode_len..tag_len];
+            if let Some(bus) = bus.as_mut() {
+                bus.add_read(&barcode, umi, &eq_class);
+            }
+            cells
+                .entry(barcode)
+                .or_insert_with(|| UmiCounter::new(options.merge_umis))
+                .add_read(Some(umi), &eq_class);
+        }
+    }
+
+    summary.cells = cells.len();
+    summary.molecules = cells.values().map(|umis| umis.summary().umis).sum();
+    Ok(CellCounts { summary, cells })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use test_utils::{build_test_index, random_seq, TempDir};
+
+    fn whitelist(barcodes: &[&str]) -> Whitelist {
+        let dir = TempDir::new();
+        Whitelist::read(dir.write("whitelist.txt", &(barcodes.join("\n") + "\n"))).unwrap()
+    }
+
+    #[test]
+    fn barcodes_are_corrected_to_the_only_whitelisted_one_a_mismatch_away() {
+        let whitelist = whitelist(&["AAAAAAAA", "CCCCCCCC", "GGGGGGGA", "GGGGGGGC"]);
+        assert_eq!(whitelist.len(), 4);
+        assert_eq!(whitelist.correct(b"AAAAAAAA"), Some(b"AAAAAAAA".to_vec()));
+        assert_eq!(whitelist.correct(b"AAAATAAA"), Some(b"AAAAAAAA".to_vec()));
+        assert_eq!(whitelist.correct(b"AAAATTAA"), None);
+        // as close to GGGGGGGA as to GGGGGGGC
+        assert_eq!(whitelist.correct(b"GGGGGGGT"), None);
+    }
+
+    #[test]
+    fn mapped_second_mates_are_counted_as_molecules_by_cell() {
+        let (tx1, tx2) = (random_seq(300, 1), random_seq(300, 2));
+        let index = build_test_index(&[("tx1", &tx1), ("tx2", &tx2)]);
+        let noise = random_seq(60, 3);
+        let pairs = vec![
+            ("AAAAAAAA", "TTTT", &tx1[0..60]),
+            // the same molecule, read from elsewhere
+            ("AAAAAAAA", "TTTT", &tx1[30..90]),
+            // corrected into the same cell, as another molecule
+            ("AAAAAAAT", "GGGG", &tx1[0..60]),
+            ("CCCCCCCC", "TTTT", &tx2[0..60]),
+            ("CCCCCCCC", "AAAA", &noise[..]),
+            ("GGGGGGGT", "TTTT", &tx1[0..60]),
+            ("TTTTTTTT", "TTTT", &tx1[0..60]),
+            // too short for a barcode and UMI
+            ("ACGT", "", &tx1[0..60]),
+        ];
+        let records = pairs.into_iter().enumerate().map(|(idx, (barcode, umi, seq))| {
+            let tag = barcode.to_string() + umi;
+            let id = format!("read{}", idx);
+            Ok::<_, Error>((
+                fastq::Record::with_attrs(&id, None, tag.as_bytes(), &vec![b'I'; tag.len()]),
+                fastq::Record::with_attrs(&id, None, seq.as_bytes(), &vec![b'I'; seq.len()]),
+            ))
+        });
+
+        let whitelist = whitelist(&["AAAAAAAA", "CCCCCCCC", "GGGGGGGA", "GGGGGGGC"]);
+        let options = BarcodeOptions { barcode_len: 8, umi_len: 4, merge_umis: false };
+        let counts = map_barcoded_reads(&index, records, options, Some(&whitelist), 2, None).unwrap();
+        assert_eq!(
+            counts.summary,
+            CellSummary {
+                reads: 8,
+                mapped_reads: 4,
+                corrected_barcodes: 1,
+                unassigned_reads: 3,
+                cells: 2,
+                molecules: 3,
+            }
+        );
+
+        let mut matrix = Vec::new();
+        counts.write_matrix(&mut matrix, &index).unwrap();
+        assert_eq!(
+            String::from_utf8(matrix).unwrap(),
+            "barcode\ttranscripts\tmolecules\nAAAAAAAA\ttx1\t2\nCCCCCCCC\ttx2\t1\n"
+        );
+    }
+}
@@ -6,6 +6,8 @@ extern crate debruijn;
 extern crate debruijn_mapping;
 extern crate docopt;
 extern crate failure;
+#[cfg(unix)]
+extern crate libc;
 extern crate pretty_env_logger;
 extern crate rayon;
 
@@ -16,16 +18,25 @@ extern crate log;
 extern crate serde;
 
 // Import some modules
-use bio::io::{fasta, fastq};
 use docopt::Docopt;
 use failure::Error;
-use std::{env, fs};
-use std::{path::PathBuf, str};
+use std::{env, fs, io};
+use std::collections::{HashMap, HashSet};
+use std::io::Write;
+use std::{path::PathBuf, str, sync::Arc, time::Instant};
 
-use debruijn_mapping::{config, utils};
-use debruijn_mapping::{build_index::build_index,
-                       pseudoaligner::process_reads,
-                       mappability::analyze_graph};
+use debruijn_mapping::{config, progress, utils};
+use debruijn_mapping::{barcode::{map_barcoded_reads, BarcodeOptions, Whitelist},
+                       build_index::{append_to_index, apply_index_options, build_index_from_fasta,
+                                     build_index_from_fasta_on_disk, subset_index, IndexOptions, SpillOptions},
+                       bus::BusWriter,
+                       checkpoint::{self, IndexBuild},
+                       pseudoaligner::{self, process_fastq_with_seed, process_read_pairs_with_seed, write_bed, write_sam,
+                                      MappingOptions, MappingOutputs},
+                       long_read::{map_long_reads, LongReadOptions},
+                       mappability::analyze_graph,
+                       stats::{estimate_build, IndexStats, RunSummary},
+                       umi::UmiSource};
 
 const PKG_NAME: &'static str = env!("CARGO_PKG_NAME");
 const PKG_VERSION: &'static str = env!("CARGO_PKG_VERSION");
@@ -33,34 +44,249 @@ const USAGE: &'static str = "
 De-bruijn-mapping
 
 Usage:
-  pseudoaligner index -i <index> <ref-fasta>
-  pseudoaligner map -i <index> <reads-fastq>
+  pseudoaligner index [--quiet] [--dry-run] [--duplicates <policy>] [--split-n] [--softmask] [--min-seq-len <n>] [--keep-short] [--strip-versions] [--spans <file>] [--exclude <file>] [--mask-entropy <bits>] [--sparse <w>] [--groups <file>] [--t2g <file> [--missing-genes <policy>]] [--work-dir <dir>] [--mem-budget <mb>] [--tmp-dir <dir>] [--compress <codec>] -i <index> <ref-fasta>...
+  pseudoaligner resume [--quiet] --work-dir <dir> -i <index>
+  pseudoaligner append [--quiet] [--duplicates <policy>] [--split-n] [--softmask] [--min-seq-len <n>] [--keep-short] [--strip-versions] [--spans <file>] [--exclude <file>] [--mask-entropy <bits>] -i <index> --output <new-index> <ref-fasta>...
+  pseudoaligner subset -i <index> --transcripts <file> --output <new-index>
+  pseudoaligner map [--mmap] [--seed <n>] [--threads <n>] [--max-reads <n>] [--subsample-fraction <f>] [--chunk-size <n>] [--quality-mask [--min-base-qual <q>]] [--kmer-stride <n> [--rescue-full]] [--rescue-gap <n>] [--min-kmer-ratio <r>] [--umi-delimiter <c> | --umi-len <n>] [--merge-umis] [--interleaved] [--disjoint-mates <policy>] [--read-format <format>] [--read-tsv <file> [--max-tx-names <n>]] [--unmapped <file> [--unmapped-mates <file>]] [--summary-json <file>] -i <index> <reads-fastq> [<mates-fastq>]
+  pseudoaligner cells [--threads <n>] [--barcode-len <n>] [--umi-len <n>] [--whitelist <file>] [--merge-umis] [--bus <dir>] [--summary-json <file>] -i <index> <reads-fastq> <mates-fastq> <tsv-out>
+  pseudoaligner batch [--mmap] [--seed <n>] [--threads <n>] [--chunk-size <n>] [--disjoint-mates <policy>] -i <index> -o <outdir> <sample-sheet>
+  pseudoaligner map-long [--threads <n>] [--window <n>] [--window-overlap <n>] [--min-windows <n>] [--windows-tsv <file>] -i <index> <reads-fastq> <tsv-out>
+  pseudoaligner sam -i <index> <reads-fastq> <sam-out>
+  pseudoaligner bed -i <index> <reads-fastq> <bed-out>
   pseudoaligner mappability [-o <outdir>] -i <index>
+  pseudoaligner stats -i <index>
+  pseudoaligner gfa -i <index> <gfa-out>
+  pseudoaligner unitigs [--max-tx-names <n>] -i <index> <fasta-out>
+  pseudoaligner eqclasses [-o <outdir>] -i <index>
+  pseudoaligner confusion [--reads-per-tx <n>] [--seed <n>] -i <index> <tsv-out>
+  pseudoaligner verify -i <index> <ref-fasta>...
   pseudoaligner -h | --help | -v | --version
 
+Reads of - are read from stdin, plain or gzipped; mate pairs piped to stdin need --interleaved.
+
 Options:
   -o --outdir DIR   Output directory
+  --quiet           Do not show build progress
+  --dry-run         Only print an estimate of the memory and disk the build takes
+  --duplicates POLICY  What to do with repeated transcript ids: error, keep-first or rename [default: error]
+  --split-n         Split reference sequences at runs of N instead of hashing them
+  --softmask        Split reference sequences at soft-masked (lowercase) bases
+  --min-seq-len N   Skip reference sequences shorter than N bases [default: 0]
+  --keep-short      Keep transcripts shorter than k, without k-mers, instead of dropping them
+  --strip-versions  Drop the .N version of transcript and gene ids read from Gencode and Ensembl headers
+  --spans FILE      Only index the tx_id/start/end spans (0-based, half-open) listed in FILE
+  --exclude FILE    Leave out the transcripts whose ids are listed in FILE, one per line
+  --mask-entropy BITS  Mask 32 bp windows with a dinucleotide entropy below BITS (0-4)
+  --sparse W        Only index the minimizers of windows of W k-mers
+  --groups FILE     Assign transcripts the groups of a tx_id/group FILE instead of their file name
+  --t2g FILE        Take the genes of transcripts from a tx_id/gene_id/gene_name FILE
+  --missing-genes POLICY  What to do with transcripts missing from the t2g file: error or unknown [default: error]
+  --work-dir DIR    Checkpoint each build phase to DIR so the build can be resumed
+  --mem-budget MB   Refuse builds estimated to take more than MB megabytes of memory, and spill k-mer buckets to disk, holding about MB megabytes of k-mers in memory
+  --tmp-dir DIR     Directory for spilled k-mer buckets instead of the system one
+  --compress CODEC  Compress the index with none, gzip or zstd [default: none]
+  --max-tx-names N  Transcript names listed per unitig header or read line [default: 10]
+  --reads-per-tx N  Reads simulated from every transcript for the confusion matrix [default: 1000]
+  --transcripts FILE  Transcript ids to keep, one per line
+  --output FILE     Where to write the extended or subset index
+  --mmap            Memory-map the index instead of reading it into memory
+  --seed N          Seed for assigning multi-mapping reads to a transcript, and for subsampling [default: 0]
+  --max-reads N     Stop after mapping N reads, or pairs
+  --subsample-fraction F  Only map every read, or pair, with probability F
+  --threads N       Number of mapping threads [default: 2]
+  --chunk-size N    Reads handed to a mapping thread at a time [default: 1000]
+  --quality-mask    Skip the k-mers of FASTQ reads holding bases of a quality below --min-base-qual
+  --min-base-qual Q  Phred quality under which --quality-mask masks bases [default: 10]
+  --kmer-stride N   Only look up every Nth k-mer of reads and mates [default: 1]
+  --rescue-full     Look up every k-mer again of the reads --kmer-stride leaves unmapped or ambiguous
+  --rescue-gap N    Count gaps of up to N bases between matches to the same transcripts of reads and mates as covered [default: 0]
+  --min-kmer-ratio R  Only map reads with at least a fraction R of their valid k-mers on the transcripts they map to [default: 0]
+  --umi-delimiter C  Count mapped reads once per UMI, taking UMIs from the read name after the last C
+  --umi-len N       Count mapped reads once per UMI, taking the first N bases of the (first mate) reads as the UMI;
+                    for cells, the UMI bases after the barcode, 12 if not given
+  --barcode-len N   Cell barcode bases at the start of the first mates, 16 if not given
+  --whitelist FILE  Correct cell barcodes to the only barcode of FILE, one per line, at most one mismatch away
+  --bus DIR         Also write the cell reads to DIR in the BUS format of bustools
+  --merge-umis      Merge UMIs one mismatch from a UMI with at least about twice their reads
+  --interleaved     Map the reads as mate pairs, interleaved in one file
+  --disjoint-mates POLICY  What to do with pairs whose mates map to disjoint classes: discard, union or better-covered [default: discard]
+  --read-format FORMAT  Format of unpaired reads: fastq, fasta, or auto to tell by their first character [default: auto]
+  --read-tsv FILE   Also write the mapping of every read to FILE as TSV, gzipped if it ends in .gz
+  --unmapped FILE   Write the reads that do not map to FILE as FASTQ, gzipped if it ends in .gz; mates interleaved
+  --unmapped-mates FILE  Write the second mates of pairs that do not map to FILE instead
+  --summary-json FILE  Write a summary of the mapping run to FILE as JSON
+  --window N        Bases of the windows long reads are mapped in [default: 200]
+  --window-overlap N  Bases a long read window shares with the next [default: 100]
+  --min-windows N   Mapped windows needed to call a long read, or each part of a chimera [default: 3]
+  --windows-tsv FILE  Also write the mapping of every long read window to FILE as TSV, gzipped if it ends in .gz
   -h --help         Show this screen.
   -v --version         Show version.
 ";
   // -l --long         Long output format (one line per read-transcript mapping)
 
 
-#[derive(Debug, Deserialize)]
+#[derive(Debug, Deserialize, Serialize)]
 struct Args {
-    arg_ref_fasta: String,
+    arg_ref_fasta: Vec<String>,
     arg_index: String,
     arg_reads_fastq: String,
+    arg_mates_fastq: Option<String>,
+    arg_gfa_out: String,
+    arg_sam_out: String,
+    arg_bed_out: String,
+    arg_fasta_out: String,
+    arg_tsv_out: String,
+    arg_sample_sheet: String,
     flag_outdir: Option<String>,
+    flag_quiet: bool,
+    flag_dry_run: bool,
+    flag_duplicates: String,
+    flag_split_n: bool,
+    flag_softmask: bool,
+    flag_min_seq_len: usize,
+    flag_keep_short: bool,
+    flag_strip_versions: bool,
+    flag_spans: Option<String>,
+    flag_exclude: Option<String>,
+    flag_mask_entropy: Option<f64>,
+    flag_sparse: Option<usize>,
+    flag_groups: Option<String>,
+    flag_t2g: Option<String>,
+    flag_missing_genes: String,
+    flag_work_dir: Option<String>,
+    flag_mem_budget: Option<usize>,
+    flag_tmp_dir: Option<String>,
+    flag_compress: String,
+    flag_output: String,
+    flag_transcripts: String,
+    flag_max_tx_names: usize,
+    flag_reads_per_tx: usize,
+    flag_mmap: bool,
+    flag_seed: u64,
+    flag_max_reads: Option<usize>,
+    flag_subsample_fraction: Option<f64>,
+    flag_threads: usize,
+    flag_chunk_size: usize,
+    flag_quality_mask: bool,
+    flag_min_base_qual: u8,
+    flag_kmer_stride: usize,
+    flag_rescue_full: bool,
+    flag_rescue_gap: usize,
+    flag_min_kmer_ratio: f64,
+    flag_umi_delimiter: Option<String>,
+    flag_umi_len: Option<usize>,
+    flag_merge_umis: bool,
+    flag_barcode_len: Option<usize>,
+    flag_whitelist: Option<String>,
+    flag_bus: Option<String>,
+    flag_interleaved: bool,
+    flag_disjoint_mates: String,
+    flag_read_format: String,
+    flag_read_tsv: Option<String>,
+    flag_unmapped: Option<String>,
+    flag_unmapped_mates: Option<String>,
+    flag_summary_json: Option<String>,
+    flag_window: usize,
+    flag_window_overlap: usize,
+    flag_min_windows: usize,
+    flag_windows_tsv: Option<String>,
     cmd_index: bool,
+    cmd_resume: bool,
+    cmd_append: bool,
+    cmd_subset: bool,
     cmd_map: bool,
+    cmd_cells: bool,
+    cmd_map_long: bool,
+    cmd_batch: bool,
+    cmd_sam: bool,
+    cmd_bed: bool,
     cmd_mappability: bool,
+    cmd_stats: bool,
+    cmd_gfa: bool,
+    cmd_unitigs: bool,
+    cmd_eqclasses: bool,
+    cmd_confusion: bool,
+    cmd_verify: bool,
 
     // flag_long: bool,
     flag_version: bool,
     flag_v: bool,
 }
 
+fn reference_options(args: &Args) -> Result<utils::ReferenceOptions, Error> {
+    Ok(utils::ReferenceOptions {
+        duplicates: args.flag_duplicates.parse()?,
+        split_at_n: args.flag_split_n,
+        min_seq_len: args.flag_min_seq_len,
+        spans: match args.flag_spans {
+            Some(ref path) => Some(utils::read_spans(path)?),
+            None => None,
+        },
+        mask_entropy: args.flag_mask_entropy,
+        keep_short: args.flag_keep_short,
+        honor_softmask: args.flag_softmask,
+        strip_versions: args.flag_strip_versions,
+        exclude: match args.flag_exclude {
+            Some(ref path) => utils::read_ids(path)?,
+            None => HashSet::new(),
+        },
+        progress: progress::Progress::default(),
+    })
+}
+
+#[cfg(unix)]
+extern "C" fn on_interrupt(_signal: libc::c_int) {
+    pseudoaligner::stop_mapping();
+    // a second Ctrl-C kills the process
+    unsafe {
+        libc::signal(libc::SIGINT, libc::SIG_DFL);
+    }
+}
+
+/// Let Ctrl-C stop the mapping after the reads already read, so their
+/// mappings and the summary are still written.
+#[cfg(unix)]
+fn stop_mapping_on_interrupt() {
+    let handler: extern "C" fn(libc::c_int) = on_interrupt;
+    unsafe {
+        libc::signal(libc::SIGINT, handler as libc::sighandler_t);
+    }
+}
+
+#[cfg(not(unix))]
+fn stop_mapping_on_interrupt() {}
+
+/// Map the reads of `sample` to `index`, writing its summary and counts to
+/// `dir`.
+fn map_sample(
+    index: &pseudoaligner::Pseudoaligner<config::KmerType>,
+    sample: &utils::Sample,
+    dir: &PathBuf,
+    options: MappingOptions,
+    policy: pseudoaligner::DisjointMatePolicy,
+) -> Result<(HashMap<Vec<u32>, u64>, RunSummary), Error> {
+    fs::create_dir_all(dir)?;
+    let mut summary = RunSummary::default();
+    summary.add_config("sample", &sample.name)?;
+    let outputs = MappingOutputs { summary: Some(&mut summary), ..MappingOutputs::default() };
+    let mapped = match sample.mates {
+        Some(ref mates) => {
+            let pairs = utils::paired_fastq_records(&sample.reads, mates)?;
+            process_read_pairs_with_seed(pairs, index, dir, options, policy, outputs)
+        }
+        None => process_fastq_with_seed(&sample.reads, utils::ReadFormat::Auto, index, dir, options, outputs),
+    };
+
+    // as for map, the summary of a failed sample is written too
+    info!("{}: {}", sample.name, summary);
+    fs::write(dir.join("summary.json"), summary.to_json()?)?;
+    let eq_class_counts = mapped?;
+    let writer = io::BufWriter::new(fs::File::create(dir.join("counts.tsv"))?);
+    utils::write_eq_class_counts_tsv(writer, index, &eq_class_counts)?;
+    Ok((eq_class_counts, summary))
+}
+
 fn main() -> Result<(), Error> {
     let args: Args = Docopt::new(USAGE)
                             .and_then(|d| d.deserialize())
@@ -76,41 +302,384 @@ fn main() -> Result<(), Error> {
     info!("Command line args:\n{:?}", args);
 
     let outdir = match args.flag_outdir {
-        Some(dir) => PathBuf::from(dir),
+        Some(ref dir) => PathBuf::from(dir),
         None => env::current_dir()?,
     };
     fs::create_dir_all(&outdir)?;
+    if args.flag_quiet {
+        progress::set_sink(Arc::new(progress::NoProgress));
+    }
 
     if args.cmd_index {
         info!("Building index from fasta");
-        let fasta = fasta::Reader::from_file(args.arg_ref_fasta)?;
-        let (seqs, tx_names, tx_gene_map) = utils::read_transcripts(fasta)?;
-        let index = build_index::<config::KmerType>(
-            &seqs, &tx_names, &tx_gene_map
-        )?;
+        let fasta_paths: Vec<PathBuf> = args.arg_ref_fasta.iter().map(PathBuf::from).collect();
+        let options = reference_options(&args)?;
+        let compression = args.flag_compress.parse()?;
+        if args.flag_dry_run || args.flag_mem_budget.is_some() {
+            let estimate = estimate_build::<config::KmerType>(&fasta_paths, &options)?;
+            if args.flag_dry_run {
+                println!("{}", estimate.to_json()?);
+                return Ok(());
+            }
+            if let Some(mem_budget) = args.flag_mem_budget {
+                if estimate.peak_memory > (mem_budget * 1024 * 1024) as u64 {
+                    return Err(failure::err_msg(format!(
+                        "The build is estimated to take {} MB of memory, more than the --mem-budget of {} MB",
+                        estimate.peak_memory / (1024 * 1024), mem_budget
+                    )));
+                }
+            }
+        }
+        let index_options = IndexOptions {
+            sparse: args.flag_sparse,
+            groups: args.flag_groups.as_ref().map(PathBuf::from),
+            t2g: args.flag_t2g.as_ref().map(PathBuf::from),
+            missing_genes: args.flag_missing_genes.parse()?,
+        };
+        let mut index = match (args.flag_work_dir.as_ref(), args.flag_mem_budget) {
+            (Some(_), Some(_)) => {
+                return Err(failure::err_msg("--work-dir can not be combined with --mem-budget"));
+            }
+            (Some(dir), None) => {
+                IndexBuild::create_with_index_options::<config::KmerType, _>(dir, fasta_paths, &options, &index_options)?
+                    .finish()?
+            }
+            (None, Some(mem_budget)) => {
+                let spill = SpillOptions {
+                    temp_dir: match args.flag_tmp_dir {
+                        Some(ref dir) => PathBuf::from(dir),
+                        None => env::temp_dir(),
+                    },
+                    memory_budget: mem_budget * 1024 * 1024,
+                };
+                build_index_from_fasta_on_disk::<config::KmerType>(fasta_paths, &options, &spill)?
+            }
+            (None, None) => build_index_from_fasta::<config::KmerType>(fasta_paths, &options)?,
+        };
+        // a checkpointed build makes the changes in its last phase
+        if args.flag_work_dir.is_none() {
+            apply_index_options(&mut index, &index_options)?;
+        }
         info!("Finished building index!");
 
         info!("Writing index to disk");
-        utils::write_obj(&index, args.arg_index)?;
+        utils::write_index_with_compression(&index, args.arg_index, compression)?;
+        info!("Finished writing index!");
+    } else if args.cmd_resume {
+        let work_dir = args.flag_work_dir.unwrap_or_default();
+        info!("Resuming index build in {}", work_dir);
+        let index = checkpoint::resume::<config::KmerType, _>(&work_dir)?;
+        info!("Finished building index!");
+
+        info!("Writing index to disk");
+        utils::write_index(&index, args.arg_index)?;
+        info!("Finished writing index!");
+    } else if args.cmd_append {
+        info!("Appending fasta to index");
+        let fasta_paths: Vec<PathBuf> = args.arg_ref_fasta.iter().map(PathBuf::from).collect();
+        let index = append_to_index::<config::KmerType, _>(
+            &args.arg_index, fasta_paths, &reference_options(&args)?
+        )?;
+        info!("Finished extending index!");
+
+        info!("Writing index to disk");
+        utils::write_index(&index, &args.flag_output)?;
+        info!("Finished writing index!");
+    } else if args.cmd_subset {
+        info!("Reading index from disk");
+        let index = utils::read_index::<config::KmerType, _>(&args.arg_index)?;
+        info!("Finished reading index!");
+        let keep = utils::read_ids(&args.flag_transcripts)?;
+        let index = subset_index(&index, &keep)?;
+        info!("Writing index to disk");
+        utils::write_index(&index, &args.flag_output)?;
         info!("Finished writing index!");
     } else if args.cmd_map {
         info!("Reading index from disk");
-        let index = utils::read_obj(args.arg_index)?;
+        let load_mode = if args.flag_mmap {
+            utils::IndexLoadMode::Mmap
+        } else {
+            utils::IndexLoadMode::Owned
+        };
+        let start = Instant::now();
+        let index = utils::read_index_with_mode(&args.arg_index, load_mode)?;
         info!("Finished reading index!");
+        let mut summary = RunSummary::default();
+        summary.add_phase("index_loading", start.elapsed().as_secs_f64());
+        summary.add_config("args", &args)?;
 
         info!("Mapping reads from fastq");
-        let reads = fastq::Reader::from_file(args.arg_reads_fastq)?;
-        process_reads::<config::KmerType, _>(reads, &index, outdir)?;
+        let policy = args.flag_disjoint_mates.parse()?;
+        let mut read_tsv = match args.flag_read_tsv {
+            Some(ref path) => Some(utils::ReadTsvWriter::create(path, &index, args.flag_max_tx_names)?),
+            None => None,
+        };
+        let mut unmapped = match (&args.flag_unmapped, &args.flag_unmapped_mates) {
+            (Some(path1), Some(path2)) => Some(utils::UnmappedWriter::create_paired(path1, path2)?),
+            (Some(path), None) => Some(utils::UnmappedWriter::create(path)?),
+            (None, Some(_)) => return Err(failure::err_msg("--unmapped-mates needs --unmapped for the first mates")),
+            (None, None) => None,
+        };
+        let outputs = MappingOutputs {
+            read_tsv: read_tsv.as_mut(),
+            unmapped: unmapped.as_mut(),
+            summary: Some(&mut summary),
+        };
+        let options = MappingOptions {
+            seed: args.flag_seed,
+            num_threads: args.flag_threads,
+            chunk_size: args.flag_chunk_size,
+            quality_mask: args.flag_quality_mask,
+            min_base_quality: args.flag_min_base_qual,
+            kmer_stride: args.flag_kmer_stride,
+            rescue_full: args.flag_rescue_full,
+            max_rescue_gap: args.flag_rescue_gap,
+            min_kmer_ratio: args.flag_min_kmer_ratio,
+            max_reads: args.flag_max_reads,
+            subsample_fraction: args.flag_subsample_fraction,
+            umi: UmiSource::from_args(args.flag_umi_delimiter.as_deref(), args.flag_umi_len)?,
+            merge_umis: args.flag_merge_umis,
+        };
+        if args.flag_merge_umis && options.umi.is_none() {
+            return Err(failure::err_msg("--merge-umis needs --umi-delimiter or --umi-len"));
+        }
+        if !(0.0..=1.0).contains(&options.min_kmer_ratio) {
+            return Err(failure::err_msg("--min-kmer-ratio must be between 0 and 1"));
+        }
+        stop_mapping_on_interrupt();
+        let mapped = if let Some(ref mates_fastq) = args.arg_mates_fastq {
+            if args.flag_interleaved {
+                return Err(failure::err_msg("--interleaved reads can not come with a separate mates file"));
+            }
+            let pairs = utils::paired_fastq_records(args.arg_reads_fastq.as_ref(), mates_fastq.as_ref())?;
+            process_read_pairs_with_seed(pairs, &index, outdir, options, policy, outputs)
+        } else if args.flag_interleaved {
+            let pairs = utils::interleaved_fastq_records(args.arg_reads_fastq.as_ref())?;
+            process_read_pairs_with_seed(pairs, &index, outdir, options, policy, outputs)
+        } else {
+            let format = args.flag_read_format.parse()?;
+            process_fastq_with_seed::<config::KmerType, _>(
+                args.arg_reads_fastq.as_ref(), format, &index, outdir, options, outputs
+            )
+        };
+
+        // the summary of a failed or interrupted run is written too
+        info!("{}", summary);
+        if let Some(ref path) = args.flag_summary_json {
+            fs::write(path, summary.to_json()?)?;
+        }
+        mapped?;
         info!("Finished mapping reads!");
+    } else if args.cmd_cells {
+        info!("Reading index from disk");
+        let index = utils::read_index::<config::KmerType, _>(args.arg_index)?;
+        info!("Finished reading index!");
+        let options = BarcodeOptions {
+            barcode_len: args.flag_barcode_len.unwrap_or(config::CELL_BARCODE_LEN),
+            umi_len: args.flag_umi_len.unwrap_or(config::CELL_UMI_LEN),
+            merge_umis: args.flag_merge_umis,
+        };
+        let whitelist = match args.flag_whitelist {
+            Some(ref path) => {
+                let whitelist = Whitelist::read(path)?;
+                info!("Read {} whitelisted barcodes", whitelist.len());
+                Some(whitelist)
+            }
+            None => None,
+        };
+        let mut bus = match args.flag_bus {
+            Some(_) => Some(BusWriter::new(&index, options.barcode_len, options.umi_len)?),
+            None => None,
+        };
+        let mates_fastq = args.arg_mates_fastq.as_ref().expect("docopt requires the mates");
+        let pairs = utils::paired_fastq_records(args.arg_reads_fastq.as_ref(), mates_fastq.as_ref())?;
+        let counts = map_barcoded_reads(&index, pairs, options, whitelist.as_ref(), args.flag_threads, bus.as_mut())?;
+
+        let summary = &counts.summary;
+        info!(
+            "{} of {} reads mapped to {} molecules of {} cells; {} barcodes corrected, {} reads unassigned",
+            summary.mapped_reads, summary.reads, summary.molecules, summary.cells,
+            summary.corrected_barcodes, summary.unassigned_reads
+        );
+        info!("Writing cell counts to {}", args.arg_tsv_out);
+        counts.write_matrix(io::BufWriter::new(fs::File::create(&args.arg_tsv_out)?), &index)?;
+        if let (Some(bus), Some(ref dir)) = (bus, &args.flag_bus) {
+            fs::create_dir_all(dir)?;
+            bus.write(&index, dir)?;
+            info!("Wrote BUS records to {}, skipping {} reads", dir, bus.num_skipped());
+        }
+        if let Some(ref path) = args.flag_summary_json {
+            fs::write(path, summary.to_json()?)?;
+        }
+    } else if args.cmd_batch {
+        let samples = utils::read_sample_sheet(&args.arg_sample_sheet)?;
+        info!("Reading index from disk");
+        let load_mode = if args.flag_mmap {
+            utils::IndexLoadMode::Mmap
+        } else {
+            utils::IndexLoadMode::Owned
+        };
+        let index = utils::read_index_with_mode(&args.arg_index, load_mode)?;
+        info!("Finished reading index!");
+        let options = MappingOptions {
+            seed: args.flag_seed,
+            num_threads: args.flag_threads,
+            chunk_size: args.flag_chunk_size,
+            ..MappingOptions::default()
+        };
+        let policy = args.flag_disjoint_mates.parse()?;
+
+        // a failed sample is recorded and the batch goes on
+        let mut sample_counts = Vec::with_capacity(samples.len());
+        let mut status = io::BufWriter::new(fs::File::create(outdir.join("samples.tsv"))?);
+        writeln!(status, "sample\tstatus\ttotal_reads\tmapped_reads\terror")?;
+        for (sample_idx, sample) in samples.iter().enumerate() {
+            info!("Mapping sample {} of {}: {}", sample_idx + 1, samples.len(), sample.name);
+            match map_sample(&index, sample, &outdir.join(&sample.name), options, policy) {
+                Ok((counts, summary)) => {
+                    writeln!(status, "{}\tok\t{}\t{}\tNA", sample.name, summary.total_reads, summary.mapped_reads)?;
+                    sample_counts.push((sample.name.as_str(), counts));
+                }
+                Err(err) => {
+                    error!("Sample {} failed: {}", sample.name, err);
+                    writeln!(status, "{}\tfailed\tNA\tNA\t{}", sample.name, err.to_string().replace(['\t', '\n'], " "))?;
+                }
+            }
+        }
+        status.flush()?;
+
+        let matrix: Vec<(&str, &HashMap<Vec<u32>, u64>)> =
+            sample_counts.iter().map(|(name, counts)| (*name, counts)).collect();
+        let writer = io::BufWriter::new(fs::File::create(outdir.join("counts_matrix.tsv"))?);
+        utils::write_counts_matrix_tsv(writer, &index, &matrix)?;
+        let num_failed = samples.len() - sample_counts.len();
+        if num_failed > 0 {
+            return Err(failure::err_msg(format!("{} of {} samples failed", num_failed, samples.len())));
+        }
+    } else if args.cmd_map_long {
+        info!("Reading index from disk");
+        let index = utils::read_index::<config::KmerType, _>(args.arg_index)?;
+        info!("Finished reading index!");
+        info!("Writing long read calls to {}", args.arg_tsv_out);
+        let options = LongReadOptions {
+            window: args.flag_window,
+            overlap: args.flag_window_overlap,
+            min_windows: args.flag_min_windows,
+        };
+        let calls = utils::create_maybe_gz(&args.arg_tsv_out)?;
+        let windows = match args.flag_windows_tsv {
+            Some(ref path) => Some(utils::create_maybe_gz(path)?),
+            None => None,
+        };
+        let records = utils::read_records(args.arg_reads_fastq.as_ref(), utils::ReadFormat::Auto)?;
+        let counts = map_long_reads(&index, records, options, args.flag_threads, calls, windows)?;
+        info!(
+            "{} of {} long reads mapped to {} equivalence classes, {} chimeric",
+            counts.num_mapped,
+            counts.num_reads,
+            counts.eq_class_counts.len(),
+            counts.num_chimeric
+        );
+    } else if args.cmd_sam {
+        info!("Reading index from disk");
+        let index = utils::read_index::<config::KmerType, _>(args.arg_index)?;
+        info!("Finished reading index!");
+        info!("Writing pseudoalignments to {}", args.arg_sam_out);
+        let writer = io::BufWriter::new(fs::File::create(&args.arg_sam_out)?);
+        let records = utils::read_records(args.arg_reads_fastq.as_ref(), utils::ReadFormat::Auto)?;
+        let mut read_error = None;
+        let reads = records
+            .map_while(|record| match record {
+                Ok(record) => Some(record),
+                Err(err) => {
+                    read_error = Some(err);
+                    None
+                }
+            })
+            .map(|record| {
+                let quals = if record.qual().is_empty() { None } else { Some(record.qual().to_vec()) };
+                (record.id().to_owned(), record.seq().to_vec(), quals)
+            });
+        let num_mapped = write_sam(writer, reads, &index)?;
+        if let Some(err) = read_error {
+            return Err(err);
+        }
+        info!("{} reads mapped", num_mapped);
+    } else if args.cmd_bed {
+        info!("Reading index from disk");
+        let index = utils::read_index::<config::KmerType, _>(args.arg_index)?;
+        info!("Finished reading index!");
+        if index.tx_node_starts.len() != index.tx_names.len() {
+            return Err(failure::err_msg("The index does not record transcript positions, which BED output needs"));
+        }
+        info!("Writing read placements to {}", args.arg_bed_out);
+        let writer = io::BufWriter::new(fs::File::create(&args.arg_bed_out)?);
+        let records = utils::read_records(args.arg_reads_fastq.as_ref(), utils::ReadFormat::Auto)?;
+        let mut read_error = None;
+        let bed_records = records
+            .map_while(|record| match record {
+                Ok(record) => Some(record),
+                Err(err) => {
+                    read_error = Some(err);
+                    None
+                }
+            })
+            .flat_map(|record| index.bed_records(record.id(), &utils::fastq_seq(&record)));
+        write_bed(writer, bed_records, &index)?;
+        if let Some(err) = read_error {
+            return Err(err);
+        }
     } else if args.cmd_mappability {
         info!("Reading index from disk");
-        let index = debruijn_mapping::utils::read_obj(args.arg_index)?;
+        let index = utils::read_index(args.arg_index)?;
         info!("Finished reading index!");
         info!("Analyzing de Bruijn graph");
         let records = analyze_graph::<config::KmerType>(&index)?;
         info!("Finished analyzing!");
         info!("{} transcripts total", records.len());
         utils::write_mappability_tsv(records, outdir)?;
+    } else if args.cmd_stats {
+        info!("Reading index from disk");
+        let index = utils::read_index::<config::KmerType, _>(args.arg_index)?;
+        info!("Finished reading index!");
+        println!("{}", IndexStats::new(&index)?.to_json()?);
+    } else if args.cmd_gfa {
+        info!("Reading index from disk");
+        let index = utils::read_index::<config::KmerType, _>(args.arg_index)?;
+        info!("Finished reading index!");
+        info!("Writing de Bruijn graph to {}", args.arg_gfa_out);
+        let writer = io::BufWriter::new(fs::File::create(&args.arg_gfa_out)?);
+        utils::write_gfa(writer, &index)?;
+    } else if args.cmd_unitigs {
+        info!("Reading index from disk");
+        let index = utils::read_index::<config::KmerType, _>(args.arg_index)?;
+        info!("Finished reading index!");
+        info!("Writing unitigs to {}", args.arg_fasta_out);
+        let writer = io::BufWriter::new(fs::File::create(&args.arg_fasta_out)?);
+        utils::write_unitigs_fasta(writer, &index, args.flag_max_tx_names)?;
+    } else if args.cmd_eqclasses {
+        info!("Reading index from disk");
+        let index = utils::read_index::<config::KmerType, _>(args.arg_index)?;
+        info!("Finished reading index!");
+        utils::write_eq_classes_tsv(&index, outdir)?;
+    } else if args.cmd_confusion {
+        info!("Reading index from disk");
+        let index = utils::read_index::<config::KmerType, _>(args.arg_index)?;
+        info!("Finished reading index!");
+        info!("Simulating {} reads per transcript", args.flag_reads_per_tx);
+        let matrix = index.confusion_matrix(args.flag_reads_per_tx, args.flag_seed)?;
+        let writer = io::BufWriter::new(fs::File::create(&args.arg_tsv_out)?);
+        utils::write_confusion_matrix_tsv(writer, &index, &matrix)?;
+    } else if args.cmd_verify {
+        info!("Reading index from disk");
+        let index = utils::read_index::<config::KmerType, _>(args.arg_index)?;
+        info!("Finished reading index!");
+        let fasta_paths: Vec<PathBuf> = args.arg_ref_fasta.iter().map(PathBuf::from).collect();
+        let verification = utils::verify_reference(&index, &fasta_paths)?;
+        println!("{}", verification);
+        if !verification.matches() {
+            return Err(failure::err_msg("Reference does not match the index"));
+        }
     }
 
     info!("Done!");
@@ -1,8 +1,17 @@
 // Copyright (c) 2018 10x Genomics, Inc. All rights reserved.
 
+use std::cmp;
 use std::sync::Arc;
-use std::collections::HashMap;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::collections::{HashMap, HashSet};
+use std::fs::{self, File};
+use std::io::{BufReader, BufWriter, Write};
+use std::path::{Path, PathBuf};
+use std::process;
+use std::time::{SystemTime, UNIX_EPOCH};
 
+use bincode::{deserialize_from, serialize_into};
+use bio::io::fasta;
 use boomphf::hashmap::{BoomHashMap2, NoKeyBoomHashMap};
 use config::{KmerType, MEM_SIZE, REPORT_ALL_KMER, STRANDED};
 use debruijn;
@@ -13,68 +22,1361 @@ use debruijn::graph::*;
 use debruijn::*;
 
 use boomphf;
-use failure::Error;
-use config::{MAX_WORKER, MIN_KMERS, U32_MAX};
-use pseudoaligner::Pseudoaligner;
+use failure::{self, Error};
+use config::{LOW_COMPLEXITY_WINDOW, MAX_WORKER, MIN_KMERS, U32_MAX};
+use error::PseudoalignerError;
+use progress::{Phase, Progress};
+use pseudoaligner::{self, Pseudoaligner};
+use serde::de::DeserializeOwned;
 use rayon;
+use utils::{self, MissingGenePolicy, ReferenceChecksum, ReferenceOptions, SkippedRecords};
 use rayon::prelude::*;
 
 const MIN_SHARD_SEQUENCES: usize = 2000;
 
+/// Bytes a k-mer is assumed to take while its bucket is assembled, for
+/// sizing spill partitions.
+const SPILL_BYTES_PER_KMER: usize = 32;
+const MAX_SPILL_PARTITIONS: usize = 256;
+/// Number of contigs bucketed in memory at a time while spilling.
+const SPILL_BATCH_CONTIGS: usize = 10_000;
+
+/// Segments, names, gene mapping and file indices of reference transcripts.
+pub(crate) type ReferenceSegments = (Vec<Vec<DnaString>>, Vec<String>, HashMap<String, String>, Vec<usize>);
+
+/// Reference transcripts with the checksums of their files, the names of
+/// their genes and what was left out of them.
+pub(crate) type ReferenceInput = (ReferenceSegments, Vec<ReferenceChecksum>, HashMap<String, String>, BuildReport);
+
+/// Graphs of the k-mer buckets and the equivalence classes labelling them.
+pub(crate) type ShardGraphs<K> = (Vec<BaseGraph<K, EqClassIdType>>, Vec<Vec<u32>>);
+
+/// Sequence chunks as spilled to disk: bucket, transcript index, sequence
+/// and extensions.
+type SpilledChunk = (u16, u32, DnaString, Exts);
+
+/// How a build spills its k-mer buckets to disk instead of holding them all
+/// in memory.
+#[derive(Clone, Debug)]
+pub struct SpillOptions {
+    /// Directory for the spill files, which go in a fresh subdirectory that
+    /// is removed when the build finishes or fails
+    pub temp_dir: PathBuf,
+    /// Approximate number of bytes the k-mers of one partition may take
+    pub memory_budget: usize,
+}
+
+/// Build an index of the transcripts `seqs`. Transcripts shorter than k
+/// are warned about and keep their index, but have no k-mers, so no read
+/// maps to them; `Pseudoaligner::short_transcripts` lists them.
 pub fn build_index<K: Kmer + Sync + Send>(
     seqs: &[DnaString],
     tx_names: &Vec<String>,
     tx_gene_map: &HashMap<String, String>
+) -> Result<Pseudoaligner<K>, PseudoalignerError> {
+    build_index_with_strandedness(seqs, tx_names, tx_gene_map, STRANDED)
+}
+
+/// Like `build_index`, but storing k-mers in the orientation of the
+/// transcripts only if `stranded`, and canonically otherwise.
+pub(crate) fn build_index_with_strandedness<K: Kmer + Sync + Send>(
+    seqs: &[DnaString],
+    tx_names: &[String],
+    tx_gene_map: &HashMap<String, String>,
+    stranded: bool,
+) -> Result<Pseudoaligner<K>, PseudoalignerError> {
+    if seqs.len() >= U32_MAX {
+        panic!("Too many ({}) sequences to handle.", seqs.len());
+    }
+
+    for (seq, tx_name) in seqs.iter().zip(tx_names) {
+        if seq.len() < K::k() {
+            warn!("Transcript {} of {} bases is shorter than k = {} and has no k-mers", tx_name, seq.len(), K::k());
+        }
+    }
+    let contigs: Vec<_> = seqs
+        .iter()
+        .enumerate()
+        .filter(|&(_, seq)| seq.len() >= K::k())
+        .map(|(id, seq)| (id as u32, seq, Exts::empty()))
+        .collect();
+    let mut index =
+        build_index_from_contigs(&contigs, tx_names, tx_gene_map, stranded, None, &Progress::default())?;
+    index.tx_lengths = seqs.iter().map(|seq| seq.len() as u32).collect();
+    index.tx_node_starts = seqs
+        .par_iter()
+        .map(|seq| transcript_node_starts(&index, std::slice::from_ref(seq)))
+        .collect();
+    index.tx_checksums = seqs
+        .par_iter()
+        .map(|seq| utils::transcript_checksum(std::slice::from_ref(seq)))
+        .collect();
+    index.tx_gc_contents = seqs.iter().map(utils::gc_content).collect();
+    Ok(index)
+}
+
+/// Build an index from transcripts given as lists of segments, as read by
+/// `utils::read_transcript_segments`. Every segment is indexed on its own
+/// but labelled with its transcript, so no k-mer spans two segments.
+/// Segments shorter than k are dropped. The recorded transcript lengths
+/// are the total lengths of their segments.
+pub fn build_index_from_segments<K: Kmer + Sync + Send>(
+    segments: &[Vec<DnaString>],
+    tx_names: &Vec<String>,
+    tx_gene_map: &HashMap<String, String>
+) -> Result<Pseudoaligner<K>, PseudoalignerError> {
+    Ok(index_segments(segments, tx_names, tx_gene_map, None, &Progress::default())?)
+}
+
+fn index_segments<K: Kmer + Sync + Send>(
+    segments: &[Vec<DnaString>],
+    tx_names: &[String],
+    tx_gene_map: &HashMap<String, String>,
+    spill: Option<&SpillOptions>,
+    progress: &Progress,
 ) -> Result<Pseudoaligner<K>, Error> {
+    if segments.len() >= U32_MAX {
+        panic!("Too many ({}) sequences to handle.", segments.len());
+    }
+
+    let contigs = segment_contigs::<K>(segments);
+    let mut index = build_index_from_contigs(&contigs, tx_names, tx_gene_map, STRANDED, spill, progress)?;
+    index.tx_lengths = segment_lengths(segments);
+    index.tx_node_starts = segments
+        .par_iter()
+        .map(|tx_segments| transcript_node_starts(&index, tx_segments))
+        .collect();
+    index.tx_checksums = segments
+        .par_iter()
+        .map(|tx_segments| utils::transcript_checksum(tx_segments))
+        .collect();
+    index.tx_gc_contents = segment_gc_contents(segments);
+    Ok(index)
+}
+
+/// Contigs of all segments of at least k bases, labelled with their transcript.
+pub(crate) fn segment_contigs<K: Kmer>(segments: &[Vec<DnaString>]) -> Vec<(u32, &DnaString, Exts)> {
+    let mut contigs = Vec::new();
+    let mut short_segments = 0;
+    for (id, tx_segments) in segments.iter().enumerate() {
+        for seq in tx_segments {
+            if seq.len() < K::k() {
+                short_segments += 1;
+            } else {
+                contigs.push((id as u32, seq, Exts::empty()));
+            }
+        }
+    }
+    info!(
+        "Indexing {} segments, dropped {} segments shorter than k",
+        contigs.len(), short_segments
+    );
+    contigs
+}
+
+/// Start of every node transcript `tx_segments` passes through, in the
+/// coordinates of its concatenated segments, sorted by node id. A start is
+/// negative if the transcript begins inside the node.
+pub(crate) fn transcript_node_starts<K: Kmer + Sync + Send>(
+    index: &Pseudoaligner<K>,
+    tx_segments: &[DnaString],
+) -> Vec<(u32, i32)> {
+    let mut segment_start = 0;
+    let placed = tx_segments.iter().map(|seq| {
+        let start = segment_start;
+        segment_start += seq.len();
+        (start, seq)
+    });
+    placed_node_starts(index, placed)
+}
+
+/// `transcript_node_starts` of segments given with their start in the
+/// transcript.
+fn placed_node_starts<'a, K, I>(index: &Pseudoaligner<K>, tx_segments: I) -> Vec<(u32, i32)>
+where
+    K: Kmer + Sync + Send,
+    I: IntoIterator<Item = (usize, &'a DnaString)>,
+{
+    let mut starts = Vec::new();
+
+    for (segment_start, seq) in tx_segments {
+        if seq.len() >= K::k() {
+            let mut last = None;
+            for (pos, kmer) in seq.iter_kmers::<K>().enumerate() {
+                match index.find_kmer(&kmer) {
+                    Some((node_id, offset)) => {
+                        let start = ((segment_start + pos) as i64 - offset as i64) as i32;
+                        if last != Some((node_id as u32, start)) {
+                            starts.push((node_id as u32, start));
+                            last = Some((node_id as u32, start));
+                        }
+                    }
+                    None => last = None,
+                }
+            }
+        }
+    }
+
+    starts.sort_unstable();
+    starts.dedup();
+    starts
+}
+
+/// Total length of the segments of every transcript.
+pub(crate) fn segment_lengths(segments: &[Vec<DnaString>]) -> Vec<u32> {
+    segments
+        .iter()
+        .map(|tx_segments| tx_segments.iter().map(|seq| seq.len() as u32).sum())
+        .collect()
+}
+
+/// `utils::gc_content` of the segments of every transcript taken together.
+pub(crate) fn segment_gc_contents(segments: &[Vec<DnaString>]) -> Vec<f64> {
+    segments
+        .iter()
+        .map(|tx_segments| utils::gc_fraction(tx_segments.iter().flat_map(|seq| seq.iter())))
+        .collect()
+}
+
+/// Build an index from `(transcript index, sequence, extensions)` triples;
+/// a transcript may contribute several sequences. The extensions give the
+/// bases known to precede and follow a sequence, empty for whole transcripts.
+/// The k-mers are stored canonically unless `stranded`, and the k-mer
+/// buckets are spilled to disk if `spill` is given.
+fn build_index_from_contigs<K: Kmer + Sync + Send>(
+    contigs: &[(u32, &DnaString, Exts)],
+    tx_names: &[String],
+    tx_gene_map: &HashMap<String, String>,
+    stranded: bool,
+    spill: Option<&SpillOptions>,
+    progress: &Progress,
+) -> Result<Pseudoaligner<K>, Error> {
+    let (dbg, eq_classes) = match spill {
+        Some(spill) => assemble_contigs_on_disk::<K>(contigs, stranded, spill, progress)?,
+        None => {
+            let (shard_dbgs, eq_classes) = assemble_contigs::<K>(contigs, stranded, progress)?;
+            println!("Starting merging disjoint graphs");
+            let dbg = merge_shard_dbgs(shard_dbgs, stranded, progress);
+            println!("Merger of graphs complete");
+            (dbg, eq_classes)
+        }
+    };
+
+    Ok(index_graph(dbg, eq_classes, tx_names, tx_gene_map, stranded, progress))
+}
+
+/// Bucket the k-mers of `contigs` by minimizer and assemble every bucket
+/// into a graph, labelling k-mers with equivalence classes.
+pub(crate) fn assemble_contigs<K: Kmer + Sync + Send>(
+    contigs: &[(u32, &DnaString, Exts)],
+    stranded: bool,
+    progress: &Progress,
+) -> Result<ShardGraphs<K>, Error> {
     // Thread pool Configuration for calling BOOMphf
     rayon::ThreadPoolBuilder::new()
         .num_threads(MAX_WORKER)
         .build()?;
 
-    if seqs.len() >= U32_MAX {
-        panic!("Too many ({}) sequences to handle.", seqs.len());
-    }
-
     println!("Sharding sequences...");
 
-    let mut buckets: Vec<_> = seqs
+    let mut buckets: Vec<_> = contigs
         .into_par_iter()
-        .enumerate()
-        .flat_map(|(id, seq)| partition_contigs::<KmerType>(seq, id as u32))
+        .flat_map(|&(id, seq, exts)| partition_contigs::<KmerType>(seq, id, exts, stranded))
         .collect();
-
-    buckets.par_sort_unstable_by_key(|x| x.0);
     println!("Got {} sequence chunks", buckets.len());
 
     let summarizer = Arc::new(debruijn::filter::CountFilterEqClass::new(MIN_KMERS));
-    let sequence_shards = group_by_slices(&buckets, |x| x.0, MIN_SHARD_SEQUENCES);
+    let shard_dbgs = assemble_buckets::<K>(&mut buckets, &summarizer, stranded, progress);
+
+    println!();
+    println!("Done separate de Bruijn graph construction");
+
+    //println!("{:?}", summarizer);
+    // TODO update rust-debruijn version and fix this
+    let eq_classes = summarizer.get_eq_classes();
+    Ok((shard_dbgs, eq_classes))
+}
+
+/// Like `assemble_contigs` followed by `merge_shard_dbgs`, but holding only
+/// one partition of the buckets in memory at a time: the sequence chunks
+/// are first written to a partition file per range of buckets, then every
+/// partition is read back, assembled and appended to the merged graph
+/// before the next one is read. Partitions are sized so that their k-mers
+/// fit in the memory budget. They hold whole shards of an in-memory build,
+/// so the index is the same.
+pub(crate) fn assemble_contigs_on_disk<K: Kmer + Sync + Send>(
+    contigs: &[(u32, &DnaString, Exts)],
+    stranded: bool,
+    spill: &SpillOptions,
+    progress: &Progress,
+) -> Result<(DebruijnGraph<K, EqClassIdType>, Vec<Vec<u32>>), Error> {
+    let (bucket_chunks, bucket_bases) = bucket_sizes(contigs, stranded);
+    let total_bases: usize = bucket_bases.iter().sum();
+    let partition_bases = (spill.memory_budget / SPILL_BYTES_PER_KMER).max(total_bases / MAX_SPILL_PARTITIONS + 1);
+    let bucket_partitions = partition_buckets(&bucket_chunks, &bucket_bases, partition_bases);
+    let num_partitions = bucket_partitions.last().map_or(1, |&partition| partition + 1);
+
+    let spill_dir = SpillDir::create(&spill.temp_dir)?;
+    println!(
+        "Spilling sequence chunks to {} partitions in {}",
+        num_partitions, spill_dir.path.display()
+    );
+
+    let mut writers = Vec::with_capacity(num_partitions);
+    for partition in 0..num_partitions {
+        writers.push(BufWriter::new(File::create(spill_dir.partition_path(partition))?));
+    }
+    let mut partition_sizes = vec![0usize; num_partitions];
+
+    for batch in contigs.chunks(SPILL_BATCH_CONTIGS) {
+        let chunks: Vec<_> = batch
+            .into_par_iter()
+            .flat_map(|&(id, seq, exts)| partition_contigs::<KmerType>(seq, id, exts, stranded))
+            .collect();
+        for (bucket_id, id, slice, exts) in chunks {
+            // whole buckets go to one partition, so no k-mer is split across two
+            let partition = bucket_partitions[bucket_id as usize];
+            serialize_into(&mut writers[partition], &(bucket_id, id, slice.to_owned(), exts))?;
+            partition_sizes[partition] += 1;
+        }
+    }
+    for mut writer in writers {
+        writer.flush()?;
+    }
+
+    let summarizer = Arc::new(debruijn::filter::CountFilterEqClass::new(MIN_KMERS));
+    let mut spill_error = None;
+    let shard_dbgs = (0..num_partitions)
+        .filter(|&partition| partition_sizes[partition] > 0)
+        .map(|partition| {
+            assemble_partition::<K>(&spill_dir, partition, partition_sizes[partition], &summarizer, stranded, progress)
+        })
+        .scan(&mut spill_error, |spill_error, result| match result {
+            Ok(shard_dbgs) => Some(shard_dbgs),
+            Err(err) => {
+                **spill_error = Some(err);
+                None
+            }
+        })
+        .flatten();
+
+    // `combine` takes the shards as the partitions are assembled, so only
+    // the merged graph and the shards of one partition are held at a time
+    println!("Starting merging disjoint graphs");
+    let combined_graph = BaseGraph::combine(shard_dbgs).finish();
+    if let Some(err) = spill_error {
+        return Err(err);
+    }
+    progress.start(Phase::MergeGraphs, None);
+    let dbg = compress_graph(stranded, &ScmapCompress::new(), combined_graph, None);
+    progress.finish(Phase::MergeGraphs);
+    println!("Merger of graphs complete");
+
+    let eq_classes = summarizer.get_eq_classes();
+    Ok((dbg, eq_classes))
+}
+
+/// Number of sequence chunks and bases of every bucket of `contigs`.
+fn bucket_sizes(contigs: &[(u32, &DnaString, Exts)], stranded: bool) -> (Vec<usize>, Vec<usize>) {
+    let mut bucket_chunks = vec![0usize; PERM.len()];
+    let mut bucket_bases = vec![0usize; PERM.len()];
+    for batch in contigs.chunks(SPILL_BATCH_CONTIGS) {
+        let chunks: Vec<_> = batch
+            .into_par_iter()
+            .flat_map(|&(id, seq, exts)| partition_contigs::<KmerType>(seq, id, exts, stranded))
+            .map(|(bucket_id, _, slice, _)| (bucket_id, slice.len()))
+            .collect();
+        for (bucket_id, len) in chunks {
+            bucket_chunks[bucket_id as usize] += 1;
+            bucket_bases[bucket_id as usize] += len;
+        }
+    }
+    (bucket_chunks, bucket_bases)
+}
+
+/// Read back the sequence chunks of a spilled partition, delete its file
+/// and assemble it.
+fn assemble_partition<K: Kmer + Sync + Send>(
+    spill_dir: &SpillDir,
+    partition: usize,
+    size: usize,
+    summarizer: &Arc<CountFilterEqClass<u32>>,
+    stranded: bool,
+    progress: &Progress,
+) -> Result<Vec<BaseGraph<K, EqClassIdType>>, Error> {
+    let path = spill_dir.partition_path(partition);
+    let mut reader = BufReader::new(File::open(&path)?);
+    let mut chunks: Vec<SpilledChunk> = Vec::with_capacity(size);
+    for _ in 0..size {
+        chunks.push(deserialize_from(&mut reader)?);
+    }
+    fs::remove_file(&path)?;
+
+    println!("Partition {}: {} sequence chunks", partition, size);
+    let mut buckets: Vec<_> = chunks
+        .iter()
+        .map(|&(bucket_id, id, ref seq, exts)| (bucket_id, id, seq.slice(0, seq.len()), exts))
+        .collect();
+    Ok(assemble_buckets::<K>(&mut buckets, summarizer, stranded, progress))
+}
+
+/// Partition of every bucket of a spilled build, given the number of
+/// sequence chunks and bases of every bucket: ranges of consecutive shards
+/// among those `assemble_buckets` makes of all the chunks, each of at most
+/// `partition_bases` bases unless it is a single larger shard. As a
+/// partition starts with a shard, `assemble_buckets` splits it into the
+/// same shards.
+fn partition_buckets(bucket_chunks: &[usize], bucket_bases: &[usize], partition_bases: usize) -> Vec<usize> {
+    // first bucket and bases of every shard
+    let mut shards: Vec<(usize, usize)> = Vec::new();
+    let mut shard_chunks = 0;
+    for (bucket, &chunks) in bucket_chunks.iter().enumerate() {
+        // as in `group_by_slices`, a shard ends at the first bucket after
+        // it holds more than `MIN_SHARD_SEQUENCES` chunks
+        if shards.is_empty() || (chunks > 0 && shard_chunks > MIN_SHARD_SEQUENCES) {
+            shards.push((bucket, 0));
+            shard_chunks = 0;
+        }
+        shards.last_mut().unwrap().1 += bucket_bases[bucket];
+        shard_chunks += chunks;
+    }
+
+    let mut partitions = vec![0; bucket_chunks.len()];
+    let (mut partition, mut bases) = (0, 0);
+    for (shard, &(first_bucket, shard_bases)) in shards.iter().enumerate() {
+        if bases > 0 && bases + shard_bases > partition_bases {
+            partition += 1;
+            bases = 0;
+        }
+        let end = shards.get(shard + 1).map_or(bucket_chunks.len(), |&(bucket, _)| bucket);
+        for bucket_partition in &mut partitions[first_bucket..end] {
+            *bucket_partition = partition;
+        }
+        bases += shard_bases;
+    }
+    partitions
+}
+
+/// Sort sequence chunks by bucket and assemble them in shards of whole buckets.
+fn assemble_buckets<K: Kmer + Sync + Send>(
+    buckets: &mut [(u16, u32, DnaStringSlice, Exts)],
+    summarizer: &Arc<CountFilterEqClass<u32>>,
+    stranded: bool,
+    progress: &Progress,
+) -> Vec<BaseGraph<K, EqClassIdType>> {
+    buckets.par_sort_unstable_by_key(|x| x.0);
+    let sequence_shards = group_by_slices(buckets, |x| x.0, MIN_SHARD_SEQUENCES);
 
     let mut shard_dbgs = Vec::with_capacity(sequence_shards.len());
 
     println!("Assembling {} shards...", sequence_shards.len());
+    progress.start(Phase::CountKmers, Some(sequence_shards.len()));
+    let shards_done = AtomicUsize::new(0);
 
     sequence_shards
         .into_par_iter()
         .map_with(summarizer.clone(), |s, strings| {
-            assemble_shard::<K>(strings, s)
+            let shard_dbg = assemble_shard::<K>(strings, s, stranded);
+            progress.update(Phase::CountKmers, shards_done.fetch_add(1, Ordering::Relaxed) + 1);
+            shard_dbg
         }).collect_into_vec(&mut shard_dbgs);
+    progress.finish(Phase::CountKmers);
+    shard_dbgs
+}
 
-    println!();
-    println!("Done separate de Bruijn graph construction");
-    println!("Starting merging disjoint graphs");
+/// A fresh directory for spill files, removed when dropped so that neither
+/// a finished nor a failed build leaves its spill files behind.
+struct SpillDir {
+    path: PathBuf,
+}
 
-    //println!("{:?}", summarizer);
-    let dbg = merge_shard_dbgs(shard_dbgs);
-    println!("Merger of graphs complete");
+impl SpillDir {
+    fn create(temp_dir: &Path) -> Result<SpillDir, Error> {
+        let nanos = SystemTime::now().duration_since(UNIX_EPOCH)?.subsec_nanos();
+        let path = temp_dir.join(format!("pseudoaligner-spill-{}-{}", process::id(), nanos));
+        fs::create_dir_all(&path)?;
+        Ok(SpillDir { path })
+    }
 
-    // TODO update rust-debruijn version and fix this
-    let eq_classes = summarizer.get_eq_classes();
+    fn partition_path(&self, partition: usize) -> PathBuf {
+        self.path.join(format!("partition-{}.bin", partition))
+    }
+}
 
+impl Drop for SpillDir {
+    fn drop(&mut self) {
+        if let Err(err) = fs::remove_dir_all(&self.path) {
+            warn!("Could not remove spill directory {}: {}", self.path.display(), err);
+        }
+    }
+}
+
+/// Index the k-mers of the merged graph.
+pub(crate) fn index_graph<K: Kmer + Sync + Send>(
+    mut dbg: DebruijnGraph<K, EqClassIdType>,
+    eq_classes: Vec<Vec<u32>>,
+    tx_names: &[String],
+    tx_gene_map: &HashMap<String, String>,
+    stranded: bool,
+    progress: &Progress,
+) -> Pseudoaligner<K> {
+    let eq_classes = renumber_eq_classes(&mut dbg, eq_classes);
     println!("Indexing de Bruijn graph");
-    let dbg_index = make_dbg_index(&dbg);
-    Ok(Pseudoaligner::new(
-        dbg, eq_classes, dbg_index, tx_names.clone(), tx_gene_map.clone()
-    ))
+    let dbg_index = make_dbg_index(&dbg, stranded, progress);
+    let mut index = Pseudoaligner::new(
+        dbg, eq_classes, dbg_index, tx_names.to_vec(), tx_gene_map.clone()
+    );
+    index.stranded = stranded;
+    index
+}
+
+/// Reference records a build from FASTA files left out of the index, the
+/// transcripts it found without k-mers, and what it found in those it kept.
+#[derive(Clone, Debug, Default, PartialEq, Serialize, Deserialize)]
+pub struct BuildReport {
+    /// Records shorter than `ReferenceOptions::min_seq_len`
+    pub skipped_records: SkippedRecords,
+    /// Number of low-complexity bases masked by `ReferenceOptions::mask_entropy`,
+    /// for every transcript with any, in transcript order
+    pub masked_bases: Vec<(String, usize)>,
+    /// Number of soft-masked bases left out by `ReferenceOptions::honor_softmask`,
+    /// for every transcript with any, in transcript order
+    pub softmasked_bases: Vec<(String, usize)>,
+    /// Transcripts without k-mers, dropped unless `ReferenceOptions::keep_short`
+    pub short_transcripts: Vec<String>,
+    /// New index of every transcript read once those in
+    /// `ReferenceOptions::exclude` are dropped, `None` for the excluded ones,
+    /// as given by `excluded_index_map`
+    pub excluded_index_map: Vec<Option<u32>>,
+    /// `utils::record_gc_content` of every transcript kept, in index order
+    pub gc_contents: Vec<f64>,
+}
+
+/// Number the equivalence classes in sorted order and relabel the nodes of
+/// `dbg` to match. The shards are labelled in parallel, so the ids the
+/// summarizer hands out depend on thread timing; sorted ids make builds of
+/// the same reference identical.
+fn renumber_eq_classes<K: Kmer>(
+    dbg: &mut DebruijnGraph<K, EqClassIdType>,
+    eq_classes: Vec<Vec<u32>>,
+) -> Vec<Vec<u32>> {
+    let mut numbered: Vec<(usize, Vec<u32>)> = eq_classes.into_iter().enumerate().collect();
+    numbered.sort_by(|a, b| a.1.cmp(&b.1));
+
+    let mut new_ids = vec![0; numbered.len()];
+    for (new_id, &(old_id, _)) in numbered.iter().enumerate() {
+        new_ids[old_id] = new_id as EqClassIdType;
+    }
+    for eq_class_id in dbg.base.data.iter_mut() {
+        *eq_class_id = new_ids[*eq_class_id as usize];
+    }
+
+    numbered.into_iter().map(|(_, eq_class)| eq_class).collect()
+}
+
+/// Build an index over the transcripts of several FASTA files, recording
+/// which file every transcript came from.
+pub fn build_index_from_fasta<K: Kmer + Sync + Send>(
+    fasta_paths: Vec<PathBuf>,
+    options: &ReferenceOptions,
+) -> Result<Pseudoaligner<K>, PseudoalignerError> {
+    build_index_from_fasta_with_report(fasta_paths, options).map(|(index, _)| index)
+}
+
+/// Like `build_index_from_fasta`, also returning the report of the records
+/// left out of the index.
+pub fn build_index_from_fasta_with_report<K: Kmer + Sync + Send>(
+    fasta_paths: Vec<PathBuf>,
+    options: &ReferenceOptions,
+) -> Result<(Pseudoaligner<K>, BuildReport), PseudoalignerError> {
+    Ok(index_fasta(fasta_paths, options, None)?)
+}
+
+/// Like `build_index_from_fasta`, but spilling the k-mer buckets to disk,
+/// for references whose k-mers do not fit in memory.
+pub fn build_index_from_fasta_on_disk<K: Kmer + Sync + Send>(
+    fasta_paths: Vec<PathBuf>,
+    options: &ReferenceOptions,
+    spill: &SpillOptions,
+) -> Result<Pseudoaligner<K>, PseudoalignerError> {
+    let (index, _) = index_fasta(fasta_paths, options, Some(spill))?;
+    Ok(index)
+}
+
+fn index_fasta<K: Kmer + Sync + Send>(
+    fasta_paths: Vec<PathBuf>,
+    options: &ReferenceOptions,
+    spill: Option<&SpillOptions>,
+) -> Result<(Pseudoaligner<K>, BuildReport), Error> {
+    let ((segments, tx_names, tx_gene_map, file_ids), checksums, gene_names, report) =
+        read_reference_segments::<K>(&fasta_paths, options)?;
+    let mut index = index_segments::<K>(&segments, &tx_names, &tx_gene_map, spill, &options.progress)?;
+    index.gene_names = gene_names;
+    index.tx_gc_contents = report.gc_contents.clone();
+
+    index.source_names = fasta_paths.iter().map(|p| source_name(p)).collect();
+    index.tx_sources = file_ids.into_iter().map(|id| id as u8).collect();
+    index.reference_checksums = checksums;
+    Ok((index, report))
+}
+
+/// Read the transcripts of `fasta_paths` as segments for
+/// `build_index_from_segments`, as requested by `options`, along with the
+/// checksum of every file, the gene names found in the headers and the
+/// records left out.
+pub(crate) fn read_reference_segments<K: Kmer>(
+    fasta_paths: &[PathBuf],
+    options: &ReferenceOptions,
+) -> Result<ReferenceInput, Error> {
+    if fasta_paths.len() > u8::MAX as usize + 1 {
+        return Err(failure::err_msg(format!(
+            "Too many ({}) reference fasta files", fasta_paths.len()
+        )));
+    }
+
+    let mut checksums = Vec::with_capacity(fasta_paths.len());
+    let mut gene_names = HashMap::new();
+    let mut report = BuildReport::default();
+    let (reference, gc_contents) = if let Some(ref spans) = options.spans {
+        if options.split_at_n || options.honor_softmask {
+            return Err(failure::err_msg(
+                "Transcript spans can not be combined with splitting at N or soft-masked bases"
+            ));
+        }
+        let (records, tx_names, tx_gene_map, file_ids) = utils::read_multiple_fasta_records(
+            fasta_paths, options, with_gc_content(utils::hashed_sequence), Some(&mut checksums),
+            Some(&mut gene_names), Some(&mut report.skipped_records),
+        )?;
+        let (seqs, gc_contents): (Vec<_>, Vec<_>) = records.into_iter().unzip();
+        let segments = restrict_to_spans(&seqs, &tx_names, spans)?;
+        ((segments, tx_names, tx_gene_map, file_ids), gc_contents)
+    } else if options.split_at_n || options.honor_softmask {
+        let to_segments = match (options.split_at_n, options.honor_softmask) {
+            (true, true) => utils::softmask_split_sequence,
+            (false, true) => utils::softmask_hashed_sequence,
+            _ => utils::split_sequence_unmasked,
+        };
+        let (records, tx_names, tx_gene_map, file_ids) = utils::read_multiple_fasta_records(
+            fasta_paths, options, with_gc_content(to_segments), Some(&mut checksums), Some(&mut gene_names),
+            Some(&mut report.skipped_records),
+        )?;
+        let (seqs, gc_contents): (Vec<_>, Vec<_>) = records.into_iter().unzip();
+        let (segments, softmasked): (Vec<_>, Vec<_>) = seqs.into_iter().unzip();
+        for (tx_name, &masked_bases) in tx_names.iter().zip(&softmasked) {
+            if masked_bases > 0 {
+                info!("Soft-masked {} bases of {}", masked_bases, tx_name);
+                report.softmasked_bases.push((tx_name.clone(), masked_bases));
+            }
+        }
+        ((segments, tx_names, tx_gene_map, file_ids), gc_contents)
+    } else {
+        let (records, tx_names, tx_gene_map, file_ids) = utils::read_multiple_fasta_records(
+            fasta_paths, options, with_gc_content(utils::hashed_sequence), Some(&mut checksums),
+            Some(&mut gene_names), Some(&mut report.skipped_records),
+        )?;
+        let (seqs, gc_contents): (Vec<DnaString>, Vec<_>) = records.into_iter().unzip();
+        let segments = seqs.into_iter().map(|seq| vec![seq]).collect();
+        ((segments, tx_names, tx_gene_map, file_ids), gc_contents)
+    };
+    // transcripts are unique by name once read, whichever are left out below
+    let gc_contents: HashMap<String, f64> = reference.1.iter().cloned().zip(gc_contents).collect();
+
+    let ((segments, tx_names, tx_gene_map, file_ids), excluded_index_map) =
+        exclude_transcripts(reference, &options.exclude);
+    report.excluded_index_map = excluded_index_map;
+
+    let segments = match options.mask_entropy {
+        Some(min_entropy) => mask_segments(segments, &tx_names, min_entropy, &mut report.masked_bases),
+        None => segments,
+    };
+    let reference = drop_short_transcripts::<K>(
+        (segments, tx_names, tx_gene_map, file_ids), options.keep_short, &mut report.short_transcripts
+    );
+    report.gc_contents = reference.1.iter().map(|tx_name| gc_contents[tx_name]).collect();
+    Ok((reference, checksums, gene_names, report))
+}
+
+/// `to_seq` paired with the `utils::record_gc_content` of the record, which
+/// still has the ambiguous bases `to_seq` hashes or splits at.
+fn with_gc_content<S>(to_seq: fn(&fasta::Record) -> S) -> impl Fn(&fasta::Record) -> (S, f64) {
+    move |record| (to_seq(record), utils::record_gc_content(record))
+}
+
+/// New index of every transcript of `tx_names` once those in `exclude` are
+/// dropped and the others numbered densely in order, `None` for the
+/// excluded ones.
+pub fn excluded_index_map(tx_names: &[String], exclude: &HashSet<String>) -> Vec<Option<u32>> {
+    let mut num_kept = 0;
+    tx_names
+        .iter()
+        .map(|tx_name| {
+            if exclude.contains(tx_name) {
+                None
+            } else {
+                num_kept += 1;
+                Some(num_kept - 1)
+            }
+        })
+        .collect()
+}
+
+/// Drop the transcripts named in `exclude` from `reference`, returning the
+/// others with the `excluded_index_map` from old to new transcript indices.
+pub(crate) fn exclude_transcripts(
+    reference: ReferenceSegments,
+    exclude: &HashSet<String>,
+) -> (ReferenceSegments, Vec<Option<u32>>) {
+    let (segments, tx_names, mut tx_gene_map, file_ids) = reference;
+    let new_ids = excluded_index_map(&tx_names, exclude);
+    if exclude.is_empty() {
+        return ((segments, tx_names, tx_gene_map, file_ids), new_ids);
+    }
+
+    let num_excluded = new_ids.iter().filter(|new_id| new_id.is_none()).count();
+    info!("Excluding {} transcripts", num_excluded);
+    if num_excluded < exclude.len() {
+        warn!("{} transcripts to exclude are not in the reference", exclude.len() - num_excluded);
+    }
+
+    let mut kept = (Vec::new(), Vec::new(), HashMap::new(), Vec::new());
+    let records = segments.into_iter().zip(tx_names).zip(file_ids).zip(&new_ids);
+    for (((tx_segments, tx_name), file_id), new_id) in records {
+        if new_id.is_some() {
+            if let Some(gene) = tx_gene_map.remove(&tx_name) {
+                kept.2.insert(tx_name.clone(), gene);
+            }
+            kept.0.push(tx_segments);
+            kept.1.push(tx_name);
+            kept.3.push(file_id);
+        }
+    }
+    (kept, new_ids)
+}
+
+/// Drop the transcripts without a segment of at least k bases, which have
+/// no k-mers to index, or keep them without k-mers if `keep_short` is set.
+/// Either way each is warned about once and added to `short`.
+fn drop_short_transcripts<K: Kmer>(
+    reference: ReferenceSegments,
+    keep_short: bool,
+    short: &mut Vec<String>,
+) -> ReferenceSegments {
+    let (segments, tx_names, mut tx_gene_map, file_ids) = reference;
+    let is_short: Vec<bool> = segments
+        .iter()
+        .map(|tx_segments| tx_segments.iter().all(|seq| seq.len() < K::k()))
+        .collect();
+    let short_names: Vec<&str> = tx_names
+        .iter()
+        .zip(&is_short)
+        .filter(|&(_, &short)| short)
+        .map(|(tx_name, _)| tx_name.as_str())
+        .collect();
+    if short_names.is_empty() {
+        return (segments, tx_names, tx_gene_map, file_ids);
+    }
+    short.extend(short_names.iter().map(|tx_name| tx_name.to_string()));
+
+    for tx_name in &short_names {
+        warn!("Transcript {} is shorter than k = {} and has no k-mers", tx_name, K::k());
+    }
+    if keep_short {
+        info!("Keeping {} transcripts shorter than k = {} without k-mers", short_names.len(), K::k());
+        return (segments, tx_names, tx_gene_map, file_ids);
+    }
+    info!("Dropping {} transcripts shorter than k = {}", short_names.len(), K::k());
+    for tx_name in &short_names {
+        tx_gene_map.remove(*tx_name);
+    }
+
+    let mut kept = (Vec::new(), Vec::new(), tx_gene_map, Vec::new());
+    let records = segments.into_iter().zip(tx_names).zip(file_ids).zip(is_short);
+    for (((tx_segments, tx_name), file_id), short) in records {
+        if !short {
+            kept.0.push(tx_segments);
+            kept.1.push(tx_name);
+            kept.3.push(file_id);
+        }
+    }
+    kept
+}
+
+/// Cut the low-complexity windows out of the segments of every transcript,
+/// adding how many bases of each were masked to `masked`.
+fn mask_segments(
+    segments: Vec<Vec<DnaString>>,
+    tx_names: &[String],
+    min_entropy: f64,
+    masked: &mut Vec<(String, usize)>,
+) -> Vec<Vec<DnaString>> {
+    let mut total_masked = 0;
+    let mut masked_transcripts = 0;
+
+    let segments = segments
+        .into_iter()
+        .zip(tx_names)
+        .map(|(tx_segments, tx_name)| {
+            let mut kept = Vec::new();
+            let mut masked_bases = 0;
+            for seq in &tx_segments {
+                let (seq_segments, seq_masked) =
+                    utils::mask_low_complexity(seq, LOW_COMPLEXITY_WINDOW, min_entropy);
+                kept.extend(seq_segments);
+                masked_bases += seq_masked;
+            }
+
+            if masked_bases > 0 {
+                info!("Masked {} low-complexity bases of {}", masked_bases, tx_name);
+                total_masked += masked_bases;
+                masked_transcripts += 1;
+                masked.push((tx_name.clone(), masked_bases));
+            }
+            kept
+        })
+        .collect();
+
+    info!(
+        "Masked {} low-complexity bases in {} transcripts",
+        total_masked, masked_transcripts
+    );
+    segments
+}
+
+/// Add the transcripts of `fasta_paths` to the index stored at `index_path`.
+/// The transcripts of the index keep their indices and the new ones follow;
+/// equivalence classes are recomputed. Refuses indices built with another k
+/// and transcript ids already in the index.
+pub fn append_to_index<K: Kmer + Sync + Send + DeserializeOwned, P: AsRef<Path>>(
+    index_path: P,
+    fasta_paths: Vec<PathBuf>,
+    options: &ReferenceOptions,
+) -> Result<Pseudoaligner<K>, PseudoalignerError> {
+    let metadata = utils::read_index_metadata(&index_path)?;
+    if metadata.kmer_length != K::k() {
+        return Err(PseudoalignerError::KmerLengthMismatch {
+            index: index_path.as_ref().display().to_string(),
+            found: metadata.kmer_length,
+            expected: K::k(),
+        });
+    }
+    let index: Pseudoaligner<K> = utils::read_index(&index_path)?;
+
+    let num_sources = index.source_names.len() + fasta_paths.len();
+    if num_sources > u8::MAX as usize + 1 {
+        return Err(PseudoalignerError::Other(format!(
+            "Too many ({}) reference fasta files", num_sources
+        )));
+    }
+
+    let ((segments, tx_names, tx_gene_map, file_ids), checksums, gene_names, report) =
+        read_reference_segments::<K>(&fasta_paths, options)?;
+    let known: Vec<&str> = tx_names
+        .iter()
+        .filter(|tx_name| index.tx_gene_mapping.contains_key(tx_name.as_str()))
+        .map(|tx_name| tx_name.as_str())
+        .collect();
+    if !known.is_empty() {
+        return Err(PseudoalignerError::DuplicateTranscripts(known.join(", ")));
+    }
+
+    let mut new_index = extend_index(&index, &segments, &tx_names, &tx_gene_map)?;
+
+    // transcripts of an index without recorded sources stay unattributed
+    if index.tx_sources.len() == index.tx_names.len() {
+        let first_source = index.source_names.len();
+        new_index.source_names = index.source_names.clone();
+        new_index.source_names.extend(fasta_paths.iter().map(|p| source_name(p)));
+        new_index.tx_sources = index.tx_sources.clone();
+        new_index.tx_sources.extend(file_ids.into_iter().map(|id| (first_source + id) as u8));
+
+        if index.reference_checksums.len() == index.source_names.len() {
+            new_index.reference_checksums = index.reference_checksums.clone();
+            new_index.reference_checksums.extend(checksums);
+        }
+    }
+    if index.tx_checksums.len() == index.tx_names.len() {
+        new_index.tx_checksums = index.tx_checksums.clone();
+        new_index.tx_checksums.extend(segments.iter().map(|tx_segments| utils::transcript_checksum(tx_segments)));
+    }
+    if index.tx_gc_contents.len() == index.tx_names.len() {
+        new_index.tx_gc_contents = index.tx_gc_contents.clone();
+        new_index.tx_gc_contents.extend(report.gc_contents);
+    }
+    // the appended transcripts are grouped by their source file
+    new_index.tx_groups = index.tx_groups.clone();
+    new_index.gene_names = gene_names;
+    new_index.gene_names.extend(index.gene_names.iter().map(|(gene_id, name)| (gene_id.clone(), name.clone())));
+    Ok(new_index)
+}
+
+/// A new index holding the transcripts of `index` followed by the given
+/// ones. The old transcripts are recovered from the graph: every node is
+/// re-indexed once per transcript of its equivalence class, keeping its
+/// extensions so that the old edges survive. If `index` records node
+/// positions on its transcripts, those of the new index are found again
+/// from the old transcripts laid out from their nodes and from the new
+/// segments.
+pub fn extend_index<K: Kmer + Sync + Send>(
+    index: &Pseudoaligner<K>,
+    segments: &[Vec<DnaString>],
+    tx_names: &[String],
+    tx_gene_map: &HashMap<String, String>,
+) -> Result<Pseudoaligner<K>, Error> {
+    let num_old = index.tx_names.len();
+    if num_old + segments.len() >= U32_MAX {
+        panic!("Too many ({}) sequences to handle.", num_old + segments.len());
+    }
+
+    let nodes: Vec<(DnaString, Exts, EqClassIdType)> = index
+        .dbg
+        .iter_nodes()
+        .map(|node| (node.sequence().to_owned(), node.exts(), *node.data()))
+        .collect();
+
+    let mut contigs = Vec::new();
+    for &(ref seq, exts, eq_class_id) in &nodes {
+        for &tx_id in &index.eq_classes[eq_class_id as usize] {
+            contigs.push((tx_id, seq, exts));
+        }
+    }
+    for (id, tx_segments) in segments.iter().enumerate() {
+        for seq in tx_segments {
+            if seq.len() >= K::k() {
+                contigs.push(((num_old + id) as u32, seq, Exts::empty()));
+            }
+        }
+    }
+
+    let mut all_tx_names = index.tx_names.clone();
+    all_tx_names.extend(tx_names.iter().cloned());
+    let mut all_tx_gene_map = index.tx_gene_mapping.clone();
+    all_tx_gene_map.extend(tx_gene_map.iter().map(|(tx, gene)| (tx.clone(), gene.clone())));
+
+    info!(
+        "Re-indexing {} nodes and adding {} transcripts",
+        nodes.len(), segments.len()
+    );
+    let mut new_index = build_index_from_contigs(
+        &contigs, &all_tx_names, &all_tx_gene_map, index.stranded, None, &Progress::default()
+    )?;
+    if let Some(window) = index.minimizer_window {
+        sparsify_index(&mut new_index, window);
+    }
+
+    // lengths are only known if the old index recorded them
+    if index.tx_lengths.len() == num_old {
+        new_index.tx_lengths = index.tx_lengths.clone();
+        new_index.tx_lengths.extend(
+            segments.iter().map(|tx_segments| tx_segments.iter().map(|seq| seq.len() as u32).sum::<u32>())
+        );
+    }
+    if index.tx_node_starts.len() == num_old && index.tx_lengths.len() == num_old {
+        let mut tx_node_starts: Vec<Vec<(u32, i32)>> = (0..num_old as u32)
+            .into_par_iter()
+            .map(|tx_id| {
+                let old_segments = laid_out_segments(index, tx_id);
+                placed_node_starts(&new_index, old_segments.iter().map(|(start, seq)| (*start, seq)))
+            })
+            .collect();
+        tx_node_starts.par_extend(segments.par_iter().map(|tx_segments| transcript_node_starts(&new_index, tx_segments)));
+        new_index.tx_node_starts = tx_node_starts;
+    }
+    if index.tx_gc_contents.len() == num_old {
+        new_index.tx_gc_contents = index.tx_gc_contents.clone();
+        new_index.tx_gc_contents.extend(segment_gc_contents(segments));
+    }
+    Ok(new_index)
+}
+
+/// The runs of bases of transcript `tx_id` of `index` that its nodes cover,
+/// with their start in the transcript.
+fn laid_out_segments<K: Kmer + Sync + Send>(index: &Pseudoaligner<K>, tx_id: u32) -> Vec<(usize, DnaString)> {
+    let bases = index.transcript_bases(tx_id).unwrap_or_default();
+    let mut segments = Vec::new();
+    let mut pos = 0;
+    while pos < bases.len() {
+        let run = bases[pos..].iter().take_while(|base| base.is_some()).count();
+        if run > 0 {
+            let run_bases: Vec<u8> = bases[pos..pos + run].iter().flatten().cloned().collect();
+            segments.push((pos, DnaString::from_bytes(&run_bases)));
+        }
+        pos += cmp::max(run, 1);
+    }
+    segments
+}
+
+/// Changes made to an index once it is built. A checkpointed build records
+/// them in its work directory, so that a resumed build makes them too.
+#[derive(Clone, Debug, Default, PartialEq, Serialize, Deserialize)]
+pub struct IndexOptions {
+    /// Minimizer window to `sparsify_index` the index with
+    pub sparse: Option<usize>,
+    /// `utils::read_groups` file of the groups of the transcripts
+    pub groups: Option<PathBuf>,
+    /// `utils::read_t2g` file of the genes of the transcripts
+    pub t2g: Option<PathBuf>,
+    /// What to do with transcripts missing from the `t2g` file
+    #[serde(default)]
+    pub missing_genes: MissingGenePolicy,
+}
+
+/// Make the changes of `options` to the built `index`.
+pub fn apply_index_options<K: Kmer + Sync + Send>(
+    index: &mut Pseudoaligner<K>,
+    options: &IndexOptions,
+) -> Result<(), Error> {
+    if let Some(window) = options.sparse {
+        sparsify_index(index, window);
+    }
+    if let Some(ref path) = options.groups {
+        index.set_tx_groups(utils::read_groups(path)?)?;
+    }
+    if let Some(ref path) = options.t2g {
+        let (tx_genes, gene_names) = utils::read_t2g(path)?;
+        index.set_tx_genes(tx_genes, gene_names, options.missing_genes)?;
+    }
+    Ok(())
+}
+
+/// Build an index of a reference given in partitions of transcript
+/// sequences and names, such as chromosomes or gene sets. The partitions
+/// are indexed on their own on `threads` threads, and their graphs are then
+/// merged the way `extend_index` re-indexes a graph: every node is indexed
+/// again once per transcript of its class, numbered after the transcripts
+/// of the earlier partitions and keeping its extensions. The merge is what
+/// makes the index correct: a k-mer shared by transcripts of different
+/// partitions only gets its full equivalence class there, and nodes are
+/// split where the graphs of the partitions meet. The result maps reads
+/// like an index built from all transcripts at once.
+pub fn build_index_partitioned<K: Kmer + Sync + Send>(
+    partitions: Vec<(Vec<DnaString>, Vec<String>)>,
+    tx_gene_map: &HashMap<String, String>,
+    threads: usize,
+) -> Result<Pseudoaligner<K>, PseudoalignerError> {
+    if partitions.is_empty() {
+        return Err(PseudoalignerError::Other("No partitions of transcripts to index".to_string()));
+    }
+    for (part, (seqs, tx_names)) in partitions.iter().enumerate() {
+        if seqs.len() != tx_names.len() {
+            return Err(PseudoalignerError::Other(format!(
+                "Partition {} has {} sequences but {} transcript names",
+                part, seqs.len(), tx_names.len()
+            )));
+        }
+    }
+
+    let mut seen = HashSet::new();
+    let duplicates: Vec<&str> = partitions
+        .iter()
+        .flat_map(|(_, tx_names)| tx_names)
+        .filter(|tx_name| !seen.insert(tx_name.as_str()))
+        .map(|tx_name| tx_name.as_str())
+        .collect();
+    if !duplicates.is_empty() {
+        return Err(PseudoalignerError::DuplicateTranscripts(duplicates.join(", ")));
+    }
+
+    let num_tx: usize = partitions.iter().map(|(seqs, _)| seqs.len()).sum();
+    if num_tx >= U32_MAX {
+        panic!("Too many ({}) sequences to handle.", num_tx);
+    }
+
+    let pool = rayon::ThreadPoolBuilder::new()
+        .num_threads(cmp::max(threads, 1))
+        .build()
+        .map_err(|err| PseudoalignerError::Other(err.to_string()))?;
+    pool.install(|| {
+        info!("Indexing {} partitions of the reference", partitions.len());
+        let sub_indices = partitions
+            .par_iter()
+            .map(|(seqs, tx_names)| build_index::<K>(seqs, tx_names, tx_gene_map))
+            .collect::<Result<Vec<_>, PseudoalignerError>>()?;
+
+        let mut nodes: Vec<(DnaString, Exts, Vec<u32>)> = Vec::new();
+        let mut first_tx = 0;
+        for (sub_index, (seqs, _)) in sub_indices.iter().zip(&partitions) {
+            for node in sub_index.dbg.iter_nodes() {
+                let tx_ids = sub_index.eq_classes[*node.data() as usize]
+                    .iter()
+                    .map(|&tx_id| first_tx + tx_id)
+                    .collect();
+                nodes.push((node.sequence().to_owned(), node.exts(), tx_ids));
+            }
+            first_tx += seqs.len() as u32;
+        }
+        drop(sub_indices);
+
+        let mut contigs = Vec::new();
+        for (seq, exts, tx_ids) in &nodes {
+            for &tx_id in tx_ids {
+                contigs.push((tx_id, seq, *exts));
+            }
+        }
+
+        let (seqs, tx_names): (Vec<&DnaString>, Vec<String>) = partitions
+            .iter()
+            .flat_map(|(seqs, tx_names)| seqs.iter().zip(tx_names.iter().cloned()))
+            .unzip();
+        info!("Merging {} nodes of the partitions", nodes.len());
+        let mut index =
+            build_index_from_contigs(&contigs, &tx_names, tx_gene_map, STRANDED, None, &Progress::default())?;
+        index.tx_lengths = seqs.iter().map(|seq| seq.len() as u32).collect();
+        index.tx_node_starts = seqs
+            .par_iter()
+            .map(|&seq| transcript_node_starts(&index, std::slice::from_ref(seq)))
+            .collect();
+        index.tx_checksums = seqs
+            .par_iter()
+            .map(|&seq| utils::transcript_checksum(std::slice::from_ref(seq)))
+            .collect();
+        Ok(index)
+    })
+}
+
+/// A standalone index of only the transcripts of `index` named in `keep`,
+/// numbered in their old order. Nodes whose equivalence class has none of
+/// them are dropped along with the edges to them, and the other nodes are
+/// re-indexed with their classes cut down to the kept transcripts, on which
+/// the node positions are found again. The checksums of the reference files
+/// are not carried over.
+pub fn subset_index<K: Kmer + Sync + Send>(
+    index: &Pseudoaligner<K>,
+    keep: &HashSet<String>,
+) -> Result<Pseudoaligner<K>, Error> {
+    let mut new_ids = vec![None; index.tx_names.len()];
+    let mut tx_names = Vec::new();
+    for (tx_id, tx_name) in index.tx_names.iter().enumerate() {
+        if keep.contains(tx_name) {
+            new_ids[tx_id] = Some(tx_names.len() as u32);
+            tx_names.push(tx_name.clone());
+        }
+    }
+    if tx_names.is_empty() {
+        return Err(failure::err_msg("None of the transcripts to keep are in the index"));
+    }
+    if tx_names.len() < keep.len() {
+        warn!("{} transcripts to keep are not in the index", keep.len() - tx_names.len());
+    }
+
+    let node_kept: Vec<bool> = index
+        .dbg
+        .iter_nodes()
+        .map(|node| index.eq_classes[*node.data() as usize].iter().any(|&tx_id| new_ids[tx_id as usize].is_some()))
+        .collect();
+    let is_kept = |kmer: &K| index.find_kmer_oriented(kmer).is_some_and(|(node_id, _, _)| node_kept[node_id]);
+
+    let mut nodes = Vec::new();
+    for node in index.dbg.iter_nodes().filter(|node| node_kept[node.node_id]) {
+        let seq = node.sequence();
+        let exts = node.exts();
+        let mut kept_exts = Exts::empty();
+        for &dir in &[Dir::Left, Dir::Right] {
+            let term_kmer: K = seq.term_kmer(dir);
+            for base in 0..4 {
+                if exts.has_ext(dir, base) && is_kept(&term_kmer.extend(base, dir)) {
+                    kept_exts = kept_exts.set(dir, base);
+                }
+            }
+        }
+        nodes.push((seq.to_owned(), kept_exts, *node.data()));
+    }
+
+    let mut contigs = Vec::new();
+    for &(ref seq, exts, eq_class_id) in &nodes {
+        for &tx_id in &index.eq_classes[eq_class_id as usize] {
+            if let Some(new_id) = new_ids[tx_id as usize] {
+                contigs.push((new_id, seq, exts));
+            }
+        }
+    }
+
+    let tx_gene_map: HashMap<String, String> = tx_names
+        .iter()
+        .filter_map(|tx_name| index.tx_gene_mapping.get(tx_name).map(|gene| (tx_name.clone(), gene.clone())))
+        .collect();
+    info!("Re-indexing {} of {} nodes for {} transcripts", nodes.len(), index.dbg.len(), tx_names.len());
+    let mut new_index =
+        build_index_from_contigs(&contigs, &tx_names, &tx_gene_map, index.stranded, None, &Progress::default())?;
+    if let Some(window) = index.minimizer_window {
+        sparsify_index(&mut new_index, window);
+    }
+
+    let kept_ids: Vec<usize> = (0..index.tx_names.len()).filter(|&tx_id| new_ids[tx_id].is_some()).collect();
+    if index.tx_lengths.len() == index.tx_names.len() {
+        new_index.tx_lengths = kept_ids.iter().map(|&tx_id| index.tx_lengths[tx_id]).collect();
+    }
+    if index.tx_sources.len() == index.tx_names.len() {
+        new_index.source_names = index.source_names.clone();
+        new_index.tx_sources = kept_ids.iter().map(|&tx_id| index.tx_sources[tx_id]).collect();
+    }
+    if index.tx_checksums.len() == index.tx_names.len() {
+        new_index.tx_checksums = kept_ids.iter().map(|&tx_id| index.tx_checksums[tx_id]).collect();
+    }
+    if index.tx_gc_contents.len() == index.tx_names.len() {
+        new_index.tx_gc_contents = kept_ids.iter().map(|&tx_id| index.tx_gc_contents[tx_id]).collect();
+    }
+    if index.tx_node_starts.len() == index.tx_names.len() && index.tx_lengths.len() == index.tx_names.len() {
+        new_index.tx_node_starts = kept_ids
+            .par_iter()
+            .map(|&tx_id| {
+                let old_segments = laid_out_segments(index, tx_id as u32);
+                placed_node_starts(&new_index, old_segments.iter().map(|(start, seq)| (*start, seq)))
+            })
+            .collect();
+    }
+    new_index.tx_groups = index
+        .tx_groups
+        .iter()
+        .filter(|&(tx_name, _)| keep.contains(tx_name))
+        .map(|(tx_name, group)| (tx_name.clone(), group.clone()))
+        .collect();
+    new_index.gene_names = index
+        .gene_names
+        .iter()
+        .filter(|&(gene_id, _)| tx_gene_map.values().any(|gene| gene == gene_id))
+        .map(|(gene_id, name)| (gene_id.clone(), name.clone()))
+        .collect();
+    Ok(new_index)
+}
+
+/// Replace the k-mer index of `index` with one over only the k-mers sampled
+/// by minimizers of `window` k-mers, which `map_read` then looks up instead
+/// of every read k-mer. Only the k-mer mphf and positions shrink, to about
+/// the minimizer density of 2 / (window + 1): the graph and equivalence
+/// classes are kept whole, and as the dense index is built first, the peak
+/// memory of the build does not drop. Sparse lookups miss the matches only
+/// other k-mers would find.
+pub fn sparsify_index<K: Kmer + Sync + Send>(index: &mut Pseudoaligner<K>, window: usize) {
+    let sampled: Vec<(K, u32, u32)> = (0..index.dbg.len())
+        .into_par_iter()
+        .flat_map(|node_id| {
+            let seq = index.dbg.get_node(node_id).sequence().to_owned();
+            pseudoaligner::sampled_kmer_positions::<K>(&seq, window)
+                .into_iter()
+                .map(|offset| (seq.get_kmer::<K>(offset), node_id as u32, offset as u32))
+                .collect::<Vec<_>>()
+        })
+        .collect();
+
+    info!("Indexing {} of {} kmers sampled by minimizers", sampled.len(), index.n_distinct_kmers());
+
+    // keyed like `make_dbg_index`, canonically unless the index is stranded
+    let key = |kmer: K| if index.stranded { kmer } else { kmer.min_rc() };
+    let kmers: Vec<K> = sampled.iter().map(|&(kmer, _, _)| key(kmer)).collect();
+    let mphf = boomphf::Mphf::new_parallel(1.7, &kmers, None);
+    let mut node_and_offsets = vec![(U32_MAX as u32, U32_MAX as u32); sampled.len()];
+    for &(kmer, node_id, offset) in &sampled {
+        node_and_offsets[mphf.hash(&key(kmer)) as usize] = (node_id, offset);
+    }
+
+    index.set_kmer_index(NoKeyBoomHashMap::new_with_mphf(mphf, node_and_offsets));
+    index.minimizer_window = Some(window);
+}
+
+/// Cut every transcript listed in `spans` down to its spans, as segments
+/// for `build_index_from_segments`; other transcripts are kept whole.
+pub fn restrict_to_spans(
+    seqs: &[DnaString],
+    tx_names: &[String],
+    spans: &HashMap<String, Vec<(usize, usize)>>,
+) -> Result<Vec<Vec<DnaString>>, Error> {
+    let mut segments = Vec::with_capacity(seqs.len());
+    let mut restricted = 0;
+
+    for (seq, tx_name) in seqs.iter().zip(tx_names) {
+        match spans.get(tx_name) {
+            None => segments.push(vec![seq.clone()]),
+            Some(tx_spans) => {
+                let mut tx_segments = Vec::with_capacity(tx_spans.len());
+                for &(start, end) in tx_spans {
+                    if start >= end || end > seq.len() {
+                        return Err(failure::err_msg(format!(
+                            "Span {}-{} is out of bounds for transcript {} of length {}",
+                            start, end, tx_name, seq.len()
+                        )));
+                    }
+                    tx_segments.push(utils::subsequence(seq, start, end));
+                }
+                segments.push(tx_segments);
+                restricted += 1;
+            }
+        }
+    }
+
+    if restricted < spans.len() {
+        warn!("{} transcripts with spans are not in the reference", spans.len() - restricted);
+    }
+    info!("Restricted {} transcripts to their spans", restricted);
+
+    Ok(segments)
+}
+
+/// Label of a reference file: its name without compression and FASTA extensions.
+pub(crate) fn source_name(path: &Path) -> String {
+    let mut name = path
+        .file_name()
+        .map(|name| name.to_string_lossy().into_owned())
+        .unwrap_or_else(|| path.to_string_lossy().into_owned());
+
+    for ext in &[".gz", ".fasta", ".fa", ".fna"] {
+        if name.len() > ext.len() && name.ends_with(ext) {
+            let new_len = name.len() - ext.len();
+            name.truncate(new_len);
+        }
+    }
+    name
+}
+
+/// Check that the first and last k-mer of every transcript, or its first
+/// and last minimizer for a sparse index, are in the index and belong to an
+/// equivalence class containing that transcript.
+pub fn validate_index<K: Kmer + Sync + Send>(
+    index: &Pseudoaligner<K>,
+    seqs: &[DnaString],
+    tx_ids: &[String],
+) -> Result<(), Error> {
+    if seqs.len() != tx_ids.len() || tx_ids.len() != index.tx_names.len() {
+        return Err(failure::err_msg(format!(
+            "Index has {} transcripts but {} sequences and {} ids were given",
+            index.tx_names.len(), seqs.len(), tx_ids.len()
+        )));
+    }
+
+    for (tx_idx, (seq, tx_id)) in seqs.iter().zip(tx_ids.iter()).enumerate() {
+        if seq.len() < K::k() {
+            continue;
+        }
+
+        let terminal_kmers: [(&str, K); 2] = match index.minimizer_window {
+            None => [("first", seq.first_kmer()), ("last", seq.last_kmer())],
+            Some(window) => {
+                let minimizers = pseudoaligner::minimizer_positions::<K>(seq, window);
+                [
+                    ("first minimizer", seq.get_kmer(minimizers[0])),
+                    ("last minimizer", seq.get_kmer(minimizers[minimizers.len() - 1])),
+                ]
+            }
+        };
+        for &(which, kmer) in terminal_kmers.iter() {
+            let node_id = match index.find_kmer(&kmer) {
+                Some((node_id, _)) => node_id,
+                None => return Err(failure::err_msg(format!(
+                    "Transcript {} (index {}): {} k-mer {} is missing from the index",
+                    tx_id, tx_idx, which, kmer.to_string()
+                ))),
+            };
+
+            let eq_class = &index.eq_classes[*index.dbg.get_node(node_id).data() as usize];
+            if eq_class.binary_search(&(tx_idx as u32)).is_err() {
+                return Err(failure::err_msg(format!(
+                    "Transcript {} (index {}): {} k-mer {} maps to an equivalence class without it",
+                    tx_id, tx_idx, which, kmer.to_string()
+                )));
+            }
+        }
+    }
+
+    Ok(())
 }
 
 type PmerType = debruijn::kmer::Kmer6;
@@ -93,6 +1395,8 @@ lazy_static! {
 fn partition_contigs<'a, K: Kmer>(
     contig: &'a DnaString,
     contig_id: u32,
+    contig_exts: Exts,
+    stranded: bool,
 ) -> Vec<(u16, u32, DnaStringSlice<'a>, Exts)> {
     // One FASTA entry possibly broken into multiple contigs
     // based on the location of `N` int he sequence.
@@ -100,11 +1404,19 @@ fn partition_contigs<'a, K: Kmer>(
     let mut bucket_slices = Vec::new();
 
     if contig.len() >= K::k() {
-        let msps = debruijn::msp::simple_scan::<_, PmerType>(K::k(), contig, &PERM, false);
+        // an unstranded index stores k-mers canonically, so a k-mer and its
+        // reverse complement must land in the same bucket
+        let msps = debruijn::msp::simple_scan::<_, PmerType>(K::k(), contig, &PERM, !stranded);
         for msp in msps {
             let bucket_id = msp.bucket();
             let slice = contig.slice(msp.start(), msp.end());
-            let exts = Exts::from_dna_string(contig, msp.start(), msp.len());
+            let mut exts = Exts::from_dna_string(contig, msp.start(), msp.len());
+            if msp.start() == 0 {
+                exts = Exts::merge(contig_exts, exts);
+            }
+            if msp.end() == contig.len() {
+                exts = Exts::merge(exts, contig_exts);
+            }
             bucket_slices.push((bucket_id, contig_id, slice, exts));
         }
     }
@@ -115,6 +1427,7 @@ fn partition_contigs<'a, K: Kmer>(
 fn assemble_shard<K: Kmer>(
     shard_data: &[(u16, u32, DnaStringSlice, Exts)],
     summarizer: &Arc<CountFilterEqClass<u32>>,
+    stranded: bool,
 ) -> BaseGraph<K, EqClassIdType> {
     let filter_input: Vec<_> = shard_data
         .into_iter()
@@ -125,24 +1438,34 @@ fn assemble_shard<K: Kmer>(
     let (phf, _): (BoomHashMap2<K, Exts, EqClassIdType>, _) = filter_kmers(
         &filter_input,
         summarizer,
-        STRANDED,
+        stranded,
         REPORT_ALL_KMER,
         MEM_SIZE,
     );
 
-    compress_kmers_with_hash(STRANDED, &ScmapCompress::new(), &phf)
+    compress_kmers_with_hash(stranded, &ScmapCompress::new(), &phf)
 }
 
-fn merge_shard_dbgs<K: Kmer + Sync + Send>(
+pub(crate) fn merge_shard_dbgs<K: Kmer + Sync + Send>(
     uncompressed_dbgs: Vec<BaseGraph<K, EqClassIdType>>,
+    stranded: bool,
+    progress: &Progress,
 ) -> DebruijnGraph<K, EqClassIdType> {
+    progress.start(Phase::MergeGraphs, None);
     let combined_graph = BaseGraph::combine(uncompressed_dbgs.into_iter()).finish();
-    compress_graph(STRANDED, &ScmapCompress::new(), combined_graph, None)
+    let dbg = compress_graph(stranded, &ScmapCompress::new(), combined_graph, None);
+    progress.finish(Phase::MergeGraphs);
+    dbg
 }
 
 #[inline(never)]
+/// Index the k-mers of `dbg` by their node and offset, keyed by the
+/// canonical k-mer unless `stranded`, as `Pseudoaligner::find_kmer_oriented`
+/// looks them up.
 fn make_dbg_index<K: Kmer + Sync + Send>(
     dbg: &DebruijnGraph<K, EqClassIdType>,
+    stranded: bool,
+    progress: &Progress,
 ) -> NoKeyBoomHashMap<K, (u32, u32)> {
     let mut total_kmers = 0;
     let kmer_length = K::k();
@@ -152,24 +1475,38 @@ fn make_dbg_index<K: Kmer + Sync + Send>(
 
     println!("Total {:?} kmers to process in dbg", total_kmers);
     println!("Making mphf of kmers");
-    let mphf = boomphf::Mphf::from_chunked_iterator_parallel(1.7, dbg, None, total_kmers, MAX_WORKER);
+    progress.start(Phase::BuildMphf, Some(total_kmers));
+    let mphf = if stranded {
+        boomphf::Mphf::from_chunked_iterator_parallel(1.7, dbg, None, total_kmers, MAX_WORKER)
+    } else {
+        let kmers: Vec<K> = dbg
+            .iter_nodes()
+            .flat_map(|node| node.into_iter().map(|kmer: K| kmer.min_rc()).collect::<Vec<K>>())
+            .collect();
+        boomphf::Mphf::new_parallel(1.7, &kmers, None)
+    };
 
     println!("Assigning offsets to kmers");
     let mut node_and_offsets = Vec::with_capacity(total_kmers);
     node_and_offsets.resize(total_kmers, (U32_MAX as u32, U32_MAX as u32));
+    let mut kmers_done = 0;
 
     for node in dbg {
         let node_id = node.node_id;
 
         for (offset, kmer) in node.into_iter().enumerate() {
-            let index = match mphf.try_hash(&kmer) {
+            let key = if stranded { kmer } else { kmer.min_rc() };
+            let index = match mphf.try_hash(&key) {
                 None => panic!("can't find kmer"),
                 Some(index) => index,
             };
 
             node_and_offsets[index as usize] = (node_id as u32, offset as u32);
+            kmers_done += 1;
         }
+        progress.update(Phase::BuildMphf, kmers_done);
     }
+    progress.finish(Phase::BuildMphf);
 
     boomphf::hashmap::NoKeyBoomHashMap::new_with_mphf(mphf, node_and_offsets)
 }
@@ -187,8 +1524,555 @@ fn group_by_slices<T, K: PartialEq, F: Fn(&T) -> K>(
             slice_start = i;
         }
     }
-    if slice_start > 0 || data.len() <= min_size {
-        result.push(&data[slice_start..]);
-    }
+    result.push(&data[slice_start..]);
     result
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use stats::IndexStats;
+    use test_utils::{build_test_index, build_unstranded_test_index, gencode_fasta, random_seq, TempDir};
+
+    fn dna(seq: &str) -> DnaString {
+        DnaString::from_dna_string(seq)
+    }
+
+    fn three_transcripts() -> Vec<(&'static str, String)> {
+        vec![
+            ("tx1", random_seq(300, 1)),
+            ("tx2", random_seq(300, 2)),
+            ("tx3", random_seq(300, 3)),
+        ]
+    }
+
+    #[test]
+    fn validate_index_accepts_its_own_reference() {
+        let txs = three_transcripts();
+        let index = build_test_index(&txs);
+        let seqs: Vec<DnaString> = txs.iter().map(|(_, seq)| dna(seq)).collect();
+        validate_index(&index, &seqs, &index.tx_names).unwrap();
+    }
+
+    #[test]
+    fn validate_index_names_the_transcript_of_a_corrupted_class() {
+        let txs = three_transcripts();
+        let mut index = build_test_index(&txs);
+        let seqs: Vec<DnaString> = txs.iter().map(|(_, seq)| dna(seq)).collect();
+
+        // drop tx2 from the class of its first k-mer
+        let (node_id, _) = index.find_kmer(&seqs[1].first_kmer::<KmerType>()).unwrap();
+        let class_id = *index.dbg.get_node(node_id).data() as usize;
+        index.eq_classes[class_id].retain(|&tx| tx != 1);
+
+        let message = validate_index(&index, &seqs, &index.tx_names).unwrap_err().to_string();
+        assert!(message.contains("tx2 (index 1)"), "{}", message);
+        assert!(message.contains("equivalence class"), "{}", message);
+    }
+
+    #[test]
+    fn validate_index_reports_missing_kmers() {
+        let txs = three_transcripts();
+        let index = build_test_index(&txs);
+        let mut seqs: Vec<DnaString> = txs.iter().map(|(_, seq)| dna(seq)).collect();
+        seqs[2] = dna(&random_seq(300, 4));
+
+        let message = validate_index(&index, &seqs, &index.tx_names).unwrap_err().to_string();
+        assert!(message.contains("tx3 (index 2)"), "{}", message);
+        assert!(message.contains("missing"), "{}", message);
+    }
+
+    #[test]
+    fn transcripts_of_every_fasta_map_and_carry_their_source() {
+        let dir = TempDir::new();
+        let (hla, decoy1, decoy2) = (random_seq(300, 1), random_seq(300, 2), random_seq(300, 3));
+        let hla_path = dir.write_fasta("hla_nuc.fasta", &[("hla1", &hla)]);
+        let decoy_path = dir.write_fasta("decoys.fa.gz", &[("decoy1", &decoy1), ("decoy2", &decoy2)]);
+        let index: Pseudoaligner<KmerType> =
+            build_index_from_fasta(vec![hla_path, decoy_path], &ReferenceOptions::default()).unwrap();
+
+        assert_eq!(index.tx_names, vec!["hla1", "decoy1", "decoy2"]);
+        for (tx_id, (seq, source)) in [(&hla, "hla_nuc"), (&decoy1, "decoys"), (&decoy2, "decoys")].iter().enumerate() {
+            let (eq_class, _) = index.map_read(&dna(&seq[100..200])).unwrap();
+            assert_eq!(eq_class, vec![tx_id as u32]);
+            assert_eq!(index.eq_class_sources(&eq_class), vec![*source]);
+        }
+
+        // the read TSV tells decoy hits apart by their source
+        let reads = [("hla_read", &hla[100..160]), ("decoy_read", &decoy2[100..160])];
+        let fastq: String = reads
+            .iter()
+            .map(|(id, seq)| format!("@{}\n{}\n+\n{}\n", id, seq, "I".repeat(seq.len())))
+            .collect();
+        let reads_path = dir.write("reads.fq", &fastq);
+        let tsv_path = dir.path().join("reads.tsv");
+        let mut read_tsv = utils::ReadTsvWriter::create(&tsv_path, &index, 1).unwrap();
+        let outputs = pseudoaligner::MappingOutputs { read_tsv: Some(&mut read_tsv), ..Default::default() };
+        let options = pseudoaligner::MappingOptions::default();
+        pseudoaligner::process_fastq_with_seed(&reads_path, utils::ReadFormat::Auto, &index, dir.path(), options, outputs)
+            .unwrap();
+        drop(read_tsv);
+        let tsv = fs::read_to_string(&tsv_path).unwrap();
+        let lines: Vec<Vec<&str>> = tsv.lines().map(|line| line.split('\t').collect()).collect();
+        assert_eq!(lines[0][7], "sources");
+        assert_eq!((lines[1][0], lines[1][7]), ("hla_read", "hla_nuc"));
+        assert_eq!((lines[2][0], lines[2][7]), ("decoy_read", "decoys"));
+
+        let stats = IndexStats::new(&index).unwrap();
+        let per_source: Vec<(&str, usize)> = stats
+            .transcripts_per_source
+            .iter()
+            .map(|(source, &count)| (source.as_str(), count))
+            .collect();
+        assert_eq!(per_source, vec![("decoys", 2), ("hla_nuc", 1)]);
+    }
+
+    #[test]
+    fn transcript_ids_repeated_across_fastas_fail_the_build() {
+        let dir = TempDir::new();
+        let hla_path = dir.write_fasta("hla.fa", &[("tx1", &random_seq(300, 1))]);
+        let decoy_path = dir.write_fasta("decoys.fa", &[("tx1", &random_seq(300, 2))]);
+        let err = build_index_from_fasta::<KmerType>(vec![hla_path, decoy_path], &ReferenceOptions::default())
+            .unwrap_err();
+        assert!(matches!(err, PseudoalignerError::DuplicateTranscripts(_)));
+    }
+
+    #[test]
+    fn splitting_at_n_indexes_both_flanks_but_no_kmer_across_the_n() {
+        let dir = TempDir::new();
+        let (left, right) = (random_seq(150, 1), random_seq(150, 2));
+        let with_n = format!("{}NNNNN{}", left, right);
+        let fasta = gencode_fasta(&[("tx1", &with_n), ("tx2", &random_seq(300, 3))]);
+        let path = dir.write("ref.fa", &fasta);
+
+        let split_options = ReferenceOptions { split_at_n: true, ..ReferenceOptions::default() };
+        let split: Pseudoaligner<KmerType> = build_index_from_fasta(vec![path.clone()], &split_options).unwrap();
+        let hashed: Pseudoaligner<KmerType> =
+            build_index_from_fasta(vec![path], &ReferenceOptions::default()).unwrap();
+
+        assert_eq!(split.map_read(&dna(&left[20..120])), Some((vec![0], 100)));
+        assert_eq!(split.map_read(&dna(&right[30..130])), Some((vec![0], 100)));
+
+        // the k-mers across the N, as hashed to bases when not splitting
+        let (seqs, _, _) = utils::read_transcripts(bio::io::fasta::Reader::new(fasta.as_bytes())).unwrap();
+        let hashed_bases = seqs[0].to_string();
+        let k = KmerType::k();
+        for start in 150 + 5 - k + 1..150 {
+            let kmer = KmerType::from_ascii(hashed_bases[start..start + k].as_bytes());
+            assert!(hashed.find_kmer(&kmer).is_some(), "{:?}", kmer);
+            assert_eq!(split.find_kmer(&kmer), None, "{:?}", kmer);
+        }
+    }
+
+    #[test]
+    fn no_kmer_inside_a_soft_masked_block_is_indexed_when_honored() {
+        let dir = TempDir::new();
+        let (left, masked, right) = (random_seq(150, 1), random_seq(60, 2), random_seq(150, 3));
+        let tx1 = format!("{}{}{}", left, masked.to_lowercase(), right);
+        let path = dir.write_fasta("ref.fa", &[("tx1", &tx1), ("tx2", &random_seq(300, 4))]);
+
+        let plain: Pseudoaligner<KmerType> =
+            build_index_from_fasta(vec![path.clone()], &ReferenceOptions::default()).unwrap();
+        let options = ReferenceOptions { honor_softmask: true, ..ReferenceOptions::default() };
+        let (softmasked, report) = build_index_from_fasta_with_report::<KmerType>(vec![path], &options).unwrap();
+        assert_eq!(report.softmasked_bases, vec![("tx1".to_string(), 60)]);
+
+        let k = KmerType::k();
+        for start in 0..=masked.len() - k {
+            let kmer = KmerType::from_ascii(masked[start..start + k].as_bytes());
+            assert!(plain.find_kmer(&kmer).is_some(), "{:?}", kmer);
+            assert_eq!(softmasked.find_kmer(&kmer), None, "{:?}", kmer);
+        }
+        assert_eq!(softmasked.map_read(&dna(&left[20..120])), Some((vec![0], 100)));
+        assert_eq!(softmasked.map_read(&dna(&right[30..130])), Some((vec![0], 100)));
+        assert_eq!(softmasked.tx_lengths[0], 300);
+    }
+
+    #[test]
+    fn masked_poly_a_tails_no_longer_share_a_class() {
+        let dir = TempDir::new();
+        let poly_a = "A".repeat(60);
+        let tx1 = format!("{}{}{}", random_seq(200, 1), poly_a, random_seq(200, 2));
+        let tx2 = format!("{}{}", random_seq(300, 3), poly_a);
+        let path = dir.write_fasta("ref.fa", &[("tx1", &tx1), ("tx2", &tx2)]);
+
+        let unmasked: Pseudoaligner<KmerType> =
+            build_index_from_fasta(vec![path.clone()], &ReferenceOptions::default()).unwrap();
+        let options = ReferenceOptions { mask_entropy: Some(1.0), ..ReferenceOptions::default() };
+        let (masked, report): (Pseudoaligner<KmerType>, _) =
+            build_index_from_fasta_with_report(vec![path], &options).unwrap();
+
+        let poly_a_kmer = KmerType::from_ascii(poly_a[..KmerType::k()].as_bytes());
+        assert!(unmasked.find_kmer(&poly_a_kmer).is_some());
+        assert!(unmasked.eq_classes.contains(&vec![0, 1]));
+        assert_eq!(masked.find_kmer(&poly_a_kmer), None);
+        assert!(masked.eq_classes.iter().all(|eq_class| eq_class.len() == 1));
+        // the flanks still map
+        assert_eq!(masked.map_read(&dna(&tx1[50..150])), Some((vec![0], 100)));
+        assert_eq!(masked.map_read(&dna(&tx2[100..200])), Some((vec![1], 100)));
+
+        let masked_txs: Vec<&str> = report.masked_bases.iter().map(|(tx_name, _)| tx_name.as_str()).collect();
+        assert_eq!(masked_txs, vec!["tx1", "tx2"]);
+        assert!(report.masked_bases.iter().all(|&(_, bases)| bases >= poly_a.len()));
+    }
+
+    #[test]
+    fn records_shorter_than_the_minimum_are_skipped_and_reported() {
+        let dir = TempDir::new();
+        let (tx1, tx2) = (random_seq(300, 1), random_seq(300, 2));
+        let fasta = gencode_fasta(&[
+            ("tx1", &tx1), ("short1", &random_seq(10, 3)), ("tx2", &tx2), ("short2", &random_seq(30, 4))
+        ]);
+        let path = dir.write("ref.fa", &fasta);
+        let options = ReferenceOptions { min_seq_len: 40, ..ReferenceOptions::default() };
+        let (index, report): (Pseudoaligner<KmerType>, _) =
+            build_index_from_fasta_with_report(vec![path], &options).unwrap();
+
+        assert_eq!(index.tx_names, vec!["tx1", "tx2"]);
+        assert_eq!(index.map_read(&dna(&tx2[100..200])), Some((vec![1], 100)));
+        let skipped = &report.skipped_records;
+        assert_eq!(skipped.count, 2);
+        assert_eq!(skipped.total_bases, 40);
+        let example_txs: Vec<_> = skipped.example_ids.iter().map(|id| id.split('|').next().unwrap()).collect();
+        assert_eq!(example_txs, vec!["short1", "short2"]);
+    }
+
+    #[test]
+    fn transcripts_shorter_than_k_are_dropped_unless_kept() {
+        let dir = TempDir::new();
+        let (tx1, short, tx2) = (random_seq(300, 1), random_seq(20, 2), random_seq(300, 3));
+        let path = dir.write_fasta("ref.fa", &[("tx1", &tx1), ("short", &short), ("tx2", &tx2)]);
+
+        let (dropped, report): (Pseudoaligner<KmerType>, _) =
+            build_index_from_fasta_with_report(vec![path.clone()], &ReferenceOptions::default()).unwrap();
+        assert_eq!(report.short_transcripts, vec!["short"]);
+        assert_eq!(dropped.tx_names, vec!["tx1", "tx2"]);
+        assert!(!dropped.tx_gene_mapping.contains_key("short"));
+        assert_eq!(dropped.map_read(&dna(&tx2[100..200])), Some((vec![1], 100)));
+
+        let options = ReferenceOptions { keep_short: true, ..ReferenceOptions::default() };
+        let (kept, report): (Pseudoaligner<KmerType>, _) =
+            build_index_from_fasta_with_report(vec![path], &options).unwrap();
+        assert_eq!(report.short_transcripts, vec!["short"]);
+        assert_eq!(kept.tx_names, vec!["tx1", "short", "tx2"]);
+        assert_eq!(kept.tx_lengths, vec![300, 20, 300]);
+        assert_eq!(kept.map_read(&dna(&tx2[100..200])), Some((vec![2], 100)));
+        // the short transcript is in no class, so nothing maps to it
+        assert!(kept.eq_classes.iter().all(|eq_class| !eq_class.contains(&1)));
+        let spanning = format!("{}{}{}", &tx1[250..], short, &tx2[..30]);
+        assert!(kept.map_read(&dna(&spanning)).map_or(true, |(eq_class, _)| !eq_class.contains(&1)));
+    }
+
+    #[test]
+    fn builds_return_the_transcripts_shorter_than_k() {
+        let (tx1, short, tx2) = (random_seq(300, 1), random_seq(20, 2), random_seq(300, 3));
+        let index = build_test_index(&[("tx1", &tx1), ("short", &short), ("tx2", &tx2)]);
+        assert_eq!(index.short_transcripts(), vec![1]);
+        // the short transcript keeps its index, so the others keep theirs
+        assert_eq!(index.tx_names, vec!["tx1", "short", "tx2"]);
+        assert_eq!(index.map_read(&dna(&tx2[100..200])), Some((vec![2], 100)));
+
+        let dir = TempDir::new();
+        let path = dir.write_fasta("ref.fa", &[("tx1", &tx1), ("short", &short), ("tx2", &tx2)]);
+        let (dropped, report): (Pseudoaligner<KmerType>, _) =
+            build_index_from_fasta_with_report(vec![path.clone()], &ReferenceOptions::default()).unwrap();
+        assert_eq!(report.short_transcripts, vec!["short"]);
+        assert!(dropped.short_transcripts().is_empty());
+        let options = ReferenceOptions { keep_short: true, ..ReferenceOptions::default() };
+        let (kept, report): (Pseudoaligner<KmerType>, _) =
+            build_index_from_fasta_with_report(vec![path], &options).unwrap();
+        assert_eq!(report.short_transcripts, vec!["short"]);
+        assert_eq!(kept.short_transcripts(), vec![1]);
+    }
+
+    #[test]
+    fn reads_outside_the_indexed_spans_do_not_map() {
+        let dir = TempDir::new();
+        let (tx1, tx2) = (random_seq(400, 1), random_seq(400, 2));
+        let path = dir.write_fasta("ref.fa", &[("tx1", &tx1), ("tx2", &tx2)]);
+        let spans: HashMap<String, Vec<(usize, usize)>> =
+            vec![("tx1".to_string(), vec![(100, 250)])].into_iter().collect();
+        let options = ReferenceOptions { spans: Some(spans), ..ReferenceOptions::default() };
+        let index: Pseudoaligner<KmerType> = build_index_from_fasta(vec![path], &options).unwrap();
+
+        assert_eq!(index.map_read(&dna(&tx1[120..220])), Some((vec![0], 100)));
+        assert_eq!(index.map_read(&dna(&tx1[260..360])), None);
+        assert_eq!(index.map_read(&dna(&tx2[260..360])), Some((vec![1], 100)));
+        assert_eq!(index.tx_lengths, vec![150, 400]);
+    }
+
+    #[test]
+    fn appending_to_an_index_maps_like_building_the_combined_reference() {
+        let dir = TempDir::new();
+        let (tx1, tx2) = (random_seq(400, 1), random_seq(400, 2));
+        // tx3 shares 200 bases with tx1, so the append has to split its nodes
+        let tx3 = tx1[100..300].to_string() + &random_seq(200, 3);
+        let old_fasta = dir.write_fasta("old.fa", &[("tx1", &tx1), ("tx2", &tx2)]);
+        let new_fasta = dir.write_fasta("new.fa", &[("tx3", &tx3)]);
+        let options = ReferenceOptions::default();
+
+        let old: Pseudoaligner<KmerType> = build_index_from_fasta(vec![old_fasta.clone()], &options).unwrap();
+        let index_path = dir.path().join("old.idx");
+        utils::write_index(&old, &index_path).unwrap();
+        let appended: Pseudoaligner<KmerType> =
+            append_to_index(&index_path, vec![new_fasta.clone()], &options).unwrap();
+        let combined: Pseudoaligner<KmerType> =
+            build_index_from_fasta(vec![old_fasta, new_fasta], &options).unwrap();
+
+        assert_eq!(appended.tx_names, combined.tx_names);
+        assert_eq!(appended.source_names, combined.source_names);
+        assert_eq!(appended.tx_sources, combined.tx_sources);
+        assert_eq!(appended.reference_checksums, combined.reference_checksums);
+        assert_eq!(appended.combined_tx_checksum(), combined.combined_tx_checksum());
+        let mut num_shared = 0;
+        for tx in &[&tx1, &tx2, &tx3] {
+            for start in (0..300).step_by(7) {
+                let read_seq = dna(&tx[start..start + 100]);
+                let mapping = combined.map_read(&read_seq);
+                if mapping.as_ref().is_some_and(|(eq_class, _)| eq_class.len() > 1) {
+                    num_shared += 1;
+                }
+                assert_eq!(appended.map_read(&read_seq), mapping, "start {}", start);
+            }
+        }
+        assert!(num_shared > 0);
+        // the appended index still places reads on every transcript
+        let pairs: Vec<(DnaString, DnaString)> =
+            [&tx1, &tx2, &tx3].iter().map(|tx| (dna(&tx[300..350]), dna(&tx[340..390]).rc())).collect();
+        assert_eq!(appended.fragment_lengths(&pairs), vec![90, 90, 90]);
+
+        let err = append_to_index::<debruijn::kmer::Kmer20, _>(&index_path, vec![], &options).unwrap_err();
+        assert!(err.to_string().contains(&format!("k = {}", KmerType::k())), "{}", err);
+        match err {
+            PseudoalignerError::KmerLengthMismatch { found, expected, .. } => {
+                assert_eq!((found, expected), (KmerType::k(), 20));
+            }
+            err => panic!("unexpected error {}", err),
+        }
+    }
+
+    #[test]
+    fn appending_transcripts_already_in_the_index_is_an_error() {
+        let dir = TempDir::new();
+        let (tx1, tx2) = (random_seq(400, 1), random_seq(400, 2));
+        let old_fasta = dir.write_fasta("old.fa", &[("tx1", &tx1), ("tx2", &tx2)]);
+        let new_fasta = dir.write_fasta("new.fa", &[("tx2", &tx2), ("tx3", &random_seq(400, 3))]);
+        let options = ReferenceOptions::default();
+
+        let old: Pseudoaligner<KmerType> = build_index_from_fasta(vec![old_fasta], &options).unwrap();
+        let index_path = dir.path().join("old.idx");
+        utils::write_index(&old, &index_path).unwrap();
+        match append_to_index::<KmerType, _>(&index_path, vec![new_fasta], &options) {
+            Err(PseudoalignerError::DuplicateTranscripts(ids)) => assert_eq!(ids, "tx2"),
+            Err(err) => panic!("unexpected error {}", err),
+            Ok(_) => panic!("appended a transcript already in the index"),
+        }
+    }
+
+    #[test]
+    fn partitioned_build_maps_like_building_all_transcripts_at_once() {
+        let (tx1, tx2, tx4) = (random_seq(400, 1), random_seq(400, 2), random_seq(400, 4));
+        // tx3 shares 200 bases with tx1 across the partitions
+        let tx3 = tx1[100..300].to_string() + &random_seq(200, 3);
+        let names = |names: &[&str]| -> Vec<String> { names.iter().map(|name| name.to_string()).collect() };
+        let partitions = vec![
+            (vec![dna(&tx1), dna(&tx2)], names(&["tx1", "tx2"])),
+            (vec![dna(&tx3), dna(&tx4)], names(&["tx3", "tx4"])),
+        ];
+        let tx_gene_map: HashMap<String, String> =
+            names(&["tx1", "tx2", "tx3", "tx4"]).into_iter().map(|id| (id.clone(), format!("G{}", id))).collect();
+
+        let partitioned: Pseudoaligner<KmerType> =
+            build_index_partitioned(partitions.clone(), &tx_gene_map, 2).unwrap();
+        let combined = build_test_index(&[("tx1", &tx1), ("tx2", &tx2), ("tx3", &tx3), ("tx4", &tx4)]);
+        assert_eq!(partitioned.tx_names, combined.tx_names);
+        assert_eq!(partitioned.tx_lengths, combined.tx_lengths);
+        let mut num_shared = 0;
+        for tx in &[&tx1, &tx2, &tx3, &tx4] {
+            for start in (0..300).step_by(7) {
+                let read_seq = dna(&tx[start..start + 100]);
+                let mapping = combined.map_read(&read_seq);
+                if mapping.as_ref().is_some_and(|(eq_class, _)| eq_class == &vec![0, 2]) {
+                    num_shared += 1;
+                }
+                assert_eq!(partitioned.map_read(&read_seq), mapping, "start {}", start);
+            }
+        }
+        assert!(num_shared > 0);
+
+        let mut repeated = partitions;
+        repeated[1].1[0] = "tx1".to_string();
+        let err = build_index_partitioned::<KmerType>(repeated, &tx_gene_map, 2).unwrap_err();
+        assert!(matches!(err, PseudoalignerError::DuplicateTranscripts(_)), "{}", err);
+    }
+
+    #[test]
+    fn builds_of_the_same_reference_write_identical_bytes() {
+        // pairs of transcripts sharing halves, so that the shards hold many classes
+        let txs: Vec<(String, String)> = (0..20u64)
+            .map(|i| (format!("tx{}", i), random_seq(200, 100 + i / 2) + &random_seq(200, 200 + i)))
+            .collect();
+        let dir = TempDir::new();
+        let write = |name: &str, index: &Pseudoaligner<KmerType>| {
+            let path = dir.path().join(name);
+            utils::write_index(index, &path).unwrap();
+            fs::read(path).unwrap()
+        };
+
+        let bytes = write("first", &build_test_index(&txs));
+        assert_eq!(write("second", &build_test_index(&txs)), bytes);
+        let pool = rayon::ThreadPoolBuilder::new().num_threads(1).build().unwrap();
+        assert_eq!(write("one-thread", &pool.install(|| build_test_index(&txs))), bytes);
+    }
+
+    #[test]
+    fn excluding_one_of_three_alleles_renumbers_the_others() {
+        let txs = three_transcripts();
+        let records: Vec<(&str, &str)> = txs.iter().map(|(id, seq)| (*id, seq.as_str())).collect();
+        let dir = TempDir::new();
+        let path = dir.write_fasta("alleles.fa", &records);
+        let exclude: HashSet<String> = vec!["tx2".to_string()].into_iter().collect();
+
+        let tx_names: Vec<String> = txs.iter().map(|(id, _)| id.to_string()).collect();
+        assert_eq!(excluded_index_map(&tx_names, &exclude), vec![Some(0), None, Some(1)]);
+
+        let options = ReferenceOptions { exclude, ..ReferenceOptions::default() };
+        let (index, report): (Pseudoaligner<KmerType>, _) =
+            build_index_from_fasta_with_report(vec![path], &options).unwrap();
+        assert_eq!(report.excluded_index_map, vec![Some(0), None, Some(1)]);
+        assert_eq!(index.tx_names, vec!["tx1", "tx3"]);
+        assert_eq!(index.map_read(&dna(&txs[0].1[0..100])), Some((vec![0], 100)));
+        assert_eq!(index.map_read(&dna(&txs[1].1[0..100])), None);
+        assert_eq!(index.map_read(&dna(&txs[2].1[0..100])), Some((vec![1], 100)));
+        assert!(index.eq_classes.iter().flatten().all(|&tx| tx < 2));
+    }
+
+    #[test]
+    fn reads_of_dropped_transcripts_do_not_map_to_a_subset() {
+        let shared = random_seq(200, 4);
+        let (tx1, tx2, tx3) = (random_seq(200, 1) + &shared, random_seq(200, 2) + &shared, random_seq(300, 3));
+        let index = build_test_index(&[("tx1", &tx1), ("tx2", &tx2), ("tx3", &tx3)]);
+        let keep: HashSet<String> = vec!["tx2".to_string(), "tx3".to_string()].into_iter().collect();
+        let subset = subset_index(&index, &keep).unwrap();
+
+        assert_eq!(subset.tx_names, vec!["tx2", "tx3"]);
+        assert_eq!(subset.tx_lengths, vec![400, 300]);
+        assert_eq!(subset.map_read(&dna(&tx1[50..150])), None);
+        assert_eq!(subset.map_read(&dna(&tx2[50..150])), Some((vec![0], 100)));
+        assert_eq!(subset.map_read(&dna(&tx1[250..350])), Some((vec![0], 100)));
+        assert_eq!(subset.map_read(&dna(&tx3[100..200])), Some((vec![1], 100)));
+        assert!(subset.eq_classes.iter().all(|eq_class| !eq_class.is_empty() && eq_class.iter().all(|&tx| tx < 2)));
+        // reads are placed on the kept transcripts as in the full index
+        assert_eq!(subset.read_tx_position(&dna(&tx2[250..350]), 0), Some(250));
+        assert_eq!(subset.read_tx_position(&dna(&tx3[100..200]), 1), Some(100));
+
+        let dir = TempDir::new();
+        utils::write_index(&subset, dir.path().join("subset")).unwrap();
+        let subset: Pseudoaligner<KmerType> = utils::read_index(dir.path().join("subset")).unwrap();
+        assert_eq!(subset.map_read(&dna(&tx3[100..200])), Some((vec![1], 100)));
+        assert_eq!(subset.gene_of(1), Some("Gtx3"));
+    }
+
+    #[test]
+    fn sparse_index_is_smaller_and_maps_unambiguous_reads_alike() {
+        let dir = TempDir::new();
+        let txs: Vec<(String, String)> =
+            (0..6).map(|i| (format!("tx{}", i), random_seq(1000, i as u64 + 1))).collect();
+        let dense = build_test_index(&txs);
+        let mut sparse = build_test_index(&txs);
+        sparsify_index(&mut sparse, 10);
+
+        let (dense_path, sparse_path) = (dir.path().join("dense.idx"), dir.path().join("sparse.idx"));
+        utils::write_index(&dense, &dense_path).unwrap();
+        utils::write_index(&sparse, &sparse_path).unwrap();
+        let dense_size = std::fs::metadata(&dense_path).unwrap().len();
+        assert!(std::fs::metadata(&sparse_path).unwrap().len() < dense_size);
+        let sparse: Pseudoaligner<KmerType> = utils::read_index(&sparse_path).unwrap();
+        assert_eq!(sparse.minimizer_window, Some(10));
+
+        for (tx_id, (_, seq)) in txs.iter().enumerate() {
+            for start in (0..900).step_by(50) {
+                let read_seq = dna(&seq[start..start + 100]);
+                let dense_class = dense.map_read(&read_seq).map(|(eq_class, _)| eq_class);
+                assert_eq!(dense_class, Some(vec![tx_id as u32]));
+                assert_eq!(sparse.map_read(&read_seq).map(|(eq_class, _)| eq_class), dense_class, "{} {}", tx_id, start);
+            }
+        }
+    }
+
+    #[test]
+    fn sparse_unstranded_index_finds_its_kmers_in_both_orientations() {
+        let txs: Vec<(String, String)> =
+            (0..3).map(|i| (format!("tx{}", i), random_seq(1000, i as u64 + 1))).collect();
+        let mut sparse = build_unstranded_test_index(&txs);
+        sparsify_index(&mut sparse, 10);
+
+        for node_id in 0..sparse.dbg.len() {
+            let seq = sparse.dbg.get_node(node_id).sequence().to_owned();
+            for offset in pseudoaligner::sampled_kmer_positions::<KmerType>(&seq, 10) {
+                let kmer: KmerType = seq.get_kmer(offset);
+                if kmer == kmer.rc() {
+                    continue;
+                }
+                assert_eq!(sparse.find_kmer_oriented(&kmer), Some((node_id, offset, false)));
+                assert_eq!(sparse.find_kmer_oriented(&kmer.rc()), Some((node_id, offset, true)));
+            }
+        }
+    }
+
+    #[test]
+    fn spilled_build_writes_the_same_index_as_an_in_memory_build() {
+        let dir = TempDir::new();
+        // enough sequence chunks for several shards, which pairs of
+        // transcripts sharing half their bases make share k-mers
+        let seqs: Vec<(String, String)> = (0..200)
+            .map(|tx| (format!("tx{}", tx), random_seq(250, tx / 2) + &random_seq(250, 1000 + tx)))
+            .collect();
+        let records: Vec<(&str, &str)> = seqs.iter().map(|(id, seq)| (id.as_str(), seq.as_str())).collect();
+        let fasta = vec![dir.write_fasta("ref.fa", &records)];
+        let options = ReferenceOptions::default();
+        let in_memory: Pseudoaligner<KmerType> = build_index_from_fasta(fasta.clone(), &options).unwrap();
+        let spill = SpillOptions { temp_dir: dir.path().to_path_buf(), memory_budget: 1 << 18 };
+        let spilled: Pseudoaligner<KmerType> = build_index_from_fasta_on_disk(fasta, &options, &spill).unwrap();
+
+        let (in_memory_path, spilled_path) = (dir.path().join("in_memory.idx"), dir.path().join("spilled.idx"));
+        utils::write_index(&in_memory, &in_memory_path).unwrap();
+        utils::write_index(&spilled, &spilled_path).unwrap();
+        assert!(fs::read(&in_memory_path).unwrap() == fs::read(&spilled_path).unwrap());
+        // the spill directory is removed
+        assert_eq!(fs::read_dir(dir.path()).unwrap().count(), 3);
+    }
+
+    #[test]
+    fn spill_partitions_fit_the_memory_budget() {
+        // shards of three buckets of 1000 chunks and 100 bases each
+        let (bucket_chunks, bucket_bases) = (vec![1000; 10], vec![100; 10]);
+        assert_eq!(partition_buckets(&bucket_chunks, &bucket_bases, 700), vec![0, 0, 0, 0, 0, 0, 1, 1, 1, 1]);
+        // a shard larger than the budget is a partition of its own
+        assert_eq!(partition_buckets(&bucket_chunks, &bucket_bases, 200), vec![0, 0, 0, 1, 1, 1, 2, 2, 2, 3]);
+
+        let seqs: Vec<DnaString> = (0..2000).map(|tx| dna(&random_seq(500, tx))).collect();
+        let contigs: Vec<_> = seqs.iter().enumerate().map(|(id, seq)| (id as u32, seq, Exts::empty())).collect();
+        let (bucket_chunks, bucket_bases) = bucket_sizes(&contigs, STRANDED);
+        let budget_bases = bucket_bases.iter().sum::<usize>() / 3;
+        let partitions = partition_buckets(&bucket_chunks, &bucket_bases, budget_bases);
+        let num_partitions = partitions.last().unwrap() + 1;
+        assert!(num_partitions >= 3);
+        for partition in 0..num_partitions {
+            let bases: usize = (0..partitions.len())
+                .filter(|&bucket| partitions[bucket] == partition)
+                .map(|bucket| bucket_bases[bucket])
+                .sum();
+            assert!(bases <= budget_bases, "partition {} holds {} bases", partition, bases);
+        }
+    }
+
+    #[test]
+    fn group_by_slices_keeps_the_last_group() {
+        let data = [1, 1, 1, 2, 2, 3];
+        assert_eq!(group_by_slices(&data, |&x| x, 2), vec![&data[..3], &data[3..]]);
+        // more than `min_size` items of one key are one group
+        let data = [1, 1, 1, 1];
+        assert_eq!(group_by_slices(&data, |&x| x, 2), vec![&data[..]]);
+    }
+}
@@ -0,0 +1,233 @@
+// Copyright (c) 2018 10x Genomics, Inc. All rights reserved.
+
+//! Output of single-cell mappings in the BUS format of bustools: a binary
+//! `output.bus` of (barcode, UMI, equivalence class, count) records, with
+//! the classes listed by transcript index in `matrix.ec` and the
+//! transcript names in `transcripts.txt`.
+use std::collections::{BTreeMap, HashMap};
+use std::io::{BufWriter, Write};
+use std::path::Path;
+
+use debruijn::Kmer;
+use failure::{self, Error};
+
+use pseudoaligner::Pseudoaligner;
+use utils::open_file;
+
+const BUS_MAGIC: &[u8; 4] = b"BUS\0";
+const BUS_VERSION: u32 = 1;
+
+/// Longest barcode or UMI a BUS record holds, at 2 bits per base.
+pub const MAX_BUS_SEQ_LEN: usize = 32;
+
+/// Collects the mapped reads of a run and writes them as BUS. Reads are
+/// kept in memory until `write`, which writes one record per distinct
+/// (barcode, UMI, class), sorted by barcode, then UMI, then class, with the
+/// number of its reads as count. Classes are numbered as in the index, and
+/// intersections that are not a class of the index are numbered after
+/// them.
+pub struct BusWriter {
+    barcode_len: usize,
+    umi_len: usize,
+    eq_classes: Vec<Vec<u32>>,
+    eq_class_ids: HashMap<Vec<u32>, u32>,
+    records: BTreeMap<(u64, u64, u32), u32>,
+    num_skipped: usize,
+}
+
+impl BusWriter {
+    /// Collect reads with barcodes of `barcode_len` and UMIs of `umi_len`
+    /// bases, mapped to `index`.
+    pub fn new<K: Kmer + Sync + Send>(
+        index: &Pseudoaligner<K>,
+        barcode_len: usize,
+        umi_len: usize,
+    ) -> Result<BusWriter, Error> {
+        if barcode_len == 0 || barcode_len > MAX_BUS_SEQ_LEN || umi_len == 0 || umi_len > MAX_BUS_SEQ_LEN {
+            return Err(failure::err_msg(format!(
+                "BUS barcodes and UMIs must be 1 to {} bases, not {} and {}",
+                MAX_BUS_SEQ_LEN, barcode_len, umi_len
+            )));
+        }
+
+        let eq_classes: Vec<Vec<u32>> = index.eq_classes().map(|(_, eq_class)| eq_class.to_vec()).collect();
+        let eq_class_ids = eq_classes
+            .iter()
+            .enumerate()
+            .map(|(id, eq_class)| (eq_class.clone(), id as u32))
+            .collect();
+        Ok(BusWriter {
+            barcode_len,
+            umi_len,
+            eq_classes,
+            eq_class_ids,
+            records: BTreeMap::new(),
+            num_skipped: 0,
+        })
+    }
+
+    /// Add a read with `barcode` and `umi` mapped to the non-empty
+    /// `eq_class`. Reads whose barcode or UMI is not of the expected length
+    /// or holds other bases than A, C, G and T are skipped; returns whether
+    /// the read was added.
+    pub fn add_read(&mut self, barcode: &[u8], umi: &[u8], eq_class: &[u32]) -> bool {
+        let codes = match (encode_bus_seq(barcode, self.barcode_len), encode_bus_seq(umi, self.umi_len)) {
+            (Some(barcode), Some(umi)) if !eq_class.is_empty() => (barcode, umi),
+            _ => {
+                self.num_skipped += 1;
+                return false;
+            }
+        };
+
+        let eq_class_id = match self.eq_class_ids.get(eq_class) {
+            Some(&id) => id,
+            None => {
+                let id = self.eq_classes.len() as u32;
+                self.eq_classes.push(eq_class.to_vec());
+                self.eq_class_ids.insert(eq_class.to_vec(), id);
+                id
+            }
+        };
+        *self.records.entry((codes.0, codes.1, eq_class_id)).or_insert(0) += 1;
+        true
+    }
+
+    /// Number of reads skipped by `add_read`.
+    pub fn num_skipped(&self) -> usize {
+        self.num_skipped
+    }
+
+    /// Write `output.bus`, `matrix.ec` and `transcripts.txt` to `outdir`.
+    pub fn write<K: Kmer + Sync + Send, P: AsRef<Path>>(&self, index: &Pseudoaligner<K>, outdir: P) -> Result<(), Error> {
+        let mut bus_file = BufWriter::new(open_file("output.bus", &outdir)?);
+        self.write_bus(&mut bus_file)?;
+        bus_file.flush()?;
+
+        let mut ec_file = BufWriter::new(open_file("matrix.ec", &outdir)?);
+        for (eq_class_id, eq_class) in self.eq_classes.iter().enumerate() {
+            let tx_ids: Vec<String> = eq_class.iter().map(|tx_id| tx_id.to_string()).collect();
+            writeln!(ec_file, "{}\t{}", eq_class_id, tx_ids.join(","))?;
+        }
+        ec_file.flush()?;
+
+        let mut tx_file = BufWriter::new(open_file("transcripts.txt", &outdir)?);
+        for tx_name in &index.tx_names {
+            writeln!(tx_file, "{}", tx_name)?;
+        }
+        tx_file.flush()?;
+        Ok(())
+    }
+
+    /// Write the BUS header and records to `writer`: the magic, version,
+    /// barcode and UMI lengths and an empty header text, then 32 byte
+    /// records of barcode, UMI, class, count, flags and padding, all little
+    /// endian.
+    pub fn write_bus<W: Write>(&self, writer: &mut W) -> Result<(), Error> {
+        writer.write_all(BUS_MAGIC)?;
+        writer.write_all(&BUS_VERSION.to_le_bytes())?;
+        writer.write_all(&(self.barcode_len as u32).to_le_bytes())?;
+        writer.write_all(&(self.umi_len as u32).to_le_bytes())?;
+        writer.write_all(&0u32.to_le_bytes())?;
+
+        for (&(barcode, umi, eq_class_id), &count) in &self.records {
+            writer.write_all(&barcode.to_le_bytes())?;
+            writer.write_all(&umi.to_le_bytes())?;
+            writer.write_all(&(eq_class_id as i32).to_le_bytes())?;
+            writer.write_all(&count.to_le_bytes())?;
+            // flags and padding
+            writer.write_all(&[0u8; 8])?;
+        }
+        Ok(())
+    }
+}
+
+/// 2 bit code of the `len` bases of `seq`, the first base in the highest
+/// bits, as bustools packs them; `None` for sequences of another length or
+/// with other bases than A, C, G and T.
+fn encode_bus_seq(seq: &[u8], len: usize) -> Option<u64> {
+    if seq.len() != len {
+        return None;
+    }
+    seq.iter().try_fold(0u64, |code, &base| {
+        let bits = match base {
+            b'A' | b'a' => 0,
+            b'C' | b'c' => 1,
+            b'G' | b'g' => 2,
+            b'T' | b't' => 3,
+            _ => return None,
+        };
+        Some(code << 2 | bits)
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::convert::TryInto;
+    use std::fs;
+
+    use debruijn::dna_string::DnaString;
+    use test_utils::{build_test_index, random_seq, TempDir};
+
+    /// A BUS record as bustools reads it.
+    #[derive(Debug, PartialEq)]
+    struct BusRecord {
+        barcode: u64,
+        umi: u64,
+        eq_class: i32,
+        count: u32,
+        flags: u32,
+    }
+
+    /// Barcode and UMI lengths and records of a BUS file, read field by
+    /// field after the header layout of bustools.
+    fn parse_bus(bytes: &[u8]) -> (u32, u32, Vec<BusRecord>) {
+        let u32_at = |pos: usize| u32::from_le_bytes(bytes[pos..pos + 4].try_into().unwrap());
+        assert_eq!(&bytes[0..4], b"BUS\0");
+        assert_eq!(u32_at(4), 1);
+        let (barcode_len, umi_len, text_len) = (u32_at(8), u32_at(12), u32_at(16) as usize);
+
+        let records = bytes[20 + text_len..]
+            .chunks(32)
+            .map(|record| {
+                assert_eq!(record.len(), 32);
+                BusRecord {
+                    barcode: u64::from_le_bytes(record[0..8].try_into().unwrap()),
+                    umi: u64::from_le_bytes(record[8..16].try_into().unwrap()),
+                    eq_class: i32::from_le_bytes(record[16..20].try_into().unwrap()),
+                    count: u32::from_le_bytes(record[20..24].try_into().unwrap()),
+                    flags: u32::from_le_bytes(record[24..28].try_into().unwrap()),
+                }
+            })
+            .collect();
+        (barcode_len, umi_len, records)
+    }
+
+    #[test]
+    fn bus_files_of_synthetic_reads_read_back() {
+        let shared = random_seq(200, 3);
+        let (tx1, tx2) = (random_seq(199, 1) + "A" + &shared, random_seq(199, 2) + "C" + &shared);
+        let index = build_test_index(&[("tx1", &tx1), ("tx2", &tx2)]);
+        let eq_class = |seq: &str| index.map_read(&DnaString::from_dna_string(seq)).unwrap().0;
+
+        let mut bus = BusWriter::new(&index, 4, 3).unwrap();
+        assert!(bus.add_read(b"CAAA", b"ACG", &eq_class(&tx2[0..60])));
+        assert!(bus.add_read(b"AAAC", b"ACG", &eq_class(&tx1[0..60])));
+        assert!(bus.add_read(b"AAAC", b"ACG", &eq_class(&tx1[20..80])));
+        assert!(bus.add_read(b"AAAC", b"ACT", &eq_class(&tx1[300..360])));
+        assert!(!bus.add_read(b"AANC", b"ACG", &eq_class(&tx1[0..60])));
+        assert!(!bus.add_read(b"AAAC", b"ACGT", &eq_class(&tx1[0..60])));
+        assert_eq!(bus.num_skipped(), 2);
+
+        let dir = TempDir::new();
+        bus.write(&index, dir.path()).unwrap();
+        let (barcode_len, umi_len, records) = parse_bus(&fs::read(dir.path().join("output.bus")).unwrap());
+        assert_eq!((barcode_len, umi_len), (4, 3));
+        // AAAC is 0b00000001 and CAAA 0b01000000; ACG is 0b000110, ACT 0b000111
+        let record = |barcode, umi, eq_class, count| BusRecord { barcode, umi, eq_class, count, flags: 0 };
+        assert_eq!(records, vec![record(1, 6, 0, 2), record(1, 7, 1, 1), record(64, 6, 2, 1)]);
+
+        assert_eq!(fs::read_to_string(dir.path().join("matrix.ec")).unwrap(), "0\t0\n1\t0,1\n2\t1\n");
+        assert_eq!(fs::read_to_string(dir.path().join("transcripts.txt")).unwrap(), "tx1\ntx2\n");
+    }
+}
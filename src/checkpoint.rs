@@ -0,0 +1,424 @@
+// Copyright (c) 2018 10x Genomics, Inc. All rights reserved.
+
+//! Resumable index builds. A build in a work directory runs in phases,
+//! each saving its output there and recording it in `manifest.json`, so an
+//! interrupted build can continue from the last completed phase.
+use std::fs::{self, File};
+use std::io::{BufReader, BufWriter, Write};
+use std::path::{Path, PathBuf};
+use std::time::UNIX_EPOCH;
+
+use bincode::{deserialize_from, serialize_into};
+use debruijn::Kmer;
+use debruijn::filter::EqClassIdType;
+use debruijn::graph::DebruijnGraph;
+use failure::{self, Error};
+use rayon::prelude::*;
+use serde::Serialize;
+use serde::de::DeserializeOwned;
+use serde_json;
+
+use build_index::{self, IndexOptions, ReferenceInput, ShardGraphs};
+use config::{MIN_KMERS, STRANDED};
+use pseudoaligner::Pseudoaligner;
+use utils::{self, ReferenceOptions};
+
+const MANIFEST_FILE: &str = "manifest.json";
+
+/// Reference read by the `Ingest` phase.
+type IngestOutput = ReferenceInput;
+
+/// Phases of an index build, in the order they run.
+#[derive(Serialize, Deserialize, Clone, Copy, Debug, PartialEq, Eq)]
+pub enum BuildPhase {
+    /// Read the reference sequences.
+    Ingest,
+    /// Bucket the k-mers, assemble every bucket and assign equivalence classes.
+    Assemble,
+    /// Merge the bucket graphs into one compressed graph.
+    Merge,
+    /// Build the k-mer MPHF and write the finished index.
+    Index,
+}
+
+const PHASES: [BuildPhase; 4] = [
+    BuildPhase::Ingest,
+    BuildPhase::Assemble,
+    BuildPhase::Merge,
+    BuildPhase::Index,
+];
+
+impl BuildPhase {
+    /// File in the work directory holding the output of the phase.
+    fn file_name(self) -> &'static str {
+        match self {
+            BuildPhase::Ingest => "reference.bin",
+            BuildPhase::Assemble => "shards.bin",
+            BuildPhase::Merge => "graph.bin",
+            BuildPhase::Index => "index.bin",
+        }
+    }
+}
+
+/// Size and modification time of a reference file, standing in for its
+/// contents, which would take as long to hash as to read the reference.
+#[derive(Serialize, Deserialize, Debug, PartialEq)]
+struct FileStamp {
+    len: u64,
+    modified_secs: u64,
+    modified_nanos: u32,
+}
+
+impl FileStamp {
+    fn new(path: &Path) -> Result<FileStamp, Error> {
+        let metadata = fs::metadata(path)?;
+        let modified = metadata.modified()?.duration_since(UNIX_EPOCH)?;
+        Ok(FileStamp {
+            len: metadata.len(),
+            modified_secs: modified.as_secs(),
+            modified_nanos: modified.subsec_nanos(),
+        })
+    }
+}
+
+/// Everything the output of a build depends on.
+#[derive(Serialize, Deserialize, Debug)]
+struct BuildConfig {
+    fasta_paths: Vec<PathBuf>,
+    /// Stamp of every file of `fasta_paths`, so that changing a reference
+    /// file invalidates the checkpoints
+    #[serde(default)]
+    fasta_stamps: Vec<FileStamp>,
+    options: ReferenceOptions,
+    /// Changes made to the index in the `Index` phase
+    #[serde(default)]
+    index_options: IndexOptions,
+    kmer_length: usize,
+    stranded: bool,
+    min_kmers: usize,
+    pkg_version: String,
+}
+
+impl BuildConfig {
+    fn new<K: Kmer>(
+        fasta_paths: Vec<PathBuf>,
+        options: ReferenceOptions,
+        index_options: IndexOptions,
+    ) -> Result<BuildConfig, Error> {
+        let fasta_stamps = fasta_paths
+            .iter()
+            .map(|path| FileStamp::new(path))
+            .collect::<Result<_, _>>()?;
+        Ok(BuildConfig {
+            fasta_paths,
+            fasta_stamps,
+            options,
+            index_options,
+            kmer_length: K::k(),
+            stranded: STRANDED,
+            min_kmers: MIN_KMERS,
+            pkg_version: env!("CARGO_PKG_VERSION").to_string(),
+        })
+    }
+
+    /// FNV-1a hash of the config as JSON with sorted keys, so that it does
+    /// not depend on hash map iteration order.
+    fn hash(&self) -> Result<u64, Error> {
+        let json = serde_json::to_value(self)?.to_string();
+        Ok(json.bytes().fold(0xcbf2_9ce4_8422_2325, |hash, byte| {
+            (hash ^ byte as u64).wrapping_mul(0x0100_0000_01b3)
+        }))
+    }
+}
+
+#[derive(Serialize, Deserialize, Debug)]
+struct BuildManifest {
+    config: BuildConfig,
+    config_hash: u64,
+    completed: Vec<BuildPhase>,
+}
+
+/// An index build whose phases are checkpointed to a work directory.
+pub struct IndexBuild {
+    work_dir: PathBuf,
+    manifest: BuildManifest,
+}
+
+impl IndexBuild {
+    /// Start a build of `fasta_paths` in `work_dir`, or pick up the
+    /// checkpoints an earlier build with the same configuration left there.
+    /// Checkpoints of a build with another configuration are an error.
+    pub fn create<K: Kmer, P: AsRef<Path>>(
+        work_dir: P,
+        fasta_paths: Vec<PathBuf>,
+        options: &ReferenceOptions,
+    ) -> Result<IndexBuild, Error> {
+        IndexBuild::create_with_index_options::<K, _>(work_dir, fasta_paths, options, &IndexOptions::default())
+    }
+
+    /// Like `create`, also recording the changes `index_options` makes to
+    /// the index in its last phase.
+    pub fn create_with_index_options<K: Kmer, P: AsRef<Path>>(
+        work_dir: P,
+        fasta_paths: Vec<PathBuf>,
+        options: &ReferenceOptions,
+        index_options: &IndexOptions,
+    ) -> Result<IndexBuild, Error> {
+        let work_dir = work_dir.as_ref().to_path_buf();
+        let config = BuildConfig::new::<K>(fasta_paths, options.clone(), index_options.clone())?;
+        let config_hash = config.hash()?;
+
+        if work_dir.join(MANIFEST_FILE).exists() {
+            let build = IndexBuild::open::<K, _>(&work_dir)?;
+            if build.manifest.config_hash != config_hash {
+                return Err(failure::err_msg(format!(
+                    "{} holds checkpoints of a build with different inputs or parameters",
+                    work_dir.display()
+                )));
+            }
+            return Ok(build);
+        }
+
+        fs::create_dir_all(&work_dir)?;
+        let build = IndexBuild {
+            work_dir,
+            manifest: BuildManifest { config, config_hash, completed: Vec::new() },
+        };
+        build.write_manifest()?;
+        Ok(build)
+    }
+
+    /// Reopen the build in `work_dir`, refusing it if its recorded
+    /// configuration or reference files were changed, or if it does not
+    /// match this build's k-mer settings.
+    pub fn open<K: Kmer, P: AsRef<Path>>(work_dir: P) -> Result<IndexBuild, Error> {
+        let work_dir = work_dir.as_ref().to_path_buf();
+        let manifest_path = work_dir.join(MANIFEST_FILE);
+        let manifest: BuildManifest = serde_json::from_reader(BufReader::new(File::open(&manifest_path)?))?;
+
+        let expected = BuildConfig::new::<K>(
+            manifest.config.fasta_paths.clone(),
+            manifest.config.options.clone(),
+            manifest.config.index_options.clone(),
+        )?;
+        if manifest.config.hash()? != manifest.config_hash || expected.hash()? != manifest.config_hash {
+            return Err(failure::err_msg(format!(
+                "Checkpoints in {} were made with different reference files or parameters",
+                work_dir.display()
+            )));
+        }
+
+        Ok(IndexBuild { work_dir, manifest })
+    }
+
+    /// The phase that runs next, or `None` once the index is written.
+    pub fn next_phase(&self) -> Option<BuildPhase> {
+        PHASES.get(self.manifest.completed.len()).cloned()
+    }
+
+    /// Run the next phase and checkpoint its output, returning the phase run.
+    pub fn run_next_phase<K>(&mut self) -> Result<Option<BuildPhase>, Error>
+    where
+        K: Kmer + Sync + Send + Serialize + DeserializeOwned,
+    {
+        let phase = match self.next_phase() {
+            Some(phase) => phase,
+            None => return Ok(None),
+        };
+        info!("Running index build phase {:?}", phase);
+        let progress = self.manifest.config.options.progress.clone();
+
+        match phase {
+            BuildPhase::Ingest => {
+                let config = &self.manifest.config;
+                let reference = build_index::read_reference_segments::<K>(&config.fasta_paths, &config.options)?;
+                self.save(phase, &reference)?;
+            }
+            BuildPhase::Assemble => {
+                let ((segments, _, _, _), _, _, _): IngestOutput = self.load(BuildPhase::Ingest)?;
+                let contigs = build_index::segment_contigs::<K>(&segments);
+                let shards: ShardGraphs<K> =
+                    build_index::assemble_contigs(&contigs, self.manifest.config.stranded, &progress)?;
+                self.save(phase, &shards)?;
+            }
+            BuildPhase::Merge => {
+                let (shard_dbgs, eq_classes): ShardGraphs<K> = self.load(BuildPhase::Assemble)?;
+                let dbg = build_index::merge_shard_dbgs(shard_dbgs, self.manifest.config.stranded, &progress);
+                self.save(phase, &(dbg, eq_classes))?;
+            }
+            BuildPhase::Index => {
+                let ((segments, tx_names, tx_gene_map, file_ids), checksums, gene_names, report): IngestOutput =
+                    self.load(BuildPhase::Ingest)?;
+                let (dbg, eq_classes): (DebruijnGraph<K, EqClassIdType>, Vec<Vec<u32>>) =
+                    self.load(BuildPhase::Merge)?;
+
+                let stranded = self.manifest.config.stranded;
+                let mut index = build_index::index_graph(dbg, eq_classes, &tx_names, &tx_gene_map, stranded, &progress);
+                index.tx_lengths = build_index::segment_lengths(&segments);
+                index.tx_node_starts = segments
+                    .par_iter()
+                    .map(|tx_segments| build_index::transcript_node_starts(&index, tx_segments))
+                    .collect();
+                index.tx_checksums = segments
+                    .par_iter()
+                    .map(|tx_segments| utils::transcript_checksum(tx_segments))
+                    .collect();
+                index.source_names = self
+                    .manifest
+                    .config
+                    .fasta_paths
+                    .iter()
+                    .map(|p| build_index::source_name(p))
+                    .collect();
+                index.tx_sources = file_ids.into_iter().map(|id| id as u8).collect();
+                index.reference_checksums = checksums;
+                index.gene_names = gene_names;
+                index.tx_gc_contents = report.gc_contents;
+                build_index::apply_index_options(&mut index, &self.manifest.config.index_options)?;
+                utils::write_index(&index, self.path(phase))?;
+            }
+        }
+
+        self.manifest.completed.push(phase);
+        self.write_manifest()?;
+        Ok(Some(phase))
+    }
+
+    /// Run the remaining phases and read the finished index.
+    pub fn finish<K>(mut self) -> Result<Pseudoaligner<K>, Error>
+    where
+        K: Kmer + Sync + Send + Serialize + DeserializeOwned,
+    {
+        while self.run_next_phase::<K>()?.is_some() {}
+        utils::read_index(self.path(BuildPhase::Index))
+    }
+
+    fn path(&self, phase: BuildPhase) -> PathBuf {
+        self.work_dir.join(phase.file_name())
+    }
+
+    fn save<T: Serialize>(&self, phase: BuildPhase, value: &T) -> Result<(), Error> {
+        let mut writer = BufWriter::new(File::create(self.path(phase))?);
+        serialize_into(&mut writer, value)?;
+        writer.flush()?;
+        Ok(())
+    }
+
+    fn load<T: DeserializeOwned>(&self, phase: BuildPhase) -> Result<T, Error> {
+        let reader = BufReader::new(File::open(self.path(phase))?);
+        Ok(deserialize_from(reader)?)
+    }
+
+    /// Replace the manifest atomically, so a crash never leaves a manifest
+    /// claiming a phase whose output is incomplete.
+    fn write_manifest(&self) -> Result<(), Error> {
+        let tmp_path = self.work_dir.join(format!("{}.tmp", MANIFEST_FILE));
+        {
+            let mut writer = BufWriter::new(File::create(&tmp_path)?);
+            serde_json::to_writer_pretty(&mut writer, &self.manifest)?;
+            writer.flush()?;
+        }
+        fs::rename(&tmp_path, self.work_dir.join(MANIFEST_FILE))?;
+        Ok(())
+    }
+}
+
+/// Continue the build in `work_dir` from its last completed phase.
+pub fn resume<K, P>(work_dir: P) -> Result<Pseudoaligner<K>, Error>
+where
+    K: Kmer + Sync + Send + Serialize + DeserializeOwned,
+    P: AsRef<Path>,
+{
+    IndexBuild::open::<K, _>(work_dir)?.finish()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use build_index::build_index_from_fasta;
+    use config::KmerType;
+    use debruijn::dna_string::DnaString;
+    use test_utils::{random_seq, TempDir};
+
+    #[test]
+    fn interrupted_build_resumes_to_the_same_index() {
+        let dir = TempDir::new();
+        let (tx1, tx2) = (random_seq(300, 1), random_seq(300, 2));
+        let fasta = dir.write_fasta("ref.fa", &[("tx1", &tx1), ("tx2", &tx2)]);
+        let work_dir = dir.path().join("work");
+        let options = ReferenceOptions::default();
+
+        // stop after two phases, as if the build had crashed
+        let mut build = IndexBuild::create::<KmerType, _>(&work_dir, vec![fasta.clone()], &options).unwrap();
+        assert_eq!(build.run_next_phase::<KmerType>().unwrap(), Some(BuildPhase::Ingest));
+        assert_eq!(build.run_next_phase::<KmerType>().unwrap(), Some(BuildPhase::Assemble));
+        drop(build);
+
+        let build = IndexBuild::open::<KmerType, _>(&work_dir).unwrap();
+        assert_eq!(build.next_phase(), Some(BuildPhase::Merge));
+        let index: Pseudoaligner<KmerType> = resume(&work_dir).unwrap();
+        let expected: Pseudoaligner<KmerType> = build_index_from_fasta(vec![fasta], &options).unwrap();
+        assert_eq!(index.tx_names, expected.tx_names);
+        assert_eq!(index.reference_checksums, expected.reference_checksums);
+        for (tx_id, tx) in [&tx1, &tx2].iter().enumerate() {
+            let read_seq = DnaString::from_dna_string(&tx[100..200]);
+            assert_eq!(index.map_read(&read_seq), Some((vec![tx_id as u32], 100)));
+        }
+    }
+
+    #[test]
+    fn resumed_build_makes_the_recorded_index_changes() {
+        let dir = TempDir::new();
+        let fasta = dir.write_fasta("ref.fa", &[("tx1", &random_seq(300, 1))]);
+        let work_dir = dir.path().join("work");
+        let index_options = IndexOptions {
+            sparse: Some(10),
+            groups: Some(dir.write("groups.tsv", "tx1\tspecies1\n")),
+            t2g: Some(dir.write("t2g.tsv", "tx1\tgene1\tGENE1\n")),
+            ..IndexOptions::default()
+        };
+
+        let mut build = IndexBuild::create_with_index_options::<KmerType, _>(
+            &work_dir, vec![fasta], &ReferenceOptions::default(), &index_options
+        ).unwrap();
+        build.run_next_phase::<KmerType>().unwrap();
+        drop(build);
+
+        let index: Pseudoaligner<KmerType> = resume(&work_dir).unwrap();
+        assert_eq!(index.minimizer_window, Some(10));
+        assert_eq!(index.tx_group(0), Some("species1"));
+        assert_eq!(index.gene_of(0), Some("gene1"));
+        assert_eq!(index.gene_name("gene1"), Some("GENE1"));
+    }
+
+    #[test]
+    fn checkpoints_of_other_parameters_are_refused() {
+        let dir = TempDir::new();
+        let fasta = dir.write_fasta("ref.fa", &[("tx1", &random_seq(300, 1))]);
+        let work_dir = dir.path().join("work");
+        let mut build =
+            IndexBuild::create::<KmerType, _>(&work_dir, vec![fasta.clone()], &ReferenceOptions::default()).unwrap();
+        build.run_next_phase::<KmerType>().unwrap();
+
+        let options = ReferenceOptions { min_seq_len: 100, ..ReferenceOptions::default() };
+        assert!(IndexBuild::create::<KmerType, _>(&work_dir, vec![fasta], &options).is_err());
+    }
+
+    #[test]
+    fn checkpoints_of_a_changed_reference_are_refused() {
+        let dir = TempDir::new();
+        let fasta = dir.write_fasta("ref.fa", &[("tx1", &random_seq(300, 1))]);
+        let work_dir = dir.path().join("work");
+        let options = ReferenceOptions::default();
+        let mut build = IndexBuild::create::<KmerType, _>(&work_dir, vec![fasta.clone()], &options).unwrap();
+        build.run_next_phase::<KmerType>().unwrap();
+
+        dir.write_fasta("ref.fa", &[("tx1", &random_seq(301, 2))]);
+        let message = IndexBuild::create::<KmerType, _>(&work_dir, vec![fasta], &options)
+            .err()
+            .expect("checkpoints of the old reference were reused")
+            .to_string();
+        assert!(message.contains("reference files"), "{}", message);
+        assert!(resume::<KmerType, _>(&work_dir).is_err());
+    }
+}
@@ -13,14 +13,80 @@ pub const STRANDED: bool = true;
 pub const REPORT_ALL_KMER: bool = false;
 pub const READ_COVERAGE_THRESHOLD: usize = 32;
 pub const LEFT_EXTEND_FRACTION: f64 = 0.4;
+pub const LOW_COMPLEXITY_WINDOW: usize = 32;
 
 pub const U32_MAX: usize = u32::max_value() as usize;
 
 // Worker queue configs
 pub const MAX_WORKER: usize = 2;
+pub const DEFAULT_MAPPING_SEED: u64 = 0;
+// Reads handed to a mapping thread at a time, and chunks of them read but
+// not yet written per thread
+pub const MAPPING_CHUNK_READS: usize = 1_000;
+pub const MAPPING_CHUNKS_PER_WORKER: usize = 2;
+// Masking read bases of low quality as N before mapping, and the Phred
+// quality they are masked below
+pub const QUALITY_MASK: bool = false;
+pub const MIN_BASE_QUALITY: u8 = 10;
+pub const PHRED_OFFSET: u8 = 33;
+// K-mers of a read between index lookups, and whether reads a strided
+// lookup leaves unmapped or ambiguous are looked up again at every k-mer
+pub const KMER_STRIDE: usize = 1;
+pub const RESCUE_FULL: bool = false;
+// Longest gap between matches to transcripts in common counted as covered
+pub const MAX_RESCUE_GAP: usize = 0;
+// Merging UMIs one mismatch apart when deduplicating reads
+pub const MERGE_UMIS: bool = false;
+// Fraction of the valid k-mers of a read that must be in the index for it
+// to map, besides the coverage threshold, and the bins the fractions of
+// the reads of a run are counted in
+pub const MIN_KMER_RATIO: f64 = 0.0;
+pub const KMER_RATIO_BINS: usize = 10;
+// Cell barcode and UMI bases at the start of the first mates of
+// single-cell reads
+pub const CELL_BARCODE_LEN: usize = 16;
+pub const CELL_UMI_LEN: usize = 12;
+// Reads of a transcript of an unstranded index needed to test it for strand
+// bias, and the z-score beyond which its reads are taken to be biased
+pub const STRAND_BIAS_MIN_READS: u64 = 20;
+pub const STRAND_BIAS_Z: f64 = 3.0;
+// Windows long reads are cut into: their length, the bases shared by
+// neighbours, and the mapped windows a call of a read or chimera part needs
+pub const LONG_READ_WINDOW: usize = 200;
+pub const LONG_READ_WINDOW_OVERLAP: usize = 100;
+pub const LONG_READ_MIN_WINDOWS: usize = 3;
 
 pub type KmerType = kmer::Kmer24;
 
+// Lines written between flushes of streamed mapping results
+pub const JSONL_FLUSH_INTERVAL: usize = 10_000;
+
+// Abundance below which transcripts do not count towards the change of an
+// EM iteration, and the change under which the EM is taken to have converged
+pub const EM_CHANGE_MIN_ABUNDANCE: f64 = 1e-2;
+pub const EM_TOLERANCE: f64 = 1e-2;
+pub const EM_MAX_ITERATIONS: usize = 10_000;
+
+// Length of the reads simulated for transcript confusion matrices
+pub const CONFUSION_READ_LEN: usize = 100;
+
+// MAPQ of SAM records of reads mapping to a single transcript
+pub const SAM_UNIQUE_MAPQ: u8 = 255;
+
+// Bytes between the bytes touched when prefetching a memory-mapped index,
+// at most the page size
+pub const PREFETCH_STRIDE: usize = 4096;
+
+// zstd level of compressed indices
+pub const INDEX_ZSTD_LEVEL: i32 = 3;
+
 // Transcriptome mappability
 pub const MAPPABILITY_COUNTS_LEN: usize = 11;
 
+// Pre-build estimates: rough costs per reference base and per distinct
+// k-mer, meant to be right within a factor of two
+pub const HLL_PRECISION: u32 = 14;
+pub const ESTIMATE_BASE_MEMORY: u64 = 8 << 20;
+pub const ESTIMATE_MEMORY_PER_BASE: u64 = 11;
+pub const ESTIMATE_MEMORY_PER_KMER: u64 = 2;
+pub const ESTIMATE_INDEX_BYTES_PER_KMER: u64 = 9;
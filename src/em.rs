@@ -0,0 +1,237 @@
+// Copyright (c) 2018 10x Genomics, Inc. All rights reserved.
+
+//! Transcript abundances from the read counts of equivalence classes, by
+//! expectation maximization. The EM advances one iteration per `em_step`,
+//! so callers decide when it converged, and can checkpoint its state to
+//! continue an interrupted run.
+use std::collections::HashMap;
+use std::fs::{self, File};
+use std::io::{BufReader, BufWriter, Write};
+use std::path::Path;
+
+use bincode::{deserialize_from, serialize_into};
+use failure::{self, Error};
+
+use config::EM_CHANGE_MIN_ABUNDANCE;
+
+/// Abundances of the transcripts, as expected numbers of reads, after
+/// `iteration` EM iterations.
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct EmState {
+    pub abundances: Vec<f64>,
+    pub iteration: usize,
+}
+
+impl EmState {
+    /// Start the EM of `num_transcripts` with the reads of `counts` spread
+    /// evenly over them.
+    pub fn new(num_transcripts: usize, counts: &[(Vec<u32>, u64)]) -> EmState {
+        let num_reads: u64 = counts.iter().map(|&(_, count)| count).sum();
+        let abundance = if num_transcripts == 0 { 0.0 } else { num_reads as f64 / num_transcripts as f64 };
+        EmState { abundances: vec![abundance; num_transcripts], iteration: 0 }
+    }
+
+    /// Write the state to `path`, replacing it atomically, so an
+    /// interruption never leaves a partial checkpoint.
+    pub fn save<P: AsRef<Path>>(&self, path: P) -> Result<(), Error> {
+        let path = path.as_ref();
+        let tmp_path = path.with_extension("tmp");
+        {
+            let mut writer = BufWriter::new(File::create(&tmp_path)?);
+            serialize_into(&mut writer, self)?;
+            writer.flush()?;
+        }
+        fs::rename(&tmp_path, path)?;
+        Ok(())
+    }
+
+    /// Read a state written by `save`.
+    pub fn load<P: AsRef<Path>>(path: P) -> Result<EmState, Error> {
+        let reader = BufReader::new(File::open(path)?);
+        Ok(deserialize_from(reader)?)
+    }
+}
+
+/// The counts of `eq_class_counts` sorted by class, the order `em_step`
+/// wants them in: floating point sums depend on the order of their terms,
+/// and hash map order changes from run to run, so only a fixed order lets
+/// a resumed EM end with the same abundances as an uninterrupted one.
+pub fn sorted_counts(eq_class_counts: &HashMap<Vec<u32>, u64>) -> Vec<(Vec<u32>, u64)> {
+    let mut counts: Vec<(Vec<u32>, u64)> = eq_class_counts
+        .iter()
+        .map(|(eq_class, &count)| (eq_class.clone(), count))
+        .collect();
+    counts.sort();
+    counts
+}
+
+/// Advance `state` by one EM iteration: the reads of every class of
+/// `counts` are split over its transcripts in proportion to their
+/// abundance per base of their effective length in `eff_lengths`.
+/// Transcripts without effective length get no reads, nor do classes
+/// without any abundance left. Returns the largest relative change of the
+/// abundances above `EM_CHANGE_MIN_ABUNDANCE`, for the caller to stop once
+/// it is small enough.
+pub fn em_step(state: &mut EmState, counts: &[(Vec<u32>, u64)], eff_lengths: &[f64]) -> Result<f64, Error> {
+    let num_transcripts = state.abundances.len();
+    if eff_lengths.len() != num_transcripts {
+        return Err(failure::err_msg(format!(
+            "EM of {} transcripts given {} effective lengths",
+            num_transcripts,
+            eff_lengths.len()
+        )));
+    }
+
+    let weights: Vec<f64> = state
+        .abundances
+        .iter()
+        .zip(eff_lengths)
+        .map(|(&abundance, &eff_length)| if eff_length > 0.0 { abundance / eff_length } else { 0.0 })
+        .collect();
+
+    let mut abundances = vec![0.0; num_transcripts];
+    for (eq_class, count) in counts {
+        if let Some(&tx_id) = eq_class.iter().find(|&&tx_id| tx_id as usize >= num_transcripts) {
+            return Err(failure::err_msg(format!(
+                "Equivalence class holds transcript {} of an EM of {} transcripts",
+                tx_id, num_transcripts
+            )));
+        }
+
+        let total: f64 = eq_class.iter().map(|&tx_id| weights[tx_id as usize]).sum();
+        if total <= 0.0 {
+            continue;
+        }
+        for &tx_id in eq_class {
+            abundances[tx_id as usize] += *count as f64 * weights[tx_id as usize] / total;
+        }
+    }
+
+    let change = abundances
+        .iter()
+        .zip(&state.abundances)
+        .filter(|&(&new, _)| new > EM_CHANGE_MIN_ABUNDANCE)
+        .map(|(&new, &old)| (new - old).abs() / new)
+        .fold(0.0, f64::max);
+
+    state.abundances = abundances;
+    state.iteration += 1;
+    Ok(change)
+}
+
+/// Assign the reads of every class of `counts` to its transcript with the
+/// highest of the converged `abundances`, the lowest index on ties, for
+/// counts of reads per transcript. Classes without any abundance get no
+/// transcript, and classes of transcripts past `abundances` are an error,
+/// as in `em_step`.
+pub fn assign_reads(counts: &HashMap<Vec<u32>, u64>, abundances: &[f64]) -> Result<HashMap<u32, u64>, Error> {
+    let mut assigned = HashMap::new();
+    for (eq_class, &count) in counts {
+        if let Some(&tx_id) = eq_class.iter().find(|&&tx_id| tx_id as usize >= abundances.len()) {
+            return Err(failure::err_msg(format!(
+                "Equivalence class holds transcript {} of an EM of {} transcripts",
+                tx_id,
+                abundances.len()
+            )));
+        }
+
+        let mut best: Option<(u32, f64)> = None;
+        for &tx_id in eq_class {
+            let abundance = abundances[tx_id as usize];
+            let better = match best {
+                Some((best_id, best_abundance)) => {
+                    abundance > best_abundance || (abundance == best_abundance && tx_id < best_id)
+                }
+                None => true,
+            };
+            if abundance > 0.0 && better {
+                best = Some((tx_id, abundance));
+            }
+        }
+
+        if let Some((tx_id, _)) = best {
+            *assigned.entry(tx_id).or_insert(0) += count;
+        }
+    }
+    Ok(assigned)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use config::{EM_MAX_ITERATIONS, EM_TOLERANCE};
+    use test_utils::TempDir;
+
+    /// 30 reads of transcript 0, 10 of transcript 1 and 60 of both.
+    fn class_counts() -> HashMap<Vec<u32>, u64> {
+        vec![(vec![0], 30), (vec![1], 10), (vec![0, 1], 60)].into_iter().collect()
+    }
+
+    /// Run `state` until it converges.
+    fn run_to_convergence(state: &mut EmState, counts: &[(Vec<u32>, u64)], eff_lengths: &[f64]) {
+        while state.iteration < EM_MAX_ITERATIONS {
+            if em_step(state, counts, eff_lengths).unwrap() < EM_TOLERANCE {
+                break;
+            }
+        }
+    }
+
+    #[test]
+    fn shared_reads_are_split_like_the_unique_ones() {
+        let counts = sorted_counts(&class_counts());
+        let mut state = EmState::new(2, &counts);
+        assert_eq!(state.abundances, vec![50.0, 50.0]);
+        run_to_convergence(&mut state, &counts, &[100.0, 100.0]);
+        assert!((state.abundances[0] - 75.0).abs() < 1.0, "{:?}", state);
+        assert!((state.abundances[1] - 25.0).abs() < 1.0, "{:?}", state);
+        assert!((state.abundances.iter().sum::<f64>() - 100.0).abs() < 1e-9);
+    }
+
+    #[test]
+    fn a_resumed_checkpoint_ends_with_the_same_abundances() {
+        let counts = sorted_counts(&class_counts());
+        let eff_lengths = [100.0, 150.0];
+        let mut uninterrupted = EmState::new(2, &counts);
+        run_to_convergence(&mut uninterrupted, &counts, &eff_lengths);
+
+        let dir = TempDir::new();
+        let path = dir.path().join("em.state");
+        let mut state = EmState::new(2, &counts);
+        for _ in 0..3 {
+            em_step(&mut state, &counts, &eff_lengths).unwrap();
+        }
+        state.save(&path).unwrap();
+        drop(state);
+
+        let mut resumed = EmState::load(&path).unwrap();
+        assert_eq!(resumed.iteration, 3);
+        run_to_convergence(&mut resumed, &counts, &eff_lengths);
+        assert_eq!(resumed, uninterrupted);
+    }
+
+    #[test]
+    fn classes_beyond_the_transcripts_are_an_error() {
+        let counts = vec![(vec![0, 2], 5)];
+        let mut state = EmState::new(2, &counts);
+        assert!(em_step(&mut state, &counts, &[100.0, 100.0]).is_err());
+        assert!(em_step(&mut state, &[], &[100.0]).is_err());
+    }
+
+    #[test]
+    fn a_shared_class_goes_to_its_more_abundant_transcript() {
+        let counts = sorted_counts(&class_counts());
+        let mut state = EmState::new(2, &counts);
+        run_to_convergence(&mut state, &counts, &[100.0, 100.0]);
+        let assigned = assign_reads(&class_counts(), &state.abundances).unwrap();
+        assert_eq!(assigned, vec![(0, 90), (1, 10)].into_iter().collect::<HashMap<u32, u64>>());
+
+        // ties go to the lower index, and classes without abundance to none
+        let counts: HashMap<Vec<u32>, u64> = vec![(vec![1, 2], 4), (vec![3], 7)].into_iter().collect();
+        let assigned = assign_reads(&counts, &[0.0, 5.0, 5.0, 0.0]).unwrap();
+        assert_eq!(assigned, vec![(1, 4)].into_iter().collect::<HashMap<u32, u64>>());
+
+        let counts: HashMap<Vec<u32>, u64> = vec![(vec![0, 4], 2)].into_iter().collect();
+        let err = assign_reads(&counts, &[0.0, 5.0, 5.0, 0.0]).unwrap_err();
+        assert_eq!(err.to_string(), "Equivalence class holds transcript 4 of an EM of 4 transcripts");
+    }
+}
@@ -0,0 +1,72 @@
+// Copyright (c) 2018 10x Genomics, Inc. All rights reserved.
+
+//! Errors the library reports in a form callers can match on. The index
+//! reading, writing and building functions return them; the other
+//! functions still return `failure::Error`, from which
+//! `downcast_ref::<PseudoalignerError>()` recovers the variant.
+use std::io;
+
+use bincode;
+use failure;
+use thiserror::Error;
+
+#[derive(Debug, Error)]
+pub enum PseudoalignerError {
+    #[error("Not a pseudoaligner index file")]
+    NotAnIndex,
+    #[error("Unsupported index format version {found} (supported: {expected})")]
+    IndexVersionMismatch { found: u32, expected: u32 },
+    #[error("Index file is truncated: expected at least {expected} bytes, found {found}")]
+    TruncatedIndex { expected: usize, found: usize },
+    #[error("Fasta record {0} has no description")]
+    MissingDescription(String),
+    #[error("Fasta record {0} does not have the expected header fields")]
+    MalformedHeader(String),
+    #[error("Unknown fasta header format of record {0}")]
+    UnknownFastaFormat(String),
+    #[error("Duplicate transcript ids in input fasta: {0}")]
+    DuplicateTranscripts(String),
+    #[error("Index {index} was built with k = {found}, not {expected}")]
+    KmerLengthMismatch { index: String, found: usize, expected: usize },
+    #[error("{0} is not a k-mer of {1} A, C, G or T bases")]
+    InvalidKmer(String, usize),
+    #[error(transparent)]
+    Io(#[from] io::Error),
+    #[error(transparent)]
+    Serialization(#[from] bincode::Error),
+    /// Any other error, by its message
+    #[error("{0}")]
+    Other(String),
+}
+
+/// Recover the variant of errors raised as `failure::Error` by the
+/// functions the index functions call.
+impl From<failure::Error> for PseudoalignerError {
+    fn from(err: failure::Error) -> PseudoalignerError {
+        let err = match err.downcast::<PseudoalignerError>() {
+            Ok(err) => return err,
+            Err(err) => err,
+        };
+        match err.downcast::<io::Error>() {
+            Ok(err) => PseudoalignerError::Io(err),
+            Err(err) => PseudoalignerError::Other(err.to_string()),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn conversion_from_failure_recovers_the_variant() {
+        let err: failure::Error = PseudoalignerError::DuplicateTranscripts("tx1".to_string()).into();
+        assert!(matches!(PseudoalignerError::from(err), PseudoalignerError::DuplicateTranscripts(ref ids) if ids == "tx1"));
+
+        let err: failure::Error = io::Error::from(io::ErrorKind::NotFound).into();
+        assert!(matches!(PseudoalignerError::from(err), PseudoalignerError::Io(ref err) if err.kind() == io::ErrorKind::NotFound));
+
+        let err = PseudoalignerError::from(failure::err_msg("no partitions"));
+        assert!(matches!(err, PseudoalignerError::Other(ref message) if message == "no partitions"));
+    }
+}
@@ -8,7 +8,17 @@ extern crate debruijn;
 extern crate flate2;
 extern crate failure;
 extern crate itertools;
+extern crate memmap2;
+extern crate rand;
+extern crate rand_chacha;
 extern crate rayon;
+#[cfg(feature = "bam")]
+extern crate rust_htslib;
+extern crate serde_json;
+extern crate sha2;
+extern crate thiserror;
+#[cfg(feature = "zstd")]
+extern crate zstd;
 
 #[macro_use]
 extern crate lazy_static;
@@ -19,8 +29,23 @@ extern crate serde;
 #[macro_use]
 extern crate log;
 
+#[cfg(feature = "bam")]
+pub mod bam;
+pub mod barcode;
 pub mod build_index;
+pub mod bus;
+pub mod checkpoint;
 pub mod config;
+pub mod em;
+pub mod error;
+pub mod long_read;
 pub mod mappability;
+#[cfg(feature = "peptide")]
+pub mod peptide;
+pub mod progress;
 pub mod pseudoaligner;
+pub mod stats;
+#[cfg(test)]
+mod test_utils;
+pub mod umi;
 pub mod utils;
@@ -0,0 +1,381 @@
+// Copyright (c) 2018 10x Genomics, Inc. All rights reserved.
+
+//! Mapping of long reads, like those of nanopore and PacBio, whose errors
+//! leave too few exact k-mer matches for the coverage threshold of a whole
+//! read. Reads are cut into overlapping windows that are mapped on their
+//! own, and the windows that map vote on the call of the read.
+use std::cmp;
+use std::collections::{BTreeMap, HashMap};
+use std::io::Write;
+
+use bio::io::fastq;
+use debruijn::Kmer;
+use failure::{self, Error};
+use rayon::prelude::*;
+use rayon::ThreadPoolBuilder;
+
+use config::{LONG_READ_MIN_WINDOWS, LONG_READ_WINDOW, LONG_READ_WINDOW_OVERLAP, MAPPING_CHUNK_READS};
+use pseudoaligner::{MappingOutcome, Pseudoaligner};
+
+/// How long reads are cut into windows, and how many windows a call needs.
+#[derive(Clone, Copy, Debug, Serialize)]
+pub struct LongReadOptions {
+    /// Bases of a window
+    pub window: usize,
+    /// Bases a window shares with the next one
+    pub overlap: usize,
+    /// Mapped windows needed to call a read, or each part of a chimera
+    pub min_windows: usize,
+}
+
+impl Default for LongReadOptions {
+    fn default() -> LongReadOptions {
+        LongReadOptions {
+            window: LONG_READ_WINDOW,
+            overlap: LONG_READ_WINDOW_OVERLAP,
+            min_windows: LONG_READ_MIN_WINDOWS,
+        }
+    }
+}
+
+impl LongReadOptions {
+    fn check(&self) -> Result<(), Error> {
+        if self.window == 0 || self.overlap >= self.window {
+            return Err(failure::err_msg(format!(
+                "Long read windows of {} bases can not overlap by {}",
+                self.window, self.overlap
+            )));
+        }
+        Ok(())
+    }
+
+    /// Start and end of the windows of a read of `read_length` bases: one
+    /// every `window - overlap` bases, and a last one ending at the end of
+    /// the read, or the whole read if it is no longer than a window.
+    pub fn windows(&self, read_length: usize) -> Vec<(usize, usize)> {
+        if read_length <= self.window {
+            return vec![(0, read_length)];
+        }
+
+        let step = cmp::max(self.window.saturating_sub(self.overlap), 1);
+        let mut windows: Vec<(usize, usize)> = (0..=read_length - self.window)
+            .step_by(step)
+            .map(|start| (start, start + self.window))
+            .collect();
+        if windows.last().map_or(true, |&(_, end)| end < read_length) {
+            windows.push((read_length - self.window, read_length));
+        }
+        windows
+    }
+}
+
+/// A window of a long read and how it mapped.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct WindowMapping {
+    pub start: usize,
+    pub end: usize,
+    pub outcome: MappingOutcome,
+}
+
+impl WindowMapping {
+    /// Equivalence class of the window, if it mapped to a non-empty one.
+    pub fn eq_class(&self) -> Option<&[u32]> {
+        match self.outcome {
+            MappingOutcome::Mapped(ref eq_class, _) | MappingOutcome::Rescued(ref eq_class, _) if !eq_class.is_empty() => {
+                Some(eq_class)
+            }
+            _ => None,
+        }
+    }
+}
+
+/// Transcripts most windows of a read mapped to, and the number of those
+/// windows.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct WindowVote {
+    pub eq_class: Vec<u32>,
+    pub windows: usize,
+}
+
+/// Call of a long read from the votes of its windows.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum LongReadCall {
+    /// The windows agree on the transcripts
+    Mapped(WindowVote),
+    /// The windows split into two parts that share no transcript, each
+    /// with enough windows, in the order they come in the read
+    Chimeric(WindowVote, WindowVote),
+    /// Too few windows mapped
+    Unmapped,
+}
+
+impl LongReadCall {
+    pub fn code(&self) -> &'static str {
+        match *self {
+            LongReadCall::Mapped(_) => "mapped",
+            LongReadCall::Chimeric(..) => "chimeric",
+            LongReadCall::Unmapped => "unmapped",
+        }
+    }
+}
+
+/// Vote of `windows`, given by their start and class: the transcripts in
+/// the most classes, with the number of those classes.
+fn majority(windows: &[(usize, &[u32])]) -> Option<WindowVote> {
+    let mut support: BTreeMap<u32, usize> = BTreeMap::new();
+    for &(_, eq_class) in windows {
+        for &tx_id in eq_class {
+            *support.entry(tx_id).or_insert(0) += 1;
+        }
+    }
+
+    let max_support = support.values().cloned().max()?;
+    let eq_class = support
+        .into_iter()
+        .filter(|&(_, windows)| windows == max_support)
+        .map(|(tx_id, _)| tx_id)
+        .collect();
+    Some(WindowVote { eq_class, windows: max_support })
+}
+
+/// Call of a read from the classes of its mapped `windows`, given by their
+/// start, in read order. The majority of the windows is the call if at
+/// least `min_windows` voted for it; if the windows sharing no transcript
+/// with it have a majority of at least `min_windows` of their own, the read
+/// is chimeric.
+pub fn vote_windows(windows: &[(usize, &[u32])], min_windows: usize) -> LongReadCall {
+    let min_windows = cmp::max(min_windows, 1);
+    let first = match majority(windows) {
+        Some(vote) if vote.windows >= min_windows => vote,
+        _ => return LongReadCall::Unmapped,
+    };
+
+    let shares_first = |eq_class: &[u32]| eq_class.iter().any(|tx_id| first.eq_class.binary_search(tx_id).is_ok());
+    let others: Vec<(usize, &[u32])> = windows.iter().cloned().filter(|&(_, eq_class)| !shares_first(eq_class)).collect();
+    let second = match majority(&others) {
+        Some(vote) if vote.windows >= min_windows => vote,
+        _ => return LongReadCall::Mapped(first),
+    };
+
+    // the part whose windows start first on average comes first
+    let mean_start = |vote: &WindowVote| {
+        let starts: Vec<usize> = windows
+            .iter()
+            .filter(|&&(_, eq_class)| eq_class.iter().any(|tx_id| vote.eq_class.binary_search(tx_id).is_ok()))
+            .map(|&(start, _)| start)
+            .collect();
+        starts.iter().sum::<usize>() as f64 / cmp::max(starts.len(), 1) as f64
+    };
+    if mean_start(&second) < mean_start(&first) {
+        LongReadCall::Chimeric(second, first)
+    } else {
+        LongReadCall::Chimeric(first, second)
+    }
+}
+
+impl<K: Kmer + Sync + Send> Pseudoaligner<K> {
+    /// Map the ASCII bases of a long read window by window, as cut by
+    /// `options`, and call the read from the windows that map.
+    pub fn map_long_read(&self, bases: &[u8], options: &LongReadOptions) -> (LongReadCall, Vec<WindowMapping>) {
+        let windows: Vec<WindowMapping> = options
+            .windows(bases.len())
+            .into_iter()
+            .map(|(start, end)| WindowMapping { start, end, outcome: self.map_read_bases_outcome(&bases[start..end]) })
+            .collect();
+
+        let mapped: Vec<(usize, &[u32])> =
+            windows.iter().filter_map(|window| window.eq_class().map(|eq_class| (window.start, eq_class))).collect();
+        (vote_windows(&mapped, options.min_windows), windows)
+    }
+}
+
+/// Reads `map_long_reads` called, and the mapped ones by class.
+#[derive(Debug, Default)]
+pub struct LongReadCounts {
+    pub num_reads: usize,
+    pub num_mapped: usize,
+    pub num_chimeric: usize,
+    pub eq_class_counts: HashMap<Vec<u32>, u64>,
+}
+
+/// Call the long reads of `records` with `map_long_read` on `threads`
+/// threads, writing the call of every read to `calls` as TSV with the
+/// columns `read call windows mapped_windows supporting_windows
+/// transcripts`, and the mapping of every window to `windows` if given,
+/// with the columns `read start end status coverage transcripts`. The
+/// parts of chimeric reads are separated by `|`; missing values are `NA`.
+pub fn map_long_reads<K, I, W>(
+    index: &Pseudoaligner<K>,
+    records: I,
+    options: LongReadOptions,
+    threads: usize,
+    mut calls: W,
+    mut windows: Option<W>,
+) -> Result<LongReadCounts, Error>
+where
+    K: Kmer + Sync + Send,
+    I: Iterator<Item = Result<fastq::Record, Error>>,
+    W: Write,
+{
+    options.check()?;
+    let pool = ThreadPoolBuilder::new().num_threads(cmp::max(threads, 1)).build()?;
+    writeln!(calls, "read\tcall\twindows\tmapped_windows\tsupporting_windows\ttranscripts")?;
+    if let Some(writer) = windows.as_mut() {
+        writeln!(writer, "read\tstart\tend\tstatus\tcoverage\ttranscripts")?;
+    }
+
+    let mut counts = LongReadCounts::default();
+    let mut batch = Vec::with_capacity(MAPPING_CHUNK_READS);
+    let mut records = records.peekable();
+    while records.peek().is_some() {
+        batch.clear();
+        while batch.len() < MAPPING_CHUNK_READS {
+            match records.next() {
+                Some(record) => batch.push(record?),
+                None => break,
+            }
+        }
+
+        let mappings: Vec<(LongReadCall, Vec<WindowMapping>)> =
+            pool.install(|| batch.par_iter().map(|record| index.map_long_read(record.seq(), &options)).collect());
+        for (record, (call, read_windows)) in batch.iter().zip(mappings) {
+            write_long_read(index, record.id(), &call, &read_windows, &mut calls, windows.as_mut())?;
+
+            counts.num_reads += 1;
+            match call {
+                LongReadCall::Mapped(vote) => {
+                    counts.num_mapped += 1;
+                    *counts.eq_class_counts.entry(vote.eq_class).or_insert(0) += 1;
+                }
+                LongReadCall::Chimeric(..) => counts.num_chimeric += 1,
+                LongReadCall::Unmapped => (),
+            }
+        }
+    }
+
+    calls.flush()?;
+    if let Some(writer) = windows.as_mut() {
+        writer.flush()?;
+    }
+    Ok(counts)
+}
+
+fn write_long_read<K: Kmer + Sync + Send, W: Write>(
+    index: &Pseudoaligner<K>,
+    read_id: &str,
+    call: &LongReadCall,
+    read_windows: &[WindowMapping],
+    calls: &mut W,
+    windows: Option<&mut W>,
+) -> Result<(), Error> {
+    let tx_names = |eq_class: &[u32]| {
+        let names: Vec<&str> = eq_class.iter().map(|&tx_id| index.tx_names[tx_id as usize].as_str()).collect();
+        names.join(",")
+    };
+
+    let num_mapped = read_windows.iter().filter(|window| window.eq_class().is_some()).count();
+    let (support, transcripts) = match *call {
+        LongReadCall::Mapped(ref vote) => (vote.windows.to_string(), tx_names(&vote.eq_class)),
+        LongReadCall::Chimeric(ref first, ref second) => (
+            format!("{}|{}", first.windows, second.windows),
+            format!("{}|{}", tx_names(&first.eq_class), tx_names(&second.eq_class)),
+        ),
+        LongReadCall::Unmapped => ("NA".to_string(), "NA".to_string()),
+    };
+    writeln!(
+        calls, "{}\t{}\t{}\t{}\t{}\t{}",
+        read_id, call.code(), read_windows.len(), num_mapped, support, transcripts
+    )?;
+
+    if let Some(writer) = windows {
+        for window in read_windows {
+            let (status, coverage, eq_class) = match window.outcome {
+                MappingOutcome::Mapped(ref eq_class, coverage) => ("mapped", coverage, &eq_class[..]),
+                MappingOutcome::Rescued(ref eq_class, coverage) => ("rescued", coverage, &eq_class[..]),
+                MappingOutcome::TooShort => ("too_short", 0, &[][..]),
+                MappingOutcome::Unmapped(reason, ref eq_class, coverage) => (reason.code(), coverage, &eq_class[..]),
+            };
+            let transcripts = if eq_class.is_empty() { "NA".to_string() } else { tx_names(eq_class) };
+            writeln!(
+                writer, "{}\t{}\t{}\t{}\t{}\t{}",
+                read_id, window.start, window.end, status, coverage, transcripts
+            )?;
+        }
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use rand::{Rng, SeedableRng};
+    use rand_chacha::ChaCha8Rng;
+    use test_utils::{build_test_index, random_seq};
+
+    /// `seq` with errors at 6% of its bases, spread over it at random by a
+    /// seeded generator: substitutions, insertions and deletions alike.
+    fn with_errors(seq: &str) -> String {
+        let mut rng = ChaCha8Rng::seed_from_u64(6);
+        let mut read = String::new();
+        for base in seq.chars() {
+            if !rng.gen_bool(0.06) {
+                read.push(base);
+                continue;
+            }
+            match rng.gen_range(0..3) {
+                0 => read.push(if base == 'A' { 'C' } else { 'A' }),
+                1 => {
+                    read.push(base);
+                    read.push(['A', 'C', 'G', 'T'][rng.gen_range(0..4)]);
+                }
+                _ => (),
+            }
+        }
+        read
+    }
+
+    #[test]
+    fn windows_overlap_and_the_last_one_ends_the_read() {
+        let options = LongReadOptions { window: 200, overlap: 100, min_windows: 3 };
+        assert_eq!(options.windows(450), vec![(0, 200), (100, 300), (200, 400), (250, 450)]);
+        assert_eq!(options.windows(400), vec![(0, 200), (100, 300), (200, 400)]);
+        assert_eq!(options.windows(150), vec![(0, 150)]);
+        assert!(LongReadOptions { window: 200, overlap: 200, min_windows: 3 }.check().is_err());
+    }
+
+    #[test]
+    fn windows_vote_for_the_transcripts_most_of_them_share() {
+        let windows = [(0, &[0][..]), (100, &[0, 1][..]), (200, &[0][..])];
+        assert_eq!(vote_windows(&windows, 3), LongReadCall::Mapped(WindowVote { eq_class: vec![0], windows: 3 }));
+        assert_eq!(vote_windows(&windows, 4), LongReadCall::Unmapped);
+        assert_eq!(vote_windows(&[], 1), LongReadCall::Unmapped);
+    }
+
+    #[test]
+    fn a_long_read_with_errors_maps_and_a_chimera_is_split() {
+        let (tx1, tx2) = (random_seq(3000, 1), random_seq(3000, 2));
+        let index = build_test_index(&[("tx1", &tx1), ("tx2", &tx2)]);
+        let options = LongReadOptions::default();
+
+        let read = with_errors(&tx1);
+        let (call, windows) = index.map_long_read(read.as_bytes(), &options);
+        assert_eq!(windows.len(), options.windows(read.len()).len());
+        match call {
+            LongReadCall::Mapped(vote) => {
+                assert_eq!(vote.eq_class, vec![0]);
+                assert!(vote.windows >= options.min_windows, "{:?}", vote);
+            }
+            call => panic!("{:?}", call),
+        }
+
+        // the first 1200 bases of tx1 then the rest of tx2
+        let chimera = with_errors(&(tx1[..1200].to_string() + &tx2[1200..]));
+        match index.map_long_read(chimera.as_bytes(), &options).0 {
+            LongReadCall::Chimeric(first, second) => {
+                assert_eq!((first.eq_class, second.eq_class), (vec![0], vec![1]));
+                assert!(first.windows >= options.min_windows && second.windows > first.windows);
+            }
+            call => panic!("{:?}", call),
+        }
+    }
+}
@@ -67,12 +67,20 @@ impl MappabilityRecord {
         }
     }
     
+    /// Zero for transcripts without k-mers.
     pub fn fraction_unique_tx(&self) -> f64 {
-        self.tx_multiplicity[0] as f64 / self.total_kmer_count() as f64
+        match self.total_kmer_count() {
+            0 => 0.0,
+            total => self.tx_multiplicity[0] as f64 / total as f64,
+        }
     }
 
+    /// Zero for transcripts without k-mers.
     pub fn fraction_unique_gene(&self) -> f64 {
-        self.gene_multiplicity[0] as f64 / self.total_kmer_count() as f64
+        match self.total_kmer_count() {
+            0 => 0.0,
+            total => self.gene_multiplicity[0] as f64 / total as f64,
+        }
     }
 }
 
@@ -123,3 +131,36 @@ pub fn analyze_graph<K: Kmer>(index: &Pseudoaligner<K>) -> Result<(Vec<Mappabili
 
     Ok(records)
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::collections::HashMap;
+    use build_index::build_index_from_segments;
+    use config::KmerType;
+    use debruijn::dna_string::DnaString;
+    use test_utils::random_seq;
+
+    #[test]
+    fn transcripts_without_kmers_are_not_unique_rather_than_nan() {
+        let (tx1, tx2) = (random_seq(300, 1), random_seq(300, 2));
+        let segments = vec![
+            vec![DnaString::from_dna_string(&tx1)],
+            vec![DnaString::from_dna_string(&random_seq(20, 3))],
+            vec![DnaString::from_dna_string(&(tx2[..150].to_string() + &tx1[..150]))],
+        ];
+        let tx_names: Vec<String> = vec!["tx1".to_string(), "short".to_string(), "tx2".to_string()];
+        let tx_gene_map: HashMap<String, String> =
+            tx_names.iter().map(|tx_name| (tx_name.clone(), format!("G{}", tx_name))).collect();
+        let index = build_index_from_segments::<KmerType>(&segments, &tx_names, &tx_gene_map).unwrap();
+
+        let records = analyze_graph(&index).unwrap();
+        assert_eq!(records[1].total_kmer_count(), 0);
+        assert_eq!(records[1].fraction_unique_tx(), 0.0);
+        assert_eq!(records[1].fraction_unique_gene(), 0.0);
+        // the k-mers of the first half of tx1 are shared with tx2
+        let num_kmers = 300 - KmerType::k() + 1;
+        assert_eq!(records[0].total_kmer_count(), num_kmers);
+        assert_eq!(records[0].fraction_unique_tx(), 150.0 / num_kmers as f64);
+    }
+}
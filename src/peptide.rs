@@ -0,0 +1,172 @@
+// Copyright (c) 2018 10x Genomics, Inc. All rights reserved.
+
+//! Pseudoalignment of peptides against an amino-acid reference. Every
+//! residue is encoded as four bases, so the DNA de Bruijn graph and the
+//! equivalence class machinery are reused unchanged: a k-mer of the index
+//! covers k / 4 residues, and peptides are only looked up at k-mers starting
+//! on a residue boundary. Each residue starts with a T, which its other
+//! bases never are, so a k-mer starting on a residue boundary never equals
+//! one that does not, and out-of-frame k-mers of the reference never match.
+use std::collections::HashMap;
+use std::path::Path;
+
+use debruijn::Kmer;
+use debruijn::dna_string::DnaString;
+use failure::{self, Error};
+
+use build_index;
+use config::STRANDED;
+use error::PseudoalignerError;
+use pseudoaligner::Pseudoaligner;
+use utils;
+
+/// The 20 standard amino acids, in the order of their codes.
+pub const AMINO_ACIDS: &[u8; 20] = b"ACDEFGHIKLMNPQRSTVWY";
+
+/// Bases encoding one residue.
+pub const RESIDUE_BASES: usize = 4;
+
+/// Base starting every encoded residue, T.
+const RESIDUE_START: u8 = 3;
+
+/// Code of `residue` (in either case), or `None` for anything but the 20
+/// standard amino acids.
+fn residue_code(residue: u8) -> Option<u8> {
+    let residue = residue.to_ascii_uppercase();
+    AMINO_ACIDS.iter().position(|&aa| aa == residue).map(|code| code as u8)
+}
+
+/// Encode `pep` as a T followed by the code of the residue in three bases of
+/// A, C and G, per residue, or `None` if it holds anything but standard
+/// amino acids.
+pub fn encode_peptide(pep: &[u8]) -> Option<DnaString> {
+    let mut bases = Vec::with_capacity(pep.len() * RESIDUE_BASES);
+    for &residue in pep {
+        let code = residue_code(residue)?;
+        bases.extend_from_slice(&[RESIDUE_START, code / 9, code / 3 % 3, code % 3]);
+    }
+    Some(DnaString::from_bytes(&bases))
+}
+
+/// Encoded maximal runs of standard amino acids of `pep`, so that stop
+/// codons and ambiguous residues split a sequence like N does for DNA.
+pub fn encode_peptide_segments(pep: &[u8]) -> Vec<DnaString> {
+    pep.split(|&residue| residue_code(residue).is_none())
+        .filter(|segment| !segment.is_empty())
+        .filter_map(encode_peptide)
+        .collect()
+}
+
+/// Build an index over the peptides of an amino-acid FASTA file. Record ids
+/// are used verbatim as transcript ids, and each peptide is its own gene.
+/// The recorded lengths count residues.
+pub fn build_peptide_index<K: Kmer + Sync + Send, P: AsRef<Path>>(
+    fasta_path: P,
+) -> Result<Pseudoaligner<K>, Error> {
+    if !STRANDED {
+        return Err(failure::err_msg(
+            "Peptide indices need a stranded build: reverse complements of encoded residues are meaningless"
+        ));
+    }
+
+    let mut segments = Vec::new();
+    let mut tx_names = Vec::new();
+    let mut tx_lengths = Vec::new();
+    let mut tx_gene_map = HashMap::new();
+    let mut duplicates = Vec::new();
+
+    for result in utils::open_fasta(fasta_path.as_ref())?.records() {
+        let record = result?;
+        let tx_name = record.id().to_string();
+        if tx_gene_map.insert(tx_name.clone(), tx_name.clone()).is_some() {
+            duplicates.push(tx_name);
+            continue;
+        }
+
+        let pep_segments = encode_peptide_segments(record.seq());
+        tx_lengths.push(pep_segments.iter().map(|seq| (seq.len() / RESIDUE_BASES) as u32).sum());
+        segments.push(pep_segments);
+        tx_names.push(tx_name);
+    }
+    if !duplicates.is_empty() {
+        return Err(PseudoalignerError::DuplicateTranscripts(duplicates.join(", ")).into());
+    }
+
+    info!("Indexing {} peptides", tx_names.len());
+    let mut index = build_index::build_index_from_segments::<K>(&segments, &tx_names, &tx_gene_map)?;
+    index.tx_lengths = tx_lengths;
+    Ok(index)
+}
+
+impl<K: Kmer + Sync + Send> Pseudoaligner<K> {
+    /// Pseudo-align peptide `pep` against an index built by
+    /// `build_peptide_index`, returning the equivalence class and the number
+    /// of residues covered. `None` if the peptide holds non-standard residues
+    /// or is shorter than an index k-mer.
+    pub fn map_peptide(&self, pep: &[u8]) -> Option<(Vec<u32>, usize)> {
+        let encoded = encode_peptide(pep)?;
+        if encoded.len() < K::k() {
+            return None;
+        }
+
+        let seeds: Vec<usize> = (0..=encoded.len() - K::k()).step_by(RESIDUE_BASES).collect();
+        self.map_read_from_seeds(&encoded, Some(&seeds))
+            .map(|(eq_class, coverage)| (eq_class, coverage / RESIDUE_BASES))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::collections::HashSet;
+    use config::KmerType;
+    use debruijn::Vmer;
+    use test_utils::{random_seq, TempDir};
+
+    /// Pseudo-random peptide of `len` residues, the same for the same `seed`.
+    fn random_peptide(len: usize, seed: u64) -> String {
+        random_seq(len * 3, seed)
+            .as_bytes()
+            .chunks(3)
+            .map(|bases| {
+                let code = bases.iter().fold(0, |code, &base| code * 4 + b"ACGT".iter().position(|&b| b == base).unwrap());
+                AMINO_ACIDS[code % AMINO_ACIDS.len()] as char
+            })
+            .collect()
+    }
+
+    fn kmers(seq: &DnaString, starts: impl Iterator<Item = usize>) -> HashSet<KmerType> {
+        starts.map(|start| seq.get_kmer::<KmerType>(start)).collect()
+    }
+
+    #[test]
+    fn peptide_read_maps_to_its_source() {
+        let dir = TempDir::new();
+        let (pep1, pep2) = (random_peptide(40, 1), random_peptide(40, 2));
+        let path = dir.write("ref.faa", &format!(">pep1\n{}\n>pep2\n{}*{}\n", pep1, pep2, random_peptide(10, 3)));
+        let index: Pseudoaligner<KmerType> = build_peptide_index(&path).unwrap();
+
+        assert_eq!(index.tx_names, vec!["pep1", "pep2"]);
+        assert_eq!(index.tx_lengths, vec![40, 50]);
+        assert_eq!(index.map_peptide(&pep1.as_bytes()[5..25]), Some((vec![0], 20)));
+        assert_eq!(index.map_peptide(&pep2.as_bytes()[20..]), Some((vec![1], 20)));
+        assert_eq!(index.map_peptide(random_peptide(20, 4).as_bytes()), None);
+        // too short for a k-mer, or not a peptide
+        assert_eq!(index.map_peptide(&pep1.as_bytes()[..5]), None);
+        assert_eq!(index.map_peptide(format!("{}X", &pep1[..20]).as_bytes()), None);
+    }
+
+    #[test]
+    fn kmers_off_a_residue_boundary_never_equal_those_on_one() {
+        // a run of one residue repeats its encoding, so a frameless encoding
+        // would give the same k-mers in every frame
+        let pep = format!("{}{}", "A".repeat(20), random_peptide(200, 5));
+        let encoded = encode_peptide(pep.as_bytes()).unwrap();
+        let k = KmerType::k();
+        let starts = 0..=encoded.len() - k;
+        let in_frame = kmers(&encoded, starts.clone().filter(|start| start % RESIDUE_BASES == 0));
+        let out_of_frame = kmers(&encoded, starts.filter(|start| start % RESIDUE_BASES != 0));
+        assert!(!in_frame.is_empty() && !out_of_frame.is_empty());
+        assert!(in_frame.is_disjoint(&out_of_frame));
+    }
+}
@@ -0,0 +1,252 @@
+// Copyright (c) 2018 10x Genomics, Inc. All rights reserved.
+
+//! Progress of the phases of an index build. Builds report to the sink of
+//! the `Progress` they are given, e.g. in `ReferenceOptions::progress`, and
+//! otherwise to a process-wide default sink, which the command line tool
+//! replaces with `set_sink`. By default a status line is redrawn on stderr
+//! when it is a terminal, and nothing is reported otherwise.
+use std::fmt;
+use std::io::{self, IsTerminal, Write};
+use std::sync::{Arc, Mutex, OnceLock, RwLock};
+use std::time::{Duration, Instant};
+
+/// Phases of an index build that report progress.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
+pub enum Phase {
+    /// Reading reference records, counted per record
+    ReadSequences,
+    /// Counting the k-mers of every shard and labelling them with their
+    /// equivalence classes, counted per shard
+    CountKmers,
+    /// Merging and compressing the shard graphs
+    MergeGraphs,
+    /// Building the k-mer mphf and positions, counted per k-mer positioned
+    BuildMphf,
+}
+
+impl fmt::Display for Phase {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let name = match *self {
+            Phase::ReadSequences => "Reading sequences",
+            Phase::CountKmers => "Counting k-mers and labelling equivalence classes",
+            Phase::MergeGraphs => "Merging graphs",
+            Phase::BuildMphf => "Building k-mer index",
+        };
+        write!(f, "{}", name)
+    }
+}
+
+/// Receiver of progress events. Every `start` of a phase is followed by
+/// any number of `update`s and a `finish`; updates may come from several
+/// threads at once.
+pub trait ProgressSink: Send + Sync {
+    /// `phase` started, with `total` items to process if known.
+    fn start(&self, phase: Phase, total: Option<usize>);
+    /// `done` items of `phase` have been processed.
+    fn update(&self, phase: Phase, done: usize);
+    /// `phase` is complete.
+    fn finish(&self, phase: Phase);
+}
+
+/// Discards all events.
+pub struct NoProgress;
+
+impl ProgressSink for NoProgress {
+    fn start(&self, _phase: Phase, _total: Option<usize>) {}
+    fn update(&self, _phase: Phase, _done: usize) {}
+    fn finish(&self, _phase: Phase) {}
+}
+
+/// Redraws a line on stderr with the phase name, items done, and the
+/// estimated time left when the total is known.
+#[derive(Default)]
+pub struct TerminalProgress {
+    state: Mutex<Option<TerminalState>>,
+}
+
+struct TerminalState {
+    total: Option<usize>,
+    started: Instant,
+    last_draw: Instant,
+}
+
+const REDRAW_INTERVAL: Duration = Duration::from_millis(200);
+
+impl TerminalProgress {
+    pub fn new() -> TerminalProgress {
+        TerminalProgress::default()
+    }
+
+    fn draw(phase: Phase, state: &TerminalState, done: usize) {
+        let mut stderr = io::stderr();
+        let _ = match state.total {
+            Some(total) if done > 0 && total >= done => {
+                let elapsed = state.started.elapsed().as_secs_f64();
+                let eta = elapsed * (total - done) as f64 / done as f64;
+                write!(
+                    stderr, "\r{}: {}/{} ({:.0}%, ETA {:.0}s)\x1b[K",
+                    phase, done, total, 100.0 * done as f64 / total as f64, eta
+                )
+            }
+            Some(total) => write!(stderr, "\r{}: {}/{}\x1b[K", phase, done, total),
+            None => write!(stderr, "\r{}: {}\x1b[K", phase, done),
+        };
+        let _ = stderr.flush();
+    }
+}
+
+impl ProgressSink for TerminalProgress {
+    fn start(&self, phase: Phase, total: Option<usize>) {
+        let now = Instant::now();
+        let state = TerminalState { total, started: now, last_draw: now };
+        TerminalProgress::draw(phase, &state, 0);
+        *self.state.lock().unwrap() = Some(state);
+    }
+
+    fn update(&self, phase: Phase, done: usize) {
+        let mut state = self.state.lock().unwrap();
+        if let Some(ref mut state) = *state {
+            if state.last_draw.elapsed() >= REDRAW_INTERVAL {
+                state.last_draw = Instant::now();
+                TerminalProgress::draw(phase, state, done);
+            }
+        }
+    }
+
+    fn finish(&self, phase: Phase) {
+        if let Some(state) = self.state.lock().unwrap().take() {
+            eprintln!("\r{}: complete in {:.1}s\x1b[K", phase, state.started.elapsed().as_secs_f64());
+        }
+    }
+}
+
+static SINK: OnceLock<RwLock<Arc<dyn ProgressSink>>> = OnceLock::new();
+
+fn sink_slot() -> &'static RwLock<Arc<dyn ProgressSink>> {
+    SINK.get_or_init(|| RwLock::new(default_sink()))
+}
+
+/// `TerminalProgress` if stderr is a terminal, `NoProgress` otherwise.
+pub fn default_sink() -> Arc<dyn ProgressSink> {
+    if io::stderr().is_terminal() {
+        Arc::new(TerminalProgress::new())
+    } else {
+        Arc::new(NoProgress)
+    }
+}
+
+/// Make `sink` the default of all `Progress` created later.
+pub fn set_sink(sink: Arc<dyn ProgressSink>) {
+    *sink_slot().write().unwrap() = sink;
+}
+
+/// Where a build reports its progress. The default is the process-wide
+/// default sink at the time it is created.
+#[derive(Clone)]
+pub struct Progress {
+    sink: Arc<dyn ProgressSink>,
+}
+
+impl Progress {
+    pub fn new(sink: Arc<dyn ProgressSink>) -> Progress {
+        Progress { sink }
+    }
+
+    pub(crate) fn start(&self, phase: Phase, total: Option<usize>) {
+        self.sink.start(phase, total);
+    }
+
+    pub(crate) fn update(&self, phase: Phase, done: usize) {
+        self.sink.update(phase, done);
+    }
+
+    pub(crate) fn finish(&self, phase: Phase) {
+        self.sink.finish(phase);
+    }
+}
+
+impl Default for Progress {
+    fn default() -> Progress {
+        Progress::new(sink_slot().read().unwrap().clone())
+    }
+}
+
+impl fmt::Debug for Progress {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "Progress")
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use build_index::build_index_from_fasta;
+    use config::KmerType;
+    use pseudoaligner::Pseudoaligner;
+    use test_utils::{random_seq, TempDir};
+    use utils::ReferenceOptions;
+
+    #[derive(Debug, PartialEq)]
+    enum Event {
+        Start(Phase),
+        Update(Phase),
+        Finish(Phase),
+    }
+
+    impl Event {
+        fn phase(&self) -> Phase {
+            match *self {
+                Event::Start(phase) | Event::Update(phase) | Event::Finish(phase) => phase,
+            }
+        }
+    }
+
+    /// Records every event it receives.
+    #[derive(Default)]
+    struct RecordingProgress {
+        events: Mutex<Vec<Event>>,
+    }
+
+    impl ProgressSink for RecordingProgress {
+        fn start(&self, phase: Phase, _total: Option<usize>) {
+            self.events.lock().unwrap().push(Event::Start(phase));
+        }
+
+        fn update(&self, phase: Phase, _done: usize) {
+            self.events.lock().unwrap().push(Event::Update(phase));
+        }
+
+        fn finish(&self, phase: Phase) {
+            self.events.lock().unwrap().push(Event::Finish(phase));
+        }
+    }
+
+    #[test]
+    fn every_build_phase_reports_to_the_given_sink_and_completes() {
+        let dir = TempDir::new();
+        let records: Vec<(String, String)> = (0..200).map(|i| (format!("tx{}", i), random_seq(300, i))).collect();
+        let records: Vec<(&str, &str)> = records.iter().map(|(id, seq)| (id.as_str(), seq.as_str())).collect();
+        let path = dir.write_fasta("ref.fa", &records);
+        let sink = Arc::new(RecordingProgress::default());
+        let options = ReferenceOptions { progress: Progress::new(sink.clone()), ..ReferenceOptions::default() };
+        let _: Pseudoaligner<KmerType> = build_index_from_fasta(vec![path], &options).unwrap();
+
+        let events = sink.events.lock().unwrap();
+        let phases = [Phase::ReadSequences, Phase::CountKmers, Phase::MergeGraphs, Phase::BuildMphf];
+        for &phase in &phases {
+            let of_phase: Vec<&Event> = events.iter().filter(|event| event.phase() == phase).collect();
+            assert_eq!(of_phase.first(), Some(&&Event::Start(phase)), "{}", phase);
+            assert_eq!(of_phase.last(), Some(&&Event::Finish(phase)), "{}", phase);
+            assert_eq!(of_phase.iter().filter(|event| ***event == Event::Finish(phase)).count(), 1, "{}", phase);
+        }
+        // the phases run one after the other
+        let finished: Vec<Phase> = events
+            .iter()
+            .filter(|event| matches!(event, Event::Finish(_)))
+            .map(Event::phase)
+            .collect();
+        assert_eq!(finished, phases);
+        assert!(events.contains(&Event::Update(Phase::ReadSequences)));
+        assert!(events.contains(&Event::Update(Phase::BuildMphf)));
+    }
+}
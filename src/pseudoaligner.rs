@@ -1,31 +1,392 @@
 // Copyright (c) 2018 10x Genomics, Inc. All rights reserved.
 
-use std::{self, cmp::Ordering, fs::File, str};
-use std::collections::HashMap;
+use std::{self, cmp::Ordering, str};
+use std::collections::{BTreeMap, BTreeSet, HashMap, HashSet};
 use std::fmt::Debug;
 use std::io::{self, Write};
+use std::ops::Range;
 use std::path::Path;
+use std::sync::atomic::{self, AtomicBool, AtomicUsize};
 use std::sync::{mpsc, Arc, Mutex};
+use std::time::Instant;
 
+use bio::alphabets::dna;
 use bio::io::fastq;
+use boomphf::Mphf;
 use boomphf::hashmap::NoKeyBoomHashMap;
 use crossbeam;
+use memmap2::Mmap;
+#[cfg(unix)]
+use memmap2::Advice;
 use debruijn::dna_string::DnaString;
 use debruijn::filter::EqClassIdType;
 use debruijn::graph::DebruijnGraph;
 use debruijn::{Dir, Kmer, Mer, Vmer};
-use failure::Error;
+use failure::{self, Error};
+use rand::{Rng, SeedableRng};
+use rand::seq::SliceRandom;
+use rand_chacha::ChaCha8Rng;
+use rayon::prelude::*;
+use serde::{Serialize, Serializer};
 
-use config::{MAX_WORKER, READ_COVERAGE_THRESHOLD, LEFT_EXTEND_FRACTION};
+use build_index;
+use config::{CONFUSION_READ_LEN, DEFAULT_MAPPING_SEED, KMER_RATIO_BINS, KMER_STRIDE, MAPPING_CHUNKS_PER_WORKER, MAPPING_CHUNK_READS,
+             MAX_RESCUE_GAP, MAX_WORKER, MERGE_UMIS, MIN_BASE_QUALITY, MIN_KMER_RATIO, PHRED_OFFSET, PREFETCH_STRIDE, QUALITY_MASK, READ_COVERAGE_THRESHOLD,
+             RESCUE_FULL, LEFT_EXTEND_FRACTION, SAM_UNIQUE_MAPQ, STRANDED};
+use error::PseudoalignerError;
+use stats::{RunSummary, TranscriptStrands};
+use umi::{UmiCounter, UmiSource};
 use utils;
 
+/// (node id, offset) of every k-mer of the graph, in mphf slot order.
+/// Either owned, or read in place from a memory-mapped index file where it is
+/// stored with the bincode layout of a `Vec<(u32, u32)>`. Build mapped
+/// positions with `KmerPositions::mapped`, which checks that `len` positions
+/// fit in the map after `offset`; the slot arithmetic below relies on it.
+#[derive(Debug)]
+pub enum KmerPositions {
+    Owned(Vec<(u32, u32)>),
+    Mapped { map: Mmap, offset: usize, len: usize },
+}
+
+impl KmerPositions {
+    /// The `len` positions stored at `offset` of `map`, checking that they
+    /// lie inside it, so that `get` never reads past the end of the map.
+    pub fn mapped(map: Mmap, offset: usize, len: usize) -> Result<KmerPositions, PseudoalignerError> {
+        let expected = len
+            .checked_mul(8)
+            .and_then(|num_bytes| num_bytes.checked_add(offset))
+            .unwrap_or(usize::MAX);
+        if expected > map.len() {
+            return Err(PseudoalignerError::TruncatedIndex { expected, found: map.len() });
+        }
+        Ok(KmerPositions::Mapped { map, offset, len })
+    }
+
+    pub fn len(&self) -> usize {
+        match *self {
+            KmerPositions::Owned(ref positions) => positions.len(),
+            KmerPositions::Mapped { len, .. } => len,
+        }
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    /// Read `slots` of a memory-mapped table into memory, advising the OS
+    /// they are needed and then touching each of their pages, so that later
+    /// lookups do not fault. A no-op for owned positions.
+    pub fn prefetch_region(&self, slots: Range<usize>) -> io::Result<()> {
+        if let KmerPositions::Mapped { ref map, offset, len } = *self {
+            let end = std::cmp::min(slots.end, len);
+            if slots.start >= end {
+                return Ok(());
+            }
+            // in bounds, and without overflow, as end <= len
+            let start = offset + 8 * slots.start;
+            let num_bytes = 8 * (end - slots.start);
+
+            #[cfg(unix)]
+            map.advise_range(Advice::WillNeed, start, num_bytes)?;
+            let mut touched = 0u8;
+            for pos in (start..start + num_bytes).step_by(PREFETCH_STRIDE) {
+                touched = touched.wrapping_add(map[pos]);
+            }
+            std::hint::black_box(touched);
+        }
+        Ok(())
+    }
+
+    #[inline]
+    pub fn get(&self, slot: usize) -> (u32, u32) {
+        match *self {
+            KmerPositions::Owned(ref positions) => positions[slot],
+            KmerPositions::Mapped { ref map, offset, len } => {
+                assert!(slot < len);
+                // slot < len, so this neither overflows nor leaves the map
+                let start = offset + 8 * slot;
+                let mut nid = [0u8; 4];
+                let mut kmer_offset = [0u8; 4];
+                nid.copy_from_slice(&map[start..start + 4]);
+                kmer_offset.copy_from_slice(&map[start + 4..start + 8]);
+                (u32::from_le_bytes(nid), u32::from_le_bytes(kmer_offset))
+            }
+        }
+    }
+}
+
+impl Default for KmerPositions {
+    fn default() -> KmerPositions {
+        KmerPositions::Owned(Vec::new())
+    }
+}
+
+/// The k-mer positions are not part of the serialized struct: `utils::write_index`
+/// stores them in a separate section so that they can be memory-mapped.
 #[derive(Serialize, Deserialize, Debug)]
 pub struct Pseudoaligner<K: Kmer> {
     pub dbg: DebruijnGraph<K, EqClassIdType>,
     pub eq_classes: Vec<Vec<u32>>,
-    dbg_index: NoKeyBoomHashMap<K, (u32, u32)>,
+    kmer_mphf: Mphf<K>,
+    #[serde(skip)]
+    kmer_positions: KmerPositions,
     pub tx_names: Vec<String>,
+    #[serde(serialize_with = "serialize_sorted")]
     pub tx_gene_mapping: HashMap<String, String>,
+    /// Names of the reference files the index was built from, if recorded
+    pub source_names: Vec<String>,
+    /// Index into `source_names` of the file each transcript came from
+    pub tx_sources: Vec<u8>,
+    /// Number of indexed bases of each transcript, if recorded
+    pub tx_lengths: Vec<u32>,
+    /// Whether k-mers are stored in the orientation of the transcripts only,
+    /// rather than canonically
+    pub stranded: bool,
+    /// Checksum of each file of `source_names`, if recorded. Stored in the
+    /// index metadata rather than the payload.
+    #[serde(skip)]
+    pub reference_checksums: Vec<utils::ReferenceChecksum>,
+    /// Window of the minimizers a sparse index stores instead of every
+    /// k-mer; `None` for dense indices
+    pub minimizer_window: Option<usize>,
+    /// Group (e.g. species) of transcripts, if assigned. Transcripts without
+    /// one are grouped by their source file.
+    #[serde(serialize_with = "serialize_sorted")]
+    pub tx_groups: HashMap<String, String>,
+    /// Name of genes of `tx_gene_mapping`, if given
+    #[serde(serialize_with = "serialize_sorted")]
+    pub gene_names: HashMap<String, String>,
+    /// `utils::transcript_checksum` of each transcript, if recorded
+    pub tx_checksums: Vec<u64>,
+    /// `(node id, start)` of every node each transcript passes through,
+    /// sorted, if recorded
+    pub tx_node_starts: Vec<Vec<(u32, i32)>>,
+    /// `utils::gc_content` of each transcript, counted on its reference
+    /// bases without the ambiguous ones, if recorded
+    pub tx_gc_contents: Vec<f64>,
+}
+
+/// Write `map` in key order, so that the bytes of an index do not depend
+/// on the iteration order of its hash maps.
+fn serialize_sorted<S: Serializer>(map: &HashMap<String, String>, serializer: S) -> Result<S::Ok, S::Error> {
+    map.iter().collect::<BTreeMap<_, _>>().serialize(serializer)
+}
+
+/// Gene of transcripts missing from a transcript to gene map under
+/// `MissingGenePolicy::Unknown`.
+pub const UNKNOWN_GENE: &str = "unknown";
+
+/// When a read counts for a group in `Pseudoaligner::count_groups`.
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub enum GroupRule {
+    /// Every transcript of its equivalence class belongs to the group
+    All,
+    /// Some transcript of its equivalence class belongs to the group
+    Any,
+}
+
+/// What `Pseudoaligner::map_read_pair_with_policy` does with a pair whose
+/// mates map to disjoint equivalence classes.
+#[derive(Copy, Clone, Debug, Default, PartialEq, Eq)]
+pub enum DisjointMatePolicy {
+    /// Give the pair an empty class, so that it counts as not mapped
+    #[default]
+    Discard,
+    /// Give the pair the union of the classes of its mates
+    Union,
+    /// Map the pair like its mate with the higher coverage
+    BetterCovered,
+}
+
+impl str::FromStr for DisjointMatePolicy {
+    type Err = Error;
+
+    fn from_str(s: &str) -> Result<Self, Error> {
+        match s {
+            "discard" => Ok(DisjointMatePolicy::Discard),
+            "union" => Ok(DisjointMatePolicy::Union),
+            "better-covered" => Ok(DisjointMatePolicy::BetterCovered),
+            _ => Err(failure::err_msg(format!(
+                "Unknown disjoint mate policy '{}', expected discard, union or better-covered", s
+            ))),
+        }
+    }
+}
+
+/// How `Pseudoaligner::map_read_pair_with_policy` combined the mates of a pair.
+#[derive(Copy, Clone, Debug, PartialEq, Eq, Hash)]
+pub enum PairMapping {
+    /// Both mates mapped, and the pair to the intersection of their classes
+    Intersected,
+    /// Both mates mapped, to disjoint classes resolved by the `DisjointMatePolicy`
+    Disjoint,
+    /// Only one mate mapped, and the pair maps like it
+    OneMate,
+    /// Neither mate mapped
+    Unmapped,
+}
+
+impl PairMapping {
+    /// Short name of the way, as used in run summaries.
+    pub fn code(self) -> &'static str {
+        match self {
+            PairMapping::Intersected => "intersected",
+            PairMapping::Disjoint => "disjoint",
+            PairMapping::OneMate => "one_mate",
+            PairMapping::Unmapped => "unmapped",
+        }
+    }
+}
+
+const PAIR_MAPPINGS: [PairMapping; 4] = [
+    PairMapping::Intersected,
+    PairMapping::Disjoint,
+    PairMapping::OneMate,
+    PairMapping::Unmapped,
+];
+
+/// Why a read did not map.
+#[derive(Copy, Clone, Debug, PartialEq, Eq, PartialOrd, Ord)]
+pub enum UnmappedReason {
+    /// The read is shorter than k
+    TooShort,
+    /// The read is too short for its k-mers to reach the coverage threshold
+    TooFewKmers,
+    /// Too few k-mers of the read are free of N or other ambiguous bases
+    TooFewValidKmers,
+    /// No k-mer of the read is in the index
+    NoKmerHits,
+    /// The read matched with less than the coverage threshold
+    LowCoverage,
+    /// The read matched transcripts with no common transcript
+    EmptyClass,
+    /// The read only maps reverse complemented, which a stranded index
+    /// does not allow
+    WrongStrand,
+    /// The read mapped, but too few of its valid k-mers are consistent with
+    /// its class for the `min_kmer_ratio` of the `MappingOptions`
+    LowKmerRatio,
+}
+
+const UNMAPPED_REASONS: [UnmappedReason; 8] = [
+    UnmappedReason::TooShort,
+    UnmappedReason::TooFewKmers,
+    UnmappedReason::TooFewValidKmers,
+    UnmappedReason::NoKmerHits,
+    UnmappedReason::LowCoverage,
+    UnmappedReason::EmptyClass,
+    UnmappedReason::WrongStrand,
+    UnmappedReason::LowKmerRatio,
+];
+
+impl UnmappedReason {
+    /// Short name of the reason, as written to unmapped read files.
+    pub fn code(self) -> &'static str {
+        match self {
+            UnmappedReason::TooShort => "too_short",
+            UnmappedReason::TooFewKmers => "too_few_kmers",
+            UnmappedReason::TooFewValidKmers => "too_few_valid_kmers",
+            UnmappedReason::NoKmerHits => "no_kmer_hits",
+            UnmappedReason::LowCoverage => "low_coverage",
+            UnmappedReason::EmptyClass => "empty_class",
+            UnmappedReason::WrongStrand => "wrong_strand",
+            UnmappedReason::LowKmerRatio => "low_kmer_ratio",
+        }
+    }
+
+    /// Whether the read may map when looked up another way, unlike reads
+    /// too short or with too few valid k-mers to map at all.
+    fn is_lookup_miss(self) -> bool {
+        matches!(self, UnmappedReason::NoKmerHits | UnmappedReason::LowCoverage | UnmappedReason::EmptyClass)
+    }
+}
+
+/// Orientation a read was mapped in, relative to the indexed transcripts.
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub enum Strand {
+    /// As read
+    Forward,
+    /// Reverse complemented
+    Reverse,
+}
+
+impl Strand {
+    /// `+` or `-`, as in BED files.
+    pub fn symbol(self) -> &'static str {
+        match self {
+            Strand::Forward => "+",
+            Strand::Reverse => "-",
+        }
+    }
+}
+
+/// K-mers of a read free of N or other ambiguous bases, and how many of
+/// them are consistent with the equivalence class it maps to.
+#[derive(Copy, Clone, Debug, Default, PartialEq, Eq)]
+pub struct KmerScore {
+    pub matched_kmers: usize,
+    pub valid_kmers: usize,
+}
+
+impl KmerScore {
+    /// Fraction of the valid k-mers that matched, or `None` without any.
+    pub fn ratio(&self) -> Option<f64> {
+        if self.valid_kmers == 0 {
+            None
+        } else {
+            Some(self.matched_kmers as f64 / self.valid_kmers as f64)
+        }
+    }
+}
+
+/// What the read TSV reports of a read besides its mapping.
+#[derive(Copy, Clone, Debug, Default, PartialEq, Eq)]
+pub struct ReadDetail {
+    /// The read is `MappingOutcome::Rescued`
+    pub rescued: bool,
+    /// Strand the read was mapped on, if known
+    pub strand: Option<Strand>,
+    /// K-mers of the read consistent with its class, of its valid ones
+    pub kmer_score: KmerScore,
+    /// The read only did not map for `UnmappedReason::LowKmerRatio`
+    pub low_kmer_ratio: bool,
+    /// Transcript of its equivalence class the read was assigned to by
+    /// `assign_transcript`
+    pub assigned: Option<u32>,
+}
+
+/// Outcome of pseudo-aligning one read.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum MappingOutcome {
+    /// The read mapped to the equivalence class, with the coverage
+    Mapped(Vec<u32>, usize),
+    /// The read mapped like `Mapped`, but only by counting the gaps between
+    /// its matches to transcripts in common as covered
+    Rescued(Vec<u32>, usize),
+    /// The read is shorter than k, so none of it was looked up
+    TooShort,
+    /// The read did not map for the reason, with the class and coverage it got
+    Unmapped(UnmappedReason, Vec<u32>, usize),
+}
+
+impl MappingOutcome {
+    /// Why the read did not map, or `None` if it did.
+    pub fn unmapped_reason(&self) -> Option<UnmappedReason> {
+        match *self {
+            MappingOutcome::Mapped(..) | MappingOutcome::Rescued(..) => None,
+            MappingOutcome::TooShort => Some(UnmappedReason::TooShort),
+            MappingOutcome::Unmapped(reason, ..) => Some(reason),
+        }
+    }
+
+    /// Equivalence class the read mapped to, or `None` if it did not map.
+    pub fn mapped_eq_class(self) -> Option<Vec<u32>> {
+        match self {
+            MappingOutcome::Mapped(eq_class, _) | MappingOutcome::Rescued(eq_class, _) => Some(eq_class),
+            _ => None,
+        }
+    }
 }
 
 impl<K: Kmer + Sync + Send> Pseudoaligner<K> {
@@ -36,379 +397,4087 @@ impl<K: Kmer + Sync + Send> Pseudoaligner<K> {
         tx_names: Vec<String>,
         tx_gene_mapping: HashMap<String, String>
     ) -> Pseudoaligner<K> {
-        Pseudoaligner {dbg, eq_classes, dbg_index, tx_names, tx_gene_mapping}
+        Pseudoaligner {
+            dbg,
+            eq_classes,
+            kmer_mphf: dbg_index.mphf,
+            kmer_positions: KmerPositions::Owned(dbg_index.values),
+            tx_names,
+            tx_gene_mapping,
+            source_names: Vec::new(),
+            tx_sources: Vec::new(),
+            tx_lengths: Vec::new(),
+            reference_checksums: Vec::new(),
+            minimizer_window: None,
+            tx_groups: HashMap::new(),
+            gene_names: HashMap::new(),
+            tx_checksums: Vec::new(),
+            tx_node_starts: Vec::new(),
+            tx_gc_contents: Vec::new(),
+            stranded: STRANDED,
+        }
     }
 
-    /// Pseudo-align `read_seq` to determine its the equivalence class.
-    pub fn map_read(&self, read_seq: &DnaString) -> Option<(Vec<u32>, usize)> {
-        let read_length = read_seq.len();
-        let mut read_coverage: usize = 0;
-        let mut colors: Vec<u32> = Vec::new();
-        let left_extend_threshold = (LEFT_EXTEND_FRACTION * read_length as f64) as usize;
+    /// Every distinct equivalence class with its id, in id order. The
+    /// transcript indices of a class are sorted. The classes are also the
+    /// public `eq_classes` field, indexed by id.
+    pub fn eq_classes(&self) -> impl Iterator<Item = (EqClassIdType, &[u32])> {
+        self.eq_classes
+            .iter()
+            .enumerate()
+            .map(|(eq_class_id, eq_class)| (eq_class_id as EqClassIdType, eq_class.as_slice()))
+    }
 
-        let mut kmer_pos: usize = 0;
-        let kmer_length = K::k();
-        let last_kmer_pos = read_length - kmer_length;
+    /// Jaccard index of the k-mer sets of transcripts `a` and `b`, counted
+    /// over the graph nodes whose equivalence classes hold them; 0 if
+    /// neither has k-mers.
+    pub fn transcript_similarity(&self, a: u32, b: u32) -> f64 {
+        let mut shared = 0;
+        let mut union = 0;
+        for node in self.dbg.iter_nodes() {
+            let eq_class = &self.eq_classes[*node.data() as usize];
+            let in_a = eq_class.binary_search(&a).is_ok();
+            let in_b = eq_class.binary_search(&b).is_ok();
+            let num_kmers = node.len() - K::k() + 1;
+            if in_a && in_b {
+                shared += num_kmers;
+            }
+            if in_a || in_b {
+                union += num_kmers;
+            }
+        }
 
-        // Scan the read for the first kmer that exists in the reference
-        let find_kmer_match = |kmer_pos: &mut usize| -> Option<(usize, usize)> {
-            while *kmer_pos <= last_kmer_pos {
-                let read_kmer = read_seq.get_kmer(*kmer_pos);
-
-                match self.dbg_index.get(&read_kmer) {
-                    None => (),
-                    Some((nid, offset)) => {
-                        let node = self.dbg.get_node(*nid as usize);
-                        let ref_seq_slice = node.sequence();
-                        let ref_kmer: K = ref_seq_slice.get_kmer(*offset as usize);
-
-                        if read_kmer == ref_kmer {
-                            return Some((*nid as usize, *offset as usize));
+        if union == 0 {
+            return 0.0;
+        }
+        shared as f64 / union as f64
+    }
+
+    /// How often reads of each transcript map to classes holding each other
+    /// transcript: entry `[i][j]` counts the reads simulated from transcript
+    /// `i` that map to a class including `j`, so the diagonal counts the
+    /// reads mapped back to their own transcript. `simulated_reads_per_tx`
+    /// reads of `CONFUSION_READ_LEN` bases, or of the whole transcript when
+    /// shorter, are drawn uniformly from every transcript, with a generator
+    /// seeded by `seed` and the transcript. Reads are rebuilt from the
+    /// transcript positions of the index, so indices that do not record them
+    /// are an error.
+    pub fn confusion_matrix(&self, simulated_reads_per_tx: usize, seed: u64) -> Result<Vec<Vec<u32>>, Error> {
+        let num_tx = self.tx_names.len();
+        if self.tx_node_starts.len() != num_tx || self.tx_lengths.len() != num_tx {
+            return Err(failure::err_msg(
+                "The index does not record transcript positions, which the confusion matrix needs"
+            ));
+        }
+
+        let matrix = (0..num_tx as u32)
+            .into_par_iter()
+            .map(|tx_id| {
+                let mut row = vec![0; num_tx];
+                let bases = match self.transcript_bases(tx_id) {
+                    Some(bases) => bases,
+                    None => return row,
+                };
+                let read_len = std::cmp::min(CONFUSION_READ_LEN, bases.len());
+                if read_len < K::k() {
+                    return row;
+                }
+
+                // reads lie on bases covered by the graph
+                let mut read_starts = Vec::new();
+                let mut covered = 0;
+                for (pos, base) in bases.iter().enumerate() {
+                    covered = if base.is_some() { covered + 1 } else { 0 };
+                    if covered >= read_len {
+                        read_starts.push(pos + 1 - read_len);
+                    }
+                }
+
+                let mut rng = ChaCha8Rng::seed_from_u64(read_seed(seed, tx_id as u64));
+                for _ in 0..simulated_reads_per_tx {
+                    let start = match read_starts.choose(&mut rng) {
+                        Some(&start) => start,
+                        None => break,
+                    };
+                    let read: Vec<u8> = bases[start..start + read_len].iter().flatten().cloned().collect();
+                    if let Some(eq_class) = self.confident_eq_class(&DnaString::from_bytes(&read)) {
+                        for &other in &eq_class {
+                            row[other as usize] += 1;
                         }
                     }
+                }
+                row
+            })
+            .collect();
+        Ok(matrix)
+    }
+
+    /// Transcripts shorter than k, which keep their index but have no
+    /// k-mers, so no read maps to them. Empty for indices that do not
+    /// record transcript lengths.
+    pub fn short_transcripts(&self) -> Vec<u32> {
+        self.tx_lengths
+            .iter()
+            .enumerate()
+            .filter(|&(_, &length)| (length as usize) < K::k())
+            .map(|(tx_id, _)| tx_id as u32)
+            .collect()
+    }
+
+    /// `utils::gc_content` of every transcript, in index order, as recorded
+    /// by the build from the reference bases, ambiguous bases left out.
+    pub fn gc_contents(&self) -> Result<Vec<f64>, Error> {
+        if self.tx_gc_contents.len() != self.tx_names.len() {
+            return Err(failure::err_msg("The index does not record transcript GC contents"));
+        }
+        Ok(self.tx_gc_contents.clone())
+    }
+
+    /// Bases of transcript `tx_id`, laid out from the nodes it passes
+    /// through; `None` at the bases no node covers. `None` if the index
+    /// does not record transcript positions and lengths.
+    pub(crate) fn transcript_bases(&self, tx_id: u32) -> Option<Vec<Option<u8>>> {
+        let starts = self.tx_node_starts.get(tx_id as usize)?;
+        let tx_len = *self.tx_lengths.get(tx_id as usize)? as i64;
+
+        let mut bases = vec![None; tx_len as usize];
+        for &(node_id, start) in starts {
+            let seq = self.dbg.get_node(node_id as usize).sequence();
+            for offset in 0..seq.len() {
+                let pos = start as i64 + offset as i64;
+                if pos >= 0 && pos < tx_len {
+                    bases[pos as usize] = Some(seq.get(offset));
+                }
+            }
+        }
+        Some(bases)
+    }
+
+    /// Name of the reference file transcript `tx_id` came from, if recorded.
+    pub fn tx_source(&self, tx_id: u32) -> Option<&str> {
+        self.tx_sources
+            .get(tx_id as usize)
+            .map(|&source| self.source_names[source as usize].as_str())
+    }
+
+    /// Sorted, distinct names of the reference files of the transcripts in `eq_class`.
+    pub fn eq_class_sources(&self, eq_class: &[u32]) -> Vec<&str> {
+        let mut sources: Vec<&str> = eq_class
+            .iter()
+            .filter_map(|&tx_id| self.tx_source(tx_id))
+            .collect();
+        sources.sort();
+        sources.dedup();
+        sources
+    }
+
+    /// Name and gene, if known, of every transcript of `eq_class`, in its
+    /// order. Transcript indices that are not in the index are an error.
+    pub fn resolve_eq_class(&self, eq_class: &[u32]) -> Result<Vec<(&str, Option<&str>)>, Error> {
+        eq_class
+            .iter()
+            .map(|&tx_id| {
+                let tx_name = self.tx_names.get(tx_id as usize).ok_or_else(|| {
+                    failure::err_msg(format!(
+                        "Transcript {} is not in the index of {} transcripts",
+                        tx_id,
+                        self.tx_names.len()
+                    ))
+                })?;
+                let gene = self.tx_gene_mapping.get(tx_name).map(|gene| gene.as_str());
+                Ok((tx_name.as_str(), gene))
+            })
+            .collect()
+    }
+
+    /// Transcripts of `eq_class` by gene, in the order of the class, so
+    /// the genes of a class mixing several can be handled one by one. A
+    /// transcript without a gene is its own gene, under its name.
+    /// Transcript indices that are not in the index are an error.
+    pub fn split_by_gene(&self, eq_class: &[u32]) -> Result<HashMap<String, Vec<u32>>, Error> {
+        let mut genes: HashMap<String, Vec<u32>> = HashMap::new();
+        for (&tx_id, (tx_name, gene)) in eq_class.iter().zip(self.resolve_eq_class(eq_class)?) {
+            genes.entry(gene.unwrap_or(tx_name).to_string()).or_default().push(tx_id);
+        }
+        Ok(genes)
+    }
+
+    /// Checksum over the indexed sequences of all transcripts, in order, if
+    /// their checksums are recorded. Unlike `reference_checksums`, it does
+    /// not depend on the FASTA files the transcripts were read from.
+    pub fn combined_tx_checksum(&self) -> Option<u64> {
+        if self.tx_checksums.len() != self.tx_names.len() {
+            return None;
+        }
+        let mut digest = utils::ReferenceDigest::default();
+        for checksum in &self.tx_checksums {
+            digest.add_line(&checksum.to_le_bytes());
+        }
+        Some(digest.finish_short())
+    }
+
+    /// Check that `seqs` are the sequences of the transcripts of the index,
+    /// in order. Indices built with sequences split or masked must be
+    /// checked against their segments with `verify_against_segments`.
+    pub fn verify_against(&self, seqs: &[DnaString]) -> Result<(), Error> {
+        let segments: Vec<Vec<DnaString>> = seqs.iter().map(|seq| vec![seq.clone()]).collect();
+        self.verify_against_segments(&segments)
+    }
+
+    /// Check that `segments` are the indexed segments of the transcripts of
+    /// the index, in order.
+    pub fn verify_against_segments(&self, segments: &[Vec<DnaString>]) -> Result<(), Error> {
+        if self.tx_checksums.len() != self.tx_names.len() {
+            return Err(failure::err_msg("The index does not record transcript checksums"));
+        }
+        if segments.len() != self.tx_names.len() {
+            return Err(failure::err_msg(format!(
+                "Index has {} transcripts but {} sequences were given",
+                self.tx_names.len(), segments.len()
+            )));
+        }
+
+        let changed: Vec<usize> = segments
+            .iter()
+            .zip(&self.tx_checksums)
+            .enumerate()
+            .filter(|&(_, (tx_segments, &checksum))| utils::transcript_checksum(tx_segments) != checksum)
+            .map(|(tx_id, _)| tx_id)
+            .collect();
+        if let Some(&tx_id) = changed.first() {
+            return Err(failure::err_msg(format!(
+                "{} transcripts differ from the indexed ones, e.g. {} (index {})",
+                changed.len(), self.tx_names[tx_id], tx_id
+            )));
+        }
+        Ok(())
+    }
+
+    /// Gene of transcript `tx_id`.
+    pub fn gene_of(&self, tx_id: u32) -> Option<&str> {
+        self.tx_names
+            .get(tx_id as usize)
+            .and_then(|tx_name| self.tx_gene_mapping.get(tx_name))
+            .map(|gene_id| gene_id.as_str())
+    }
+
+    /// Name of gene `gene_id`, if given.
+    pub fn gene_name(&self, gene_id: &str) -> Option<&str> {
+        self.gene_names.get(gene_id).map(|name| name.as_str())
+    }
+
+    /// Distinct genes of the transcripts, sorted.
+    pub fn genes(&self) -> BTreeSet<String> {
+        self.tx_gene_mapping.values().cloned().collect()
+    }
+
+    /// Replace the genes of the transcripts, taken from the FASTA headers,
+    /// by those of `tx_genes`, e.g. from `utils::read_t2g`. Transcripts
+    /// missing from it are handled according to `missing`.
+    pub fn set_tx_genes(
+        &mut self,
+        tx_genes: HashMap<String, String>,
+        gene_names: HashMap<String, String>,
+        missing: utils::MissingGenePolicy,
+    ) -> Result<(), Error> {
+        let missing_txs: Vec<&str> = self
+            .tx_names
+            .iter()
+            .filter(|tx_name| !tx_genes.contains_key(tx_name.as_str()))
+            .map(|tx_name| tx_name.as_str())
+            .collect();
+        if !missing_txs.is_empty() {
+            match missing {
+                utils::MissingGenePolicy::Error => {
+                    return Err(failure::err_msg(format!(
+                        "{} transcripts are missing from the transcript to gene map, e.g. {}",
+                        missing_txs.len(), missing_txs[0]
+                    )));
+                }
+                utils::MissingGenePolicy::Unknown => warn!(
+                    "Assigning {} transcripts missing from the transcript to gene map to gene {}",
+                    missing_txs.len(), UNKNOWN_GENE
+                ),
+            }
+        }
+
+        let tx_gene_mapping: HashMap<String, String> = self
+            .tx_names
+            .iter()
+            .map(|tx_name| {
+                let gene_id = tx_genes.get(tx_name).map_or(UNKNOWN_GENE, |gene_id| gene_id.as_str());
+                (tx_name.clone(), gene_id.to_string())
+            })
+            .collect();
+        let genes: HashSet<&String> = tx_gene_mapping.values().collect();
+        self.gene_names = gene_names
+            .into_iter()
+            .filter(|(gene_id, _)| genes.contains(gene_id))
+            .collect();
+        self.tx_gene_mapping = tx_gene_mapping;
+        Ok(())
+    }
+
+    /// Group of transcript `tx_id`: the one assigned to it, otherwise the
+    /// name of its reference file, if recorded. `None` if `tx_id` is not a
+    /// transcript of the index.
+    pub fn tx_group(&self, tx_id: u32) -> Option<&str> {
+        match self.tx_groups.get(self.tx_names.get(tx_id as usize)?) {
+            Some(group) => Some(group.as_str()),
+            None => self.tx_source(tx_id),
+        }
+    }
+
+    /// Sorted, distinct groups of the transcripts in `eq_class`.
+    pub fn eq_class_groups(&self, eq_class: &[u32]) -> Vec<&str> {
+        let mut groups: Vec<&str> = eq_class
+            .iter()
+            .filter_map(|&tx_id| self.tx_group(tx_id))
+            .collect();
+        groups.sort();
+        groups.dedup();
+        groups
+    }
+
+    /// Assign every transcript the group `groups` gives for its id, e.g.
+    /// from `utils::read_groups`. All transcripts must be listed; ids that
+    /// are not in the index are ignored with a warning.
+    pub fn set_tx_groups(&mut self, groups: HashMap<String, String>) -> Result<(), Error> {
+        let missing: Vec<&str> = self
+            .tx_names
+            .iter()
+            .filter(|tx_name| !groups.contains_key(tx_name.as_str()))
+            .map(|tx_name| tx_name.as_str())
+            .collect();
+        if !missing.is_empty() {
+            return Err(failure::err_msg(format!(
+                "{} transcripts have no group, e.g. {}", missing.len(), missing[0]
+            )));
+        }
+
+        let unknown = groups.len() - self.tx_names.len();
+        if unknown > 0 {
+            warn!("Ignoring the groups of {} ids that are not in the index", unknown);
+        }
+        self.tx_groups = groups
+            .into_iter()
+            .filter(|(tx_id, _)| self.tx_gene_mapping.contains_key(tx_id))
+            .collect();
+        Ok(())
+    }
+
+    /// Collapse read counts per equivalence class, as from `count_reads`, to
+    /// counts per group. Under `GroupRule::All` reads of an equivalence class
+    /// spanning several groups count for none of them; under
+    /// `GroupRule::Any` they count once for each.
+    pub fn count_groups(&self, eq_class_counts: &HashMap<Vec<u32>, u64>, rule: GroupRule) -> HashMap<String, u64> {
+        let mut counts = HashMap::new();
+        for (eq_class, &count) in eq_class_counts {
+            let groups = self.eq_class_groups(eq_class);
+            if rule == GroupRule::All && groups.len() != 1 {
+                continue;
+            }
+            for group in groups {
+                *counts.entry(group.to_string()).or_insert(0) += count;
+            }
+        }
+        counts
+    }
+
+    /// Add transcripts `seqs` named `tx_ids` to the index, returning the
+    /// indices assigned to them; existing transcripts keep theirs. A new
+    /// transcript takes its gene from `tx_gene_mapping` when one was entered
+    /// there for its id beforehand, and is otherwise its own gene. The new
+    /// transcripts have no recorded source file.
+    ///
+    /// The graph is re-assembled from its nodes plus the new sequences, so
+    /// the work grows with the size of the graph rather than the size of the
+    /// reference: much cheaper than a clean rebuild when the reference is
+    /// large and the graph compresses it well, but not free. The mphf is
+    /// built anew, so the memory of a full index build is still needed.
+    pub fn add_sequences(&mut self, seqs: &[DnaString], tx_ids: &[String]) -> Result<Vec<usize>, Error> {
+        if seqs.len() != tx_ids.len() {
+            return Err(failure::err_msg(format!(
+                "Got {} sequences but {} transcript ids", seqs.len(), tx_ids.len()
+            )));
+        }
+
+        let mut seen: HashSet<&str> = self.tx_names.iter().map(|tx_name| tx_name.as_str()).collect();
+        let known: Vec<&str> = tx_ids
+            .iter()
+            .filter(|tx_id| !seen.insert(tx_id.as_str()))
+            .map(|tx_id| tx_id.as_str())
+            .collect();
+        if !known.is_empty() {
+            return Err(PseudoalignerError::DuplicateTranscripts(known.join(", ")).into());
+        }
+
+        let segments: Vec<Vec<DnaString>> = seqs.iter().map(|seq| vec![seq.clone()]).collect();
+        let tx_gene_map: HashMap<String, String> = tx_ids
+            .iter()
+            .map(|tx_id| {
+                let gene = self.tx_gene_mapping.get(tx_id).unwrap_or(tx_id);
+                (tx_id.clone(), gene.clone())
+            })
+            .collect();
+        let mut new_index = build_index::extend_index(self, &segments, tx_ids, &tx_gene_map)?;
+
+        let first_id = self.tx_names.len();
+        if self.tx_checksums.len() == first_id {
+            new_index.tx_checksums = std::mem::take(&mut self.tx_checksums);
+            new_index.tx_checksums.extend(segments.iter().map(|tx_segments| utils::transcript_checksum(tx_segments)));
+        }
+        new_index.source_names = std::mem::take(&mut self.source_names);
+        new_index.tx_sources = std::mem::take(&mut self.tx_sources);
+        new_index.reference_checksums = std::mem::take(&mut self.reference_checksums);
+        new_index.tx_groups = std::mem::take(&mut self.tx_groups);
+        new_index.gene_names = std::mem::take(&mut self.gene_names);
+        *self = new_index;
+        Ok((first_id..first_id + seqs.len()).collect())
+    }
+
+    /// Number of distinct k-mers of the index, counted from the node
+    /// sequences: every k-mer lies on exactly one node, which holds `node
+    /// length - k + 1` of them. Also counts the k-mers a sparse index does
+    /// not look up.
+    pub fn n_distinct_kmers(&self) -> usize {
+        self.dbg.iter_nodes().map(|node| node.len() - K::k() + 1).sum()
+    }
+
+    pub fn kmer_mphf(&self) -> &Mphf<K> {
+        &self.kmer_mphf
+    }
+
+    pub fn kmer_positions(&self) -> &KmerPositions {
+        &self.kmer_positions
+    }
+
+    pub fn set_kmer_positions(&mut self, kmer_positions: KmerPositions) {
+        self.kmer_positions = kmer_positions;
+    }
+
+    /// Read all k-mer positions of an index loaded with
+    /// `IndexLoadMode::Mmap` into memory, so that the first queries do not
+    /// wait for page faults. A no-op for indices read into memory.
+    pub fn prefetch(&self) -> Result<(), Error> {
+        self.prefetch_region(0..self.kmer_positions.len())
+    }
+
+    /// Like `prefetch`, for the k-mer positions of mphf slots `slots` only.
+    pub fn prefetch_region(&self, slots: Range<usize>) -> Result<(), Error> {
+        self.kmer_positions.prefetch_region(slots)?;
+        Ok(())
+    }
+
+    /// Replace the k-mer mphf and positions.
+    pub(crate) fn set_kmer_index(&mut self, dbg_index: NoKeyBoomHashMap<K, (u32, u32)>) {
+        self.kmer_mphf = dbg_index.mphf;
+        self.kmer_positions = KmerPositions::Owned(dbg_index.values);
+    }
+
+    /// Read coverage needed to call a read mapped. After a mismatch a sparse
+    /// index only picks a read up again at its next minimizer, losing half a
+    /// window of coverage on average, so its threshold is lowered by that
+    /// much, but never below k.
+    pub fn coverage_threshold(&self) -> usize {
+        match self.minimizer_window {
+            None => READ_COVERAGE_THRESHOLD,
+            Some(window) => std::cmp::max(
+                READ_COVERAGE_THRESHOLD.saturating_sub((window + 1) / 2),
+                K::k(),
+            ),
+        }
+    }
+
+    /// Locate `kmer` in the graph, returning the node id and the offset of the k-mer in that node.
+    pub fn find_kmer(&self, kmer: &K) -> Option<(usize, usize)> {
+        match self.find_kmer_oriented(kmer) {
+            Some((nid, offset, false)) => Some((nid, offset)),
+            _ => None,
+        }
+    }
+
+    /// Locate `kmer` in the graph in either orientation, also telling
+    /// whether the node holds its reverse complement. Unstranded indices are
+    /// keyed by the canonical k-mer, the smaller of the k-mer and its
+    /// reverse complement, so one lookup finds both; stranded indices only
+    /// hold k-mers as the transcripts read.
+    pub fn find_kmer_oriented(&self, kmer: &K) -> Option<(usize, usize, bool)> {
+        let key = if self.stranded { *kmer } else { kmer.min_rc() };
+        let slot = self.kmer_mphf.try_hash(&key)?;
+        let (nid, offset) = self.kmer_positions.get(slot as usize);
+        let node = self.dbg.get_node(nid as usize);
+        let ref_kmer: K = node.sequence().get_kmer(offset as usize);
+
+        // the mphf returns an arbitrary slot for k-mers absent from the graph
+        if ref_kmer == *kmer {
+            Some((nid as usize, offset as usize, false))
+        } else if !self.stranded && ref_kmer == kmer.rc() {
+            Some((nid as usize, offset as usize, true))
+        } else {
+            None
+        }
+    }
+
+    /// Transcripts of the equivalence class of `kmer`, given as bases, or
+    /// `None` if it is not in the index. Strings that are not k bases of
+    /// A, C, G or T are an `InvalidKmer` error.
+    pub fn lookup_kmer(&self, kmer: &str) -> Result<Option<&[u32]>, Error> {
+        let bases = kmer.as_bytes();
+        if bases.len() != K::k() || !bases.iter().all(|b| b"ACGTacgt".contains(b)) {
+            return Err(PseudoalignerError::InvalidKmer(kmer.to_string(), K::k()).into());
+        }
+
+        Ok(self.find_kmer_oriented(&K::from_ascii(bases)).map(|(node_id, _, _)| {
+            let eq_class_id = *self.dbg.get_node(node_id).data() as usize;
+            self.eq_classes[eq_class_id].as_slice()
+        }))
+    }
+
+    /// Number of the k-mers of the ASCII bases of a read free of N or
+    /// other ambiguous bases, and how many of them are consistent with the
+    /// equivalence class the read maps to, counted like `mapping_score`
+    /// counts them while the read is mapped. Unlike the coverage of a
+    /// mapping, which counts the read bases covered, this tells a read
+    /// matching in part from one matching in full however many bases the
+    /// matches cover.
+    pub fn kmer_score(&self, bases: &[u8]) -> KmerScore {
+        self.map_read_bases_scored(bases, 1, 0).2
+    }
+
+    /// Pseudo-align `read_seq`, telling reads shorter than k, which have
+    /// no k-mer to look up, from reads that do not map for another reason.
+    pub fn map_read_outcome(&self, read_seq: &DnaString) -> MappingOutcome {
+        self.map_read_outcome_stranded(read_seq).0
+    }
+
+    /// Like `map_read_outcome`, also telling the strand the read was mapped
+    /// on, which is always `Forward` for stranded indices.
+    pub fn map_read_outcome_stranded(&self, read_seq: &DnaString) -> (MappingOutcome, Strand) {
+        let (mapping, strand) =
+            if read_seq.len() < K::k() { (None, Strand::Forward) } else { self.map_read_stranded(read_seq) };
+        (self.outcome_of(read_seq.len(), mapping), strand)
+    }
+
+    /// Like `map_read_outcome`, for the ASCII bases of a read that may hold
+    /// N or other ambiguous bases. Only the read k-mers free of them are
+    /// looked up, and reads with fewer such k-mers than a read just long
+    /// enough to reach the coverage threshold has do not map, as
+    /// `TooFewValidKmers`.
+    pub fn map_read_bases_outcome(&self, bases: &[u8]) -> MappingOutcome {
+        self.map_read_bases_strided(bases, 1)
+    }
+
+    /// Like `map_read_bases_outcome`, only starting lookups at every
+    /// `kmer_stride`-th k-mer of the read, in the orientation it is mapped
+    /// in, for about `kmer_stride` times fewer index lookups. The coverage
+    /// threshold then counts the sampled windows: a read needs a
+    /// `kmer_stride`-th of the k-mers an unstrided lookup needs among them,
+    /// and only the sampled k-mers consistent with its class count towards
+    /// its coverage, each for `kmer_stride` k-mers.
+    pub fn map_read_bases_strided(&self, bases: &[u8], kmer_stride: usize) -> MappingOutcome {
+        self.map_read_bases_rescuing(bases, kmer_stride, 0)
+    }
+
+    /// Like `map_read_bases_strided`, counting the read bases between two
+    /// matches as covered when there are at most `max_rescue_gap` of them
+    /// and the classes on both sides share a transcript, as they do around
+    /// an isolated mismatch, but not around the junction of a chimeric
+    /// read. At a k-mer stride, the gaps are the sampled windows missed
+    /// between two that match, each counting for `kmer_stride` bases. Reads
+    /// that only reach the coverage threshold that way are `Rescued`.
+    pub fn map_read_bases_rescuing(&self, bases: &[u8], kmer_stride: usize, max_rescue_gap: usize) -> MappingOutcome {
+        self.map_read_bases_stranded(bases, kmer_stride, max_rescue_gap).0
+    }
+
+    /// Like `map_read_bases_rescuing`, also telling the strand the read was
+    /// mapped on. Reads a stranded index does not map, but would map
+    /// reverse complemented, are `WrongStrand`, with the class and coverage
+    /// they got as read.
+    pub fn map_read_bases_stranded(
+        &self,
+        bases: &[u8],
+        kmer_stride: usize,
+        max_rescue_gap: usize,
+    ) -> (MappingOutcome, Strand) {
+        let (outcome, strand, _) = self.map_read_bases_scored(bases, kmer_stride, max_rescue_gap);
+        (outcome, strand)
+    }
+
+    /// Like `map_read_bases_stranded`, also giving the `KmerScore` of the
+    /// read as it was mapped, counted from the bases matched while mapping
+    /// it, without looking up any k-mer again.
+    pub fn map_read_bases_scored(
+        &self,
+        bases: &[u8],
+        kmer_stride: usize,
+        max_rescue_gap: usize,
+    ) -> (MappingOutcome, Strand, KmerScore) {
+        let (outcome, strand, score) = self.map_read_bases_oriented(bases, kmer_stride, max_rescue_gap);
+        if !self.stranded {
+            return (outcome, strand, score);
+        }
+
+        match outcome {
+            MappingOutcome::Unmapped(reason, eq_class, coverage) if reason.is_lookup_miss() => {
+                let bases_rc = reverse_complement_bases(bases);
+                let (outcome_rc, _, _) = self.map_read_bases_oriented(&bases_rc, kmer_stride, max_rescue_gap);
+                if outcome_rc.unmapped_reason().is_none() {
+                    (MappingOutcome::Unmapped(UnmappedReason::WrongStrand, eq_class, coverage), Strand::Reverse, score)
+                } else {
+                    (MappingOutcome::Unmapped(reason, eq_class, coverage), strand, score)
+                }
+            }
+            outcome => (outcome, strand, score),
+        }
+    }
+
+    fn map_read_bases_oriented(
+        &self,
+        bases: &[u8],
+        kmer_stride: usize,
+        max_rescue_gap: usize,
+    ) -> (MappingOutcome, Strand, KmerScore) {
+        let read_seq = DnaString::from_acgt_bytes(bases);
+        let kmer_length = K::k();
+        let kmer_stride = std::cmp::max(kmer_stride, 1);
+        if bases.len() < kmer_length {
+            let (outcome, strand) = self.map_read_outcome_stranded(&read_seq);
+            return (outcome, strand, KmerScore::default());
+        }
+        let all_valid = bases.iter().all(|base| b"ACGTacgt".contains(base));
+        // reads mapped as `map_read` maps them, whose unmapped reasons tell
+        // reads with too few k-mers apart
+        let plain = all_valid && kmer_stride == 1 && max_rescue_gap == 0;
+
+        // k-mers overlapping an ambiguous base would only be false mismatches
+        let mut seeds = valid_kmer_starts(bases, kmer_length);
+        let (read_seq, strand) = if self.is_reverse_strand(&read_seq) {
+            seeds = seeds.iter().rev().map(|&pos| bases.len() - kmer_length - pos).collect();
+            (read_seq.rc(), Strand::Reverse)
+        } else {
+            (read_seq, Strand::Forward)
+        };
+        let valid = seeds.clone();
+        let mut score = KmerScore { matched_kmers: 0, valid_kmers: valid.len() };
+        if kmer_stride > 1 {
+            seeds.retain(|pos| pos % kmer_stride == 0);
+        }
+        // windows an unstrided lookup needs, of which every stride-th is sampled
+        let min_windows = (self.coverage_threshold() + 1).saturating_sub(kmer_length);
+        if !plain && seeds.len() < (min_windows + kmer_stride - 1) / kmer_stride {
+            return (MappingOutcome::Unmapped(UnmappedReason::TooFewValidKmers, Vec::new(), 0), strand, score);
+        }
+
+        let sampled = seeds.clone();
+        if let Some(window) = self.minimizer_window {
+            let minimizers = minimizer_positions::<K>(&read_seq, window);
+            seeds.retain(|pos| minimizers.binary_search(pos).is_ok());
+        }
+
+        // probing every k-mer, like `map_read`
+        let probed = if all_valid && kmer_stride == 1 && self.minimizer_window.is_none() {
+            None
+        } else {
+            Some(&seeds[..])
+        };
+        let mapping = self.map_read_from_seeds_exact(&read_seq, probed, max_rescue_gap);
+        if let Some((_, _, ref exact, _)) = mapping {
+            // k-mers matching the nodes base for base, sampled or not
+            score.matched_kmers = valid
+                .iter()
+                .filter(|&&pos| exact[pos..pos + kmer_length].iter().all(|&is_exact| is_exact))
+                .count();
+        }
+        let mapping = match mapping {
+            Some((eq_class, _, exact, _)) if kmer_stride > 1 => {
+                // the sampled windows missed between two hits make the gaps
+                let (mut hits, mut rescued_windows, mut missed, mut seen_hit) = (0, 0, 0, false);
+                for &pos in &sampled {
+                    if !exact[pos..pos + kmer_length].iter().all(|&is_exact| is_exact) {
+                        missed += 1;
+                        continue;
+                    }
+                    if seen_hit && missed * kmer_stride <= max_rescue_gap {
+                        rescued_windows += missed;
+                    }
+                    hits += 1;
+                    missed = 0;
+                    seen_hit = true;
+                }
+                let coverage = match hits {
+                    0 => 0,
+                    hits => std::cmp::min((hits + rescued_windows) * kmer_stride + kmer_length - 1, bases.len()),
                 };
-                *kmer_pos += 1;
+                Some((eq_class, coverage, std::cmp::min(rescued_windows * kmer_stride, coverage)))
+            }
+            mapping => mapping.map(|(eq_class, coverage, _, rescued_bases)| (eq_class, coverage, rescued_bases)),
+        };
+        let rescued_bases = mapping.as_ref().map_or(0, |&(_, _, rescued_bases)| rescued_bases);
+        let outcome = match self.outcome_of(bases.len(), mapping.map(|(eq_class, coverage, _)| (eq_class, coverage))) {
+            MappingOutcome::Mapped(eq_class, coverage) if coverage - rescued_bases < self.coverage_threshold() => {
+                MappingOutcome::Rescued(eq_class, coverage)
+            }
+            outcome => outcome,
+        };
+        (outcome, strand, score)
+    }
+
+    /// Outcome of a read of `read_length` bases that mapped to `mapping`.
+    fn outcome_of(&self, read_length: usize, mapping: Option<(Vec<u32>, usize)>) -> MappingOutcome {
+        let (eq_class, coverage) = mapping.unwrap_or_default();
+        match self.unmapped_reason(read_length, &eq_class, coverage) {
+            None => MappingOutcome::Mapped(eq_class, coverage),
+            Some(UnmappedReason::TooShort) => MappingOutcome::TooShort,
+            Some(reason) => MappingOutcome::Unmapped(reason, eq_class, coverage),
+        }
+    }
+
+    /// Whether masking `bases` as `masked` changed whether the read maps,
+    /// given the `outcome` of `masked`, judged without mapping `bases` again.
+    /// A read left with too few valid k-mers had enough before if `bases`
+    /// has them, and a mapped read would not have mapped if a k-mer masking
+    /// removed lies only on transcripts outside its class.
+    fn masking_changed_outcome(&self, bases: &[u8], masked: &[u8], outcome: &MappingOutcome) -> bool {
+        let kmer_length = K::k();
+        match *outcome {
+            MappingOutcome::Unmapped(UnmappedReason::TooFewValidKmers, ..) => {
+                valid_kmer_starts(bases, kmer_length).len() + kmer_length - 1 >= self.coverage_threshold()
             }
+            MappingOutcome::Mapped(ref eq_class, _) | MappingOutcome::Rescued(ref eq_class, _) => {
+                let masked_starts = valid_kmer_starts(masked, kmer_length);
+                valid_kmer_starts(bases, kmer_length)
+                    .into_iter()
+                    .filter(|start| masked_starts.binary_search(start).is_err())
+                    .any(|start| {
+                        let kmer = K::from_ascii(&bases[start..start + kmer_length]);
+                        let hit = match self.find_kmer(&kmer) {
+                            None if !self.stranded => self.find_kmer(&kmer.rc()),
+                            hit => hit,
+                        };
+                        hit.map_or(false, |(node_id, _)| {
+                            let hit_class = &self.eq_classes[*self.dbg.get_node(node_id).data() as usize];
+                            !hit_class.iter().any(|tx_id| eq_class.binary_search(tx_id).is_ok())
+                        })
+                    })
+            }
+            _ => false,
+        }
+    }
+
+    /// Pseudo-align `read_seq` to determine its the equivalence class. A
+    /// sparse index only looks up the read's minimizers.
+    pub fn map_read(&self, read_seq: &DnaString) -> Option<(Vec<u32>, usize)> {
+        if let Some(window) = self.minimizer_window {
+            return self.map_read_minimizer(read_seq, window);
+        }
+        self.map_read_stranded(read_seq).0
+    }
+
+    /// `map_read` of a read of at least k bases, also telling the strand
+    /// it was mapped on.
+    fn map_read_stranded(&self, read_seq: &DnaString) -> (Option<(Vec<u32>, usize)>, Strand) {
+        if self.is_reverse_strand(read_seq) {
+            (self.map_read_as_is(&read_seq.rc()), Strand::Reverse)
+        } else {
+            (self.map_read_as_is(read_seq), Strand::Forward)
+        }
+    }
+
+    /// `map_read` of `read_seq` in the orientation it is given in.
+    fn map_read_as_is(&self, read_seq: &DnaString) -> Option<(Vec<u32>, usize)> {
+        match self.minimizer_window {
+            Some(window) => {
+                let seeds = minimizer_positions::<K>(read_seq, window);
+                self.map_read_from_seeds(read_seq, Some(&seeds))
+            }
+            None => self.map_read_from_seeds(read_seq, None),
+        }
+    }
+
+    /// Like `map_read`, keeping only the transcripts of the equivalence class
+    /// that are in `allowed`, e.g. a target panel. Reads whose class has
+    /// none of them do not map.
+    pub fn map_read_restricted(&self, read_seq: &DnaString, allowed: &HashSet<u32>) -> Option<(Vec<u32>, usize)> {
+        let (mut eq_class, coverage) = self.map_read(read_seq)?;
+        eq_class.retain(|tx_id| allowed.contains(tx_id));
+        if eq_class.is_empty() {
+            return None;
+        }
+        Some((eq_class, coverage))
+    }
+
+    /// Like `map_read`, also returning the `mapping_score` of the read, so
+    /// that a short read matching in full can be told from a long read
+    /// matching in part. Reads shorter than k do not map.
+    pub fn map_read_with_score(&self, read_seq: &DnaString) -> Option<(Vec<u32>, usize, f64)> {
+        if read_seq.len() < K::k() {
+            return None;
+        }
+        let total_kmers = read_seq.len() - K::k() + 1;
+
+        let read_seq = if self.is_reverse_strand(read_seq) { read_seq.rc() } else { read_seq.clone() };
+        let seeds = self
+            .minimizer_window
+            .map(|window| minimizer_positions::<K>(&read_seq, window));
+        self.map_read_from_seeds_counted(&read_seq, seeds.as_ref().map(|seeds| seeds.as_slice()))
+            .map(|(eq_class, coverage, consistent_kmers)| {
+                (eq_class, coverage, consistent_kmers as f64 / total_kmers as f64)
+            })
+    }
+
+    /// Fraction of the k-mers of `read_seq` consistent with the equivalence
+    /// class it maps to, from 0 to 1: those on the nodes the class was
+    /// intersected from, matching them base for base. 0 for reads that do
+    /// not map, map to an empty class or are shorter than k; the ratio of
+    /// the `kmer_score` of the read.
+    pub fn mapping_score(&self, read_seq: &DnaString) -> f64 {
+        self.kmer_score(read_seq.to_string().as_bytes()).ratio().unwrap_or(0.0)
+    }
+
+    /// `map_read` of every read of `reads`, given with its id, returning the
+    /// mappings lazily, in order and paired with the ids, so that callers
+    /// need not keep the ids of the reads apart. Reads shorter than k do
+    /// not map.
+    pub fn map_named_reads<'a, I>(&'a self, reads: I) -> impl Iterator<Item = (String, Option<(Vec<u32>, usize)>)> + 'a
+    where
+        I: Iterator<Item = (String, DnaString)> + 'a,
+    {
+        reads.map(move |(read_id, read_seq)| {
+            let mapping = if read_seq.len() < K::k() { None } else { self.map_read(&read_seq) };
+            (read_id, mapping)
+        })
+    }
+
+    /// Pseudo-align `read_seq` like `map_read`, but only look up the read's
+    /// (`window`, k) minimizers in the index, so that a long read needs about
+    /// one lookup per `window` k-mers. Once a minimizer hits, the read is
+    /// extended along the graph as usual. For reads matching the reference
+    /// exactly the result is the same as `map_read`. They can differ when
+    /// sequencing errors fall inside a graph extension: `map_read` resumes
+    /// at the next matching k-mer, while this only resumes at the next
+    /// matching minimizer, so fewer nodes (and colors) may be seen.
+    pub fn map_read_minimizer(&self, read_seq: &DnaString, window: usize) -> Option<(Vec<u32>, usize)> {
+        if read_seq.len() < K::k() {
+            return None;
+        }
+
+        if self.is_reverse_strand(read_seq) {
+            let read_rc = read_seq.rc();
+            let seeds = minimizer_positions::<K>(&read_rc, window);
+            return self.map_read_from_seeds(&read_rc, Some(&seeds));
+        }
+
+        let seeds = minimizer_positions::<K>(read_seq, window);
+        self.map_read_from_seeds(read_seq, Some(&seeds))
+    }
+
+    /// In an unstranded index every k-mer is stored in only one orientation,
+    /// which need not be the read's. Tell whether the first non-palindromic
+    /// read k-mer found by its canonical lookup is stored reverse
+    /// complemented; reads without one take the lexicographically smaller of
+    /// the read and its reverse complement. The read is then mapped as its
+    /// reverse complement, so a read and its reverse complement get the same
+    /// equivalence class. Always false for stranded indices.
+    fn is_reverse_strand(&self, read_seq: &DnaString) -> bool {
+        if self.stranded || read_seq.len() < K::k() {
+            return false;
+        }
+
+        let first_hit = read_seq
+            .iter_kmers::<K>()
+            .filter(|kmer| *kmer != kmer.rc())
+            .find_map(|kmer| self.find_kmer_oriented(&kmer));
+        match first_hit {
+            Some((_, _, reverse)) => reverse,
+            None => {
+                let len = read_seq.len();
+                (0..len)
+                    .map(|i| (read_seq.get(i), 3 - read_seq.get(len - 1 - i)))
+                    .find(|&(base, rc_base)| base != rc_base)
+                    .is_some_and(|(base, rc_base)| rc_base < base)
+            }
+        }
+    }
+
+    /// Position on transcript `tx_id` of the start of `read_seq`, placed by
+    /// its k-mers on the nodes of the transcript; negative if the read
+    /// overhangs the transcript start. A node the transcript passes through
+    /// more than once places the read at each of its occurrences, and later
+    /// k-mers keep the placements they agree with; of those left, the
+    /// leftmost is returned. `None` if the index does not record transcript
+    /// positions or no read k-mer lies on the transcript.
+    pub fn read_tx_position(&self, read_seq: &DnaString, tx_id: u32) -> Option<i64> {
+        let starts = self.tx_node_starts.get(tx_id as usize)?;
+        if read_seq.len() < K::k() {
+            return None;
+        }
+
+        let mut placements: Option<Vec<i64>> = None;
+        for (read_pos, kmer) in read_seq.iter_kmers::<K>().enumerate() {
+            if let Some((node_id, offset)) = self.find_kmer(&kmer) {
+                let first = match starts.binary_search(&(node_id as u32, i32::MIN)) {
+                    Ok(idx) | Err(idx) => idx,
+                };
+                let kmer_placements: Vec<i64> = starts[first..]
+                    .iter()
+                    .take_while(|&&(start_node, _)| start_node == node_id as u32)
+                    .map(|&(_, start)| start as i64 + offset as i64 - read_pos as i64)
+                    .collect();
+                if kmer_placements.is_empty() {
+                    continue;
+                }
+
+                match placements {
+                    None => placements = Some(kmer_placements),
+                    Some(ref mut placements) => {
+                        let agreed: Vec<i64> = placements
+                            .iter()
+                            .cloned()
+                            .filter(|placement| kmer_placements.contains(placement))
+                            .collect();
+                        if !agreed.is_empty() {
+                            *placements = agreed;
+                        }
+                    }
+                }
+                if placements.as_ref().is_some_and(|placements| placements.len() == 1) {
+                    break;
+                }
+            }
+        }
+        placements.and_then(|placements| placements.into_iter().min())
+    }
+
+    /// Placements of `read_seq`, if it maps, on every transcript of its
+    /// class, as `(read_id, transcript, start, end)` records for
+    /// `write_bed`: the read bases placed by `read_tx_position`, clipped to
+    /// the transcript. Reads from the reverse strand of an unstranded index
+    /// are placed by their reverse complement, and transcripts the read can
+    /// not be placed on are left out.
+    pub fn bed_records(&self, read_id: &str, read_seq: &DnaString) -> Vec<(String, u32, usize, usize)> {
+        let eq_class = match self.confident_eq_class(read_seq) {
+            Some(eq_class) => eq_class,
+            None => return Vec::new(),
+        };
+        let read_seq = if self.is_reverse_strand(read_seq) { read_seq.rc() } else { read_seq.clone() };
+
+        eq_class
+            .into_iter()
+            .filter_map(|tx_id| {
+                let start = self.read_tx_position(&read_seq, tx_id)?;
+                let tx_length = self.tx_lengths.get(tx_id as usize).map_or(i64::MAX, |&length| length as i64);
+                let end = std::cmp::min(start + read_seq.len() as i64, tx_length);
+                let start = std::cmp::max(start, 0);
+                if start >= end {
+                    return None;
+                }
+                Some((read_id.to_string(), tx_id, start as usize, end as usize))
+            })
+            .collect()
+    }
+
+    /// Pseudo-align a pair of mates to the transcripts both are compatible
+    /// with: the intersection of their equivalence classes, with their
+    /// coverages summed. Mates come from opposite strands, so the second is
+    /// mapped reverse complemented. A mate that does not map to a non-empty
+    /// class, or is shorter than k, is ignored, so the pair maps like its
+    /// other mate. Mates mapping to disjoint classes give an empty class.
+    pub fn map_read_pair(&self, mate1: &DnaString, mate2: &DnaString) -> Option<(Vec<u32>, usize)> {
+        self.map_read_pair_with_policy(mate1, mate2, DisjointMatePolicy::Discard).0
+    }
+
+    /// Like `map_read_pair`, resolving mates that map to disjoint classes
+    /// by `policy`, and telling how the mates were combined.
+    pub fn map_read_pair_with_policy(
+        &self,
+        mate1: &DnaString,
+        mate2: &DnaString,
+        policy: DisjointMatePolicy,
+    ) -> (Option<(Vec<u32>, usize)>, PairMapping) {
+        let map_mate = |mate: &DnaString| {
+            if mate.len() < K::k() {
+                return None;
+            }
+            self.map_read(mate).filter(|(eq_class, _)| !eq_class.is_empty())
+        };
+        combine_mates(map_mate(mate1), map_mate(&mate2.rc()), policy)
+    }
+
+    /// Like `map_read_pair_with_policy`, for the ASCII bases of the mates.
+    /// Each mate is mapped like `map_read_bases_outcome` maps a read, so the
+    /// k-mers overlapping N or other ambiguous bases are skipped.
+    pub fn map_read_pair_bases_with_policy(
+        &self,
+        mate1: &[u8],
+        mate2: &[u8],
+        policy: DisjointMatePolicy,
+    ) -> (Option<(Vec<u32>, usize)>, PairMapping) {
+        let mapping1 = mate_mapping(self.map_read_bases_outcome(mate1));
+        let mapping2 = mate_mapping(self.map_read_bases_outcome(&dna::revcomp(mate2)));
+        combine_mates(mapping1, mapping2, policy)
+    }
+
+    /// Fragment length of every pair of mates mapping to exactly one shared
+    /// transcript, from the outermost mate ends on that transcript. Mates
+    /// come from opposite strands, so a pair is tried as given with the
+    /// second mate reverse complemented and the other way round. Pairs
+    /// without a unique shared transcript, or with different ones in the two
+    /// orientations, are skipped, as are all pairs if the index does not
+    /// record transcript positions.
+    pub fn fragment_lengths(&self, pairs: &[(DnaString, DnaString)]) -> Vec<usize> {
+        pairs
+            .iter()
+            .filter_map(|(mate1, mate2)| self.fragment_length(mate1, mate2))
+            .collect()
+    }
+
+    fn fragment_length(&self, mate1: &DnaString, mate2: &DnaString) -> Option<usize> {
+        let orientations = [(mate1.clone(), mate2.rc()), (mate1.rc(), mate2.clone())];
+        let mut fragment = None;
+
+        for (mate1, mate2) in &orientations {
+            let mut shared = match (self.confident_eq_class(mate1), self.confident_eq_class(mate2)) {
+                (Some(eq_class1), Some(eq_class2)) => {
+                    let mut shared = eq_class1;
+                    intersect(&mut shared, &eq_class2);
+                    shared
+                }
+                _ => continue,
+            };
+            if shared.len() != 1 {
+                continue;
+            }
+            let tx_id = shared.pop().unwrap();
+
+            let (start1, start2) = match (self.read_tx_position(mate1, tx_id), self.read_tx_position(mate2, tx_id)) {
+                (Some(start1), Some(start2)) => (start1, start2),
+                _ => continue,
+            };
+            let start = std::cmp::min(start1, start2);
+            let end = std::cmp::max(start1 + mate1.len() as i64, start2 + mate2.len() as i64);
+            if fragment.is_some() {
+                return None;
+            }
+            fragment = Some((end - start) as usize);
+        }
+
+        fragment
+    }
+
+    /// Equivalence class of `read_seq` if it maps with enough coverage.
+    pub(crate) fn confident_eq_class(&self, read_seq: &DnaString) -> Option<Vec<u32>> {
+        match self.map_read(read_seq) {
+            Some((eq_class, coverage)) if self.is_confident(&eq_class, coverage) => Some(eq_class),
+            _ => None,
+        }
+    }
+
+    /// Whether a read mapped to `eq_class` with `coverage` counts as mapped.
+    pub(crate) fn is_confident(&self, eq_class: &[u32], coverage: usize) -> bool {
+        coverage >= self.coverage_threshold() && !eq_class.is_empty()
+    }
+
+    /// Why a read of `read_length` bases mapped to `eq_class` with
+    /// `coverage` does not count as mapped, or `None` if it does.
+    pub fn unmapped_reason(&self, read_length: usize, eq_class: &[u32], coverage: usize) -> Option<UnmappedReason> {
+        if read_length < K::k() {
+            Some(UnmappedReason::TooShort)
+        } else if read_length < self.coverage_threshold() {
+            // coverage counts read bases, so it never exceeds the length
+            Some(UnmappedReason::TooFewKmers)
+        } else if coverage == 0 {
+            Some(UnmappedReason::NoKmerHits)
+        } else if coverage < self.coverage_threshold() {
+            Some(UnmappedReason::LowCoverage)
+        } else if eq_class.is_empty() {
+            Some(UnmappedReason::EmptyClass)
+        } else {
+            None
+        }
+    }
+
+    /// Count mapped reads per equivalence class.
+    pub fn count_reads<I: Iterator<Item = DnaString>>(&self, reads: I) -> HashMap<Vec<u32>, u64> {
+        let mut counts = HashMap::new();
+        for read_seq in reads {
+            if let Some(eq_class) = self.confident_eq_class(&read_seq) {
+                *counts.entry(eq_class).or_insert(0) += 1;
+            }
+        }
+        counts
+    }
+
+    /// Count reads per equivalence class, collapsing PCR duplicates: every
+    /// distinct (UMI, equivalence class) combination among the mapped reads
+    /// counts once. A UMI whose reads map to several equivalence classes
+    /// counts once for each of them.
+    pub fn count_reads_umi<I: Iterator<Item = (String, DnaString)>>(
+        &self,
+        reads: I,
+    ) -> HashMap<Vec<u32>, u64> {
+        let mut counter = UmiCounter::new(false);
+        for (umi, read_seq) in reads {
+            if let Some(eq_class) = self.confident_eq_class(&read_seq) {
+                counter.add_read(Some(umi.as_bytes()), &eq_class);
+            }
+        }
+        counter.eq_class_counts()
+    }
+
+    /// Core of the mappers: probe the read k-mers at `seeds` (sorted
+    /// positions), or at every position if `None`, to start graph extensions.
+    pub(crate) fn map_read_from_seeds(&self, read_seq: &DnaString, seeds: Option<&[usize]>) -> Option<(Vec<u32>, usize)> {
+        self.map_read_from_seeds_counted(read_seq, seeds)
+            .map(|(eq_class, coverage, _)| (eq_class, coverage))
+    }
+
+    /// `map_read_from_seeds`, also counting the read k-mers consistent with
+    /// the equivalence class: those matching the nodes it was intersected
+    /// from base for base. Reads with an empty class have none.
+    fn map_read_from_seeds_counted(
+        &self,
+        read_seq: &DnaString,
+        seeds: Option<&[usize]>,
+    ) -> Option<(Vec<u32>, usize, usize)> {
+        let kmer_length = K::k();
+        self.map_read_from_seeds_exact(read_seq, seeds, 0).map(|(eq_class, coverage, exact, _)| {
+            // k-mers within runs of exactly matched bases lie on the nodes
+            let mut run = 0;
+            let consistent_kmers = exact
+                .iter()
+                .filter(|&&is_exact| {
+                    run = if is_exact { run + 1 } else { 0 };
+                    run >= kmer_length
+                })
+                .count();
+            (eq_class, coverage, consistent_kmers)
+        })
+    }
+
+    /// `map_read_from_seeds`, also telling which read bases equal those of
+    /// the nodes the equivalence class was intersected from, none for reads
+    /// with an empty class, and counting gaps of at most `max_rescue_gap`
+    /// read bases between matches to nodes whose classes share a transcript
+    /// as covered. The last element is how many bases of the coverage were
+    /// such gaps.
+    fn map_read_from_seeds_exact(
+        &self,
+        read_seq: &DnaString,
+        seeds: Option<&[usize]>,
+        max_rescue_gap: usize,
+    ) -> Option<(Vec<u32>, usize, Vec<bool>, usize)> {
+        let read_length = read_seq.len();
+        let mut read_coverage: usize = 0;
+        let mut rescued_bases: usize = 0;
+        let mut colors: Vec<u32> = Vec::new();
+        // read bases equal to those of the nodes they were matched to
+        let mut exact = vec![false; read_length];
+        let left_extend_threshold = (LEFT_EXTEND_FRACTION * read_length as f64) as usize;
+
+        let mut kmer_pos: usize = 0;
+        let kmer_length = K::k();
+        let last_kmer_pos = read_length - kmer_length;
+
+        // Scan the read for the first kmer that exists in the reference
+        let find_kmer_match = |kmer_pos: &mut usize| -> Option<(usize, usize)> {
+            match seeds {
+                None => {
+                    while *kmer_pos <= last_kmer_pos {
+                        let read_kmer = read_seq.get_kmer(*kmer_pos);
+
+                        if let Some(hit) = self.find_kmer(&read_kmer) {
+                            return Some(hit);
+                        }
+                        *kmer_pos += 1;
+                    }
+                }
+                Some(seeds) => {
+                    let first_seed = match seeds.binary_search(kmer_pos) {
+                        Ok(idx) | Err(idx) => idx,
+                    };
+                    for &seed_pos in &seeds[first_seed..] {
+                        *kmer_pos = seed_pos;
+                        let read_kmer = read_seq.get_kmer(seed_pos);
+
+                        if let Some(hit) = self.find_kmer(&read_kmer) {
+                            return Some(hit);
+                        }
+                    }
+                    *kmer_pos = last_kmer_pos + 1;
+                }
+            }
+
+            None
+        };
+
+        // extract the first exact matching position of read
+        let (mut node_id, mut kmer_offset) =
+            // get the first match through mphf
+            match find_kmer_match(&mut kmer_pos) {
+                None => (None, None),
+                Some((nid, offset)) => (Some(nid), Some(offset))
+            };
+        if node_id.is_some() {
+            for base in &mut exact[kmer_pos..kmer_pos + kmer_length] {
+                *base = true;
+            }
+        }
+
+        // check if we can extend back if there were SNP in every kmer query,
+        // or if the read k-mers before the first seed were never looked up
+        let skipped_unprobed = seeds.is_some() && kmer_pos > 0;
+        if (kmer_pos >= left_extend_threshold || skipped_unprobed) && node_id.is_some() {
+            let mut last_pos = kmer_pos - 1;
+            let mut prev_node_id = node_id.unwrap();
+            // length of the node sequence before the kmer match
+            let mut skipped_ref = kmer_offset.unwrap();
+
+            loop {
+                let node = self.dbg.get_node(prev_node_id);
+                //println!("{:?}, {:?}, {:?}, {:?}, {:?}",
+                //         node, node.sequence(),
+                //         &eq_classes[ *node.data() as usize],
+                //         skipped_ref, last_pos);
+
+                // length of remaining read before kmer match
+                let skipped_read = last_pos + 1;
+
+                // find maximum extention possbile before fork or eof read
+                let max_matchable_pos = std::cmp::min(skipped_read, skipped_ref);
+
+                let ref_seq_slice = node.sequence();
+                let mut premature_break = false;
+                let mut matched_bases = 0;
+                let mut seen_snp = 0;
+                for idx in 0..max_matchable_pos {
+                    let ref_pos = skipped_ref - 1 - idx;
+                    let read_offset = last_pos - idx;
+
+                    // compare base by base
+                    if ref_seq_slice.get(ref_pos) != read_seq.get(read_offset) {
+                        if seen_snp > 3 {
+                            premature_break = true;
+                            break;
+                        }
+
+                        // Allowing 2-SNP
+                        seen_snp += 1;
+                    } else {
+                        exact[read_offset] = true;
+                    }
+
+                    matched_bases += 1;
+                    read_coverage += 1;
+                }
+
+                //break the loop if end of read reached or a premature mismatch
+                if last_pos + 1 - matched_bases == 0 || premature_break {
+                    break;
+                }
+
+                // adjust last position
+                last_pos -= matched_bases;
+
+                // If reached here then a fork is found in the reference.
+                let exts = node.exts();
+                let next_base = read_seq.get(last_pos);
+                if exts.has_ext(Dir::Left, next_base) {
+                    // found a left extention.
+                    let index = exts
+                        .get(Dir::Left)
+                        .iter()
+                        .position(|&x| x == next_base)
+                        .unwrap();
+
+                    let edge = node.l_edges()[index];
+
+                    //update the previous node's id
+                    prev_node_id = edge.0;
+                    let prev_node = self.dbg.get_node(prev_node_id);
+                    skipped_ref = prev_node.sequence().len() - kmer_length + 1;
+
+                    // extract colors
+                    let color = prev_node.data();
+                    colors.push(*color);
+                } else {
+                    break;
+                }
+            } // end-loop
+        } //end-if
+
+        // forward search
+        if kmer_pos <= last_kmer_pos {
+            loop {
+                let node = self.dbg.get_node(node_id.unwrap());
+                //println!("{:?}, {:?}, {:?}, {:?}",
+                //         node, node.sequence(),
+                //         &eq_classes[ *node.data() as usize],
+                //         kmer_offset);
+                kmer_pos += kmer_length;
+                read_coverage += kmer_length;
+
+                // extract colors
+                let color = node.data();
+                colors.push(*color);
+
+                // length of remaining read after kmer match
+                let remaining_read = read_length - kmer_pos;
+
+                // length of the remaining node sequence after kmer match
+                let ref_seq_slice = node.sequence();
+                let ref_length = ref_seq_slice.len();
+                let ref_offset = kmer_offset.unwrap() + kmer_length;
+                let informative_ref = ref_length - ref_offset;
+
+                // find maximum extention possbile before fork or eof read
+                let max_matchable_pos = std::cmp::min(remaining_read, informative_ref);
+
+                let mut premature_break = false;
+                let mut matched_bases = 0;
+                let mut seen_snp = 0;
+                for idx in 0..max_matchable_pos {
+                    let ref_pos = ref_offset + idx;
+                    let read_offset = kmer_pos + idx;
+
+                    // compare base by base
+                    if ref_seq_slice.get(ref_pos) != read_seq.get(read_offset) {
+                        if seen_snp > 3 {
+                            premature_break = true;
+                            break;
+                        }
+
+                        // Allowing 2-SNP
+                        seen_snp += 1;
+                    } else {
+                        exact[read_offset] = true;
+                    }
+
+                    matched_bases += 1;
+                    read_coverage += 1;
+                }
+
+                kmer_pos += matched_bases;
+                //break the loop if end of read reached or a premature mismatch
+                if kmer_pos >= read_length {
+                    break;
+                }
+
+                // If reached here then a fork is found in the reference.
+                let exts = node.exts();
+                let next_base = read_seq.get(kmer_pos);
+
+                if !premature_break && exts.has_ext(Dir::Right, next_base) {
+                    // found a right extention.
+                    let index = exts
+                        .get(Dir::Right)
+                        .iter()
+                        .position(|&x| x == next_base)
+                        .unwrap();
+
+                    let edge = node.r_edges()[index];
+
+                    //update the next node's id
+                    node_id = Some(edge.0);
+                    kmer_offset = Some(0);
+
+                    //adjust for kmer_position
+                    exact[kmer_pos] = true;
+                    kmer_pos -= kmer_length - 1;
+                    read_coverage -= kmer_length - 1;
+                } else {
+                    // can't extend node in dbg extract read using mphf
+                    // TODO: might have to check some cases
+                    if kmer_pos > last_kmer_pos {
+                        // can't search in mphf if no full kmer can be made
+                        break;
+                    }
+
+                    // get the match through mphf
+                    let gap_start = kmer_pos;
+                    match find_kmer_match(&mut kmer_pos) {
+                        None => break,
+                        Some((nid, offset)) => {
+                            let gap = kmer_pos - gap_start;
+                            if gap > 0 && gap <= max_rescue_gap {
+                                let next_color = *self.dbg.get_node(nid).data();
+                                let shared = colors.last().is_some_and(|&color| {
+                                    share_transcript(&self.eq_classes[color as usize], &self.eq_classes[next_color as usize])
+                                });
+                                if shared {
+                                    read_coverage += gap;
+                                    rescued_bases += gap;
+                                }
+                            }
+                            node_id = Some(nid);
+                            kmer_offset = Some(offset);
+                            for base in &mut exact[kmer_pos..kmer_pos + kmer_length] {
+                                *base = true;
+                            }
+                        }
+                    };
+                }
+            } // end-loop
+        } //end-if
+
+        // Take the intersection of the sets
+        let colors_len = colors.len();
+        if colors_len == 0 {
+            if read_coverage != 0 {
+                panic!(
+                    "Different read coverage {:?} than num of eqclasses {:?}",
+                    colors_len, read_coverage
+                );
+            }
+
+            None
+        } else {
+            // Intersect the equivalence classes
+            let first_color = colors.pop().unwrap();
+            let mut eq_class = self.eq_classes[first_color as usize].clone();
+
+            for color in colors {
+                intersect(&mut eq_class, &self.eq_classes[color as usize]);
+            }
+
+            if eq_class.is_empty() {
+                exact = vec![false; read_length];
+            }
+            Some((eq_class, read_coverage, exact, rescued_bases))
+        }
+    }
+}
+
+/// Reverse complement of the ASCII bases of a read, with every base other
+/// than A, C, G and T read as N.
+fn reverse_complement_bases(bases: &[u8]) -> Vec<u8> {
+    bases
+        .iter()
+        .rev()
+        .map(|&base| match base {
+            b'A' | b'a' => b'T',
+            b'C' | b'c' => b'G',
+            b'G' | b'g' => b'C',
+            b'T' | b't' => b'A',
+            _ => b'N',
+        })
+        .collect()
+}
+
+/// Mix the bits of a k-mer so that minimizers are not biased towards poly-A.
+fn kmer_hash<K: Kmer>(kmer: &K) -> u64 {
+    let mut x = kmer.to_u64();
+    x ^= x >> 33;
+    x = x.wrapping_mul(0xff51_afd7_ed55_8ccd);
+    x ^= x >> 33;
+    x = x.wrapping_mul(0xc4ce_b9fe_1a85_ec53);
+    x ^ (x >> 33)
+}
+
+/// Start positions of the k-mers of the ASCII `bases` free of N and other
+/// ambiguous bases.
+fn valid_kmer_starts(bases: &[u8], kmer_length: usize) -> Vec<usize> {
+    let mut starts = Vec::new();
+    let mut valid_bases = 0;
+    for (pos, base) in bases.iter().enumerate() {
+        valid_bases = if b"ACGTacgt".contains(base) { valid_bases + 1 } else { 0 };
+        if valid_bases >= kmer_length {
+            starts.push(pos + 1 - kmer_length);
+        }
+    }
+    starts
+}
+
+/// Sorted, distinct start positions of the k-mers of `seq` with the smallest
+/// hash within each run of `window` consecutive k-mers.
+pub(crate) fn minimizer_positions<K: Kmer>(seq: &DnaString, window: usize) -> Vec<usize> {
+    let hashes: Vec<u64> = seq.iter_kmers::<K>().map(|kmer| kmer_hash(&kmer)).collect();
+    let window = std::cmp::max(window, 1);
+
+    let mut positions: Vec<usize> = Vec::new();
+    if hashes.len() <= window {
+        let (pos, _) = hashes.iter().enumerate().min_by_key(|&(_, h)| *h).unwrap();
+        positions.push(pos);
+        return positions;
+    }
+
+    for start in 0..(hashes.len() - window + 1) {
+        let mut min_pos = start;
+        for pos in start..(start + window) {
+            if hashes[pos] < hashes[min_pos] {
+                min_pos = pos;
+            }
+        }
+
+        if positions.last() != Some(&min_pos) {
+            positions.push(min_pos);
+        }
+    }
+
+    positions
+}
+
+/// Positions of the k-mers of graph node sequence `seq` that a sparse index
+/// stores: the minimizers of its runs of `window` k-mers, plus the k-mers of
+/// smallest hash of its shorter prefixes and suffixes. A read window that
+/// spans several nodes takes its minimizer from such a prefix or suffix, so
+/// every minimizer of a read matching the graph is stored.
+pub(crate) fn sampled_kmer_positions<K: Kmer>(seq: &DnaString, window: usize) -> Vec<usize> {
+    let hashes: Vec<u64> = seq.iter_kmers::<K>().map(|kmer| kmer_hash(&kmer)).collect();
+    let window = std::cmp::max(window, 1);
+    let mut positions = minimizer_positions::<K>(seq, window);
+
+    // ties go to the leftmost k-mer, as in `minimizer_positions`
+    let partial_len = std::cmp::min(window - 1, hashes.len());
+    let mut min_pos = 0;
+    for pos in 0..partial_len {
+        if hashes[pos] < hashes[min_pos] {
+            min_pos = pos;
+        }
+        positions.push(min_pos);
+    }
+    let mut min_pos = hashes.len() - 1;
+    for pos in (hashes.len() - partial_len..hashes.len()).rev() {
+        if hashes[pos] <= hashes[min_pos] {
+            min_pos = pos;
+        }
+        positions.push(min_pos);
+    }
+
+    positions.sort_unstable();
+    positions.dedup();
+    positions
+}
+
+/// Denoise equivalence class `counts`: the reads of every class counted
+/// fewer than `min_count` times are moved to the class counted at least
+/// `min_count` times that shares most transcripts with it, ties going to
+/// the more abundant class, or dropped if no such class shares any.
+pub fn collapse_rare_classes(counts: &HashMap<Vec<u32>, u64>, min_count: u64) -> HashMap<Vec<u32>, u64> {
+    let mut collapsed: HashMap<Vec<u32>, u64> = counts
+        .iter()
+        .filter(|&(_, &count)| count >= min_count)
+        .map(|(eq_class, &count)| (eq_class.clone(), count))
+        .collect();
+    let mut survivors: Vec<(&Vec<u32>, u64)> = collapsed.iter().map(|(eq_class, &count)| (eq_class, count)).collect();
+    survivors.sort_by(|a, b| b.1.cmp(&a.1).then_with(|| a.0.cmp(b.0)));
+
+    let mut moved: Vec<(Vec<u32>, u64)> = Vec::new();
+    for (eq_class, &count) in counts.iter().filter(|&(_, &count)| count < min_count) {
+        let mut best: Option<(&Vec<u32>, usize)> = None;
+        for &(survivor, _) in &survivors {
+            let shared = shared_len(eq_class, survivor);
+            if shared > best.map_or(0, |(_, best_shared)| best_shared) {
+                best = Some((survivor, shared));
+            }
+        }
+        if let Some((survivor, _)) = best {
+            moved.push((survivor.clone(), count));
+        }
+    }
+
+    for (eq_class, count) in moved {
+        *collapsed.get_mut(&eq_class).unwrap() += count;
+    }
+    collapsed
+}
+
+/// Number of elements two sorted slices have in common.
+fn shared_len<T: Ord>(v1: &[T], v2: &[T]) -> usize {
+    let (mut idx1, mut idx2, mut shared) = (0, 0, 0);
+    while idx1 < v1.len() && idx2 < v2.len() {
+        match v1[idx1].cmp(&v2[idx2]) {
+            Ordering::Less => idx1 += 1,
+            Ordering::Greater => idx2 += 1,
+            Ordering::Equal => {
+                shared += 1;
+                idx1 += 1;
+                idx2 += 1;
+            }
+        }
+    }
+    shared
+}
+
+/// Whether the sorted `v1` and `v2` have an element in common.
+fn share_transcript(v1: &[u32], v2: &[u32]) -> bool {
+    let (mut idx1, mut idx2) = (0, 0);
+    while idx1 < v1.len() && idx2 < v2.len() {
+        match v1[idx1].cmp(&v2[idx2]) {
+            Ordering::Less => idx1 += 1,
+            Ordering::Greater => idx2 += 1,
+            Ordering::Equal => return true,
+        }
+    }
+    false
+}
+
+/// Compute the intersection of v1 and v2 inplace on top of v1
+/// v1 and v2 must be sorted
+fn intersect<T: Eq + Ord>(v1: &mut Vec<T>, v2: &[T]) {
+    if v1.is_empty() {
+        return;
+    }
+
+    if v2.is_empty() {
+        v1.clear();
+    }
+
+    let mut fill_idx1 = 0;
+    let mut idx1 = 0;
+    let mut idx2 = 0;
+
+    while idx1 < v1.len() && idx2 < v2.len() {
+        match v1[idx1].cmp(&v2[idx2]) {
+            Ordering::Less => idx1 += 1,
+            Ordering::Greater => idx2 += 1,
+            Ordering::Equal => {
+                v1.swap(fill_idx1, idx1);
+                idx1 += 1;
+                idx2 += 1;
+                fill_idx1 += 1;
+            }
+        }
+    }
+
+    v1.truncate(fill_idx1);
+}
+
+/// Write the reads that do not map to `index` to `writer` as FASTQ, like
+/// `utils::UnmappedWriter` with their `UnmappedReason`, returning how many
+/// were written. Reads without qualities get `I` for every base.
+pub fn write_unmapped<K, W, I>(mut writer: W, reads: I, index: &Pseudoaligner<K>) -> Result<usize, Error>
+where
+    K: Kmer + Sync + Send,
+    W: Write,
+    I: Iterator<Item = (String, DnaString, Option<Vec<u8>>)>,
+{
+    let mut num_unmapped = 0;
+    for (read_id, read_seq, quals) in reads {
+        let mapping = if read_seq.len() >= K::k() { index.map_read(&read_seq) } else { None };
+        let (eq_class, coverage) = mapping.unwrap_or_default();
+        let reason = match index.unmapped_reason(read_seq.len(), &eq_class, coverage) {
+            Some(reason) => reason,
+            None => continue,
+        };
+
+        let record = fastq::Record::with_attrs(&read_id, None, read_seq.to_string().as_bytes(), &quals.unwrap_or_default());
+        utils::write_unmapped_record(&mut writer, &record, reason)?;
+        num_unmapped += 1;
+    }
+    Ok(num_unmapped)
+}
+
+/// Write the pseudoalignments of `reads`, given as id, ASCII bases and
+/// qualities, to `writer` as SAM, returning how many reads mapped. Reads are
+/// mapped like `Pseudoaligner::map_read_bases_outcome` maps them, and
+/// written with their bases as given. The header has an `@SQ` line per
+/// transcript. A mapped read is placed on the first transcript of its equivalence class by its
+/// first k-mer, as a match over the span it shares with the transcript,
+/// soft clipped where it overhangs the transcript ends. Its MAPQ is
+/// `SAM_UNIQUE_MAPQ` if the class has one transcript, and -10 log10(1 - 1/n)
+/// for n transcripts otherwise; `NH` holds n and `ZT` the other
+/// transcripts. Other reads, and mapped reads that cannot be placed on the
+/// transcript, are written unmapped, with flag 4. Reads of at least k bases
+/// carry their `kmer_score`: `ZK` holds the matched k-mers, `ZV` the valid
+/// ones and `ZR` their ratio, if there are any.
+pub fn write_sam<K, W, I>(mut writer: W, reads: I, index: &Pseudoaligner<K>) -> Result<usize, Error>
+where
+    K: Kmer + Sync + Send,
+    W: Write,
+    I: Iterator<Item = (String, Vec<u8>, Option<Vec<u8>>)>,
+{
+    if index.tx_lengths.len() != index.tx_names.len() {
+        return Err(failure::err_msg("The index does not record transcript lengths, which SAM output needs"));
+    }
+
+    writeln!(writer, "@HD\tVN:1.6\tSO:unsorted")?;
+    for (tx_name, tx_length) in index.tx_names.iter().zip(&index.tx_lengths) {
+        writeln!(writer, "@SQ\tSN:{}\tLN:{}", tx_name, tx_length)?;
+    }
+    writeln!(writer, "@PG\tID:pseudoaligner\tPN:pseudoaligner\tVN:{}", env!("CARGO_PKG_VERSION"))?;
+
+    let mut num_mapped = 0;
+    for (read_id, bases, quals) in reads {
+        let (outcome, _, score) = index.map_read_bases_scored(&bases, 1, 0);
+        let score_tags = if bases.len() >= K::k() {
+            let mut tags = format!("\tZK:i:{}\tZV:i:{}", score.matched_kmers, score.valid_kmers);
+            if let Some(ratio) = score.ratio() {
+                tags.push_str(&format!("\tZR:f:{:.4}", ratio));
+            }
+            tags
+        } else {
+            String::new()
+        };
+        let eq_class = outcome.mapped_eq_class();
+        let read_seq = DnaString::from_acgt_bytes(&bases);
+        // place reads from the reverse strand of an unstranded index by their reverse complement
+        let reverse = eq_class.is_some() && index.is_reverse_strand(&read_seq);
+        let placed_seq = if reverse { read_seq.rc() } else { read_seq };
+        let start = eq_class.as_ref().and_then(|eq_class| index.read_tx_position(&placed_seq, eq_class[0]));
+        let (eq_class, start) = match (eq_class, start) {
+            (Some(eq_class), Some(start)) => (eq_class, start),
+            // reads that cannot be placed on their transcript are written unmapped
+            _ => {
+                let quals = quals.map_or_else(|| "*".to_string(), |quals| String::from_utf8_lossy(&quals).into_owned());
+                let bases = String::from_utf8_lossy(&bases);
+                writeln!(writer, "{}\t4\t*\t0\t0\t*\t*\t0\t0\t{}\t{}{}", read_id, bases, quals, score_tags)?;
+                continue;
+            }
+        };
+        num_mapped += 1;
+
+        let (flag, bases, quals) = if reverse {
+            (16, dna::revcomp(&bases), quals.map(|quals| quals.into_iter().rev().collect::<Vec<u8>>()))
+        } else {
+            (0, bases, quals)
+        };
+
+        let tx_id = eq_class[0];
+        let read_length = bases.len() as i64;
+        let tx_length = index.tx_lengths[tx_id as usize] as i64;
+        let left_clip = std::cmp::max(0, -start);
+        let right_clip = std::cmp::max(0, start + read_length - tx_length);
+        let mut cigar = String::new();
+        if left_clip > 0 {
+            cigar.push_str(&format!("{}S", left_clip));
+        }
+        cigar.push_str(&format!("{}M", read_length - left_clip - right_clip));
+        if right_clip > 0 {
+            cigar.push_str(&format!("{}S", right_clip));
+        }
+        let pos = std::cmp::max(start, 0) + 1;
+
+        let mapq = if eq_class.len() == 1 {
+            SAM_UNIQUE_MAPQ
+        } else {
+            (-10.0 * (1.0 - 1.0 / eq_class.len() as f64).log10()).round() as u8
+        };
+        let other_txs: Vec<&str> = eq_class[1..].iter().map(|&tx| index.tx_names[tx as usize].as_str()).collect();
+        let quals = quals.map_or_else(|| "*".to_string(), |quals| String::from_utf8_lossy(&quals).into_owned());
+
+        write!(
+            writer, "{}\t{}\t{}\t{}\t{}\t{}\t*\t0\t0\t{}\t{}\tNH:i:{}",
+            read_id, flag, index.tx_names[tx_id as usize], pos, mapq, cigar,
+            String::from_utf8_lossy(&bases), quals, eq_class.len()
+        )?;
+        if !other_txs.is_empty() {
+            write!(writer, "\tZT:Z:{}", other_txs.join(","))?;
+        }
+        writeln!(writer, "{}", score_tags)?;
+    }
+    Ok(num_mapped)
+}
+
+/// Write `records` of (read id, transcript, start, end), as from
+/// `Pseudoaligner::bed_records`, as BED lines of `chrom start end name`
+/// for genome browsers, with the transcript name as chrom and the read id
+/// as name. Coordinates are 0-based and half-open.
+pub fn write_bed<K, W, I>(mut writer: W, records: I, index: &Pseudoaligner<K>) -> Result<(), Error>
+where
+    K: Kmer + Sync + Send,
+    W: Write,
+    I: Iterator<Item = (String, u32, usize, usize)>,
+{
+    for (read_id, tx_id, start, end) in records {
+        let tx_name = index.tx_names.get(tx_id as usize).ok_or_else(|| {
+            failure::err_msg(format!("BED record of {} on transcript {} of {}", read_id, tx_id, index.tx_names.len()))
+        })?;
+        writeln!(writer, "{}\t{}\t{}\t{}", tx_name, start, end, read_id)?;
+    }
+    writer.flush()?;
+    Ok(())
+}
+
+/// Mapping result of one read: whether it mapped, that is has no
+/// `UnmappedReason`, read id, equivalence class, coverage and the read
+/// length.
+type ReadMapping = (bool, String, Vec<u32>, usize, usize);
+
+pub fn process_reads<K: Kmer + Sync + Send, P: AsRef<Path> + Debug, R: io::Read + Send>(
+    reader: fastq::Reader<R>,
+    index: &Pseudoaligner<K>,
+    outdir: P,
+) -> Result<(), Error> {
+    process_reads_with_seed(reader, index, outdir, DEFAULT_MAPPING_SEED, MAX_WORKER)
+}
+
+/// Mapping a mate with `outcome` contributes to its pair: its class and
+/// coverage, unless the class is empty.
+fn mate_mapping(outcome: MappingOutcome) -> Option<(Vec<u32>, usize)> {
+    match outcome {
+        MappingOutcome::Mapped(eq_class, coverage)
+        | MappingOutcome::Rescued(eq_class, coverage)
+        | MappingOutcome::Unmapped(_, eq_class, coverage)
+            if !eq_class.is_empty() =>
+        {
+            Some((eq_class, coverage))
+        }
+        _ => None,
+    }
+}
+
+/// Pair mapping of mates mapped to `mapping1` and `mapping2`, `None` for
+/// a mate that does not map to a non-empty class, as described by
+/// `Pseudoaligner::map_read_pair`.
+fn combine_mates(
+    mapping1: Option<(Vec<u32>, usize)>,
+    mapping2: Option<(Vec<u32>, usize)>,
+    policy: DisjointMatePolicy,
+) -> (Option<(Vec<u32>, usize)>, PairMapping) {
+    match (mapping1, mapping2) {
+        (Some((eq_class1, coverage1)), Some((eq_class2, coverage2))) => {
+            let mut shared = eq_class1.clone();
+            intersect(&mut shared, &eq_class2);
+            if !shared.is_empty() {
+                return (Some((shared, coverage1 + coverage2)), PairMapping::Intersected);
+            }
+
+            let mapping = match policy {
+                DisjointMatePolicy::Discard => (Vec::new(), coverage1 + coverage2),
+                DisjointMatePolicy::Union => {
+                    let mut union = eq_class1;
+                    union.extend(eq_class2);
+                    union.sort();
+                    union.dedup();
+                    (union, coverage1 + coverage2)
+                }
+                DisjointMatePolicy::BetterCovered if coverage1 >= coverage2 => (eq_class1, coverage1),
+                DisjointMatePolicy::BetterCovered => (eq_class2, coverage2),
+            };
+            (Some(mapping), PairMapping::Disjoint)
+        }
+        (Some(mapping), None) | (None, Some(mapping)) => (Some(mapping), PairMapping::OneMate),
+        (None, None) => (None, PairMapping::Unmapped),
+    }
+}
+
+/// Map the reads of `reader` on `num_threads` threads. A read mapping to
+/// several transcripts is assigned one of them at random, from a generator
+/// seeded by `seed` and the position of the read in the input, and the
+/// results are written in input order; so the output only depends on
+/// `seed`, not on the number of threads or their scheduling.
+pub fn process_reads_with_seed<K: Kmer + Sync + Send, P: AsRef<Path> + Debug, R: io::Read + Send>(
+    reader: fastq::Reader<R>,
+    index: &Pseudoaligner<K>,
+    outdir: P,
+    seed: u64,
+    num_threads: usize,
+) -> Result<(), Error> {
+    let options = MappingOptions { seed, num_threads, ..MappingOptions::default() };
+    let record_counts = RecordCounts::default();
+    map_records(reader.records(), index, outdir, options, MappingOutputs::default(), |record: &fastq::Record| {
+        let (outcome, strand, kmer_score) = map_fastq_record(index, record, &options, &record_counts);
+        (record.id().to_owned(), record.seq().len(), outcome, Some(strand), kmer_score)
+    })?;
+    record_counts.log(&options);
+    Ok(())
+}
+
+/// Like `process_reads_with_seed`, for the reads of the plain or gzipped
+/// FASTQ or FASTA file `path`, as read by `utils::read_records`, also
+/// writing the mappings to `outputs`. Returns the number of reads mapped
+/// to each equivalence class.
+pub fn process_fastq_with_seed<K: Kmer + Sync + Send, P: AsRef<Path> + Debug>(
+    path: &Path,
+    format: utils::ReadFormat,
+    index: &Pseudoaligner<K>,
+    outdir: P,
+    options: MappingOptions,
+    outputs: MappingOutputs,
+) -> Result<HashMap<Vec<u32>, u64>, Error> {
+    let records = utils::read_records(path, format)?;
+    let record_counts = RecordCounts::default();
+    let eq_class_counts = map_records(records, index, outdir, options, outputs, |record: &fastq::Record| {
+        let (outcome, strand, kmer_score) = map_fastq_record(index, record, &options, &record_counts);
+        (record.id().to_owned(), record.seq().len(), outcome, Some(strand), kmer_score)
+    })?;
+    record_counts.log(&options);
+    Ok(eq_class_counts)
+}
+
+/// Like `process_reads_with_seed`, for mate pairs, as read by
+/// `utils::interleaved_fastq_records` or `utils::paired_fastq_records`,
+/// whose mates are masked like single reads and combined like
+/// `map_read_pair_bases_with_policy` combines them. Pairs are reported
+/// under the id of their first mate without its `/1` suffix, and the
+/// number of pairs combined each way is logged at the end, and recorded in
+/// the `summary` of `outputs`. Pairs are written to the `read_tsv` of
+/// `outputs` with the length of both mates, and both mates of pairs that
+/// do not map to its `unmapped`.
+pub fn process_read_pairs_with_seed<K, P, I>(
+    pairs: I,
+    index: &Pseudoaligner<K>,
+    outdir: P,
+    options: MappingOptions,
+    policy: DisjointMatePolicy,
+    outputs: MappingOutputs,
+) -> Result<HashMap<Vec<u32>, u64>, Error>
+where
+    K: Kmer + Sync + Send,
+    P: AsRef<Path> + Debug,
+    I: Iterator<Item = Result<(fastq::Record, fastq::Record), Error>> + Send,
+{
+    let MappingOutputs { read_tsv, unmapped, mut summary } = outputs;
+    let outputs = MappingOutputs { read_tsv, unmapped, summary: summary.as_deref_mut() };
+    let pair_counts: [AtomicUsize; 4] = Default::default();
+    let record_counts = RecordCounts::default();
+    let eq_class_counts = map_records(pairs, index, outdir, options, outputs, |pair: &(fastq::Record, fastq::Record)| {
+        let (mate1, mate2) = pair;
+        let (outcome1, _, score1) = map_fastq_record(index, mate1, &options, &record_counts);
+        // the second mate is mapped reverse complemented, as by map_read_pair
+        let quals2: Vec<u8> = mate2.qual().iter().rev().cloned().collect();
+        let (outcome2, _, score2) =
+            map_masked_bases(index, &dna::revcomp(mate2.seq()), &quals2, &options, &record_counts);
+        let (read_data, how) = combine_mates(mate_mapping(outcome1), mate_mapping(outcome2), policy);
+        pair_counts[how as usize].fetch_add(1, atomic::Ordering::Relaxed);
+        let read_length = mate1.seq().len() + mate2.seq().len();
+        // the k-mers of both mates
+        let kmer_score = KmerScore {
+            matched_kmers: score1.matched_kmers + score2.matched_kmers,
+            valid_kmers: score1.valid_kmers + score2.valid_kmers,
+        };
+        (utils::mate_id(mate1.id()).to_owned(), read_length, index.outcome_of(read_length, read_data), None, kmer_score)
+    });
+
+    for (how, count) in PAIR_MAPPINGS.iter().zip(&pair_counts) {
+        info!("{:?}: {} pairs", how, count.load(atomic::Ordering::Relaxed));
+    }
+    record_counts.log(&options);
+    // also for interrupted runs
+    if let Some(summary) = summary {
+        summary.pairs = Some(
+            PAIR_MAPPINGS
+                .iter()
+                .zip(&pair_counts)
+                .map(|(how, count)| (how.code().to_string(), count.load(atomic::Ordering::Relaxed)))
+                .collect(),
+        );
+    }
+    eq_class_counts
+}
+
+/// How the read mapping functions spread the reads over threads.
+#[derive(Clone, Copy, Debug, Serialize)]
+pub struct MappingOptions {
+    /// Seed for assigning multi-mapping reads to a transcript, and for
+    /// subsampling reads
+    pub seed: u64,
+    /// Number of mapping threads
+    pub num_threads: usize,
+    /// Number of reads handed to a thread at a time
+    pub chunk_size: usize,
+    /// Read the bases of FASTQ reads with a quality below
+    /// `min_base_quality` as N, so their k-mers are not looked up
+    pub quality_mask: bool,
+    /// Phred quality below which `quality_mask` masks bases
+    pub min_base_quality: u8,
+    /// Only start lookups at every `kmer_stride`-th k-mer of reads and mates
+    pub kmer_stride: usize,
+    /// Look up every k-mer of the reads a strided lookup does not map or
+    /// maps to several transcripts, unless they are too short or hold too
+    /// few valid k-mers to map
+    pub rescue_full: bool,
+    /// Longest gap between matches to transcripts in common that reads and
+    /// mates count as covered, 0 for none
+    pub max_rescue_gap: usize,
+    /// Where to take the UMIs of reads from, to count the mapped reads of
+    /// every UMI and equivalence class once
+    pub umi: Option<UmiSource>,
+    /// Merge UMIs one mismatch apart
+    pub merge_umis: bool,
+    /// Fraction of the valid k-mers of a read, or of both mates, that must
+    /// be consistent with its class for the read to map, besides the
+    /// coverage threshold; 0 for any
+    pub min_kmer_ratio: f64,
+    /// Stop after mapping this many reads, or pairs
+    pub max_reads: Option<usize>,
+    /// Only map every read, or pair, with this probability, drawn in input
+    /// order from a generator seeded by `seed`
+    pub subsample_fraction: Option<f64>,
+}
+
+impl Default for MappingOptions {
+    fn default() -> MappingOptions {
+        MappingOptions {
+            seed: DEFAULT_MAPPING_SEED,
+            num_threads: MAX_WORKER,
+            chunk_size: MAPPING_CHUNK_READS,
+            quality_mask: QUALITY_MASK,
+            min_base_quality: MIN_BASE_QUALITY,
+            kmer_stride: KMER_STRIDE,
+            rescue_full: RESCUE_FULL,
+            max_rescue_gap: MAX_RESCUE_GAP,
+            umi: None,
+            merge_umis: MERGE_UMIS,
+            min_kmer_ratio: MIN_KMER_RATIO,
+            max_reads: None,
+            subsample_fraction: None,
+        }
+    }
+}
+
+/// Bases masked by their quality, reads, or mates of pairs, whose mapping
+/// that changed, and reads rescued by a full lookup after a strided one,
+/// counted across the mapping threads.
+#[derive(Default)]
+struct RecordCounts {
+    masked_bases: AtomicUsize,
+    changed_reads: AtomicUsize,
+    rescued_reads: AtomicUsize,
+}
+
+impl RecordCounts {
+    fn log(&self, options: &MappingOptions) {
+        if options.quality_mask {
+            info!(
+                "Masked {} bases below quality {}, changing whether {} reads map",
+                self.masked_bases.load(atomic::Ordering::Relaxed),
+                options.min_base_quality,
+                self.changed_reads.load(atomic::Ordering::Relaxed)
+            );
+        }
+        if options.kmer_stride > 1 && options.rescue_full {
+            info!(
+                "Looked up every k-mer again of {} reads unmapped or ambiguous at k-mer stride {}",
+                self.rescued_reads.load(atomic::Ordering::Relaxed),
+                options.kmer_stride
+            );
+        }
+    }
+}
+
+/// `MappingOutcome`, strand and `KmerScore` of `bases` at the k-mer stride
+/// and rescue gap of `options`, looking up every k-mer again when the
+/// strided lookup found too little or left the read on several transcripts,
+/// and `options` asks for a rescue.
+fn map_strided_bases<K: Kmer + Sync + Send>(
+    index: &Pseudoaligner<K>,
+    bases: &[u8],
+    options: &MappingOptions,
+    counts: &RecordCounts,
+) -> (MappingOutcome, Strand, KmerScore) {
+    let mapped = index.map_read_bases_scored(bases, options.kmer_stride, options.max_rescue_gap);
+    if options.kmer_stride <= 1 || !options.rescue_full {
+        return mapped;
+    }
+
+    let rescue = match mapped.0 {
+        MappingOutcome::Mapped(ref eq_class, _) | MappingOutcome::Rescued(ref eq_class, _) => eq_class.len() > 1,
+        _ => match mapped.0.unmapped_reason() {
+            Some(reason) => reason.is_lookup_miss() || reason == UnmappedReason::WrongStrand,
+            None => false,
+        },
+    };
+    if !rescue {
+        return mapped;
+    }
+    counts.rescued_reads.fetch_add(1, atomic::Ordering::Relaxed);
+    index.map_read_bases_scored(bases, 1, options.max_rescue_gap)
+}
+
+/// `MappingOutcome`, strand and `KmerScore` of `record` with
+/// `map_strided_bases`, with the bases below the minimum quality of
+/// `options` read as N when it asks for a quality mask. UMI bases at the
+/// start of the read are not mapped.
+fn map_fastq_record<K: Kmer + Sync + Send>(
+    index: &Pseudoaligner<K>,
+    record: &fastq::Record,
+    options: &MappingOptions,
+    counts: &RecordCounts,
+) -> (MappingOutcome, Strand, KmerScore) {
+    let umi_bases = std::cmp::min(options.umi.map_or(0, |umi| umi.read_bases()), record.seq().len());
+    let quals = record.qual().get(umi_bases..).unwrap_or(&[]);
+    map_masked_bases(index, &record.seq()[umi_bases..], quals, options, counts)
+}
+
+/// `map_fastq_record` for the bases and qualities of a read. The read is
+/// mapped once, masked, and `Pseudoaligner::masking_changed_outcome` tells
+/// whether masking changed whether it maps.
+fn map_masked_bases<K: Kmer + Sync + Send>(
+    index: &Pseudoaligner<K>,
+    bases: &[u8],
+    quals: &[u8],
+    options: &MappingOptions,
+    counts: &RecordCounts,
+) -> (MappingOutcome, Strand, KmerScore) {
+    // FASTA reads come without qualities
+    if !options.quality_mask || quals.len() != bases.len() {
+        return map_strided_bases(index, bases, options, counts);
+    }
+
+    let min_qual = PHRED_OFFSET.saturating_add(options.min_base_quality);
+    let mut masked_bases = 0;
+    let masked: Vec<u8> = bases
+        .iter()
+        .zip(quals)
+        .map(|(&base, &qual)| {
+            if qual < min_qual {
+                masked_bases += 1;
+                b'N'
+            } else {
+                base
+            }
+        })
+        .collect();
+    if masked_bases == 0 {
+        return map_strided_bases(index, bases, options, counts);
+    }
+
+    counts.masked_bases.fetch_add(masked_bases, atomic::Ordering::Relaxed);
+    let mapped = map_strided_bases(index, &masked, options, counts);
+    if index.masking_changed_outcome(bases, &masked, &mapped.0) {
+        counts.changed_reads.fetch_add(1, atomic::Ordering::Relaxed);
+    }
+    mapped
+}
+
+static STOP_MAPPING: AtomicBool = AtomicBool::new(false);
+
+/// Make the running read mapping stop reading reads. The reads already read
+/// are still mapped and written, and the summary logged, before the mapping
+/// returns an error. Only sets a flag, so it can be called from a signal
+/// handler.
+pub fn stop_mapping() {
+    STOP_MAPPING.store(true, atomic::Ordering::Relaxed);
+}
+
+/// Files the read mapping functions also write the mappings to, in input
+/// order.
+#[derive(Default)]
+pub struct MappingOutputs<'a> {
+    /// Every read as a line of TSV
+    pub read_tsv: Option<&'a mut utils::ReadTsvWriter>,
+    /// The reads that do not map, as FASTQ
+    pub unmapped: Option<&'a mut utils::UnmappedWriter>,
+    /// Summary of the run, filled in even when the mapping fails or is
+    /// stopped
+    pub summary: Option<&'a mut RunSummary>,
+}
+
+/// Records `map_records` maps, which it writes back when they do not map.
+trait ReadRecord {
+    fn write_unmapped(&self, writer: &mut utils::UnmappedWriter, reason: UnmappedReason) -> Result<(), Error>;
+
+    fn umi(&self, source: UmiSource) -> Option<&[u8]>;
+}
+
+impl ReadRecord for fastq::Record {
+    fn write_unmapped(&self, writer: &mut utils::UnmappedWriter, reason: UnmappedReason) -> Result<(), Error> {
+        writer.write_read(self, reason)
+    }
+
+    fn umi(&self, source: UmiSource) -> Option<&[u8]> {
+        source.umi(self.id(), self.seq())
+    }
+}
+
+impl ReadRecord for (fastq::Record, fastq::Record) {
+    fn write_unmapped(&self, writer: &mut utils::UnmappedWriter, reason: UnmappedReason) -> Result<(), Error> {
+        writer.write_pair(&self.0, &self.1, reason)
+    }
+
+    /// UMI of the first mate, whose name is taken without its `/1` suffix.
+    fn umi(&self, source: UmiSource) -> Option<&[u8]> {
+        source.umi(utils::mate_id(self.0.id()), self.0.seq())
+    }
+}
+
+/// Result of one read in a chunk.
+struct MappedRead<T> {
+    mapping: ReadMapping,
+    /// Why the read does not map, if it does not
+    reason: Option<UnmappedReason>,
+    detail: ReadDetail,
+    /// UMI of the read, if it maps and reads are deduplicated by UMI
+    umi: Option<Vec<u8>>,
+    /// The record, if it does not map and unmapped reads are kept
+    record: Option<T>,
+}
+
+/// Map the records of `records` with `map_record`, which gives the id,
+/// length, `MappingOutcome`, strand, if known, and `KmerScore` of a record,
+/// and print the results in input order, also writing them to `outputs`. A
+/// reader thread hands chunks of records to the mapping threads, whose
+/// results are written by the calling thread; at most
+/// `MAPPING_CHUNKS_PER_WORKER` chunks per thread are read and not yet
+/// written, so a slow writer stalls the reader instead of filling memory.
+/// Returns the number of reads mapped to each equivalence class, counted by
+/// every thread on its own and merged at the end, from which the summary is
+/// filled in; with a UMI source in `options`, the number of molecules
+/// instead. Records left out by the subsampling of `options` are read but
+/// neither mapped nor written, and the reader stops after its maximum number
+/// of reads. Mapping stops at the first record that can not be read, which
+/// is returned as the error, or after `stop_mapping`.
+fn map_records<K, P, T, E, I, F>(
+    records: I,
+    index: &Pseudoaligner<K>,
+    outdir: P,
+    options: MappingOptions,
+    outputs: MappingOutputs,
+    map_record: F,
+) -> Result<HashMap<Vec<u32>, u64>, Error>
+where
+    K: Kmer + Sync + Send,
+    P: AsRef<Path> + Debug,
+    T: ReadRecord + Send,
+    E: std::fmt::Display,
+    I: Iterator<Item = Result<T, E>> + Send,
+    F: Fn(&T) -> (String, usize, MappingOutcome, Option<Strand>, KmerScore) + Sync,
+{
+    if let Some(fraction) = options.subsample_fraction {
+        if !(fraction > 0.0 && fraction <= 1.0) {
+            return Err(failure::err_msg(format!("Can not subsample a fraction {} of the reads", fraction)));
+        }
+    }
+
+    info!("Done Reading index");
+    info!("Starting Multi-threaded Mapping");
+    info!("Output directory: {:?}", outdir);
+
+    let num_threads = std::cmp::max(options.num_threads, 1);
+    let chunk_size = std::cmp::max(options.chunk_size, 1);
+    let max_chunks = num_threads * MAPPING_CHUNKS_PER_WORKER;
+    let seed = options.seed;
+
+    // the reader takes a free chunk before reading one, which the writer
+    // gives back once it wrote it
+    let (free_chunks_tx, free_chunks_rx) = mpsc::sync_channel(max_chunks);
+    for _ in 0..max_chunks {
+        free_chunks_tx.send(()).expect("Could not send data!");
+    }
+    let (chunk_tx, chunk_rx) = mpsc::sync_channel::<(usize, Vec<(usize, T)>)>(max_chunks);
+    let chunk_rx = Arc::new(Mutex::new(chunk_rx));
+    let (result_tx, result_rx) = mpsc::sync_channel::<(usize, Vec<MappedRead<T>>)>(max_chunks);
+
+    let map_record = &map_record;
+    let read_error: Mutex<Option<String>> = Mutex::new(None);
+    let read_error = &read_error;
+    let MappingOutputs { mut read_tsv, mut unmapped, summary } = outputs;
+    let keep_unmapped = unmapped.is_some();
+
+    info!("Spawning {} threads for Mapping.\n", num_threads);
+    let start = Instant::now();
+    let mut write_error = None;
+    let (eq_class_counts, read_counter, reason_counts, rescued_reads, strand_counts, umi_counter, kmer_ratios, (interrupted, skipped_reads)) = crossbeam::scope(|scope| {
+        // tells whether the mapping was stopped, and how many records the subsampling left out
+        let reader = scope.spawn(move || {
+            // records keep their index in the input, so they are assigned alike with or without subsampling
+            let mut records = records.enumerate();
+            let mut subsample_rng = ChaCha8Rng::seed_from_u64(seed);
+            let mut kept_reads = 0;
+            let mut skipped_reads = 0;
+            let mut chunk_idx = 0;
+            loop {
+                if STOP_MAPPING.load(atomic::Ordering::Relaxed) {
+                    return (true, skipped_reads);
+                }
+                if free_chunks_rx.recv().is_err() {
+                    return (false, skipped_reads);
+                }
+
+                let mut chunk = Vec::with_capacity(chunk_size);
+                let mut done = false;
+                while chunk.len() < chunk_size {
+                    if options.max_reads.is_some_and(|max_reads| kept_reads >= max_reads) {
+                        done = true;
+                        break;
+                    }
+                    match records.next() {
+                        Some((read_idx, Ok(record))) => {
+                            if let Some(fraction) = options.subsample_fraction {
+                                if subsample_rng.gen::<f64>() >= fraction {
+                                    skipped_reads += 1;
+                                    continue;
+                                }
+                            }
+                            kept_reads += 1;
+                            chunk.push((read_idx, record));
+                        }
+                        Some((read_idx, Err(err))) => {
+                            *read_error.lock().unwrap() = Some(format!("{} (at read {})", err, read_idx + 1));
+                            done = true;
+                            break;
+                        }
+                        None => {
+                            done = true;
+                            break;
+                        }
+                    }
+                }
+
+                if !chunk.is_empty() {
+                    chunk_tx.send((chunk_idx, chunk)).expect("Could not send data!");
+                    chunk_idx += 1;
+                }
+                if done {
+                    return (false, skipped_reads);
+                }
+            }
+        });
+
+        let mut workers = Vec::with_capacity(num_threads);
+        for _ in 0..num_threads {
+            let chunk_rx = Arc::clone(&chunk_rx);
+            let result_tx = result_tx.clone();
+
+            workers.push(scope.spawn(move || {
+                let mut eq_class_counts: HashMap<Vec<u32>, u64> = HashMap::new();
+                loop {
+                    // the reader hung up once all records are read
+                    let next_chunk = chunk_rx.lock().unwrap().recv();
+                    let (chunk_idx, chunk) = match next_chunk {
+                        Ok(chunk) => chunk,
+                        Err(_) => break,
+                    };
+
+                    let mapped_reads = chunk
+                        .into_iter()
+                        .map(|(read_idx, record)| {
+                            let (read_id, read_length, outcome, strand, kmer_score) = map_record(&record);
+                            let (outcome, low_kmer_ratio) = match (outcome, kmer_score.ratio()) {
+                                (MappingOutcome::Mapped(eq_class, coverage), Some(ratio))
+                                | (MappingOutcome::Rescued(eq_class, coverage), Some(ratio))
+                                    if ratio < options.min_kmer_ratio =>
+                                {
+                                    (MappingOutcome::Unmapped(UnmappedReason::LowKmerRatio, eq_class, coverage), true)
+                                }
+                                (outcome, _) => (outcome, false),
+                            };
+                            let reason = outcome.unmapped_reason();
+                            let rescued = matches!(outcome, MappingOutcome::Rescued(..));
+                            // reads that neither map nor would reverse complemented have no strand
+                            let strand = match reason {
+                                None | Some(UnmappedReason::WrongStrand) => strand,
+                                Some(_) => None,
+                            };
+
+                            let (wrapped_read_data, assigned) = match outcome {
+                                MappingOutcome::Mapped(eq_class, coverage)
+                                | MappingOutcome::Rescued(eq_class, coverage)
+                                | MappingOutcome::Unmapped(_, eq_class, coverage) => {
+                                    let assigned = assign_transcript(&eq_class, seed, read_idx);
+                                    if !low_kmer_ratio && index.is_confident(&eq_class, coverage) {
+                                        *eq_class_counts.entry(eq_class.clone()).or_insert(0) += 1;
+                                    }
+                                    ((reason.is_none(), read_id, eq_class, coverage, read_length), assigned)
+                                }
+                                MappingOutcome::TooShort => ((false, read_id, Vec::new(), 0, read_length), None),
+                            };
+
+                            let umi = match (options.umi, reason) {
+                                (Some(source), None) => record.umi(source).map(|umi| umi.to_vec()),
+                                _ => None,
+                            };
+                            MappedRead {
+                                mapping: wrapped_read_data,
+                                reason,
+                                detail: ReadDetail { rescued, strand, kmer_score, low_kmer_ratio, assigned },
+                                umi,
+                                record: if keep_unmapped && reason.is_some() { Some(record) } else { None },
+                            }
+                        })
+                        .collect();
+
+                    result_tx.send((chunk_idx, mapped_reads)).expect("Could not send data!");
+                }
+                eq_class_counts
+            }));
+        }
+        // the results end once every worker hung up
+        drop(result_tx);
+
+        let mut read_counter: usize = 0;
+        let mut mapped_read_counter: usize = 0;
+        let mut source_counts: HashMap<String, usize> = HashMap::new();
+        let mut reason_counts: BTreeMap<UnmappedReason, usize> =
+            UNMAPPED_REASONS.iter().map(|&reason| (reason, 0)).collect();
+        let mut rescued_reads: usize = 0;
+        // mapped reads of every transcript on the forward and reverse strand
+        let mut strand_counts = vec![[0u64; 2]; index.tx_names.len()];
+        let mut umi_counter = options.umi.map(|_| UmiCounter::new(options.merge_umis));
+        let mut kmer_ratios = vec![0u64; KMER_RATIO_BINS];
+
+        // chunks arriving ahead of an earlier one wait here until it is written
+        let mut pending: BTreeMap<usize, Vec<MappedRead<T>>> = BTreeMap::new();
+        let mut next_chunk = 0;
+
+        for (chunk_idx, mapped_reads) in result_rx.iter() {
+            pending.insert(chunk_idx, mapped_reads);
+
+            while let Some(mapped_reads) = pending.remove(&next_chunk) {
+                for mapped_read in mapped_reads {
+                    let MappedRead { mapping: read_data, reason, detail, umi, record: unmapped_record } = mapped_read;
+                    let sources = index.eq_class_sources(&read_data.2);
+                    println!(
+                        "{:?}",
+                        (read_data.0, &read_data.1, &read_data.2, read_data.3, &sources, detail.assigned)
+                    );
+
+                    if let Some(tsv) = read_tsv.as_mut() {
+                        // keep receiving after a failed write, so no worker blocks
+                        let written = tsv.write_read(index, &read_data.1, read_data.4, &read_data.2, read_data.3, detail);
+                        if let Err(err) = written {
+                            write_error = Some(err);
+                            read_tsv = None;
+                        }
+                    }
+                    if let (Some(writer), Some(record), Some(reason)) = (unmapped.as_mut(), unmapped_record, reason) {
+                        if let Err(err) = record.write_unmapped(writer, reason) {
+                            write_error = Some(err);
+                            unmapped = None;
+                        }
+                    }
+                    if let Some(reason) = reason {
+                        *reason_counts.entry(reason).or_insert(0) += 1;
+                    }
+                    if detail.rescued {
+                        rescued_reads += 1;
+                    }
+                    if let Some(ratio) = detail.kmer_score.ratio() {
+                        kmer_ratios[std::cmp::min((ratio * KMER_RATIO_BINS as f64) as usize, KMER_RATIO_BINS - 1)] += 1;
+                    }
+                    if let (Some(counter), None) = (umi_counter.as_mut(), reason) {
+                        counter.add_read(umi.as_deref(), &read_data.2);
+                    }
+                    if let (Some(strand), None) = (detail.strand, reason) {
+                        for &tx_id in &read_data.2 {
+                            strand_counts[tx_id as usize][(strand == Strand::Reverse) as usize] += 1;
+                        }
+                    }
+
+                    if read_data.0 {
+                        mapped_read_counter += 1;
+                    }
+
+                    if !detail.low_kmer_ratio && index.is_confident(&read_data.2, read_data.3) && !sources.is_empty() {
+                        *source_counts.entry(sources.join("+")).or_insert(0) += 1;
+                    }
+
+                    read_counter += 1;
+                    if read_counter % 1_000_000 == 0 {
+                        let frac_mapped = mapped_read_counter as f32 * 100.0 / read_counter as f32;
+                        eprint!(
+                            "\rDone Mapping {} reads w/ Rate: {}",
+                            read_counter, frac_mapped
+                        );
+                        io::stderr().flush().expect("Could not flush stdout");
+                    }
+                }
+
+                next_chunk += 1;
+                // fails once the reader is done
+                let _ = free_chunks_tx.send(());
+            }
+        } // end-for
+
+        let mut source_counts: Vec<_> = source_counts.into_iter().collect();
+        source_counts.sort();
+        for (sources, count) in source_counts {
+            info!("{} reads mapped to transcripts from {}", count, sources);
+        }
+        for (reason, count) in &reason_counts {
+            info!("{} reads not mapped: {}", count, reason.code());
+        }
+        if options.max_rescue_gap > 0 {
+            info!("{} reads mapped by counting gaps of up to {} bases", rescued_reads, options.max_rescue_gap);
+        }
+
+        let mut eq_class_counts: HashMap<Vec<u32>, u64> = HashMap::new();
+        for worker in workers {
+            for (eq_class, count) in worker.join().expect("Mapping thread panicked") {
+                *eq_class_counts.entry(eq_class).or_insert(0) += count;
+            }
+        }
+        let reader_result = reader.join().expect("Reading thread panicked");
+        (eq_class_counts, read_counter, reason_counts, rescued_reads, strand_counts, umi_counter, kmer_ratios, reader_result)
+    }); //end crossbeam
+
+    eprintln!();
+    info!(
+        "{} of {} reads mapped to {} equivalence classes",
+        eq_class_counts.values().sum::<u64>(),
+        read_counter,
+        eq_class_counts.len()
+    );
+    if skipped_reads > 0 {
+        info!("Subsampling left out {} of {} reads", skipped_reads, read_counter + skipped_reads);
+    }
+    if options.max_reads == Some(read_counter) {
+        info!("Mapped the maximum of {} reads", read_counter);
+    }
+    let mapped_reads = eq_class_counts.values().sum();
+    // molecules rather than reads once deduplicated
+    let eq_class_counts = match umi_counter {
+        Some(ref counter) => {
+            let umi_summary = counter.summary();
+            info!(
+                "{} molecules in {} mapped reads with a UMI ({:.1}% duplicates), {} reads without a UMI",
+                umi_summary.umis,
+                umi_summary.reads,
+                umi_summary.dedup_rate * 100.0,
+                umi_summary.reads_without_umi
+            );
+            counter.eq_class_counts()
+        }
+        None => eq_class_counts,
+    };
+    if let Some(summary) = summary {
+        summary.total_reads = read_counter;
+        summary.examined_reads = read_counter + skipped_reads;
+        summary.skipped_reads = skipped_reads;
+        summary.mapped_reads = mapped_reads;
+        summary.umis = umi_counter.as_ref().map(|counter| counter.summary());
+        summary.unmapped_reads = reason_counts
+            .iter()
+            .map(|(reason, &count)| (reason.code().to_string(), count))
+            .collect();
+        summary.rescued_reads = rescued_reads;
+        summary.kmer_ratios = kmer_ratios;
+        summary.tx_strands = strand_counts
+            .iter()
+            .enumerate()
+            .filter(|&(_, &[forward, reverse])| forward + reverse > 0)
+            .map(|(tx_id, &[forward, reverse])| {
+                TranscriptStrands::new(&index.tx_names[tx_id], forward, reverse, index.stranded)
+            })
+            .collect();
+        summary.num_eq_classes = eq_class_counts.len();
+        summary.eq_class_sizes.clear();
+        for (eq_class, &count) in &eq_class_counts {
+            *summary.eq_class_sizes.entry(eq_class.len()).or_insert(0) += count;
+        }
+        summary.add_phase("mapping", start.elapsed().as_secs_f64());
+        summary.add_config("kmer_length", &K::k())?;
+        summary.add_config("coverage_threshold", &index.coverage_threshold())?;
+        summary.add_config("mapping_options", &options)?;
+    }
+    if let Some(err) = read_error.lock().unwrap().take() {
+        return Err(failure::err_msg(format!("Error in reading reads: {}", err)));
+    }
+    if let Some(err) = write_error {
+        return Err(failure::err_msg(format!("Error in writing read mappings: {}", err)));
+    }
+    if let Some(tsv) = read_tsv {
+        tsv.flush()?;
+    }
+    if let Some(writer) = unmapped {
+        writer.flush()?;
+    }
+    if interrupted {
+        STOP_MAPPING.store(false, atomic::Ordering::Relaxed);
+        return Err(failure::err_msg(format!("Mapping interrupted after {} reads", read_counter)));
+    }
+    info!("Done Mapping Reads");
+    Ok(eq_class_counts)
+}
+
+/// Transcript of `eq_class` the read at `read_idx` of the input is
+/// assigned to, at random from a generator seeded by `seed` and `read_idx`.
+pub fn assign_transcript(eq_class: &[u32], seed: u64, read_idx: usize) -> Option<u32> {
+    let mut rng = ChaCha8Rng::seed_from_u64(read_seed(seed, read_idx as u64));
+    eq_class.choose(&mut rng).cloned()
+}
+
+/// Seed of the random generator of the read at `read_idx`: the splitmix64
+/// finalizer of the base seed offset by the read index, so that neighbouring
+/// reads get unrelated streams.
+fn read_seed(seed: u64, read_idx: u64) -> u64 {
+    let mut z = seed.wrapping_add(read_idx.wrapping_add(1).wrapping_mul(0x9e37_79b9_7f4a_7c15));
+    z = (z ^ (z >> 30)).wrapping_mul(0xbf58_476d_1ce4_e5b9);
+    z = (z ^ (z >> 27)).wrapping_mul(0x94d0_49bb_1331_11eb);
+    z ^ (z >> 31)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use config::KmerType;
+    use test_utils::{build_stranded_test_index, build_test_index, build_unstranded_test_index, random_seq, TempDir};
+
+    fn dna(seq: &str) -> DnaString {
+        DnaString::from_dna_string(seq)
+    }
+
+    /// Transcripts `X + Y` and `Z + Y`, so that a graph node starts where
+    /// they join `Y`.
+    fn shared_suffix_transcripts() -> (String, String) {
+        let shared = random_seq(200, 3);
+        (random_seq(199, 1) + "A" + &shared, random_seq(199, 2) + "C" + &shared)
+    }
+
+    #[test]
+    fn intersect_keeps_only_shared_elements() {
+        let mut v1 = vec![1, 2, 3, 4, 6];
+        intersect(&mut v1, &[2, 4, 5, 6]);
+        assert_eq!(v1, vec![2, 4, 6]);
+
+        let mut v1 = vec![1, 3];
+        intersect(&mut v1, &[2, 4]);
+        assert!(v1.is_empty());
+    }
+
+    #[test]
+    fn minimizer_seeded_mapping_matches_full_mapping_on_long_exact_reads() {
+        // long transcripts joining a shared 2 kb suffix, so that reads of
+        // 4 kb run through thousands of windows and across the junction
+        let shared = random_seq(2000, 3);
+        let tx1 = random_seq(2999, 1) + "A" + &shared;
+        let tx2 = random_seq(2999, 2) + "C" + &shared;
+        let index = build_test_index(&[("tx1", &tx1), ("tx2", &tx2)]);
+
+        for &start in &[0, 333, 1000] {
+            let read_seq = dna(&tx1[start..start + 4000]);
+            let expected = index.map_read(&read_seq);
+            assert_eq!(expected, Some((vec![0], 4000)), "start {}", start);
+            for &window in &[5, 10, 20, 50] {
+                assert_eq!(index.map_read_minimizer(&read_seq, window), expected, "start {} window {}", start, window);
+            }
+        }
+    }
+
+    #[test]
+    fn left_extension_from_the_start_of_a_node_continues_on_the_previous_node() {
+        let (tx1, tx2) = shared_suffix_transcripts();
+        let index = build_test_index(&[("tx1", &tx1), ("tx2", &tx2)]);
+
+        // seed each read at the first k-mer of the shared node, which is
+        // the k-mer at offset 0 of its node, so that left extension has to
+        // move on to the tx1 node straight away
+        for start in 150..200 {
+            let read_seq = dna(&tx1[start..start + 100]);
+            let seed = 200 - start;
+            assert_eq!(index.find_kmer(&read_seq.get_kmer::<KmerType>(seed)).map(|(_, offset)| offset), Some(0));
+            assert_eq!(index.map_read_from_seeds(&read_seq, Some(&[seed])), Some((vec![0], 100)), "start {}", start);
+        }
+    }
+
+    #[test]
+    fn reads_sharing_a_umi_and_class_count_once() {
+        let (tx1, tx2) = (random_seq(300, 1), random_seq(300, 2));
+        let index = build_test_index(&[("tx1", &tx1), ("tx2", &tx2)]);
+        let read = |seq: &str, start: usize| dna(&seq[start..start + 100]);
+        let reads = vec![
+            ("AAAA".to_string(), read(&tx1, 0)),
+            ("AAAA".to_string(), read(&tx1, 0)),
+            // a duplicate need not be the same read of the molecule
+            ("AAAA".to_string(), read(&tx1, 50)),
+            ("CCCC".to_string(), read(&tx1, 0)),
+            // the same UMI on another class counts for that class too
+            ("AAAA".to_string(), read(&tx2, 100)),
+            ("GGGG".to_string(), dna(&random_seq(100, 4))),
+        ];
+
+        let counts = index.count_reads_umi(reads.into_iter());
+        assert_eq!(counts.len(), 2);
+        assert_eq!(counts[&vec![0]], 2);
+        assert_eq!(counts[&vec![1]], 1);
+    }
+
+    #[test]
+    fn a_read_and_its_reverse_complement_map_alike_unless_stranded() {
+        let (tx1, tx2) = shared_suffix_transcripts();
+        let records = [("tx1", &tx1), ("tx2", &tx2)];
+        let reads = [(dna(&tx1[50..150]), vec![0]), (dna(&tx2[250..350]), vec![0, 1])];
+
+        let stranded = build_test_index(&records);
+        assert!(stranded.stranded);
+        for (read_seq, eq_class) in &reads {
+            assert_eq!(stranded.map_read(read_seq), Some((eq_class.clone(), 100)));
+            assert_eq!(stranded.map_read(&read_seq.rc()), None);
+            assert!(!stranded.is_reverse_strand(read_seq));
+            assert!(!stranded.is_reverse_strand(&read_seq.rc()));
+        }
+
+        let unstranded = build_unstranded_test_index(&records);
+        assert!(!unstranded.stranded);
+        for (read_seq, eq_class) in &reads {
+            let forward = unstranded.map_read(read_seq);
+            assert_eq!(forward, Some((eq_class.clone(), 100)));
+            assert_eq!(unstranded.map_read(&read_seq.rc()), forward);
+            assert_eq!(unstranded.map_read_minimizer(&read_seq.rc(), 10), forward);
+            // exactly one of the two orientations is mapped reverse complemented
+            assert_ne!(unstranded.is_reverse_strand(read_seq), unstranded.is_reverse_strand(&read_seq.rc()));
+            // one canonical lookup finds a k-mer and its reverse complement on the same node
+            let kmer: KmerType = read_seq.get_kmer(0);
+            let (node_id, offset, reverse) = unstranded.find_kmer_oriented(&kmer).unwrap();
+            assert_eq!(unstranded.find_kmer_oriented(&kmer.rc()), Some((node_id, offset, !reverse)));
+            assert_eq!(unstranded.mapping_score(&read_seq.rc()), 1.0);
+        }
+    }
+
+    #[test]
+    fn iterating_eq_classes_yields_each_distinct_sorted_class_once() {
+        let (tx1, tx2) = shared_suffix_transcripts();
+        // tx3 shares the start of tx2
+        let tx3 = tx2[..150].to_string() + &random_seq(150, 4);
+        let index = build_test_index(&[("tx1", &tx1), ("tx2", &tx2), ("tx3", &tx3)]);
+
+        let eq_classes: Vec<(EqClassIdType, &[u32])> = index.eq_classes().collect();
+        let ids: Vec<EqClassIdType> = eq_classes.iter().map(|&(eq_class_id, _)| eq_class_id).collect();
+        assert_eq!(ids, (0..index.eq_classes.len() as EqClassIdType).collect::<Vec<_>>());
+        let mut classes: Vec<&[u32]> = eq_classes.iter().map(|&(_, eq_class)| eq_class).collect();
+        assert!(classes.iter().all(|eq_class| eq_class.windows(2).all(|pair| pair[0] < pair[1])));
+        classes.sort();
+        assert_eq!(classes, vec![&[0][..], &[0, 1], &[1], &[1, 2], &[2]]);
+    }
+
+    #[test]
+    fn reads_count_for_the_groups_of_their_class() {
+        let (shared_a, shared_b, cross) = (random_seq(200, 10), random_seq(200, 11), random_seq(200, 12));
+        let unique: Vec<String> = (1..=4).map(|seed| random_seq(200, seed)).collect();
+        let txs = vec![
+            ("a1", format!("{}{}{}", cross, unique[0], shared_a)),
+            ("a2", format!("{}{}", unique[1], shared_a)),
+            ("b1", format!("{}{}{}", cross, unique[2], shared_b)),
+            ("b2", format!("{}{}", unique[3], shared_b)),
+        ];
+        let mut index = build_test_index(&txs);
+        let groups = txs.iter().map(|(tx_id, _)| (tx_id.to_string(), tx_id[..1].to_string())).collect();
+        index.set_tx_groups(groups).unwrap();
+
+        let reads = vec![
+            dna(&unique[0][50..150]),
+            dna(&unique[0][80..180]),
+            dna(&shared_a[50..150]),
+            dna(&shared_b[0..100]),
+            dna(&shared_b[50..150]),
+            dna(&shared_b[100..200]),
+            dna(&cross[50..150]),
+        ];
+        let eq_class_counts = index.count_reads(reads.into_iter());
+        assert_eq!(eq_class_counts[&vec![0, 1]], 1);
+        assert_eq!(eq_class_counts[&vec![0, 2]], 1);
+
+        let group_counts = |rule| {
+            let mut counts: Vec<(String, u64)> = index.count_groups(&eq_class_counts, rule).into_iter().collect();
+            counts.sort();
+            counts
+        };
+        // the read of the class across groups counts for none, or both
+        assert_eq!(group_counts(GroupRule::All), vec![("a".to_string(), 3), ("b".to_string(), 3)]);
+        assert_eq!(group_counts(GroupRule::Any), vec![("a".to_string(), 4), ("b".to_string(), 4)]);
+
+        // the groups are stored in the index
+        let dir = TempDir::new();
+        utils::write_index(&index, dir.path().join("index")).unwrap();
+        let index: Pseudoaligner<KmerType> = utils::read_index(dir.path().join("index")).unwrap();
+        assert_eq!(index.eq_class_groups(&[0, 1, 2]), vec!["a", "b"]);
+        assert_eq!(index.tx_group(3), Some("b"));
+        assert_eq!(index.tx_group(4), None);
+    }
+
+    #[test]
+    fn prefetching_leaves_query_results_unchanged() {
+        let (tx1, tx2) = shared_suffix_transcripts();
+        let owned = build_test_index(&[("tx1", &tx1), ("tx2", &tx2)]);
+        let dir = TempDir::new();
+        utils::write_index(&owned, dir.path().join("index")).unwrap();
+        let mapped: Pseudoaligner<KmerType> =
+            utils::read_index_with_mode(dir.path().join("index"), utils::IndexLoadMode::Mmap).unwrap();
+
+        let reads: Vec<DnaString> = (0..300).step_by(25).map(|start| dna(&tx1[start..start + 100])).collect();
+        let mappings = |index: &Pseudoaligner<KmerType>| reads.iter().map(|read| index.map_read(read)).collect::<Vec<_>>();
+        let expected = mappings(&owned);
+        assert_eq!(mappings(&mapped), expected);
+
+        let len = mapped.kmer_positions.len();
+        mapped.prefetch_region(len / 3..len / 2).unwrap();
+        assert_eq!(mappings(&mapped), expected);
+        // regions are cut to the table
+        mapped.prefetch_region(len / 2..len + 100).unwrap();
+        mapped.prefetch_region(len + 1..len + 100).unwrap();
+        mapped.prefetch().unwrap();
+        assert_eq!(mappings(&mapped), expected);
+        owned.prefetch().unwrap();
+        assert_eq!(mappings(&owned), expected);
+    }
+
+    #[test]
+    fn half_overlapping_reads_score_below_full_length_ones() {
+        let (tx1, tx2) = (random_seq(300, 1), random_seq(300, 2));
+        let index = build_test_index(&[("tx1", &tx1), ("tx2", &tx2)]);
+        let k = KmerType::k();
+
+        let full = dna(&tx1[100..200]);
+        assert_eq!(index.map_read_with_score(&full), Some((vec![0], 100, 1.0)));
+        // the second half of the read is not in the index
+        let half = dna(&(tx1[250..].to_string() + &random_seq(50, 3)));
+        let (eq_class, _, score) = index.map_read_with_score(&half).unwrap();
+        assert_eq!(eq_class, vec![0]);
+        assert_eq!(score, (50 - k + 1) as f64 / (100 - k + 1) as f64);
+        // the k-mers of a chimera of both transcripts are in the index, but
+        // agree on no transcript
+        let chimera = dna(&(tx1[100..150].to_string() + &tx2[100..150]));
+        assert_eq!(index.map_read(&chimera).map(|(eq_class, _)| eq_class), Some(vec![]));
+        assert_eq!(index.mapping_score(&chimera), 0.0);
+
+        assert_eq!(index.mapping_score(&dna(&random_seq(100, 4))), 0.0);
+        assert_eq!(index.mapping_score(&dna(&tx1[..k - 1])), 0.0);
+        assert_eq!(index.mapping_score(&DnaString::new()), 0.0);
+    }
+
+    #[test]
+    fn genes_of_a_t2g_replace_those_of_the_headers() {
+        let mut index = build_test_index(&[
+            ("tx1", random_seq(300, 1)),
+            ("tx2", random_seq(300, 2)),
+            ("tx3", random_seq(300, 3)),
+        ]);
+        let to_map = |pairs: &[(&str, &str)]| -> HashMap<String, String> {
+            pairs.iter().map(|&(key, value)| (key.to_string(), value.to_string())).collect()
+        };
+        let tx_genes = to_map(&[("tx1", "gene1"), ("tx2", "gene1"), ("tx3", "gene2")]);
+        let gene_names = to_map(&[("gene1", "A"), ("gene2", "B")]);
+
+        index.set_tx_genes(tx_genes.clone(), gene_names.clone(), utils::MissingGenePolicy::Error).unwrap();
+        assert_eq!(index.gene_of(0), Some("gene1"));
+        assert_eq!(index.gene_of(1), Some("gene1"));
+        assert_eq!(index.gene_of(2), Some("gene2"));
+        assert_eq!(index.gene_of(3), None);
+        assert_eq!(index.gene_name("gene2"), Some("B"));
+
+        let partial = to_map(&[("tx1", "gene1"), ("tx3", "gene2")]);
+        let err = index.set_tx_genes(partial.clone(), gene_names.clone(), utils::MissingGenePolicy::Error).unwrap_err();
+        assert!(err.to_string().contains("tx2"), "{}", err);
+        // a failed update keeps the genes
+        assert_eq!(index.gene_of(1), Some("gene1"));
+        index.set_tx_genes(partial, gene_names, utils::MissingGenePolicy::Unknown).unwrap();
+        assert_eq!(index.gene_of(1), Some(UNKNOWN_GENE));
+        assert_eq!(index.gene_of(2), Some("gene2"));
+    }
+
+    #[test]
+    fn restricting_to_one_transcript_filters_out_the_other() {
+        let (tx1, tx2) = shared_suffix_transcripts();
+        let index = build_test_index(&[("tx1", &tx1), ("tx2", &tx2)]);
+        let allowed: HashSet<u32> = vec![1].into_iter().collect();
+
+        let shared = dna(&tx1[250..350]);
+        assert_eq!(index.map_read(&shared), Some((vec![0, 1], 100)));
+        assert_eq!(index.map_read_restricted(&shared, &allowed), Some((vec![1], 100)));
+        // a read only of the off-target transcript
+        let off_target = dna(&tx1[50..150]);
+        assert_eq!(index.map_read(&off_target), Some((vec![0], 100)));
+        assert_eq!(index.map_read_restricted(&off_target, &allowed), None);
+        assert_eq!(index.map_read_restricted(&dna(&tx2[50..150]), &allowed), Some((vec![1], 100)));
+    }
+
+    #[test]
+    fn a_modified_reference_fails_verification() {
+        let seqs = vec![dna(&random_seq(300, 1)), dna(&random_seq(300, 2))];
+        let records = vec![("tx1", seqs[0].to_string()), ("tx2", seqs[1].to_string())];
+        let index = build_test_index(&records);
+        index.verify_against(&seqs).unwrap();
+        let checksum = index.combined_tx_checksum().unwrap();
+        assert_eq!(build_test_index(&records).combined_tx_checksum(), Some(checksum));
+
+        let mut changed = records[1].1.clone().into_bytes();
+        changed[150] = if changed[150] == b'A' { b'C' } else { b'A' };
+        let changed = String::from_utf8(changed).unwrap();
+        let err = index.verify_against(&[seqs[0].clone(), dna(&changed)]).unwrap_err();
+        assert!(err.to_string().contains("tx2 (index 1)"), "{}", err);
+        assert!(index.verify_against(&seqs[..1]).is_err());
+        let changed_index = build_test_index(&[("tx1", records[0].1.clone()), ("tx2", changed)]);
+        assert_ne!(changed_index.combined_tx_checksum(), Some(checksum));
+    }
+
+    #[test]
+    fn only_unmapped_reads_are_written_as_fastq() {
+        let (tx1, tx2) = (random_seq(300, 1), random_seq(300, 2));
+        let index = build_test_index(&[("tx1", &tx1), ("tx2", &tx2)]);
+        let reads = vec![
+            ("mapped1".to_string(), dna(&tx1[0..100]), None),
+            ("unmapped1".to_string(), dna(&random_seq(50, 3)), Some(vec![b'#'; 50])),
+            ("mapped2".to_string(), dna(&tx2[100..200]), Some(vec![b'F'; 100])),
+            ("too_short".to_string(), dna(&tx1[0..20]), None),
+        ];
+
+        let mut fastq = Vec::new();
+        assert_eq!(write_unmapped(&mut fastq, reads.into_iter(), &index).unwrap(), 2);
+        let expected = format!(
+            "@unmapped1 unmapped=no_kmer_hits\n{}\n+\n{}\n@too_short unmapped=too_short\n{}\n+\n{}\n",
+            random_seq(50, 3), "#".repeat(50), &tx1[0..20], "I".repeat(20)
+        );
+        assert_eq!(String::from_utf8(fastq).unwrap(), expected);
+    }
+
+    #[test]
+    fn similarity_is_the_fraction_of_shared_kmers() {
+        let (tx1, tx2) = shared_suffix_transcripts();
+        let tx3 = random_seq(300, 4);
+        let index = build_test_index(&[("tx1", &tx1), ("tx2", &tx2), ("tx3", &tx3)]);
+
+        // the 177 k-mers of the shared 200 bases, out of 377 in each transcript
+        let jaccard = 177.0 / (377.0 + 377.0 - 177.0);
+        assert!((index.transcript_similarity(0, 1) - jaccard).abs() < 1e-12);
+        assert_eq!(index.transcript_similarity(1, 0), index.transcript_similarity(0, 1));
+        assert_eq!(index.transcript_similarity(0, 0), 1.0);
+        assert_eq!(index.transcript_similarity(0, 2), 0.0);
+    }
+
+    #[test]
+    fn a_singleton_class_is_absorbed_by_the_overlapping_abundant_one() {
+        let counts: HashMap<Vec<u32>, u64> = vec![
+            (vec![0, 1], 50),
+            (vec![2], 20),
+            (vec![1, 2, 3], 1),
+            (vec![5], 1),
+        ]
+        .into_iter()
+        .collect();
+        let collapsed = collapse_rare_classes(&counts, 2);
+
+        // [1, 2, 3] shares a transcript with both, [0, 1] being the more abundant,
+        // and [5] shares none
+        assert_eq!(collapsed.len(), 2);
+        assert_eq!(collapsed[&vec![0, 1]], 51);
+        assert_eq!(collapsed[&vec![2]], 20);
+        assert_eq!(collapse_rare_classes(&counts, 1), counts);
+    }
+
+    #[test]
+    fn a_kmer_of_the_shared_suffix_is_in_both_transcripts() {
+        let (tx1, tx2) = shared_suffix_transcripts();
+        let index = build_test_index(&[("tx1", &tx1), ("tx2", &tx2)]);
+        let k = KmerType::k();
+
+        assert_eq!(index.lookup_kmer(&tx1[300..300 + k]).unwrap(), Some(&[0, 1][..]));
+        assert_eq!(index.lookup_kmer(&tx2[10..10 + k]).unwrap(), Some(&[1][..]));
+        assert_eq!(index.lookup_kmer(&random_seq(k, 9)).unwrap(), None);
+        assert!(index.lookup_kmer(&tx1[300..300 + k - 1]).is_err());
+        let with_n = format!("N{}", &tx1[301..300 + k]);
+        assert!(index.lookup_kmer(&with_n).unwrap_err().to_string().contains(&with_n));
+    }
+
+    #[test]
+    fn read_tsv_lines_follow_the_input_order() {
+        let (tx1, tx2) = shared_suffix_transcripts();
+        let index = build_test_index(&[("tx1", &tx1), ("tx2", &tx2)]);
+        let reads = [
+            ("unique", &tx1[0..60]),
+            ("shared", &tx2[300..360]),
+            ("random", &random_seq(60, 9)[..]),
+            ("short", &tx1[0..20]),
+        ];
+        let fastq: String = reads
+            .iter()
+            .map(|(id, seq)| format!("@{}\n{}\n+\n{}\n", id, seq, "I".repeat(seq.len())))
+            .collect();
+        let dir = TempDir::new();
+        let path = dir.write("reads.fq", &fastq);
+        let tsv_path = dir.path().join("reads.tsv.gz");
+
+        let mut read_tsv = utils::ReadTsvWriter::create(&tsv_path, &index, 1).unwrap();
+        let options = MappingOptions { num_threads: 4, chunk_size: 1, ..MappingOptions::default() };
+        let outputs = MappingOutputs { read_tsv: Some(&mut read_tsv), ..MappingOutputs::default() };
+        process_fastq_with_seed(&path, utils::ReadFormat::Auto, &index, dir.path(), options, outputs).unwrap();
+        drop(read_tsv);
+
+        let mut tsv = String::new();
+        utils::open_with_gz(&tsv_path).unwrap().read_to_string(&mut tsv).unwrap();
+        // the shared read is the second of the input
+        let shared_tx = &index.tx_names[assign_transcript(&[0, 1], DEFAULT_MAPPING_SEED, 1).unwrap() as usize];
+        let expected = format!(
+            "read\tstatus\teq_class\tcoverage\tread_length\ttranscripts\tassigned\tsources\tstrand\tmatched_kmers\tvalid_kmers\tkmer_ratio\n\
+             unique\tmapped\t0\t60\t60\ttx1\ttx1\tNA\t+\t37\t37\t1.0000\n\
+             shared\tmapped\t1\t60\t60\ttx1,+1\t{}\tNA\t+\t37\t37\t1.0000\n\
+             random\tunmapped\tNA\t0\t60\tNA\tNA\tNA\tNA\t0\t37\t0.0000\n\
+             short\tunmapped\tNA\t0\t20\tNA\tNA\tNA\tNA\t0\t0\tNA\n",
+            shared_tx
+        );
+        assert_eq!(tsv, expected);
+    }
+
+    #[test]
+    fn read_tsv_classes_missing_from_the_index_get_ids_past_its_own() {
+        // tx0 has no k-mer of its own: its start is in tx1 and its end in tx2
+        let (p, q) = (random_seq(200, 1), random_seq(200, 2));
+        let tx0 = p.clone() + &q;
+        let tx1 = random_seq(100, 3) + &p + &q[..100];
+        let tx2 = q.clone() + &random_seq(100, 4);
+        let index = build_test_index(&[("tx0", &tx0), ("tx1", &tx1), ("tx2", &tx2)]);
+        assert!(index.eq_classes().all(|(_, eq_class)| eq_class != [0]));
+        let num_eq_classes = index.eq_classes().count();
+
+        let reads = [("across1", &tx0[180..320]), ("across2", &tx0[190..330]), ("unique", &tx1[0..60])];
+        let fastq: String = reads
+            .iter()
+            .map(|(id, seq)| format!("@{}\n{}\n+\n{}\n", id, seq, "I".repeat(seq.len())))
+            .collect();
+        let dir = TempDir::new();
+        let path = dir.write("reads.fq", &fastq);
+        let tsv_path = dir.path().join("reads.tsv");
+        let mut read_tsv = utils::ReadTsvWriter::create(&tsv_path, &index, 3).unwrap();
+        let outputs = MappingOutputs { read_tsv: Some(&mut read_tsv), ..MappingOutputs::default() };
+        process_fastq_with_seed(&path, utils::ReadFormat::Auto, &index, dir.path(), MappingOptions::default(), outputs)
+            .unwrap();
+        drop(read_tsv);
+
+        let tsv = std::fs::read_to_string(&tsv_path).unwrap();
+        let lines: Vec<Vec<&str>> = tsv.lines().skip(1).map(|line| line.split('\t').collect()).collect();
+        let run_id = num_eq_classes.to_string();
+        assert_eq!(lines[0][..3], ["across1", "mapped", run_id.as_str()]);
+        assert_eq!(lines[0][5], "tx0");
+        assert_eq!(lines[1][..3], ["across2", "mapped", run_id.as_str()]);
+        let unique_id: usize = lines[2][2].parse().unwrap();
+        assert!(unique_id < num_eq_classes);
+        assert_eq!(index.eq_classes().nth(unique_id).unwrap().1, [1]);
+    }
+
+    #[test]
+    fn sam_records_of_unique_shared_and_unmapped_reads() {
+        let (tx1, tx2) = shared_suffix_transcripts();
+        let index = build_test_index(&[("tx1", &tx1), ("tx2", &tx2)]);
+        let reads = vec![
+            ("unique".to_string(), tx1[50..110].as_bytes().to_vec(), Some(vec![b'F'; 60])),
+            ("shared".to_string(), tx2[300..360].as_bytes().to_vec(), None),
+            ("random".to_string(), random_seq(60, 9).into_bytes(), None),
+        ];
+        let mut sam = Vec::new();
+        assert_eq!(write_sam(&mut sam, reads.into_iter(), &index).unwrap(), 2);
+        let sam = String::from_utf8(sam).unwrap();
+
+        let (header, records): (Vec<&str>, Vec<&str>) = sam.lines().partition(|line| line.starts_with('@'));
+        assert_eq!(header[0], "@HD\tVN:1.6\tSO:unsorted");
+        assert_eq!(&header[1..3], &["@SQ\tSN:tx1\tLN:400", "@SQ\tSN:tx2\tLN:400"]);
+        let records: Vec<Vec<&str>> = records.iter().map(|line| line.split('\t').collect()).collect();
+        assert_eq!(records.len(), 3);
+        for fields in &records {
+            assert!(fields.len() >= 11, "{:?}", fields);
+            // the CIGAR spans the whole read
+            if fields[5] != "*" {
+                assert_eq!(fields[5].trim_end_matches('M').parse::<usize>().unwrap(), fields[9].len());
+            }
+        }
+
+        assert_eq!(&records[0][1..6], &["0", "tx1", "51", "255", "60M"]);
+        assert_eq!(records[0][10], "F".repeat(60));
+        assert!(records[0].contains(&"NH:i:1"));
+        // -10 log10(1 - 1/2) for a read of two transcripts
+        assert_eq!(&records[1][1..6], &["0", "tx1", "301", "3", "60M"]);
+        assert!(records[1].contains(&"NH:i:2") && records[1].contains(&"ZT:Z:tx2"));
+        assert_eq!(&records[2][1..6], &["4", "*", "0", "0", "*"]);
+    }
+
+    #[test]
+    fn sam_records_without_a_position_are_unmapped() {
+        let (tx1, tx2) = shared_suffix_transcripts();
+        let mut index = build_test_index(&[("tx1", &tx1), ("tx2", &tx2)]);
+        index.tx_node_starts.clear();
+        let reads = vec![("unique".to_string(), tx1[50..110].as_bytes().to_vec(), None)];
+        let mut sam = Vec::new();
+        assert_eq!(write_sam(&mut sam, reads.into_iter(), &index).unwrap(), 0);
+        let sam = String::from_utf8(sam).unwrap();
+        let record: Vec<&str> = sam.lines().last().unwrap().split('\t').collect();
+        assert_eq!(&record[..6], &["unique", "4", "*", "0", "0", "*"]);
+        assert_eq!(record[9], &tx1[50..110]);
+    }
+
+    #[test]
+    fn sam_reads_with_an_n_map_and_keep_it() {
+        let (tx1, tx2) = shared_suffix_transcripts();
+        let index = build_test_index(&[("tx1", &tx1), ("tx2", &tx2)]);
+        let mut bases = tx1.as_bytes()[50..110].to_vec();
+        bases[30] = b'N';
+        let reads = vec![("one_n".to_string(), bases.clone(), None)];
+        let mut sam = Vec::new();
+        assert_eq!(write_sam(&mut sam, reads.into_iter(), &index).unwrap(), 1);
+        let sam = String::from_utf8(sam).unwrap();
+        let record: Vec<&str> = sam.lines().last().unwrap().split('\t').collect();
+        assert_eq!(&record[..6], &["one_n", "0", "tx1", "51", "255", "60M"]);
+        assert_eq!(record[9].as_bytes(), &bases[..]);
+    }
+
+    #[test]
+    fn perfect_and_half_matching_reads_report_their_kmer_scores() {
+        let (tx1, tx2) = (random_seq(300, 1), random_seq(300, 2));
+        let index = build_test_index(&[("tx1", &tx1), ("tx2", &tx2)]);
+        let perfect = tx1[100..200].to_string();
+        // only the 27 k-mers of the first 50 bases are in the index
+        let half = tx1[0..50].to_string() + &random_seq(50, 9);
+        assert_eq!(index.kmer_score(perfect.as_bytes()), KmerScore { matched_kmers: 77, valid_kmers: 77 });
+        assert_eq!(index.kmer_score(half.as_bytes()), KmerScore { matched_kmers: 27, valid_kmers: 77 });
+
+        let reads = vec![
+            ("perfect".to_string(), perfect.as_bytes().to_vec(), None),
+            ("half".to_string(), half.as_bytes().to_vec(), None),
+        ];
+        let mut sam = Vec::new();
+        write_sam(&mut sam, reads.into_iter(), &index).unwrap();
+        let sam = String::from_utf8(sam).unwrap();
+        let records: Vec<&str> = sam.lines().filter(|line| !line.starts_with('@')).collect();
+        assert!(records[0].ends_with("\tZK:i:77\tZV:i:77\tZR:f:1.0000"), "{}", records[0]);
+        assert!(records[1].ends_with("\tZK:i:27\tZV:i:77\tZR:f:0.3506"), "{}", records[1]);
+
+        let fastq = format!("@perfect\n{}\n+\n{}\n@half\n{}\n+\n{}\n", perfect, "I".repeat(100), half, "I".repeat(100));
+        let dir = TempDir::new();
+        let path = dir.write("reads.fq", &fastq);
+        let tsv_path = dir.path().join("reads.tsv");
+        for &min_kmer_ratio in &[0.0, 0.5] {
+            let mut read_tsv = utils::ReadTsvWriter::create(&tsv_path, &index, 1).unwrap();
+            let mut summary = RunSummary::default();
+            let outputs = MappingOutputs { read_tsv: Some(&mut read_tsv), summary: Some(&mut summary), unmapped: None };
+            let options = MappingOptions { min_kmer_ratio, ..MappingOptions::default() };
+            process_fastq_with_seed(&path, utils::ReadFormat::Auto, &index, dir.path(), options, outputs).unwrap();
+            drop(read_tsv);
+
+            let tsv = std::fs::read_to_string(&tsv_path).unwrap();
+            let lines: Vec<Vec<&str>> = tsv.lines().skip(1).map(|line| line.split('\t').collect()).collect();
+            assert_eq!(lines[0][..2], ["perfect", "mapped"]);
+            assert_eq!(lines[0][9..], ["77", "77", "1.0000"]);
+            assert_eq!(lines[1][9..], ["27", "77", "0.3506"]);
+            assert_eq!(summary.kmer_ratios, vec![0, 0, 0, 1, 0, 0, 0, 0, 0, 1]);
+            // the ratio filter alone unmaps the half read, which has the coverage
+            if min_kmer_ratio > 0.0 {
+                assert_eq!(lines[1][..2], ["half", "unmapped"]);
+                assert_eq!((summary.mapped_reads, summary.unmapped_reads["low_kmer_ratio"]), (1, 1));
+            } else {
+                assert_eq!(lines[1][..2], ["half", "mapped"]);
+                assert_eq!((summary.mapped_reads, summary.unmapped_reads["low_kmer_ratio"]), (2, 0));
+            }
+        }
+    }
+
+    #[cfg(feature = "bam")]
+    #[test]
+    fn sam_output_parses_with_htslib() {
+        use rust_htslib::bam::{self, Read};
+        use std::fs::File;
+
+        let (tx1, tx2) = shared_suffix_transcripts();
+        let index = build_test_index(&[("tx1", &tx1), ("tx2", &tx2)]);
+        let reads = vec![
+            ("unique".to_string(), tx1[50..110].as_bytes().to_vec(), None),
+            ("random".to_string(), random_seq(60, 9).into_bytes(), None),
+        ];
+        let dir = TempDir::new();
+        let path = dir.path().join("reads.sam");
+        write_sam(File::create(&path).unwrap(), reads.into_iter(), &index).unwrap();
+
+        let mut reader = bam::Reader::from_path(&path).unwrap();
+        let records: Vec<bam::Record> = reader.records().map(|record| record.unwrap()).collect();
+        assert_eq!(records.len(), 2);
+        assert_eq!((records[0].tid(), records[0].pos(), records[0].mapq()), (0, 50, 255));
+        assert!(records[1].is_unmapped());
+    }
+
+    #[test]
+    fn unmapped_reads_and_pairs_are_written_with_their_reason() {
+        let (tx1, tx2) = (random_seq(300, 1), random_seq(300, 2));
+        let index = build_test_index(&[("tx1", &tx1), ("tx2", &tx2)]);
+        let fastq_record = |header: &str, seq: &str| format!("@{}\n{}\n+\n{}\n", header, seq, "F".repeat(seq.len()));
+        // 26 bases of tx1 cover too few bases to map
+        let partial = tx1[0..26].to_string() + &random_seq(34, 8);
+        let reads = [
+            ("mapped", &tx1[0..60]),
+            ("random", &random_seq(60, 9)[..]),
+            ("short", &tx2[0..20]),
+            ("partial", &partial[..]),
+        ];
+        let fastq: String = reads.iter().map(|(id, seq)| fastq_record(&format!("{} from test", id), seq)).collect();
+        let dir = TempDir::new();
+        let path = dir.write("reads.fq", &fastq);
+        let read_gz = |path: &Path| {
+            let mut contents = String::new();
+            utils::open_with_gz(path).unwrap().read_to_string(&mut contents).unwrap();
+            contents
+        };
+
+        let unmapped_path = dir.path().join("unmapped.fq.gz");
+        let mut unmapped = utils::UnmappedWriter::create(&unmapped_path).unwrap();
+        let outputs = MappingOutputs { unmapped: Some(&mut unmapped), ..MappingOutputs::default() };
+        process_fastq_with_seed(&path, utils::ReadFormat::Auto, &index, dir.path(), MappingOptions::default(), outputs)
+            .unwrap();
+        drop(unmapped);
+        let expected = fastq_record("random from test unmapped=no_kmer_hits", reads[1].1)
+            + &fastq_record("short from test unmapped=too_short", reads[2].1)
+            + &fastq_record("partial from test unmapped=low_coverage", reads[3].1);
+        assert_eq!(read_gz(&unmapped_path), expected);
+
+        let pairs = [
+            ("pair1", tx1[0..60].to_string(), tx1[200..260].to_string()),
+            ("pair2", random_seq(60, 10), random_seq(60, 11)),
+        ];
+        let records = pairs.iter().map(|(id, seq1, seq2)| {
+            let qual = vec![b'F'; 60];
+            Ok((
+                fastq::Record::with_attrs(id, None, seq1.as_bytes(), &qual),
+                fastq::Record::with_attrs(id, None, seq2.as_bytes(), &qual),
+            ))
+        });
+        let (path1, path2) = (dir.path().join("unmapped_1.fq"), dir.path().join("unmapped_2.fq"));
+        let mut unmapped = utils::UnmappedWriter::create_paired(&path1, &path2).unwrap();
+        let outputs = MappingOutputs { unmapped: Some(&mut unmapped), ..MappingOutputs::default() };
+        let policy = DisjointMatePolicy::Discard;
+        process_read_pairs_with_seed(records, &index, dir.path(), MappingOptions::default(), policy, outputs).unwrap();
+        drop(unmapped);
+        assert_eq!(read_gz(&path1), fastq_record("pair2 unmapped=no_kmer_hits", &pairs[1].1));
+        assert_eq!(read_gz(&path2), fastq_record("pair2 unmapped=no_kmer_hits", &pairs[1].2));
+    }
+
+    #[test]
+    fn run_summary_json_counts_the_reads_of_a_small_fixture() {
+        let (tx1, tx2) = shared_suffix_transcripts();
+        let index = build_test_index(&[("tx1", &tx1), ("tx2", &tx2)]);
+        let partial = tx1[0..26].to_string() + &random_seq(34, 8);
+        let reads = [
+            &tx1[0..60],
+            &tx1[50..110],
+            &tx2[0..60],
+            &tx1[250..310],
+            &tx2[300..360],
+            &tx1[0..20],
+            &random_seq(60, 9)[..],
+            &partial[..],
+        ];
+        let fastq: String = reads
+            .iter()
+            .enumerate()
+            .map(|(read, seq)| format!("@read{}\n{}\n+\n{}\n", read, seq, "I".repeat(seq.len())))
+            .collect();
+        let dir = TempDir::new();
+        let path = dir.write("reads.fq", &fastq);
+
+        let mut summary = RunSummary::default();
+        let outputs = MappingOutputs { summary: Some(&mut summary), ..MappingOutputs::default() };
+        process_fastq_with_seed(&path, utils::ReadFormat::Auto, &index, dir.path(), MappingOptions::default(), outputs)
+            .unwrap();
+        let json: serde_json::Value = serde_json::from_str(&summary.to_json().unwrap()).unwrap();
+        assert_eq!(json["total_reads"], 8);
+        assert_eq!(json["mapped_reads"], 5);
+        assert_eq!(json["unmapped_reads"]["too_short"], 1);
+        assert_eq!(json["unmapped_reads"]["no_kmer_hits"], 1);
+        assert_eq!(json["unmapped_reads"]["low_coverage"], 1);
+        assert_eq!(json["unmapped_reads"]["empty_class"], 0);
+        assert_eq!(json["pairs"], serde_json::Value::Null);
+        assert_eq!(json["num_eq_classes"], 3);
+        assert_eq!(json["eq_class_sizes"], serde_json::json!({"1": 3, "2": 2}));
+        assert!(json["phase_seconds"]["mapping"].as_f64().unwrap() >= 0.0);
+        assert_eq!(json["config"]["kmer_length"], KmerType::k());
+        assert_eq!(json["config"]["coverage_threshold"], READ_COVERAGE_THRESHOLD);
+        assert_eq!(json["config"]["mapping_options"]["seed"], MappingOptions::default().seed);
+
+        // the second mates are read off the reverse strand
+        let mate2 = |seq: &str| dna(seq).rc().to_string();
+        let pairs = [
+            (tx1[0..60].to_string(), mate2(&tx1[100..160])),
+            (tx1[0..60].to_string(), mate2(&tx2[0..60])),
+            (tx2[0..60].to_string(), random_seq(60, 10)),
+            (random_seq(60, 11), random_seq(60, 12)),
+        ];
+        let records = pairs.iter().enumerate().map(|(pair, (seq1, seq2))| {
+            let (id, qual) = (format!("pair{}", pair), vec![b'I'; 60]);
+            Ok((
+                fastq::Record::with_attrs(&id, None, seq1.as_bytes(), &qual),
+                fastq::Record::with_attrs(&id, None, seq2.as_bytes(), &qual),
+            ))
+        });
+        let mut summary = RunSummary::default();
+        let outputs = MappingOutputs { summary: Some(&mut summary), ..MappingOutputs::default() };
+        let policy = DisjointMatePolicy::Discard;
+        process_read_pairs_with_seed(records, &index, dir.path(), MappingOptions::default(), policy, outputs).unwrap();
+        let json: serde_json::Value = serde_json::from_str(&summary.to_json().unwrap()).unwrap();
+        assert_eq!(json["total_reads"], 4);
+        assert_eq!(json["mapped_reads"], 2);
+        assert_eq!(
+            json["pairs"],
+            serde_json::json!({"intersected": 1, "disjoint": 1, "one_mate": 1, "unmapped": 1})
+        );
+    }
+
+    #[test]
+    fn one_and_four_threads_count_and_order_reads_alike() {
+        let (tx1, tx2) = shared_suffix_transcripts();
+        let index = build_test_index(&[("tx1", &tx1), ("tx2", &tx2)]);
+        let fastq: String = (0..300)
+            .map(|read| {
+                let seq = match read % 3 {
+                    0 => tx1[read % 340..read % 340 + 60].to_string(),
+                    1 => tx2[read % 340..read % 340 + 60].to_string(),
+                    _ => random_seq(60, 1000 + read as u64),
+                };
+                format!("@read{}\n{}\n+\n{}\n", read, seq, "I".repeat(60))
+            })
+            .collect();
+        let dir = TempDir::new();
+        let path = dir.write("reads.fq", &fastq);
+
+        let run = |num_threads: usize, chunk_size: usize| {
+            let tsv_path = dir.path().join(format!("reads-{}.tsv", num_threads));
+            let mut read_tsv = utils::ReadTsvWriter::create(&tsv_path, &index, 2).unwrap();
+            let mut summary = RunSummary::default();
+            let options = MappingOptions { num_threads, chunk_size, ..MappingOptions::default() };
+            let outputs = MappingOutputs { read_tsv: Some(&mut read_tsv), summary: Some(&mut summary), unmapped: None };
+            let counts =
+                process_fastq_with_seed(&path, utils::ReadFormat::Auto, &index, dir.path(), options, outputs).unwrap();
+            drop(read_tsv);
+            (counts, summary, std::fs::read_to_string(tsv_path).unwrap())
+        };
+
+        let (counts, summary, tsv) = run(1, 1);
+        assert_eq!(counts.values().sum::<u64>(), 200);
+        assert_eq!(summary.mapped_reads, 200);
+        let read_ids: Vec<&str> = tsv.lines().skip(1).map(|line| line.split('\t').next().unwrap()).collect();
+        assert_eq!(read_ids, (0..300).map(|read| format!("read{}", read)).collect::<Vec<_>>());
+
+        let (parallel_counts, parallel_summary, parallel_tsv) = run(4, 7);
+        assert_eq!(parallel_counts, counts);
+        assert_eq!(parallel_summary.mapped_reads, summary.mapped_reads);
+        assert_eq!(parallel_summary.unmapped_reads, summary.unmapped_reads);
+        assert_eq!(parallel_summary.eq_class_sizes, summary.eq_class_sizes);
+        assert_eq!(parallel_tsv, tsv);
+    }
+
+    #[test]
+    fn max_reads_stops_at_its_count_and_a_seeded_fraction_repeats() {
+        let (tx1, tx2) = shared_suffix_transcripts();
+        let index = build_test_index(&[("tx1", &tx1), ("tx2", &tx2)]);
+        let seqs: Vec<String> = (0..300).map(|read| tx1[read % 340..read % 340 + 60].to_string()).collect();
+        let fastq: String = seqs
+            .iter()
+            .enumerate()
+            .map(|(read, seq)| format!("@read{}\n{}\n+\n{}\n", read, seq, "I".repeat(60)))
+            .collect();
+        let dir = TempDir::new();
+        let path = dir.write("reads.fq", &fastq);
+
+        let run = |options: MappingOptions| {
+            let tsv_path = dir.path().join("reads.tsv");
+            let mut read_tsv = utils::ReadTsvWriter::create(&tsv_path, &index, 2).unwrap();
+            let mut summary = RunSummary::default();
+            let outputs = MappingOutputs { read_tsv: Some(&mut read_tsv), summary: Some(&mut summary), unmapped: None };
+            process_fastq_with_seed(&path, utils::ReadFormat::Auto, &index, dir.path(), options, outputs).unwrap();
+            drop(read_tsv);
+            let tsv = std::fs::read_to_string(tsv_path).unwrap();
+            let read_ids: Vec<String> =
+                tsv.lines().skip(1).map(|line| line.split('\t').next().unwrap().to_string()).collect();
+            (summary, read_ids)
+        };
+
+        let options = MappingOptions { max_reads: Some(7), num_threads: 4, chunk_size: 3, ..MappingOptions::default() };
+        let (summary, read_ids) = run(options);
+        assert_eq!((summary.total_reads, summary.examined_reads, summary.skipped_reads), (7, 7, 0));
+        assert_eq!(read_ids, (0..7).map(|read| format!("read{}", read)).collect::<Vec<_>>());
+
+        // a pair counts as one read
+        let pairs = seqs.iter().enumerate().map(|(read, seq)| {
+            let id = format!("read{}", read);
+            let qual = vec![b'I'; 60];
+            Ok((
+                fastq::Record::with_attrs(&id, None, seq.as_bytes(), &qual),
+                fastq::Record::with_attrs(&id, None, seq.as_bytes(), &qual),
+            ))
+        });
+        let mut summary = RunSummary::default();
+        let outputs = MappingOutputs { summary: Some(&mut summary), ..MappingOutputs::default() };
+        let options = MappingOptions { max_reads: Some(5), ..MappingOptions::default() };
+        process_read_pairs_with_seed(pairs, &index, dir.path(), options, DisjointMatePolicy::Discard, outputs).unwrap();
+        assert_eq!((summary.total_reads, summary.examined_reads), (5, 5));
+
+        let subsample = |seed: u64, num_threads: usize, chunk_size: usize| {
+            run(MappingOptions {
+                subsample_fraction: Some(0.3),
+                seed,
+                num_threads,
+                chunk_size,
+                ..MappingOptions::default()
+            })
+        };
+        let (summary, read_ids) = subsample(5, 1, 1);
+        assert_eq!(summary.examined_reads, 300);
+        assert_eq!(summary.total_reads + summary.skipped_reads, 300);
+        assert_eq!(read_ids.len(), summary.total_reads);
+        assert!(summary.total_reads > 50 && summary.total_reads < 150, "{}", summary.total_reads);
+        let (parallel_summary, parallel_ids) = subsample(5, 4, 7);
+        assert_eq!(parallel_summary.total_reads, summary.total_reads);
+        assert_eq!(parallel_summary.skipped_reads, summary.skipped_reads);
+        assert_eq!(parallel_ids, read_ids);
+        assert_ne!(subsample(6, 4, 7).1, read_ids);
+
+        let err = process_fastq_with_seed(
+            &path,
+            utils::ReadFormat::Auto,
+            &index,
+            dir.path(),
+            MappingOptions { subsample_fraction: Some(0.0), ..MappingOptions::default() },
+            MappingOutputs::default(),
+        )
+        .unwrap_err();
+        assert_eq!(err.to_string(), "Can not subsample a fraction 0 of the reads");
+    }
+
+    #[test]
+    fn nearly_identical_transcripts_confuse_most_reads() {
+        // tx2 is tx1 with one substitution in the middle
+        let tx1 = random_seq(400, 1);
+        let substituted = if &tx1[200..201] == "A" { "C" } else { "A" };
+        let tx2 = tx1[..200].to_string() + substituted + &tx1[201..];
+        let index = build_test_index(&[("tx1", &tx1), ("tx2", &tx2), ("tx3", &random_seq(400, 3))]);
+
+        let matrix = index.confusion_matrix(1000, 5).unwrap();
+        assert_eq!(matrix.len(), 3);
+        for (tx, row) in matrix.iter().enumerate() {
+            assert_eq!(row[tx], 1000);
+        }
+        assert!(matrix[0][1] > 500 && matrix[1][0] > 500, "{:?}", matrix);
+        assert!(matrix[0][1] < 1000 && matrix[1][0] < 1000, "{:?}", matrix);
+        assert_eq!((matrix[0][2], matrix[2][0], matrix[2][1]), (0, 0, 0));
+        assert_eq!(index.confusion_matrix(1000, 5).unwrap(), matrix);
+    }
+
+    #[test]
+    fn confusion_matrix_needs_transcript_positions() {
+        let mut index = build_test_index(&[("tx1", &random_seq(400, 1)), ("tx2", &random_seq(400, 2))]);
+        index.tx_node_starts.clear();
+        let err = index.confusion_matrix(10, 5).unwrap_err();
+        assert!(err.to_string().contains("transcript positions"), "{}", err);
+    }
+
+    #[test]
+    fn reads_around_k_bases_are_too_short_or_have_too_few_kmers() {
+        let tx1 = random_seq(300, 1);
+        let index = build_test_index(&[("tx1", &tx1)]);
+        let k = KmerType::k();
+
+        assert_eq!(index.map_read_outcome(&dna(&tx1[0..k - 1])), MappingOutcome::TooShort);
+        for &len in &[k, k + 3] {
+            match index.map_read_outcome(&dna(&tx1[0..len])) {
+                MappingOutcome::Unmapped(UnmappedReason::TooFewKmers, eq_class, coverage) => {
+                    assert_eq!((eq_class, coverage), (vec![0], len));
+                }
+                outcome => panic!("read of {} bases: {:?}", len, outcome),
+            }
+        }
+
+        let fastq: String = [k - 1, k, k + 3, 60]
+            .iter()
+            .map(|&len| format!("@read{}\n{}\n+\n{}\n", len, &tx1[0..len], "I".repeat(len)))
+            .collect();
+        let dir = TempDir::new();
+        let path = dir.write("reads.fq", &fastq);
+        let unmapped_path = dir.path().join("unmapped.fq");
+        let mut unmapped = utils::UnmappedWriter::create(&unmapped_path).unwrap();
+        let mut summary = RunSummary::default();
+        let outputs = MappingOutputs { read_tsv: None, unmapped: Some(&mut unmapped), summary: Some(&mut summary) };
+        let counts =
+            process_fastq_with_seed(&path, utils::ReadFormat::Auto, &index, dir.path(), MappingOptions::default(), outputs)
+                .unwrap();
+        drop(unmapped);
+
+        assert_eq!(counts.values().sum::<u64>(), 1);
+        assert_eq!(summary.mapped_reads, 1);
+        assert_eq!(summary.unmapped_reads["too_short"], 1);
+        assert_eq!(summary.unmapped_reads["too_few_kmers"], 2);
+        let unmapped = std::fs::read_to_string(unmapped_path).unwrap();
+        let headers: Vec<&str> = unmapped.lines().step_by(4).collect();
+        assert_eq!(headers, vec![
+            format!("@read{} unmapped=too_short", k - 1),
+            format!("@read{} unmapped=too_few_kmers", k),
+            format!("@read{} unmapped=too_few_kmers", k + 3),
+        ]);
+    }
+
+    #[test]
+    fn umi_duplicates_of_a_run_count_once_by_name_or_read_start() {
+        let (tx1, tx2) = (random_seq(300, 1), random_seq(300, 2));
+        let index = build_test_index(&[("tx1", &tx1), ("tx2", &tx2)]);
+        // a duplicate need not be the same read of the molecule
+        let reads = [
+            ("AAAAAA", &tx1[0..60]),
+            ("AAAAAA", &tx1[20..80]),
+            ("AAAAAT", &tx1[0..60]),
+            ("CCCCCC", &tx2[0..60]),
+        ];
+        let by_name: String = reads
+            .iter()
+            .enumerate()
+            .map(|(idx, (umi, seq))| format!("@read{}_{}\n{}\n+\n{}\n", idx, umi, seq, "I".repeat(seq.len())))
+            .collect();
+        let at_start: String = reads
+            .iter()
+            .enumerate()
+            .map(|(idx, (umi, seq))| format!("@read{}\n{}{}\n+\n{}\n", idx, umi, seq, "I".repeat(umi.len() + seq.len())))
+            .collect();
+        let dir = TempDir::new();
+
+        for &(fastq, umi) in &[(&by_name, UmiSource::ReadName('_')), (&at_start, UmiSource::ReadStart(6))] {
+            let path = dir.write("reads.fq", fastq);
+            for &merge_umis in &[false, true] {
+                let options = MappingOptions { umi: Some(umi), merge_umis, ..MappingOptions::default() };
+                let mut summary = RunSummary::default();
+                let outputs = MappingOutputs { summary: Some(&mut summary), ..MappingOutputs::default() };
+                let counts =
+                    process_fastq_with_seed(&path, utils::ReadFormat::Auto, &index, dir.path(), options, outputs).unwrap();
+
+                // AAAAAT is one read against the two of AAAAAA
+                let tx1_molecules = if merge_umis { 1 } else { 2 };
+                assert_eq!(counts, vec![(vec![0], tx1_molecules), (vec![1], 1)].into_iter().collect::<HashMap<_, _>>());
+                let umis = summary.umis.unwrap();
+                assert_eq!((umis.reads, umis.reads_without_umi, umis.umis), (4, 0, tx1_molecules + 1), "{:?}", umi);
+            }
+        }
+    }
+
+    #[test]
+    fn an_interior_n_is_skipped_but_n_riddled_reads_have_too_few_valid_kmers() {
+        let tx1 = random_seq(300, 1);
+        let index = build_test_index(&[("tx1", &tx1)]);
+        let mut one_n = tx1.as_bytes()[0..100].to_vec();
+        one_n[50] = b'N';
+        let riddled: Vec<u8> = tx1[0..100]
+            .bytes()
+            .enumerate()
+            .map(|(pos, base)| if pos % 10 == 9 { b'N' } else { base })
+            .collect();
+
+        match index.map_read_bases_outcome(&one_n) {
+            MappingOutcome::Mapped(eq_class, coverage) => {
+                assert_eq!(eq_class, vec![0]);
+                assert!(coverage >= 99, "{}", coverage);
+            }
+            outcome => panic!("{:?}", outcome),
+        }
+        assert_eq!(
+            index.map_read_bases_outcome(&riddled),
+            MappingOutcome::Unmapped(UnmappedReason::TooFewValidKmers, Vec::new(), 0)
+        );
+
+        let fastq = format!(
+            "@one_n\n{}\n+\n{}\n@riddled\n{}\n+\n{}\n",
+            str::from_utf8(&one_n).unwrap(), "I".repeat(100), str::from_utf8(&riddled).unwrap(), "I".repeat(100)
+        );
+        let dir = TempDir::new();
+        let path = dir.write("reads.fq", &fastq);
+        let unmapped_path = dir.path().join("unmapped.fq");
+        let mut unmapped = utils::UnmappedWriter::create(&unmapped_path).unwrap();
+        let mut summary = RunSummary::default();
+        let outputs = MappingOutputs { read_tsv: None, unmapped: Some(&mut unmapped), summary: Some(&mut summary) };
+        let counts =
+            process_fastq_with_seed(&path, utils::ReadFormat::Auto, &index, dir.path(), MappingOptions::default(), outputs)
+                .unwrap();
+        drop(unmapped);
+
+        assert_eq!(counts.values().sum::<u64>(), 1);
+        assert_eq!(summary.mapped_reads, 1);
+        assert_eq!(summary.unmapped_reads["too_few_valid_kmers"], 1);
+        let unmapped = std::fs::read_to_string(unmapped_path).unwrap();
+        assert_eq!(unmapped.lines().next(), Some("@riddled unmapped=too_few_valid_kmers"));
+    }
+
+    #[test]
+    fn a_read_with_errors_in_its_low_quality_tail_maps_only_when_masked() {
+        let (tx1, tx2) = (random_seq(300, 1), random_seq(300, 2));
+        let index = build_test_index(&[("tx1", &tx1), ("tx2", &tx2)]);
+        // the errors of the tail spell k-mers of tx2, which empty the class of the read
+        let seq = tx1[0..40].to_string() + &tx2[150..210];
+        let qual = "I".repeat(40) + &"#".repeat(60);
+        let dir = TempDir::new();
+        let path = dir.write("reads.fq", &format!("@read\n{}\n+\n{}\n", seq, qual));
+        let unmapped_path = dir.path().join("unmapped.fq");
+
+        let map = |quality_mask: bool| {
+            let options = MappingOptions { quality_mask, ..MappingOptions::default() };
+            let mut unmapped = utils::UnmappedWriter::create(&unmapped_path).unwrap();
+            let mut summary = RunSummary::default();
+            let outputs = MappingOutputs { read_tsv: None, unmapped: Some(&mut unmapped), summary: Some(&mut summary) };
+            let counts =
+                process_fastq_with_seed(&path, utils::ReadFormat::Auto, &index, dir.path(), options, outputs).unwrap();
+            drop(unmapped);
+            (counts, summary, std::fs::read_to_string(&unmapped_path).unwrap())
+        };
+        let (counts, summary, unmapped) = map(false);
+        assert!(counts.is_empty());
+        assert_eq!(summary.unmapped_reads["empty_class"], 1);
+        assert_eq!(unmapped.lines().next(), Some("@read unmapped=empty_class"));
+        let (counts, _, _) = map(true);
+        assert_eq!(counts, vec![(vec![0], 1)].into_iter().collect());
+    }
+
+    #[test]
+    fn mates_with_errors_in_their_low_quality_tails_map_only_when_masked() {
+        let (tx1, tx2) = (random_seq(300, 1), random_seq(300, 2));
+        let index = build_test_index(&[("tx1", &tx1), ("tx2", &tx2)]);
+        // as for single reads, the errors of the tails spell k-mers of tx2
+        let seq1 = tx1[0..40].to_string() + &tx2[150..210];
+        let seq2 = String::from_utf8(dna::revcomp(tx1[200..240].as_bytes())).unwrap()
+            + &String::from_utf8(dna::revcomp(tx2[50..110].as_bytes())).unwrap();
+        let qual = "I".repeat(40) + &"#".repeat(60);
+        let dir = TempDir::new();
 
-            None
+        let map = |quality_mask: bool| {
+            let records = std::iter::once(Ok((
+                fastq::Record::with_attrs("pair", None, seq1.as_bytes(), qual.as_bytes()),
+                fastq::Record::with_attrs("pair", None, seq2.as_bytes(), qual.as_bytes()),
+            )));
+            let options = MappingOptions { quality_mask, ..MappingOptions::default() };
+            let policy = DisjointMatePolicy::Discard;
+            process_read_pairs_with_seed(records, &index, dir.path(), options, policy, MappingOutputs::default()).unwrap()
         };
+        assert!(map(false).is_empty());
+        assert_eq!(map(true), vec![(vec![0], 1)].into_iter().collect());
+    }
 
-        // extract the first exact matching position of read
-        let (mut node_id, mut kmer_offset) =
-            // get the first match through mphf
-            match find_kmer_match(&mut kmer_pos) {
-                None => (None, None),
-                Some((nid, offset)) => (Some(nid), Some(offset))
-            };
+    #[test]
+    fn stride_four_maps_exact_reads_and_rescues_reads_it_misses() {
+        let (tx1, tx2) = (random_seq(300, 1), random_seq(300, 2));
+        let index = build_test_index(&[("tx1", &tx1), ("tx2", &tx2)]);
+        let exact = &tx1.as_bytes()[100..160];
+        assert!(matches!(index.map_read_bases_strided(exact, 4), MappingOutcome::Mapped(ref eq_class, _) if eq_class == &[0]));
 
-        // check if we can extend back if there were SNP in every kmer query
-        if kmer_pos >= left_extend_threshold && node_id.is_some() {
-            let mut last_pos = kmer_pos - 1;
-            let mut prev_node_id = node_id.unwrap();
-            let mut prev_kmer_offset = if kmer_offset.unwrap() > 0 { kmer_offset.unwrap() - 1 } else { 0 };
+        // errors at 0, 24 and 49 leave only the k-mer at 25 free of them,
+        // which stride 4 does not look up
+        let mut read = tx1.as_bytes()[0..60].to_vec();
+        for &pos in &[0, 24, 49] {
+            read[pos] = if read[pos] == b'A' { b'C' } else { b'A' };
+        }
+        assert_eq!(index.map_read_bases_strided(&read, 4).unmapped_reason(), Some(UnmappedReason::NoKmerHits));
+        assert!(matches!(index.map_read_bases_strided(&read, 1), MappingOutcome::Mapped(ref eq_class, _) if eq_class == &[0]));
 
-            loop {
-                let node = self.dbg.get_node(prev_node_id);
-                //println!("{:?}, {:?}, {:?}, {:?}, {:?}",
-                //         node, node.sequence(),
-                //         &eq_classes[ *node.data() as usize],
-                //         prev_kmer_offset, last_pos);
+        let strided = |rescue_full: bool| {
+            let options = MappingOptions { kmer_stride: 4, rescue_full, ..MappingOptions::default() };
+            let counts = RecordCounts::default();
+            let (outcome, _, _) = map_strided_bases(&index, &read, &options, &counts);
+            (outcome.unmapped_reason(), counts.rescued_reads.load(atomic::Ordering::Relaxed))
+        };
+        assert_eq!(strided(false), (Some(UnmappedReason::NoKmerHits), 0));
+        assert_eq!(strided(true), (None, 1));
+    }
 
-                // length of remaining read before kmer match
-                let skipped_read = last_pos + 1;
+    #[test]
+    fn stride_rescues_reads_left_on_several_transcripts() {
+        let (tx1, tx2) = shared_suffix_transcripts();
+        let index = build_test_index(&[("tx1", &tx1), ("tx2", &tx2)]);
+        let options = MappingOptions { kmer_stride: 4, rescue_full: true, ..MappingOptions::default() };
+        let counts = RecordCounts::default();
+        let (outcome, _, _) = map_strided_bases(&index, &tx1.as_bytes()[250..310], &options, &counts);
+        assert_eq!(outcome.mapped_eq_class(), Some(vec![0, 1]));
+        assert_eq!(counts.rescued_reads.load(atomic::Ordering::Relaxed), 1);
 
-                // length of the skipped node sequence before kmer match
-                let skipped_ref = prev_kmer_offset + 1;
+        let (outcome, _, _) = map_strided_bases(&index, &tx1.as_bytes()[0..60], &options, &counts);
+        assert_eq!(outcome.mapped_eq_class(), Some(vec![0]));
+        assert_eq!(counts.rescued_reads.load(atomic::Ordering::Relaxed), 1);
+    }
 
-                // find maximum extention possbile before fork or eof read
-                let max_matchable_pos = std::cmp::min(skipped_read, skipped_ref);
+    /// `seq` with its bases at `positions` changed.
+    fn with_errors_at(seq: &str, positions: &[usize]) -> Vec<u8> {
+        let mut seq = seq.as_bytes().to_vec();
+        for &pos in positions {
+            seq[pos] = if seq[pos] == b'A' { b'C' } else { b'A' };
+        }
+        seq
+    }
 
-                let ref_seq_slice = node.sequence();
-                let mut premature_break = false;
-                let mut matched_bases = 0;
-                let mut seen_snp = 0;
-                for idx in 0..max_matchable_pos {
-                    let ref_pos = prev_kmer_offset - idx;
-                    let read_offset = last_pos - idx;
+    #[test]
+    fn strided_coverage_counts_the_sampled_windows_free_of_errors() {
+        let tx1 = random_seq(300, 1);
+        let index = build_test_index(&[("tx1", &tx1)]);
+        let k = KmerType::k();
+        assert_eq!((k, index.coverage_threshold()), (24, 32));
 
-                    // compare base by base
-                    if ref_seq_slice.get(ref_pos) != read_seq.get(read_offset) {
-                        if seen_snp > 3 {
-                            premature_break = true;
-                            break;
-                        }
+        // errors at 28 and 40 leave the sampled windows at 0 and 4, two of
+        // the three stride 4 needs for the 9 windows of the threshold
+        let read = with_errors_at(&tx1[0..60], &[28, 40]);
+        assert_eq!(
+            index.map_read_bases_strided(&read, 4),
+            MappingOutcome::Unmapped(UnmappedReason::LowCoverage, vec![0], 2 * 4 + k - 1)
+        );
+        // an error at 40 only leaves those at 0, 4, 8, 12 and 16
+        let read = with_errors_at(&tx1[0..60], &[40]);
+        assert_eq!(index.map_read_bases_strided(&read, 4), MappingOutcome::Mapped(vec![0], 5 * 4 + k - 1));
+    }
 
-                        // Allowing 2-SNP
-                        seen_snp += 1;
-                    }
+    #[test]
+    fn a_substitution_between_two_sampled_hits_is_rescued_by_a_gap_of_k() {
+        let tx1 = random_seq(300, 1);
+        let index = build_test_index(&[("tx1", &tx1)]);
+        let k = KmerType::k();
 
-                    matched_bases += 1;
-                    read_coverage += 1;
-                }
+        // the error at 24 leaves only the sampled windows at 0 and 28, with
+        // the six windows missed between them spanning k bases at stride 4
+        let read = with_errors_at(&tx1[0..52], &[24]);
+        let unmapped = MappingOutcome::Unmapped(UnmappedReason::LowCoverage, vec![0], 2 * 4 + k - 1);
+        assert_eq!(index.map_read_bases_rescuing(&read, 4, 0), unmapped);
+        assert_eq!(index.map_read_bases_rescuing(&read, 4, k - 1), unmapped);
+        assert_eq!(index.map_read_bases_rescuing(&read, 4, k), MappingOutcome::Rescued(vec![0], 52));
 
-                //break the loop if end of read reached or a premature mismatch
-                if last_pos + 1 - matched_bases == 0 || premature_break {
-                    break;
-                }
+        // looking up every k-mer, the read maps without any gap
+        assert_eq!(index.map_read_bases_rescuing(&read, 1, k), MappingOutcome::Mapped(vec![0], 52));
+    }
 
-                // adjust last position
-                last_pos -= matched_bases;
+    #[test]
+    fn kmer_stride_applies_to_both_mates() {
+        let tx1 = random_seq(300, 1);
+        let index = build_test_index(&[("tx1", &tx1)]);
+        let mate1 = with_errors_at(&tx1[0..60], &[28, 40]);
+        let mate2 = dna::revcomp(&with_errors_at(&tx1[200..260], &[28, 40]));
+        let qual = vec![b'I'; 60];
+        let dir = TempDir::new();
 
-                // If reached here then a fork is found in the reference.
-                let exts = node.exts();
-                let next_base = read_seq.get(last_pos);
-                if exts.has_ext(Dir::Left, next_base) {
-                    // found a left extention.
-                    let index = exts
-                        .get(Dir::Left)
-                        .iter()
-                        .position(|&x| x == next_base)
-                        .unwrap();
+        let map = |kmer_stride: usize| {
+            let records = std::iter::once(Ok((
+                fastq::Record::with_attrs("pair", None, &mate1, &qual),
+                fastq::Record::with_attrs("pair", None, &mate2, &qual),
+            )));
+            let options = MappingOptions { kmer_stride, ..MappingOptions::default() };
+            let policy = DisjointMatePolicy::Discard;
+            process_read_pairs_with_seed(records, &index, dir.path(), options, policy, MappingOutputs::default()).unwrap()
+        };
+        assert_eq!(map(1), vec![(vec![0], 1)].into_iter().collect());
+        assert!(map(4).is_empty());
+    }
 
-                    let edge = node.l_edges()[index];
+    #[test]
+    fn a_gap_between_matches_is_bridged_only_within_a_transcript() {
+        let (tx1, tx2) = shared_suffix_transcripts();
+        let index = build_test_index(&[("tx1", &tx1), ("tx2", &tx2)]);
+        let other_base = |avoid: &[u8]| *b"ACGT".iter().find(|base| !avoid.contains(base)).unwrap();
 
-                    //update the previous node's id
-                    prev_node_id = edge.0;
-                    let prev_node = self.dbg.get_node(prev_node_id);
-                    prev_kmer_offset = prev_node.sequence().len() - kmer_length;
+        // tx1[..223] is the node of tx1 alone, which ends where the shared
+        // node goes on; the substitution of the base after it leaves a gap
+        // of one k-mer before the shared node is found again
+        let mut read = tx1.as_bytes()[150..250].to_vec();
+        read[73] = other_base(&[read[73]]);
+        assert_eq!(index.map_read_bases_rescuing(&read, 1, 0), MappingOutcome::Mapped(vec![0], 99));
+        assert_eq!(index.map_read_bases_rescuing(&read, 1, 1), MappingOutcome::Mapped(vec![0], 100));
 
-                    // extract colors
-                    let color = prev_node.data();
-                    colors.push(*color);
-                } else {
-                    break;
-                }
-            } // end-loop
-        } //end-if
+        // the same gap, between k-mers of tx1 alone and of tx2 alone
+        let mut chimeric = tx1.as_bytes()[150..223].to_vec();
+        chimeric.push(other_base(&[tx1.as_bytes()[223], tx2.as_bytes()[99]]));
+        chimeric.extend_from_slice(&tx2.as_bytes()[100..150]);
+        let unmapped = MappingOutcome::Unmapped(UnmappedReason::EmptyClass, Vec::new(), 123);
+        assert_eq!(index.map_read_bases_rescuing(&chimeric, 1, 0), unmapped);
+        assert_eq!(index.map_read_bases_rescuing(&chimeric, 1, 1), unmapped);
+    }
 
-        // forward search
-        if kmer_pos <= last_kmer_pos {
-            loop {
-                let node = self.dbg.get_node(node_id.unwrap());
-                //println!("{:?}, {:?}, {:?}, {:?}",
-                //         node, node.sequence(),
-                //         &eq_classes[ *node.data() as usize],
-                //         kmer_offset);
-                kmer_pos += kmer_length;
-                read_coverage += kmer_length;
+    #[test]
+    fn a_two_member_class_resolves_to_its_names_and_genes() {
+        let (tx1, tx2) = shared_suffix_transcripts();
+        let mut index = build_test_index(&[("tx1", &tx1), ("tx2", &tx2)]);
+        index.tx_gene_mapping.remove("tx2");
+        let (eq_class, _) = index.map_read(&dna(&tx1[300..360])).unwrap();
+        assert_eq!(eq_class, vec![0, 1]);
+        assert_eq!(index.resolve_eq_class(&eq_class).unwrap(), vec![("tx1", Some("Gtx1")), ("tx2", None)]);
+        assert_eq!(index.resolve_eq_class(&[1]).unwrap(), vec![("tx2", None)]);
 
-                // extract colors
-                let color = node.data();
-                colors.push(*color);
+        let err = index.resolve_eq_class(&[0, 2]).unwrap_err();
+        assert_eq!(err.to_string(), "Transcript 2 is not in the index of 2 transcripts");
+    }
 
-                // length of remaining read after kmer match
-                let remaining_read = read_length - kmer_pos;
+    #[test]
+    fn named_reads_come_back_with_their_own_mappings() {
+        let (tx1, tx2) = shared_suffix_transcripts();
+        let index = build_test_index(&[("tx1", &tx1), ("tx2", &tx2)]);
+        let reads = vec![
+            ("unique2".to_string(), dna(&tx2[50..110])),
+            ("short".to_string(), dna(&tx1[0..10])),
+            ("shared".to_string(), dna(&tx1[300..360])),
+            ("random".to_string(), dna(&random_seq(60, 9))),
+            ("unique1".to_string(), dna(&tx1[50..110])),
+        ];
+        let expected: Vec<(String, Option<(Vec<u32>, usize)>)> = reads
+            .iter()
+            .map(|(id, seq)| (id.clone(), if seq.len() < KmerType::k() { None } else { index.map_read(seq) }))
+            .collect();
 
-                // length of the remaining node sequence after kmer match
-                let ref_seq_slice = node.sequence();
-                let ref_length = ref_seq_slice.len();
-                let ref_offset = kmer_offset.unwrap() + kmer_length;
-                let informative_ref = ref_length - ref_offset;
+        let mapped: Vec<(String, Option<(Vec<u32>, usize)>)> = index.map_named_reads(reads.into_iter()).collect();
+        assert_eq!(mapped, expected);
+        let eq_classes: Vec<(&str, Option<&[u32]>)> = mapped
+            .iter()
+            .map(|(id, mapping)| (id.as_str(), mapping.as_ref().map(|(eq_class, _)| &eq_class[..])))
+            .collect();
+        assert_eq!(eq_classes[0], ("unique2", Some(&[1][..])));
+        assert_eq!(eq_classes[1], ("short", None));
+        assert_eq!(eq_classes[2], ("shared", Some(&[0, 1][..])));
+        assert_eq!(eq_classes[4], ("unique1", Some(&[0][..])));
+    }
 
-                // find maximum extention possbile before fork or eof read
-                let max_matchable_pos = std::cmp::min(remaining_read, informative_ref);
+    #[test]
+    fn distinct_kmers_count_shared_kmers_once() {
+        let k = KmerType::k();
+        let index = build_test_index(&[("tx1", random_seq(35, 1)), ("tx2", random_seq(46, 2))]);
+        assert_eq!(index.n_distinct_kmers(), (35 - k + 1) + (46 - k + 1));
 
-                let mut premature_break = false;
-                let mut matched_bases = 0;
-                let mut seen_snp = 0;
-                for idx in 0..max_matchable_pos {
-                    let ref_pos = ref_offset + idx;
-                    let read_offset = kmer_pos + idx;
+        // the 200 shared bases hold 200 - k + 1 k-mers of both transcripts
+        let (tx1, tx2) = shared_suffix_transcripts();
+        let index = build_test_index(&[("tx1", &tx1), ("tx2", &tx2)]);
+        assert_eq!(index.n_distinct_kmers(), 2 * (400 - k + 1) - (200 - k + 1));
+    }
 
-                    // compare base by base
-                    if ref_seq_slice.get(ref_pos) != read_seq.get(read_offset) {
-                        if seen_snp > 3 {
-                            premature_break = true;
-                            break;
-                        }
+    #[test]
+    fn a_class_of_two_genes_splits_into_their_transcripts() {
+        let shared = random_seq(200, 3);
+        let txs = [
+            random_seq(199, 1) + "A" + &shared,
+            random_seq(199, 2) + "C" + &shared,
+            random_seq(199, 4) + "G" + &shared,
+        ];
+        let mut index = build_test_index(&[("A*01:01", &txs[0]), ("A*02:01", &txs[1]), ("B*07:02", &txs[2])]);
+        index.tx_gene_mapping = vec![("A*01:01", "HLA-A"), ("A*02:01", "HLA-A"), ("B*07:02", "HLA-B")]
+            .into_iter()
+            .map(|(tx_name, gene)| (tx_name.to_string(), gene.to_string()))
+            .collect();
+        let (eq_class, _) = index.map_read(&dna(&txs[0][250..310])).unwrap();
+        assert_eq!(eq_class, vec![0, 1, 2]);
 
-                        // Allowing 2-SNP
-                        seen_snp += 1;
-                    }
+        let genes = index.split_by_gene(&eq_class).unwrap();
+        assert_eq!(genes.len(), 2);
+        assert_eq!(genes["HLA-A"], vec![0, 1]);
+        assert_eq!(genes["HLA-B"], vec![2]);
 
-                    matched_bases += 1;
-                    read_coverage += 1;
-                }
+        // a transcript without a gene is its own
+        index.tx_gene_mapping.remove("B*07:02");
+        let genes = index.split_by_gene(&eq_class).unwrap();
+        assert_eq!(genes["B*07:02"], vec![2]);
+        assert!(index.split_by_gene(&[0, 3]).is_err());
+    }
 
-                kmer_pos += matched_bases;
-                //break the loop if end of read reached or a premature mismatch
-                if kmer_pos >= read_length {
-                    break;
-                }
+    /// Status and strand in the read TSV of every read of `reads` mapped to
+    /// `index`, and the summary of the run.
+    fn read_strands(index: &Pseudoaligner<KmerType>, reads: &[(String, String)]) -> (Vec<(String, String)>, RunSummary) {
+        let fastq: String = reads
+            .iter()
+            .map(|(id, seq)| format!("@{}\n{}\n+\n{}\n", id, seq, "I".repeat(seq.len())))
+            .collect();
+        let dir = TempDir::new();
+        let path = dir.write("reads.fq", &fastq);
+        let tsv_path = dir.path().join("reads.tsv");
+        let mut read_tsv = utils::ReadTsvWriter::create(&tsv_path, index, 2).unwrap();
+        let mut summary = RunSummary::default();
+        let outputs = MappingOutputs { read_tsv: Some(&mut read_tsv), summary: Some(&mut summary), unmapped: None };
+        process_fastq_with_seed(&path, utils::ReadFormat::Auto, index, dir.path(), MappingOptions::default(), outputs)
+            .unwrap();
+        drop(read_tsv);
 
-                // If reached here then a fork is found in the reference.
-                let exts = node.exts();
-                let next_base = read_seq.get(kmer_pos);
+        let tsv = std::fs::read_to_string(&tsv_path).unwrap();
+        let strands = tsv
+            .lines()
+            .skip(1)
+            .map(|line| {
+                let fields: Vec<&str> = line.split('\t').collect();
+                (fields[1].to_string(), fields[8].to_string())
+            })
+            .collect();
+        (strands, summary)
+    }
 
-                if !premature_break && exts.has_ext(Dir::Right, next_base) {
-                    // found a right extention.
-                    let index = exts
-                        .get(Dir::Right)
-                        .iter()
-                        .position(|&x| x == next_base)
-                        .unwrap();
+    fn status(status: &str, strand: &str) -> (String, String) {
+        (status.to_string(), strand.to_string())
+    }
 
-                    let edge = node.r_edges()[index];
+    #[test]
+    fn a_stranded_index_reports_reverse_reads_as_on_the_wrong_strand() {
+        let (tx1, tx2) = shared_suffix_transcripts();
+        let index = build_stranded_test_index(&[("tx1", &tx1), ("tx2", &tx2)]);
+        let reads = [
+            ("forward".to_string(), tx1[0..60].to_string()),
+            ("shared".to_string(), tx1[300..360].to_string()),
+            ("reverse".to_string(), dna(&tx1[100..160]).rc().to_string()),
+            ("random".to_string(), random_seq(60, 7)),
+        ];
+        let (strands, summary) = read_strands(&index, &reads);
+        assert_eq!(
+            strands,
+            vec![status("mapped", "+"), status("mapped", "+"), status("unmapped", "-"), status("unmapped", "NA")]
+        );
+        assert_eq!(summary.unmapped_reads["wrong_strand"], 1);
+        assert_eq!(
+            summary.tx_strands,
+            vec![TranscriptStrands::new("tx1", 2, 0, true), TranscriptStrands::new("tx2", 1, 0, true)]
+        );
+        assert!(summary.tx_strands.iter().all(|strands| !strands.strand_biased));
+    }
 
-                    //update the next node's id
-                    node_id = Some(edge.0);
-                    kmer_offset = Some(0);
+    #[test]
+    fn an_unstranded_index_flags_transcripts_with_reads_of_one_strand() {
+        let (tx1, tx2) = shared_suffix_transcripts();
+        let index = build_unstranded_test_index(&[("tx1", &tx1), ("tx2", &tx2)]);
+        let mut reads = vec![
+            ("forward".to_string(), tx1[0..60].to_string()),
+            ("shared".to_string(), tx1[300..360].to_string()),
+        ];
+        // reverse reads of tx1 alone, enough to tell a bias
+        reads.extend((0..20).map(|start| (format!("reverse{}", start), dna(&tx1[start..start + 60]).rc().to_string())));
+        let (strands, summary) = read_strands(&index, &reads);
+        assert_eq!(&strands[..3], &[status("mapped", "+"), status("mapped", "+"), status("mapped", "-")]);
+        assert!(strands[3..].iter().all(|strand| *strand == status("mapped", "-")));
+        assert_eq!(
+            summary.tx_strands,
+            vec![TranscriptStrands::new("tx1", 2, 20, false), TranscriptStrands::new("tx2", 1, 0, false)]
+        );
+        assert_eq!(
+            summary.tx_strands.iter().map(|strands| strands.strand_biased).collect::<Vec<_>>(),
+            vec![true, false]
+        );
+    }
 
-                    //adjust for kmer_position
-                    kmer_pos -= kmer_length - 1;
-                    read_coverage -= kmer_length - 1;
-                } else {
-                    // can't extend node in dbg extract read using mphf
-                    // TODO: might have to check some cases
-                    if kmer_pos > last_kmer_pos {
-                        // can't search in mphf if no full kmer can be made
-                        break;
-                    }
+    #[test]
+    fn mates_at_known_positions_give_their_fragment_length() {
+        let (tx1, tx2) = shared_suffix_transcripts();
+        let tx3 = random_seq(600, 4);
+        let index = build_test_index(&[("tx1", &tx1), ("tx2", &tx2), ("tx3", &tx3)]);
+        let (mate1, mate2) = (dna(&tx3[50..150]), dna(&tx3[350..450]).rc());
 
-                    // get the match through mphf
-                    match find_kmer_match(&mut kmer_pos) {
-                        None => break,
-                        Some((nid, offset)) => {
-                            node_id = Some(nid);
-                            kmer_offset = Some(offset);
-                        }
-                    };
-                }
-            } // end-loop
-        } //end-if
+        let pairs = vec![
+            (mate1.clone(), mate2.clone()),
+            // the mates of the other strand
+            (mate2.clone(), mate1.clone()),
+            // no unique transcript: both mates are on the shared suffix
+            (dna(&tx1[220..320]), dna(&tx1[280..380]).rc()),
+            // mates of different transcripts
+            (mate1, dna(&tx1[50..150]).rc()),
+        ];
+        assert_eq!(index.fragment_lengths(&pairs), vec![400, 400]);
+    }
 
-        // Take the intersection of the sets
-        let colors_len = colors.len();
-        if colors_len == 0 {
-            if read_coverage != 0 {
-                panic!(
-                    "Different read coverage {:?} than num of eqclasses {:?}",
-                    colors_len, read_coverage
-                );
-            }
+    #[test]
+    fn bed_lines_place_a_read_on_every_transcript_of_its_class() {
+        let (tx1, tx2) = shared_suffix_transcripts();
+        let tx3 = random_seq(600, 4);
+        let index = build_test_index(&[("tx1", &tx1), ("tx2", &tx2), ("tx3", &tx3)]);
 
-            None
-        } else {
-            // Intersect the equivalence classes
-            let first_color = colors.pop().unwrap();
-            let mut eq_class = self.eq_classes[first_color as usize].clone();
+        let mut bed = Vec::new();
+        write_bed(&mut bed, index.bed_records("unique", &dna(&tx3[37..117])).into_iter(), &index).unwrap();
+        assert_eq!(String::from_utf8(bed).unwrap(), "tx3\t37\t117\tunique\n");
 
-            for color in colors {
-                intersect(&mut eq_class, &self.eq_classes[color as usize]);
-            }
+        let records = index
+            .bed_records("shared", &dna(&tx1[250..330]))
+            .into_iter()
+            .chain(index.bed_records("random", &dna(&random_seq(80, 5))));
+        let mut bed = Vec::new();
+        write_bed(&mut bed, records, &index).unwrap();
+        assert_eq!(String::from_utf8(bed).unwrap(), "tx1\t250\t330\tshared\ntx2\t250\t330\tshared\n");
 
-            Some((eq_class, read_coverage))
-        }
+        let err = write_bed(Vec::new(), vec![("read".to_string(), 3, 0, 80)].into_iter(), &index).unwrap_err();
+        assert_eq!(err.to_string(), "BED record of read on transcript 3 of 3");
     }
-}
 
-/// Compute the intersection of v1 and v2 inplace on top of v1
-/// v1 and v2 must be sorted
-fn intersect<T: Eq + Ord>(v1: &mut Vec<T>, v2: &[T]) {
-    if v1.is_empty() {
-        return;
-    }
+    #[test]
+    fn a_read_on_a_repeated_node_is_placed_at_the_occurrence_it_agrees_with() {
+        let repeat = random_seq(100, 5);
+        let (x, y, z) = (random_seq(200, 1), random_seq(200, 2), random_seq(200, 3));
+        let tx = x.clone() + &repeat + &y + &repeat + &z;
+        let index = build_test_index(&[("tx1", &tx)]);
 
-    if v2.is_empty() {
-        v1.clear();
+        // starts in the second copy of the repeat and ends past it
+        let read = dna(&tx[550..650]);
+        assert_eq!(index.read_tx_position(&read, 0), Some(550));
+        // inside the repeat, either copy fits: the leftmost is taken
+        let read = dna(&tx[520..580]);
+        assert_eq!(index.read_tx_position(&read, 0), Some(220));
     }
 
-    let mut fill_idx1 = 0;
-    let mut idx1 = 0;
-    let mut idx2 = 0;
+    #[test]
+    fn mates_of_one_transcript_map_to_it() {
+        let (tx1, tx2) = shared_suffix_transcripts();
+        let index = build_test_index(&[("tx1", &tx1), ("tx2", &tx2)]);
 
-    while idx1 < v1.len() && idx2 < v2.len() {
-        match v1[idx1].cmp(&v2[idx2]) {
-            Ordering::Less => idx1 += 1,
-            Ordering::Greater => idx2 += 1,
-            Ordering::Equal => {
-                v1.swap(fill_idx1, idx1);
-                idx1 += 1;
-                idx2 += 1;
-                fill_idx1 += 1;
-            }
+        // the first mate is on the shared suffix, the second only on tx1
+        let (mate1, mate2) = (dna(&tx1[250..350]), dna(&tx1[50..150]).rc());
+        for &policy in &[DisjointMatePolicy::Discard, DisjointMatePolicy::Union, DisjointMatePolicy::BetterCovered] {
+            assert_eq!(
+                index.map_read_pair_with_policy(&mate1, &mate2, policy),
+                (Some((vec![0], 200)), PairMapping::Intersected)
+            );
         }
     }
-}
-
-pub fn process_reads<K: Kmer + Sync + Send, P: AsRef<Path> + Debug>(
-    reader: fastq::Reader<File>,
-    index: &Pseudoaligner<K>,
-    outdir: P,
-) -> Result<(), Error> {
-    info!("Done Reading index");
-    info!("Starting Multi-threaded Mapping");
-    info!("Output directory: {:?}", outdir);
 
-    let (tx, rx) = mpsc::sync_channel(MAX_WORKER);
-    let atomic_reader = Arc::new(Mutex::new(reader.records()));
+    #[test]
+    fn mates_with_an_n_skip_its_kmers() {
+        let (tx1, tx2) = shared_suffix_transcripts();
+        let index = build_test_index(&[("tx1", &tx1), ("tx2", &tx2)]);
 
-    info!("Spawning {} threads for Mapping.\n", MAX_WORKER);
-    crossbeam::scope(|scope| {
-        for _ in 0..MAX_WORKER {
-            let tx = tx.clone();
-            let reader = Arc::clone(&atomic_reader);
+        let mut mate1 = tx1.as_bytes()[250..350].to_vec();
+        mate1[40] = b'N';
+        let mut mate2 = dna::revcomp(tx1[50..150].as_bytes());
+        mate2[60] = b'N';
+        match index.map_read_pair_bases_with_policy(&mate1, &mate2, DisjointMatePolicy::Discard) {
+            (Some((eq_class, coverage)), PairMapping::Intersected) => {
+                assert_eq!(eq_class, vec![0]);
+                assert!(coverage >= 198, "{}", coverage);
+            }
+            mapping => panic!("{:?}", mapping),
+        }
+    }
 
-            scope.spawn(move || {
-                loop {
-                    // If work is available, do that work.
-                    match utils::get_next_record(&reader) {
-                        Some(result_record) => {
-                            let record = match result_record {
-                                Ok(record) => record,
-                                Err(err) => panic!("Error {:?} in reading fastq", err),
-                            };
+    #[test]
+    fn mates_of_disjoint_classes_are_resolved_by_the_policy() {
+        let (tx1, tx2) = (random_seq(300, 1), random_seq(300, 2));
+        let index = build_test_index(&[("tx1", &tx1), ("tx2", &tx2)]);
+        // the better covered mate is the longer one, on tx2
+        let (mate1, mate2) = (dna(&tx1[0..60]), dna(&tx2[100..200]).rc());
 
-                            let dna_string = str::from_utf8(record.seq()).unwrap();
-                            let seq = DnaString::from_dna_string(dna_string);
-                            let read_data = index.map_read(&seq);
+        let pair = |policy| index.map_read_pair_with_policy(&mate1, &mate2, policy);
+        assert_eq!(pair(DisjointMatePolicy::Discard), (Some((vec![], 160)), PairMapping::Disjoint));
+        assert_eq!(pair(DisjointMatePolicy::Union), (Some((vec![0, 1], 160)), PairMapping::Disjoint));
+        assert_eq!(pair(DisjointMatePolicy::BetterCovered), (Some((vec![1], 100)), PairMapping::Disjoint));
+        assert_eq!(index.map_read_pair(&mate1, &mate2), Some((vec![], 160)));
+    }
 
-                            let wrapped_read_data = match read_data {
-                                Some((eq_class, coverage)) => {
-                                    if coverage >= READ_COVERAGE_THRESHOLD && eq_class.is_empty() {
-                                        Some((true, record.id().to_owned(), eq_class, coverage))
-                                    } else {
-                                        Some((false, record.id().to_owned(), eq_class, coverage))
-                                    }
-                                }
-                                None => Some((false, record.id().to_owned(), Vec::new(), 0)),
-                            };
+    #[test]
+    fn a_pair_with_one_mate_unmapped_maps_like_the_other() {
+        let tx1 = random_seq(300, 1);
+        let index = build_test_index(&[("tx1", &tx1)]);
+        let (mapped, unmapped) = (dna(&tx1[0..100]), dna(&random_seq(100, 9)));
 
-                            tx.send(wrapped_read_data).expect("Could not send data!");
-                        }
-                        None => {
-                            // send None to tell receiver that the queue ended
-                            tx.send(None).expect("Could not send data!");
-                            break;
-                        }
-                    }; //end-match
-                } // end loop
-            }); //end-scope
-        } // end-for
+        let pair = |mate1: &DnaString, mate2: &DnaString| {
+            index.map_read_pair_with_policy(mate1, mate2, DisjointMatePolicy::Discard)
+        };
+        assert_eq!(pair(&mapped, &unmapped), (Some((vec![0], 100)), PairMapping::OneMate));
+        assert_eq!(pair(&unmapped, &mapped.rc()), (Some((vec![0], 100)), PairMapping::OneMate));
+        // a mate shorter than k is ignored too
+        assert_eq!(pair(&mapped, &dna("ACGT")), (Some((vec![0], 100)), PairMapping::OneMate));
+        assert_eq!(pair(&unmapped, &unmapped), (None, PairMapping::Unmapped));
+    }
 
-        let mut read_counter: usize = 0;
-        let mut mapped_read_counter: usize = 0;
-        let mut dead_thread_count = 0;
+    #[test]
+    fn added_transcripts_map_to_their_new_index() {
+        let (tx1, tx2) = (random_seq(300, 1), random_seq(300, 2));
+        let mut index = build_test_index(&[("tx1", &tx1), ("tx2", &tx2)]);
+        // tx3 shares bases 100..250 with tx1
+        let tx3 = tx1[100..250].to_string() + &random_seq(150, 3);
+        index.tx_gene_mapping.insert("tx3".to_string(), "Gtx1".to_string());
 
-        for eq_class in rx.iter() {
-            match eq_class {
-                None => {
-                    dead_thread_count += 1;
-                    if dead_thread_count == MAX_WORKER {
-                        drop(tx);
-                        // can't continue with a flag check
-                        // weird Rusty way !
-                        // Consume whatever is remaining
-                        // Not worrying about counters; hunch is their
-                        // should be less
-                        for eq_class in rx.iter() {
-                            eq_class.map_or((), |eq_class| eprintln!("{:?}", eq_class));
-                        }
-                        break;
-                    }
-                }
-                Some(read_data) => {
-                    println!("{:?}", read_data);
+        let added = index.add_sequences(&[dna(&tx3)], &["tx3".to_string()]).unwrap();
+        assert_eq!(added, vec![2]);
+        assert_eq!(index.gene_of(2), Some("Gtx1"));
+        assert_eq!(index.map_read(&dna(&tx3[160..260])), Some((vec![2], 100)));
+        assert_eq!(index.map_read(&dna(&tx1[120..220])), Some((vec![0, 2], 100)));
+        assert_eq!(index.map_read(&dna(&tx1[0..100])), Some((vec![0], 100)));
+        assert_eq!(index.map_read(&dna(&tx2[100..200])), Some((vec![1], 100)));
+        // reads are still placed on the old transcripts and on the added one
+        let pairs = [
+            (dna(&tx3[160..210]), dna(&tx3[250..300]).rc()),
+            (dna(&tx1[0..50]), dna(&tx1[40..90]).rc()),
+        ];
+        assert_eq!(index.fragment_lengths(&pairs), vec![140, 90]);
 
-                    if read_data.0 {
-                        mapped_read_counter += 1;
-                    }
+        let added = index.add_sequences(&[dna(&random_seq(300, 4))], &["tx4".to_string()]).unwrap();
+        assert_eq!(added, vec![3]);
+        assert_eq!(index.tx_gene_mapping["tx4"], "tx4");
 
-                    read_counter += 1;
-                    if read_counter % 1_000_000 == 0 {
-                        let frac_mapped = mapped_read_counter as f32 * 100.0 / read_counter as f32;
-                        eprint!(
-                            "\rDone Mapping {} reads w/ Rate: {}",
-                            read_counter, frac_mapped
-                        );
-                        io::stderr().flush().expect("Could not flush stdout");
-                    }
-                } // end-Some
-            } // end-match
-        } // end-for
-    }); //end crossbeam
+        let err = index.add_sequences(&[dna(&random_seq(300, 5))], &["tx2".to_string()]);
+        assert!(err.unwrap_err().to_string().contains("tx2"));
+    }
 
-    eprintln!();
-    info!("Done Mapping Reads");
-    Ok(())
+    #[test]
+    fn genes_are_listed_once_and_sorted() {
+        let txs: Vec<String> = (1..5).map(|seed| random_seq(200, seed)).collect();
+        let mut index = build_test_index(&[
+            ("MICB*002:01", &txs[0]),
+            ("B*07:02", &txs[1]),
+            ("A*01:01", &txs[2]),
+            ("A*02:01", &txs[3]),
+        ]);
+        index.tx_gene_mapping = vec![
+            ("MICB*002:01", "MICB"),
+            ("B*07:02", "HLA-B"),
+            ("A*01:01", "HLA-A"),
+            ("A*02:01", "HLA-A"),
+        ]
+        .into_iter()
+        .map(|(tx_name, gene)| (tx_name.to_string(), gene.to_string()))
+        .collect();
+        assert_eq!(index.genes().into_iter().collect::<Vec<_>>(), vec!["HLA-A", "HLA-B", "MICB"]);
+    }
 }
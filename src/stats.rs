@@ -0,0 +1,438 @@
+// Copyright (c) 2018 10x Genomics, Inc. All rights reserved.
+
+//! Summary statistics of an index, estimates of the resources a build of a
+//! reference will take, and summaries of read mapping runs.
+use std::collections::BTreeMap;
+use std::collections::hash_map::DefaultHasher;
+use std::fmt;
+use std::hash::Hasher;
+use std::path::PathBuf;
+
+use bincode;
+use debruijn::{Kmer, Vmer};
+use failure::Error;
+use serde::Serialize;
+use serde_json;
+
+use config::{
+    ESTIMATE_BASE_MEMORY, ESTIMATE_INDEX_BYTES_PER_KMER, ESTIMATE_MEMORY_PER_BASE,
+    ESTIMATE_MEMORY_PER_KMER, HLL_PRECISION, STRAND_BIAS_MIN_READS, STRAND_BIAS_Z,
+};
+use pseudoaligner::Pseudoaligner;
+use umi::UmiSummary;
+use utils::{self, ReferenceOptions};
+
+/// Size and redundancy of an index, computable right after building it or
+/// from an index read from disk.
+#[derive(Serialize, Debug, Clone, PartialEq)]
+pub struct IndexStats {
+    pub kmer_length: usize,
+    /// Minimizer window of a sparse index
+    pub minimizer_window: Option<usize>,
+    pub num_transcripts: usize,
+    /// Number of transcripts from each reference file, if the index records
+    /// their sources
+    pub transcripts_per_source: BTreeMap<String, usize>,
+    /// Total length of the indexed transcripts, if the index records it
+    pub total_reference_bases: Option<u64>,
+    pub num_nodes: usize,
+    pub num_distinct_kmers: usize,
+    pub num_eq_classes: usize,
+    pub eq_class_size: EqClassSizeStats,
+    pub memory: MemoryEstimate,
+}
+
+/// Distribution of the number of transcripts per equivalence class.
+#[derive(Serialize, Debug, Clone, PartialEq)]
+pub struct EqClassSizeStats {
+    pub min: usize,
+    pub median: usize,
+    pub max: usize,
+}
+
+/// Approximate sizes in bytes of the major structures of an index, taken
+/// as their serialized sizes.
+#[derive(Serialize, Debug, Clone, PartialEq)]
+pub struct MemoryEstimate {
+    pub graph: u64,
+    pub eq_classes: u64,
+    pub kmer_mphf: u64,
+    pub kmer_positions: u64,
+    pub transcripts: u64,
+    pub total: u64,
+}
+
+impl IndexStats {
+    pub fn new<K: Kmer + Sync + Send + Serialize>(index: &Pseudoaligner<K>) -> Result<IndexStats, Error> {
+        let total_reference_bases = if index.tx_lengths.is_empty() && !index.tx_names.is_empty() {
+            None
+        } else {
+            Some(index.tx_lengths.iter().map(|&len| len as u64).sum())
+        };
+
+        let mut transcripts_per_source = BTreeMap::new();
+        for tx_id in 0..index.tx_names.len() {
+            if let Some(source) = index.tx_source(tx_id as u32) {
+                *transcripts_per_source.entry(source.to_string()).or_insert(0) += 1;
+            }
+        }
+
+        let graph = bincode::serialized_size(&index.dbg)?;
+        let eq_classes = bincode::serialized_size(&index.eq_classes)?;
+        let kmer_mphf = bincode::serialized_size(index.kmer_mphf())?;
+        let kmer_positions = (index.kmer_positions().len() * 8) as u64;
+        let transcripts = bincode::serialized_size(&index.tx_names)?
+            + bincode::serialized_size(&index.tx_gene_mapping)?;
+
+        Ok(IndexStats {
+            kmer_length: K::k(),
+            minimizer_window: index.minimizer_window,
+            num_transcripts: index.tx_names.len(),
+            transcripts_per_source,
+            total_reference_bases,
+            num_nodes: index.dbg.len(),
+            num_distinct_kmers: index.n_distinct_kmers(),
+            num_eq_classes: index.eq_classes.len(),
+            eq_class_size: EqClassSizeStats::new(&index.eq_classes),
+            memory: MemoryEstimate {
+                graph,
+                eq_classes,
+                kmer_mphf,
+                kmer_positions,
+                transcripts,
+                total: graph + eq_classes + kmer_mphf + kmer_positions + transcripts,
+            },
+        })
+    }
+
+    pub fn to_json(&self) -> Result<String, Error> {
+        Ok(serde_json::to_string_pretty(self)?)
+    }
+}
+
+impl EqClassSizeStats {
+    fn new(eq_classes: &[Vec<u32>]) -> EqClassSizeStats {
+        let mut sizes: Vec<usize> = eq_classes.iter().map(|eq_class| eq_class.len()).collect();
+        sizes.sort();
+
+        EqClassSizeStats {
+            min: sizes.first().cloned().unwrap_or(0),
+            median: sizes.get(sizes.len() / 2).cloned().unwrap_or(0),
+            max: sizes.last().cloned().unwrap_or(0),
+        }
+    }
+}
+
+/// Size of a reference and the resources an index build of it is expected
+/// to take, from `estimate_build`. The estimates are meant to be within a
+/// factor of two.
+#[derive(Serialize, Debug, Clone, PartialEq)]
+pub struct BuildEstimate {
+    pub kmer_length: usize,
+    pub num_sequences: usize,
+    pub total_bases: u64,
+    /// Estimated number of distinct canonical k-mers
+    pub distinct_kmers: u64,
+    /// Estimated peak memory of an in-memory build, in bytes
+    pub peak_memory: u64,
+    /// Estimated size of the index file, in bytes
+    pub index_size: u64,
+}
+
+impl BuildEstimate {
+    pub fn to_json(&self) -> Result<String, Error> {
+        Ok(serde_json::to_string_pretty(self)?)
+    }
+}
+
+/// What a read mapping run did, filled in by the read mapping functions
+/// when given one in their `MappingOutputs`.
+#[derive(Serialize, Debug, Clone, Default, PartialEq)]
+pub struct RunSummary {
+    /// Reads, or pairs of paired reads, mapped
+    pub total_reads: usize,
+    /// Reads, or pairs, read from the input, including those the
+    /// subsampling left out; fewer than the input holds if the mapping
+    /// stopped at a maximum number of reads
+    pub examined_reads: usize,
+    /// Reads, or pairs, the subsampling left out, which are in no other count
+    pub skipped_reads: usize,
+    /// Reads mapped to a non-empty equivalence class with enough coverage
+    pub mapped_reads: u64,
+    /// Reads that did not map, by `UnmappedReason::code`
+    pub unmapped_reads: BTreeMap<String, usize>,
+    /// Mapped reads that only reached the coverage threshold by counting
+    /// gaps between matches as covered
+    pub rescued_reads: usize,
+    /// Reads with valid k-mers by the fraction of them in the index, in
+    /// `KMER_RATIO_BINS` bins of equal width from 0 to 1, the last one
+    /// including 1
+    pub kmer_ratios: Vec<u64>,
+    /// Pairs by how their mates were combined, by `PairMapping::code`, if
+    /// the reads were paired
+    pub pairs: Option<BTreeMap<String, usize>>,
+    /// Distinct equivalence classes of the mapped reads
+    pub num_eq_classes: usize,
+    /// Mapped reads, or molecules if deduplicated by UMI, by the number of
+    /// transcripts in their class
+    pub eq_class_sizes: BTreeMap<usize, u64>,
+    /// Wall time of the phases of the run, in seconds
+    pub phase_seconds: BTreeMap<String, f64>,
+    /// Settings the run used
+    pub config: BTreeMap<String, serde_json::Value>,
+    /// Mapped reads by strand, for every transcript of their classes
+    pub tx_strands: Vec<TranscriptStrands>,
+    /// Reads and molecules by UMI, if the reads were deduplicated
+    pub umis: Option<UmiSummary>,
+}
+
+impl RunSummary {
+    /// Record that `phase` took `seconds`.
+    pub fn add_phase(&mut self, phase: &str, seconds: f64) {
+        self.phase_seconds.insert(phase.to_string(), seconds);
+    }
+
+    /// Record `value` as the setting `name`.
+    pub fn add_config<T: Serialize>(&mut self, name: &str, value: &T) -> Result<(), Error> {
+        self.config.insert(name.to_string(), serde_json::to_value(value)?);
+        Ok(())
+    }
+
+    /// The summary as pretty-printed JSON, as `map --summary-json` writes it.
+    pub fn to_json(&self) -> Result<String, Error> {
+        Ok(serde_json::to_string_pretty(self)?)
+    }
+}
+
+/// Reads mapped to a transcript on its forward and reverse strand.
+#[derive(Serialize, Debug, Clone, PartialEq)]
+pub struct TranscriptStrands {
+    pub transcript: String,
+    pub forward: u64,
+    pub reverse: u64,
+    /// Whether an unstranded index saw more reads of one strand than chance
+    /// allows; reads of a stranded index are all forward by construction
+    pub strand_biased: bool,
+}
+
+impl TranscriptStrands {
+    /// Strands of the reads of `transcript`, of an index that is
+    /// `stranded` or not. On an unstranded index with at least
+    /// `STRAND_BIAS_MIN_READS` reads, it is biased if its forward reads
+    /// are further than `STRAND_BIAS_Z` standard deviations from half of
+    /// them.
+    pub fn new(transcript: &str, forward: u64, reverse: u64, stranded: bool) -> TranscriptStrands {
+        let num_reads = forward + reverse;
+        let strand_biased = !stranded && num_reads >= STRAND_BIAS_MIN_READS && {
+            let n = num_reads as f64;
+            (forward as f64 - n / 2.0).abs() > STRAND_BIAS_Z * n.sqrt() / 2.0
+        };
+        TranscriptStrands {
+            transcript: transcript.to_string(),
+            forward,
+            reverse,
+            strand_biased,
+        }
+    }
+}
+
+impl fmt::Display for RunSummary {
+    /// One line: the reads mapped, the classes they mapped to, and the
+    /// reasons the others did not map.
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let rate = if self.total_reads == 0 {
+            0.0
+        } else {
+            self.mapped_reads as f64 * 100.0 / self.total_reads as f64
+        };
+        write!(
+            f,
+            "{} of {} reads mapped ({:.1}%) to {} equivalence classes",
+            self.mapped_reads, self.total_reads, rate, self.num_eq_classes
+        )?;
+
+        let unmapped: Vec<String> = self
+            .unmapped_reads
+            .iter()
+            .filter(|&(_, &count)| count > 0)
+            .map(|(reason, count)| format!("{} {}", count, reason))
+            .collect();
+        if !unmapped.is_empty() {
+            write!(f, "; not mapped: {}", unmapped.join(", "))?;
+        }
+        if self.skipped_reads > 0 {
+            write!(f, "; {} of {} reads left out by subsampling", self.skipped_reads, self.examined_reads)?;
+        }
+        Ok(())
+    }
+}
+
+/// Scan the records of `fasta_paths` that a build with `options` would
+/// index, without building anything, and estimate what the build takes.
+/// Distinct canonical k-mers are counted with a HyperLogLog sketch, so the
+/// scan runs in constant memory.
+pub fn estimate_build<K: Kmer>(fasta_paths: &[PathBuf], options: &ReferenceOptions) -> Result<BuildEstimate, Error> {
+    let mut sketch = HyperLogLog::new(HLL_PRECISION);
+    let mut num_sequences = 0;
+    let mut total_bases = 0;
+    let mut name_bytes = 0;
+
+    for path in fasta_paths {
+        for result in utils::open_fasta(path)?.records() {
+            let record = result?;
+            if record.seq().is_empty() || record.seq().len() < options.min_seq_len {
+                continue;
+            }
+            num_sequences += 1;
+            total_bases += record.seq().len() as u64;
+            name_bytes += record.id().len() as u64;
+
+            let segments = if options.split_at_n {
+                utils::split_sequence(&record)
+            } else {
+                vec![utils::hashed_sequence(&record)]
+            };
+            for seq in segments.iter().filter(|seq| seq.len() >= K::k()) {
+                for kmer in seq.iter_kmers::<K>() {
+                    let mut hasher = DefaultHasher::new();
+                    kmer.min_rc().hash(&mut hasher);
+                    sketch.insert(hasher.finish());
+                }
+            }
+        }
+    }
+
+    let distinct_kmers = sketch.estimate().round() as u64;
+    Ok(BuildEstimate {
+        kmer_length: K::k(),
+        num_sequences,
+        total_bases,
+        distinct_kmers,
+        peak_memory: ESTIMATE_BASE_MEMORY
+            + ESTIMATE_MEMORY_PER_BASE * total_bases
+            + ESTIMATE_MEMORY_PER_KMER * distinct_kmers,
+        // transcript ids are stored with the names and as keys of the gene map
+        index_size: ESTIMATE_INDEX_BYTES_PER_KMER * distinct_kmers + 3 * (name_bytes + 8 * num_sequences as u64),
+    })
+}
+
+/// HyperLogLog sketch of the number of distinct 64-bit hashes inserted.
+struct HyperLogLog {
+    precision: u32,
+    registers: Vec<u8>,
+}
+
+impl HyperLogLog {
+    /// A sketch of `2^precision` registers, with a relative standard error
+    /// of about `1.04 / 2^(precision / 2)`.
+    fn new(precision: u32) -> HyperLogLog {
+        HyperLogLog { precision, registers: vec![0; 1 << precision] }
+    }
+
+    fn insert(&mut self, hash: u64) {
+        let register = (hash >> (64 - self.precision)) as usize;
+        // the sentinel bit bounds the rank when the remaining bits are all 0
+        let rest = (hash << self.precision) | (1 << (self.precision - 1));
+        let rank = rest.leading_zeros() as u8 + 1;
+        if rank > self.registers[register] {
+            self.registers[register] = rank;
+        }
+    }
+
+    fn estimate(&self) -> f64 {
+        let m = self.registers.len() as f64;
+        let alpha = 0.7213 / (1.0 + 1.079 / m);
+        let sum: f64 = self.registers.iter().map(|&rank| 2f64.powi(-(rank as i32))).sum();
+        let raw = alpha * m * m / sum;
+
+        // linear counting is more accurate for small cardinalities
+        let num_zero = self.registers.iter().filter(|&&rank| rank == 0).count();
+        if raw <= 2.5 * m && num_zero > 0 {
+            m * (m / num_zero as f64).ln()
+        } else {
+            raw
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use config::KmerType;
+    use std::collections::HashSet;
+    use debruijn::dna_string::DnaString;
+    use test_utils::{build_test_index, random_seq, TempDir};
+
+    #[test]
+    fn only_unstranded_lopsided_transcripts_are_strand_biased() {
+        assert!(TranscriptStrands::new("tx1", 30, 0, false).strand_biased);
+        assert!(TranscriptStrands::new("tx1", 2, 28, false).strand_biased);
+        assert!(!TranscriptStrands::new("tx1", 16, 14, false).strand_biased);
+        // too few reads to tell
+        assert!(!TranscriptStrands::new("tx1", 10, 0, false).strand_biased);
+        // the reads of a stranded index are all forward
+        assert!(!TranscriptStrands::new("tx1", 30, 0, true).strand_biased);
+    }
+
+    #[test]
+    fn sketched_distinct_kmers_are_close_to_the_exact_count() {
+        // pairs of transcripts sharing their first 1000 bases
+        let txs: Vec<(String, String)> = (0..40u64)
+            .map(|i| (format!("tx{}", i), random_seq(1000, 100 + i / 2) + &random_seq(1000, 200 + i)))
+            .collect();
+        let records: Vec<(&str, &str)> = txs.iter().map(|(id, seq)| (id.as_str(), seq.as_str())).collect();
+        let dir = TempDir::new();
+        let path = dir.write_fasta("ref.fa", &records);
+
+        let exact: HashSet<KmerType> = txs
+            .iter()
+            .flat_map(|(_, seq)| DnaString::from_dna_string(seq).iter_kmers::<KmerType>().collect::<Vec<_>>())
+            .map(|kmer| kmer.min_rc())
+            .collect();
+        let estimate = estimate_build::<KmerType>(&[path], &ReferenceOptions::default()).unwrap();
+        assert_eq!(estimate.kmer_length, KmerType::k());
+        assert_eq!(estimate.num_sequences, 40);
+        assert_eq!(estimate.total_bases, 80_000);
+        let error = (estimate.distinct_kmers as f64 - exact.len() as f64).abs() / exact.len() as f64;
+        assert!(error < 0.05, "estimated {} distinct k-mers, counted {}", estimate.distinct_kmers, exact.len());
+        assert!(estimate.peak_memory > estimate.index_size);
+
+        // the reverse complement of a transcript adds no canonical k-mers
+        let tx0_rc = DnaString::from_dna_string(&txs[0].1).rc().to_string();
+        let mut with_rc = records.clone();
+        with_rc.push(("tx0_rc", &tx0_rc));
+        let path = dir.write_fasta("with_rc.fa", &with_rc);
+        let estimate_with_rc = estimate_build::<KmerType>(&[path], &ReferenceOptions::default()).unwrap();
+        assert_eq!(estimate_with_rc.num_sequences, 41);
+        assert_eq!(estimate_with_rc.distinct_kmers, estimate.distinct_kmers);
+    }
+
+    #[test]
+    fn index_stats_count_a_two_transcript_reference() {
+        // two transcripts of 400 bases sharing their last 200
+        let shared = random_seq(200, 3);
+        let index = build_test_index(&[
+            ("tx1", random_seq(199, 1) + "A" + &shared),
+            ("tx2", random_seq(199, 2) + "C" + &shared),
+        ]);
+        let k = KmerType::k();
+        let distinct_kmers = 2 * (400 - k + 1) - (200 - k + 1);
+
+        let stats = IndexStats::new(&index).unwrap();
+        assert_eq!(stats.kmer_length, k);
+        assert_eq!(stats.minimizer_window, None);
+        assert_eq!(stats.num_transcripts, 2);
+        assert_eq!(stats.total_reference_bases, Some(800));
+        assert_eq!(stats.num_nodes, 3);
+        assert_eq!(stats.num_distinct_kmers, distinct_kmers);
+        assert_eq!(stats.num_eq_classes, 3);
+        assert_eq!(stats.eq_class_size, EqClassSizeStats { min: 1, median: 1, max: 2 });
+        assert_eq!(stats.memory.kmer_positions, 8 * distinct_kmers as u64);
+
+        let dir = TempDir::new();
+        let path = dir.path().join("index.bin");
+        utils::write_index(&index, &path).unwrap();
+        let read: Pseudoaligner<KmerType> = utils::read_index(&path).unwrap();
+        assert_eq!(IndexStats::new(&read).unwrap(), stats);
+    }
+}
@@ -0,0 +1,108 @@
+// Copyright (c) 2018 10x Genomics, Inc. All rights reserved.
+
+//! Helpers shared by the unit tests.
+
+use std::collections::HashMap;
+use std::fs::{self, File};
+use std::io::Write;
+use std::path::{Path, PathBuf};
+use std::process;
+use std::sync::atomic::{AtomicUsize, Ordering};
+
+use debruijn::dna_string::DnaString;
+
+use build_index;
+use config::{KmerType, STRANDED};
+use pseudoaligner::Pseudoaligner;
+
+static NEXT_DIR: AtomicUsize = AtomicUsize::new(0);
+
+/// A fresh directory under the system temp dir, removed when dropped.
+pub struct TempDir {
+    path: PathBuf,
+}
+
+impl TempDir {
+    pub fn new() -> TempDir {
+        let path = std::env::temp_dir().join(format!(
+            "pseudoaligner-test-{}-{}",
+            process::id(),
+            NEXT_DIR.fetch_add(1, Ordering::SeqCst)
+        ));
+        fs::create_dir_all(&path).unwrap();
+        TempDir { path }
+    }
+
+    pub fn path(&self) -> &Path {
+        &self.path
+    }
+
+    /// Write `contents` to file `name` of the directory.
+    pub fn write(&self, name: &str, contents: &str) -> PathBuf {
+        let path = self.path.join(name);
+        File::create(&path).unwrap().write_all(contents.as_bytes()).unwrap();
+        path
+    }
+
+    /// Write the `(id, sequence)` records to FASTA file `name` of the
+    /// directory, with Gencode-style headers naming gene `G<id>`.
+    pub fn write_fasta(&self, name: &str, records: &[(&str, &str)]) -> PathBuf {
+        self.write(name, &gencode_fasta(records))
+    }
+}
+
+impl Drop for TempDir {
+    fn drop(&mut self) {
+        let _ = fs::remove_dir_all(&self.path);
+    }
+}
+
+/// FASTA of the `(id, sequence)` records with Gencode-style headers, every
+/// transcript `<id>` belonging to gene `G<id>`.
+pub fn gencode_fasta(records: &[(&str, &str)]) -> String {
+    records
+        .iter()
+        .map(|(id, seq)| format!(">{}|G{}|-|-|{}-001|{}|{}|protein_coding|\n{}\n", id, id, id, id, seq.len(), seq))
+        .collect()
+}
+
+/// Pseudo-random sequence of `len` bases, the same for the same `seed`.
+pub fn random_seq(len: usize, seed: u64) -> String {
+    let mut state = seed.wrapping_mul(6364136223846793005).wrapping_add(1442695040888963407);
+    (0..len)
+        .map(|_| {
+            state = state.wrapping_mul(6364136223846793005).wrapping_add(1442695040888963407);
+            b"ACGT"[(state >> 62) as usize] as char
+        })
+        .collect()
+}
+
+/// Index of the `(id, sequence)` transcripts, every transcript `<id>`
+/// belonging to gene `G<id>`.
+pub fn build_test_index<S: AsRef<str>, T: AsRef<str>>(records: &[(S, T)]) -> Pseudoaligner<KmerType> {
+    build_test_index_with_strandedness(records, STRANDED)
+}
+
+/// Like `build_test_index`, but storing k-mers canonically.
+pub fn build_unstranded_test_index<S: AsRef<str>, T: AsRef<str>>(records: &[(S, T)]) -> Pseudoaligner<KmerType> {
+    build_test_index_with_strandedness(records, false)
+}
+
+/// Like `build_test_index`, but storing k-mers as they are, whatever
+/// `config::STRANDED` says.
+pub fn build_stranded_test_index<S: AsRef<str>, T: AsRef<str>>(records: &[(S, T)]) -> Pseudoaligner<KmerType> {
+    build_test_index_with_strandedness(records, true)
+}
+
+fn build_test_index_with_strandedness<S: AsRef<str>, T: AsRef<str>>(
+    records: &[(S, T)],
+    stranded: bool,
+) -> Pseudoaligner<KmerType> {
+    let seqs: Vec<DnaString> = records.iter().map(|(_, seq)| DnaString::from_dna_string(seq.as_ref())).collect();
+    let tx_names: Vec<String> = records.iter().map(|(id, _)| id.as_ref().to_string()).collect();
+    let tx_gene_map: HashMap<String, String> = tx_names
+        .iter()
+        .map(|id| (id.clone(), format!("G{}", id)))
+        .collect();
+    build_index::build_index_with_strandedness::<KmerType>(&seqs, &tx_names, &tx_gene_map, stranded).unwrap()
+}
@@ -0,0 +1,220 @@
+// Copyright (c) 2018 10x Genomics, Inc. All rights reserved.
+
+//! Collapsing of PCR duplicates by their unique molecular identifier (UMI):
+//! the mapped reads sharing a UMI and equivalence class count as one
+//! molecule. UMIs one mismatch apart can be merged by the directional
+//! adjacency method of UMI-tools, which takes the rarer of two such UMIs
+//! for a sequencing error of the more common one.
+use std::collections::HashMap;
+
+use failure::{self, Error};
+
+/// Where the UMI of a read is taken from.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Serialize)]
+pub enum UmiSource {
+    /// The read name after the last occurrence of the delimiter
+    ReadName(char),
+    /// The first bases of the read, which are then not mapped
+    ReadStart(usize),
+}
+
+impl UmiSource {
+    /// UMI of the read `read_id` with `bases`, or `None` if it has none:
+    /// its name holds no delimiter or nothing after it, or it is shorter
+    /// than the UMI.
+    pub fn umi<'a>(self, read_id: &'a str, bases: &'a [u8]) -> Option<&'a [u8]> {
+        let umi = match self {
+            UmiSource::ReadName(delimiter) => read_id.rsplit_once(delimiter)?.1.as_bytes(),
+            UmiSource::ReadStart(len) => bases.get(..len)?,
+        };
+        if umi.is_empty() {
+            None
+        } else {
+            Some(umi)
+        }
+    }
+
+    /// Bases at the start of reads that are the UMI rather than sequence to map.
+    pub fn read_bases(self) -> usize {
+        match self {
+            UmiSource::ReadName(_) => 0,
+            UmiSource::ReadStart(len) => len,
+        }
+    }
+
+    /// Source of UMIs from the options of the command line: a one-character
+    /// delimiter in the read name, or a length at the start of the read.
+    pub fn from_args(delimiter: Option<&str>, len: Option<usize>) -> Result<Option<UmiSource>, Error> {
+        match (delimiter, len) {
+            (Some(_), Some(_)) => Err(failure::err_msg("UMIs come either from the read name or the read start")),
+            (Some(delimiter), None) => {
+                let mut chars = delimiter.chars();
+                match (chars.next(), chars.next()) {
+                    (Some(delimiter), None) => Ok(Some(UmiSource::ReadName(delimiter))),
+                    _ => Err(failure::err_msg(format!("UMI delimiter {:?} is not one character", delimiter))),
+                }
+            }
+            (None, Some(0)) => Err(failure::err_msg("UMIs at the read start need at least one base")),
+            (None, Some(len)) => Ok(Some(UmiSource::ReadStart(len))),
+            (None, None) => Ok(None),
+        }
+    }
+}
+
+/// Reads and molecules of a run with UMIs.
+#[derive(Serialize, Debug, Clone, Default, PartialEq)]
+pub struct UmiSummary {
+    /// Mapped reads with a UMI
+    pub reads: u64,
+    /// Mapped reads without a UMI, which are not counted
+    pub reads_without_umi: u64,
+    /// Distinct molecules, by UMI and equivalence class, after merging
+    pub umis: u64,
+    /// Fraction of the reads that were duplicates of another molecule read
+    pub dedup_rate: f64,
+}
+
+/// Collects the UMIs of the mapped reads of every equivalence class.
+#[derive(Debug, Default)]
+pub struct UmiCounter {
+    merge_mismatches: bool,
+    umi_counts: HashMap<Vec<u32>, HashMap<Vec<u8>, u64>>,
+    reads_without_umi: u64,
+}
+
+impl UmiCounter {
+    /// Collect UMIs, merging those one mismatch apart if `merge_mismatches`.
+    pub fn new(merge_mismatches: bool) -> UmiCounter {
+        UmiCounter { merge_mismatches, ..UmiCounter::default() }
+    }
+
+    /// Add a read mapped to `eq_class`, with `umi` or without any.
+    pub fn add_read(&mut self, umi: Option<&[u8]>, eq_class: &[u32]) {
+        match umi {
+            Some(umi) => {
+                let umis = self.umi_counts.entry(eq_class.to_vec()).or_default();
+                *umis.entry(umi.to_vec()).or_insert(0) += 1;
+            }
+            None => self.reads_without_umi += 1,
+        }
+    }
+
+    /// Number of molecules of every equivalence class.
+    pub fn eq_class_counts(&self) -> HashMap<Vec<u32>, u64> {
+        self.umi_counts
+            .iter()
+            .map(|(eq_class, umis)| (eq_class.clone(), self.count_molecules(umis)))
+            .collect()
+    }
+
+    pub fn summary(&self) -> UmiSummary {
+        let reads: u64 = self.umi_counts.values().flat_map(|umis| umis.values()).sum();
+        let umis: u64 = self.eq_class_counts().values().sum();
+        UmiSummary {
+            reads,
+            reads_without_umi: self.reads_without_umi,
+            umis,
+            dedup_rate: if reads == 0 { 0.0 } else { 1.0 - umis as f64 / reads as f64 },
+        }
+    }
+
+    /// Molecules among the read counts of `umis`. Merging connects a UMI
+    /// to every UMI one mismatch away with at most about half its reads,
+    /// and counts every group so connected, going from the most read UMIs
+    /// down, once. Compares all pairs of UMIs, which is fine for the
+    /// number of molecules of one class.
+    fn count_molecules(&self, umis: &HashMap<Vec<u8>, u64>) -> u64 {
+        if !self.merge_mismatches {
+            return umis.len() as u64;
+        }
+
+        // most read first, ties in UMI order, so the groups do not depend on hash order
+        let mut umis: Vec<(&[u8], u64)> = umis.iter().map(|(umi, &count)| (umi.as_slice(), count)).collect();
+        umis.sort_by(|a, b| b.1.cmp(&a.1).then(a.0.cmp(b.0)));
+
+        let mut grouped = vec![false; umis.len()];
+        let mut molecules = 0;
+        for first in 0..umis.len() {
+            if grouped[first] {
+                continue;
+            }
+            molecules += 1;
+            grouped[first] = true;
+
+            let mut group = vec![first];
+            while let Some(idx) = group.pop() {
+                let (umi, count) = umis[idx];
+                for other in 0..umis.len() {
+                    let (other_umi, other_count) = umis[other];
+                    if !grouped[other] && count + 1 >= 2 * other_count && is_one_mismatch(umi, other_umi) {
+                        grouped[other] = true;
+                        group.push(other);
+                    }
+                }
+            }
+        }
+        molecules
+    }
+}
+
+fn is_one_mismatch(umi1: &[u8], umi2: &[u8]) -> bool {
+    umi1.len() == umi2.len() && umi1.iter().zip(umi2).filter(|&(a, b)| a != b).count() == 1
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn umis_come_from_the_read_name_or_start() {
+        let source = UmiSource::from_args(Some("_"), None).unwrap().unwrap();
+        assert_eq!(source.umi("read_1_ACGT", b"TTTTGG"), Some(&b"ACGT"[..]));
+        assert_eq!(source.umi("read", b"TTTTGG"), None);
+        assert_eq!(source.umi("read_", b"TTTTGG"), None);
+        assert_eq!(source.read_bases(), 0);
+
+        let source = UmiSource::from_args(None, Some(4)).unwrap().unwrap();
+        assert_eq!(source.umi("read_1_ACGT", b"TTTTGG"), Some(&b"TTTT"[..]));
+        assert_eq!(source.umi("read", b"TTT"), None);
+        assert_eq!(source.read_bases(), 4);
+
+        assert!(UmiSource::from_args(Some("_"), Some(4)).is_err());
+        assert!(UmiSource::from_args(Some("::"), None).is_err());
+        assert!(UmiSource::from_args(None, Some(0)).is_err());
+        assert_eq!(UmiSource::from_args(None, None).unwrap(), None);
+    }
+
+    #[test]
+    fn reads_sharing_a_umi_and_class_count_once() {
+        let mut counter = UmiCounter::new(false);
+        counter.add_read(Some(&b"AAAA"[..]), &[0]);
+        counter.add_read(Some(&b"AAAA"[..]), &[0]);
+        counter.add_read(Some(&b"AAAA"[..]), &[0, 1]);
+        counter.add_read(Some(&b"AAAT"[..]), &[0]);
+        counter.add_read(None, &[0]);
+        let expected: HashMap<Vec<u32>, u64> = vec![(vec![0], 2), (vec![0, 1], 1)].into_iter().collect();
+        assert_eq!(counter.eq_class_counts(), expected);
+        assert_eq!(
+            counter.summary(),
+            UmiSummary { reads: 4, reads_without_umi: 1, umis: 3, dedup_rate: 0.25 }
+        );
+    }
+
+    #[test]
+    fn one_mismatch_umis_merge_only_when_asked() {
+        let reads = [("AAAA", 3), ("AAAT", 1), ("AATT", 1), ("GGGG", 2), ("GGGC", 2)];
+        let count = |merge_mismatches| {
+            let mut counter = UmiCounter::new(merge_mismatches);
+            for &(umi, count) in &reads {
+                for _ in 0..count {
+                    counter.add_read(Some(umi.as_bytes()), &[0]);
+                }
+            }
+            counter.eq_class_counts()[&vec![0]]
+        };
+        assert_eq!(count(false), 5);
+        // AAAT, and through it AATT, are errors of AAAA; GGGC is as common
+        // as GGGG, so it is a molecule of its own
+        assert_eq!(count(true), 3);
+    }
+}
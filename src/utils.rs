@@ -1,23 +1,43 @@
 // Copyright (c) 2018 10x Genomics, Inc. All rights reserved.
 
 //! Utility methods.
-use std::collections::HashMap;
-use std::fmt::Debug;
+use std::collections::{BTreeSet, HashMap, HashSet};
+use std::convert::TryFrom;
+use std::fmt::{self, Debug};
 use std::fs::{File};
-use std::io::{self, Write, BufRead, BufReader, BufWriter};
-use std::path::Path;
-use std::sync::{Arc, Mutex};
+use std::io::{self, Read, Write, BufRead, BufReader, BufWriter};
+use std::path::{Path, PathBuf};
+use std::str;
+use std::sync::{mpsc, Arc, Mutex};
+use std::thread;
 
 use bincode::{self, deserialize_from, serialize_into};
 use failure::{self, Error};
+use flate2::Compression;
 use flate2::read::MultiGzDecoder;
-use serde::{Serialize, de::DeserializeOwned};
+use flate2::write::GzEncoder;
+use serde::{Serialize, Serializer, de::DeserializeOwned};
+use serde_json;
 
 use bio::io::{fasta, fastq};
+use boomphf::Mphf;
+use boomphf::hashmap::NoKeyBoomHashMap;
 use debruijn::dna_string::DnaString;
+use debruijn::filter::EqClassIdType;
+use debruijn::graph::DebruijnGraph;
+use debruijn::{Dir, Kmer, Mer};
+use memmap2::Mmap;
+use rand::{self, SeedableRng};
+use rand_chacha::ChaCha8Rng;
+use sha2::{Digest, Sha256};
+#[cfg(feature = "zstd")]
+use zstd;
 
 use config;
+use error::PseudoalignerError;
 use mappability::MappabilityRecord;
+use progress::{Phase, Progress};
+use pseudoaligner::{KmerPositions, Pseudoaligner, ReadDetail, UnmappedReason};
 
 const MAPPABILITY_HEADER_STRING: &'static str = "tx_name\tgene_name\ttx_kmer_count\ttx_fraction_unique\tgene_fraction_unique\n";
 
@@ -44,131 +64,3062 @@ pub fn read_obj<T: DeserializeOwned, P: AsRef<Path> + Debug>(
     deserialize_from(&mut reader)
 }
 
-/// Open a (possibly gzipped) file into a BufReader.
-fn _open_with_gz<P: AsRef<Path>>(p: P) -> Result<Box<BufRead>, Error> {
-    let r = File::open(p.as_ref())?;
+/// Leading bytes of every index file.
+pub const INDEX_MAGIC: &[u8; 8] = b"PSALIDX\0";
 
-    if p.as_ref().extension().unwrap() == "gz" {
-        let gz = MultiGzDecoder::new(r);
-        let buf_reader = BufReader::with_capacity(32 * 1024, gz);
-        Ok(Box::new(buf_reader))
-    } else {
-        let buf_reader = BufReader::with_capacity(32 * 1024, r);
-        Ok(Box::new(buf_reader))
+/// Version of the on-disk index layout written by `write_index`.
+/// Version 1 stored the k-mer positions inside the bincode payload; since
+/// version 2 they follow the payload in their own section. Later versions
+/// only append fields to the payload:
+/// * 3: reference file of each transcript
+/// * 4: length of each transcript
+/// * 5: whether the index is stranded, or keyed by canonical k-mers
+/// * 6: checksum of each reference file, kept in the metadata
+/// * 7: minimizer window of sparse indices, also in the metadata
+/// * 8: transcript groups
+/// * 9: gene names
+/// * 10: checksums of the transcript sequences
+/// * 11: node starts of each transcript
+/// * 12: compression codec of the payload, stored after the version
+/// * 13: GC content of each transcript
+pub const INDEX_FORMAT_VERSION: u32 = 13;
+
+/// How `write_index_with_compression` compresses the index payload. The
+/// codec is recorded in the file, so `read_index` detects it.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum IndexCompression {
+    #[default]
+    None,
+    Gzip,
+    /// Only available when built with the `zstd` feature
+    Zstd,
+}
+
+impl IndexCompression {
+    fn code(self) -> u8 {
+        match self {
+            IndexCompression::None => 0,
+            IndexCompression::Gzip => 1,
+            IndexCompression::Zstd => 2,
+        }
+    }
+
+    fn from_code(code: u8) -> Result<IndexCompression, Error> {
+        match code {
+            0 => Ok(IndexCompression::None),
+            1 => Ok(IndexCompression::Gzip),
+            2 => Ok(IndexCompression::Zstd),
+            _ => Err(failure::err_msg(format!("Unknown index compression codec {}", code))),
+        }
     }
 }
 
-pub fn read_transcripts(
-    reader: fasta::Reader<File>,
-) -> Result<(Vec<DnaString>, Vec<String>, HashMap<String, String>), Error> {
-    let mut seqs = Vec::new();
-    let mut transcript_counter = 0;
-    let mut tx_ids = Vec::new();
-    let mut tx_to_gene_map = HashMap::new();
+impl str::FromStr for IndexCompression {
+    type Err = Error;
 
-    let mut fasta_format: Option<u8> = None;
+    fn from_str(s: &str) -> Result<Self, Error> {
+        match s {
+            "none" => Ok(IndexCompression::None),
+            "gzip" => Ok(IndexCompression::Gzip),
+            "zstd" => Ok(IndexCompression::Zstd),
+            _ => Err(failure::err_msg(format!(
+                "Unknown index compression '{}', expected none, gzip or zstd", s
+            ))),
+        }
+    }
+}
 
-    info!("Starting reading the Fasta file\n");
-    for result in reader.records() {
-        // obtain record or fail with error
-        let record = result?;
+#[cfg(not(feature = "zstd"))]
+fn zstd_unsupported() -> PseudoalignerError {
+    PseudoalignerError::Other("zstd compressed indices need a build with the zstd feature".to_string())
+}
 
-        // Sequence
-        let dna_string = DnaString::from_acgt_bytes_hashn(record.seq(), record.id().as_bytes());
-        seqs.push(dna_string);
+/// Small description of an index, stored ahead of the index itself.
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct IndexMetadata {
+    pub kmer_length: usize,
+    pub num_transcripts: usize,
+    pub num_eq_classes: usize,
+    pub pkg_version: String,
+    /// Checksum of each reference file, if recorded
+    pub reference_checksums: Vec<ReferenceChecksum>,
+    /// Minimizer window of a sparse index
+    pub minimizer_window: Option<usize>,
+}
 
-        if let None = fasta_format {
-            fasta_format = detect_fasta_format(&record);
+/// Layout of `IndexMetadata` before format version 6.
+#[derive(Deserialize)]
+struct IndexMetadataV1 {
+    kmer_length: usize,
+    num_transcripts: usize,
+    num_eq_classes: usize,
+    pkg_version: String,
+}
+
+/// Layout of `IndexMetadata` in format version 6.
+#[derive(Deserialize)]
+struct IndexMetadataV2 {
+    kmer_length: usize,
+    num_transcripts: usize,
+    num_eq_classes: usize,
+    pkg_version: String,
+    reference_checksums: Vec<ReferenceChecksum>,
+}
+
+impl IndexMetadata {
+    pub fn new<K: Kmer>(index: &Pseudoaligner<K>) -> IndexMetadata {
+        IndexMetadata {
+            kmer_length: K::k(),
+            num_transcripts: index.tx_names.len(),
+            num_eq_classes: index.eq_classes.len(),
+            pkg_version: env!("CARGO_PKG_VERSION").to_string(),
+            reference_checksums: index.reference_checksums.clone(),
+            minimizer_window: index.minimizer_window,
         }
+    }
+}
+
+/// How `read_index_with_mode` brings the index into memory.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum IndexLoadMode {
+    /// Deserialize the whole index onto the heap.
+    Owned,
+    /// Memory-map the file and read the k-mer positions, the largest table
+    /// of the index, in place. The rest of the index is still deserialized.
+    Mmap,
+}
 
-        let (tx_id, gene_id) = extract_tx_gene_id(&record, fasta_format)?;
+/// Write `index` to `filename` uncompressed.
+pub fn write_index<K: Kmer + Sync + Send + Serialize, P: AsRef<Path> + Debug>(
+    index: &Pseudoaligner<K>,
+    filename: P,
+) -> Result<(), PseudoalignerError> {
+    write_index_with_compression(index, filename, IndexCompression::None)
+}
 
-        tx_ids.push(tx_id.clone());
-        tx_to_gene_map.insert(tx_id, gene_id);
+/// Write `index` to `filename` as magic bytes, format version, compression
+/// codec, metadata, and then, compressed with `compression`, the index
+/// itself and finally the k-mer positions section.
+pub fn write_index_with_compression<K: Kmer + Sync + Send + Serialize, P: AsRef<Path> + Debug>(
+    index: &Pseudoaligner<K>,
+    filename: P,
+    compression: IndexCompression,
+) -> Result<(), PseudoalignerError> {
+    let mut writer = BufWriter::new(File::create(&filename)?);
+    writer.write_all(INDEX_MAGIC)?;
+    writer.write_all(&INDEX_FORMAT_VERSION.to_le_bytes())?;
+    writer.write_all(&[compression.code()])?;
+    serialize_into(&mut writer, &IndexMetadata::new(index))?;
 
-        transcript_counter += 1;
-        if transcript_counter % 100 == 0 {
-            print!("\r Done reading {} sequences", transcript_counter);
-            io::stdout().flush().expect("Could not flush stdout");
+    match compression {
+        IndexCompression::None => write_index_payload(&mut writer, index)?,
+        IndexCompression::Gzip => {
+            let mut encoder = GzEncoder::new(&mut writer, Compression::default());
+            write_index_payload(&mut encoder, index)?;
+            encoder.finish()?;
         }
+        #[cfg(feature = "zstd")]
+        IndexCompression::Zstd => {
+            let mut encoder = zstd::Encoder::new(&mut writer, config::INDEX_ZSTD_LEVEL)?;
+            write_index_payload(&mut encoder, index)?;
+            encoder.finish()?;
+        }
+        #[cfg(not(feature = "zstd"))]
+        IndexCompression::Zstd => return Err(zstd_unsupported()),
     }
 
-    println!();
-    info!(
-        "Done reading the Fasta file; Found {} sequences",
-        transcript_counter
-    );
+    writer.flush()?;
+    Ok(())
+}
 
-    Ok((seqs, tx_ids, tx_to_gene_map))
+/// The index and its k-mer positions section.
+fn write_index_payload<K: Kmer + Sync + Send + Serialize, W: Write>(
+    mut writer: W,
+    index: &Pseudoaligner<K>,
+) -> Result<(), Error> {
+    serialize_into(&mut writer, index)?;
+
+    // Same bytes as bincode writes for a Vec<(u32, u32)>
+    let positions = index.kmer_positions();
+    writer.write_all(&(positions.len() as u64).to_le_bytes())?;
+    for slot in 0..positions.len() {
+        let (nid, offset) = positions.get(slot);
+        writer.write_all(&nid.to_le_bytes())?;
+        writer.write_all(&offset.to_le_bytes())?;
+    }
+    Ok(())
 }
 
-pub fn detect_fasta_format(record: &fasta::Record) -> Option<u8> {
-    let id_tokens: Vec<&str> = record.id().split('|').collect();
-    if id_tokens.len() == 9 {
-        return Some(config::FASTA_FORMAT_GENCODE)
+/// Check the magic bytes and format version at the start of an index file,
+/// and read its compression codec and metadata.
+fn read_index_header<R: Read>(reader: &mut R) -> Result<(u32, IndexCompression, IndexMetadata), Error> {
+    let mut magic = [0u8; 8];
+    if reader.read_exact(&mut magic).is_err() || &magic != INDEX_MAGIC {
+        return Err(PseudoalignerError::NotAnIndex.into());
     }
-    let desc_tokens: Vec<&str> = record.desc().unwrap().split(' ').collect();
-    if desc_tokens.len() == 5 {
-        Some(config::FASTA_FORMAT_ENSEMBL)
+
+    let mut version = [0u8; 4];
+    reader.read_exact(&mut version).map_err(|_| PseudoalignerError::NotAnIndex)?;
+    let version = u32::from_le_bytes(version);
+    if version == 0 || version > INDEX_FORMAT_VERSION {
+        return Err(PseudoalignerError::IndexVersionMismatch {
+            found: version,
+            expected: INDEX_FORMAT_VERSION,
+        }.into());
+    }
+
+    let compression = if version >= 12 {
+        let mut code = [0u8; 1];
+        reader.read_exact(&mut code)?;
+        IndexCompression::from_code(code[0])?
+    } else {
+        IndexCompression::None
+    };
+
+    let metadata = match version {
+        1..=5 => {
+            let old: IndexMetadataV1 = deserialize_from(reader)?;
+            IndexMetadata {
+                kmer_length: old.kmer_length,
+                num_transcripts: old.num_transcripts,
+                num_eq_classes: old.num_eq_classes,
+                pkg_version: old.pkg_version,
+                reference_checksums: Vec::new(),
+                minimizer_window: None,
+            }
+        }
+        6 => {
+            let old: IndexMetadataV2 = deserialize_from(reader)?;
+            IndexMetadata {
+                kmer_length: old.kmer_length,
+                num_transcripts: old.num_transcripts,
+                num_eq_classes: old.num_eq_classes,
+                pkg_version: old.pkg_version,
+                reference_checksums: old.reference_checksums,
+                minimizer_window: None,
+            }
+        }
+        _ => deserialize_from(reader)?,
+    };
+    Ok((version, compression, metadata))
+}
+
+/// Layout of the index payload in format version 1.
+#[derive(Deserialize)]
+struct PseudoalignerV1<K: Kmer> {
+    dbg: DebruijnGraph<K, EqClassIdType>,
+    eq_classes: Vec<Vec<u32>>,
+    dbg_index: NoKeyBoomHashMap<K, (u32, u32)>,
+    tx_names: Vec<String>,
+    tx_gene_mapping: HashMap<String, String>,
+}
+
+/// Leading fields of the index payload in every format since version 2.
+#[derive(Deserialize)]
+struct PseudoalignerV2<K: Kmer> {
+    dbg: DebruijnGraph<K, EqClassIdType>,
+    eq_classes: Vec<Vec<u32>>,
+    kmer_mphf: Mphf<K>,
+    tx_names: Vec<String>,
+    tx_gene_mapping: HashMap<String, String>,
+}
+
+/// Deserialize an index stored with format `version`. Layouts written by
+/// older versions are converted to the current `Pseudoaligner` here, leaving
+/// fields they predate empty. The returned flag is set when the k-mer
+/// positions were part of the payload; otherwise the caller reads them.
+fn migrate_index<K: Kmer + Sync + Send + DeserializeOwned, R: Read>(
+    version: u32,
+    mut reader: R,
+) -> Result<(Pseudoaligner<K>, bool), Error> {
+    match version {
+        1 => {
+            let old: PseudoalignerV1<K> = deserialize_from(reader)?;
+            let index = Pseudoaligner::new(
+                old.dbg, old.eq_classes, old.dbg_index, old.tx_names, old.tx_gene_mapping
+            );
+            Ok((index, true))
+        }
+        INDEX_FORMAT_VERSION => Ok((deserialize_from(reader)?, false)),
+        2..=INDEX_FORMAT_VERSION => {
+            let old: PseudoalignerV2<K> = deserialize_from(&mut reader)?;
+            let dbg_index = NoKeyBoomHashMap::new_with_mphf(old.kmer_mphf, Vec::new());
+            let mut index = Pseudoaligner::new(
+                old.dbg, old.eq_classes, dbg_index, old.tx_names, old.tx_gene_mapping
+            );
+
+            if version >= 3 {
+                index.source_names = deserialize_from(&mut reader)?;
+                index.tx_sources = deserialize_from(&mut reader)?;
+            }
+            if version >= 4 {
+                index.tx_lengths = deserialize_from(&mut reader)?;
+            }
+            if version >= 5 {
+                index.stranded = deserialize_from(&mut reader)?;
+            }
+            if version >= 7 {
+                index.minimizer_window = deserialize_from(&mut reader)?;
+            }
+            if version >= 8 {
+                index.tx_groups = deserialize_from(&mut reader)?;
+            }
+            if version >= 9 {
+                index.gene_names = deserialize_from(&mut reader)?;
+            }
+            if version >= 10 {
+                index.tx_checksums = deserialize_from(&mut reader)?;
+            }
+            if version >= 11 {
+                index.tx_node_starts = deserialize_from(&mut reader)?;
+            }
+            if version >= 13 {
+                index.tx_gc_contents = deserialize_from(&mut reader)?;
+            }
+
+            Ok((index, false))
+        }
+        found => Err(PseudoalignerError::IndexVersionMismatch {
+            found,
+            expected: INDEX_FORMAT_VERSION,
+        }.into()),
+    }
+}
+
+/// Read only the metadata of an index written by `write_index`.
+pub fn read_index_metadata<P: AsRef<Path>>(filename: P) -> Result<IndexMetadata, PseudoalignerError> {
+    let mut reader = BufReader::new(File::open(&filename)?);
+    let (_, _, metadata) = read_index_header(&mut reader)?;
+    Ok(metadata)
+}
+
+/// Read an index written by `write_index` onto the heap.
+pub fn read_index<K: Kmer + Sync + Send + DeserializeOwned, P: AsRef<Path>>(
+    filename: P,
+) -> Result<Pseudoaligner<K>, PseudoalignerError> {
+    read_index_with_mode(filename, IndexLoadMode::Owned)
+}
+
+/// Read an index written by `write_index`, loading it as requested by `mode`.
+/// Compressed indices are always read onto the heap.
+pub fn read_index_with_mode<K: Kmer + Sync + Send + DeserializeOwned, P: AsRef<Path>>(
+    filename: P,
+    mode: IndexLoadMode,
+) -> Result<Pseudoaligner<K>, PseudoalignerError> {
+    let file = File::open(&filename)?;
+    let mut reader = BufReader::new(file);
+    let (version, compression, metadata) = read_index_header(&mut reader)?;
+
+    if mode == IndexLoadMode::Owned || compression != IndexCompression::None {
+        if mode == IndexLoadMode::Mmap {
+            warn!("The index is compressed, so it is read into memory instead of memory-mapped");
+        }
+        let mut reader: Box<dyn Read> = match compression {
+            IndexCompression::None => Box::new(reader),
+            IndexCompression::Gzip => Box::new(MultiGzDecoder::new(reader)),
+            #[cfg(feature = "zstd")]
+            IndexCompression::Zstd => Box::new(zstd::Decoder::new(reader)?),
+            #[cfg(not(feature = "zstd"))]
+            IndexCompression::Zstd => return Err(zstd_unsupported()),
+        };
+        let (mut index, has_positions) = migrate_index(version, &mut reader)?;
+        index.reference_checksums = metadata.reference_checksums;
+        if !has_positions {
+            let positions: Vec<(u32, u32)> = deserialize_from(&mut reader)?;
+            index.set_kmer_positions(KmerPositions::Owned(positions));
+        }
+        return Ok(index);
+    }
+
+    // The map is only valid as long as nobody truncates the file; validate
+    // every length up front so that later reads stay in bounds.
+    let map = unsafe { Mmap::map(reader.get_ref())? };
+    let file_len = map.len();
+    let (version, index, positions_start) = {
+        let mut reader: &[u8] = &map;
+        let (version, _, metadata) = read_index_header(&mut reader)?;
+        let (mut index, has_positions) = migrate_index::<K, _>(version, &mut reader)?;
+        index.reference_checksums = metadata.reference_checksums;
+        if has_positions {
+            return Ok(index);
+        }
+        (version, index, file_len - reader.len())
+    };
+
+    if positions_start + 8 > file_len {
+        return Err(PseudoalignerError::TruncatedIndex { expected: positions_start + 8, found: file_len });
+    }
+    let mut len_bytes = [0u8; 8];
+    len_bytes.copy_from_slice(&map[positions_start..positions_start + 8]);
+    // a corrupt length may not even fit in memory; report it as truncation
+    // rather than letting the size computation overflow
+    let len = usize::try_from(u64::from_le_bytes(len_bytes)).unwrap_or(usize::MAX);
+
+    debug!("Memory-mapped {} k-mer positions of a version {} index", len, version);
+    let mut index = index;
+    index.set_kmer_positions(KmerPositions::mapped(map, positions_start + 8, len)?);
+    Ok(index)
+}
+
+/// Prefixes I/O errors with the path of the file being read.
+struct PathReader<R> {
+    inner: R,
+    path: PathBuf,
+}
+
+impl<R: Read> Read for PathReader<R> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        let path = &self.path;
+        self.inner.read(buf).map_err(|err| {
+            io::Error::new(err.kind(), format!("{}: {}", path.display(), err))
+        })
+    }
+}
+
+/// Open a possibly gzipped file, detected from its magic bytes rather than its
+/// extension. Files of several gzip members, like bgzip output, are read in full.
+/// Read errors, including corrupt gzip data, name the file.
+pub fn open_with_gz<P: AsRef<Path>>(p: P) -> Result<Box<dyn Read + Send>, Error> {
+    let path = p.as_ref().to_path_buf();
+    let (inner, _) = open_detecting_gz(&path)?;
+    Ok(Box::new(PathReader { inner, path }))
+}
+
+/// `open_with_gz` without naming the file in read errors, also telling
+/// whether the file is gzipped.
+fn open_detecting_gz<P: AsRef<Path>>(p: P) -> Result<(Box<dyn Read + Send>, bool), Error> {
+    let path = p.as_ref().to_path_buf();
+    let file = File::open(&path)
+        .map_err(|err| failure::err_msg(format!("Could not open {}: {}", path.display(), err)))?;
+    detect_gz(file)
+}
+
+/// `reader`, decompressed if it starts with the gzip magic bytes, and
+/// whether it does; for readers without a file name to go by, like stdin.
+fn detect_gz<R: Read + Send + 'static>(mut reader: R) -> Result<(Box<dyn Read + Send>, bool), Error> {
+    // a pipe may hand out fewer bytes than asked for at a time
+    let mut magic = Vec::with_capacity(2);
+    (&mut reader).take(2).read_to_end(&mut magic)?;
+    let is_gzip = magic == [0x1f, 0x8b];
+
+    let buf_reader = BufReader::with_capacity(32 * 1024, io::Cursor::new(magic).chain(reader));
+    if is_gzip {
+        Ok((Box::new(MultiGzDecoder::new(buf_reader)), true))
+    } else {
+        Ok((Box::new(buf_reader), false))
+    }
+}
+
+/// Mate pairs of the interleaved, possibly gzipped, FASTQ file `path`:
+/// records 1 and 2 are the first pair, 3 and 4 the second, and so on. An
+/// odd number of records, mates whose ids differ once a `/1` or `/2`
+/// suffix is dropped, or mates numbered the wrong way round by their suffix
+/// or Casava `1:`/`2:` description, are errors.
+pub fn interleaved_fastq_reader(
+    path: &Path,
+) -> Result<impl Iterator<Item = Result<(DnaString, DnaString), Error>>, Error> {
+    let pairs = interleaved_fastq_records(path)?;
+    Ok(pairs.map(|pair| pair.map(|(mate1, mate2)| (fastq_seq(&mate1), fastq_seq(&mate2)))))
+}
+
+/// The mate records behind `interleaved_fastq_reader`.
+pub fn interleaved_fastq_records(
+    path: &Path,
+) -> Result<impl Iterator<Item = Result<(fastq::Record, fastq::Record), Error>> + Send, Error> {
+    let mut records = fastq_records(path)?;
+    let path = path.to_path_buf();
+
+    Ok(std::iter::from_fn(move || {
+        let mate1 = match records.next()? {
+            Ok(record) => record,
+            Err(err) => return Some(Err(err)),
+        };
+        let mate2 = match records.next() {
+            Some(Ok(record)) => record,
+            Some(Err(err)) => return Some(Err(err)),
+            None => {
+                return Some(Err(failure::err_msg(format!(
+                    "{} has an odd number of records: {} has no mate",
+                    path.display(), mate1.id()
+                ))))
+            }
+        };
+        if !are_mates(&mate1, &mate2) {
+            return Some(Err(failure::err_msg(format!(
+                "Records {} and {} in {} are not mates 1 and 2 of a pair",
+                mate1.id(), mate2.id(), path.display()
+            ))));
+        }
+        Some(Ok((mate1, mate2)))
+    }))
+}
+
+/// Mate pairs of the possibly gzipped FASTQ files `path1` and `path2`,
+/// records at the same position in both being mates. Files of different
+/// lengths, and mates that do not pair up as in `interleaved_fastq_reader`,
+/// are errors. Mates piped to stdin need to be interleaved instead, so
+/// neither path may be `-`.
+pub fn paired_fastq_records(
+    path1: &Path,
+    path2: &Path,
+) -> Result<impl Iterator<Item = Result<(fastq::Record, fastq::Record), Error>> + Send, Error> {
+    if path1 == Path::new(STDIN_PATH) || path2 == Path::new(STDIN_PATH) {
+        return Err(failure::err_msg("Mate pairs read from stdin need to be interleaved in one stream"));
+    }
+    let mut records1 = fastq_records(path1)?;
+    let mut records2 = fastq_records(path2)?;
+    let (path1, path2) = (path1.to_path_buf(), path2.to_path_buf());
+
+    Ok(std::iter::from_fn(move || {
+        let (mate1, mate2) = match (records1.next(), records2.next()) {
+            (None, None) => return None,
+            (Some(Ok(mate1)), Some(Ok(mate2))) => (mate1, mate2),
+            (Some(Err(err)), _) | (_, Some(Err(err))) => return Some(Err(err)),
+            (Some(Ok(_)), None) | (None, Some(Ok(_))) => {
+                return Some(Err(failure::err_msg(format!(
+                    "{} and {} have different numbers of records",
+                    path1.display(), path2.display()
+                ))))
+            }
+        };
+        if !are_mates(&mate1, &mate2) {
+            return Some(Err(failure::err_msg(format!(
+                "Records {} in {} and {} in {} are not mates 1 and 2 of a pair",
+                mate1.id(), path1.display(), mate2.id(), path2.display()
+            ))));
+        }
+        Some(Ok((mate1, mate2)))
+    }))
+}
+
+/// `id` without a `/1` or `/2` mate suffix.
+pub(crate) fn mate_id(id: &str) -> &str {
+    id.strip_suffix("/1").or_else(|| id.strip_suffix("/2")).unwrap_or(id)
+}
+
+/// Mate number of `record`, from a `/1` or `/2` id suffix or a Casava
+/// `1:` or `2:` description, if it has one.
+fn mate_number(record: &fastq::Record) -> Option<u8> {
+    let desc = record.desc().unwrap_or("");
+    if record.id().ends_with("/1") || desc.starts_with("1:") {
+        Some(1)
+    } else if record.id().ends_with("/2") || desc.starts_with("2:") {
+        Some(2)
     } else {
         None
     }
 }
 
-pub fn extract_tx_gene_id(record: &fasta::Record, fasta_format: Option<u8>) -> Result<(String, String), Error>{
-    match fasta_format {
-        Some(config::FASTA_FORMAT_GENCODE) => {
-            let id_tokens: Vec<&str> = record.id().split('|').collect();
-            let tx_id = id_tokens[0].to_string();
-            let gene_id = id_tokens[1].to_string();
-            Ok((tx_id, gene_id))
-        },
-        Some(config::FASTA_FORMAT_ENSEMBL) => {
-            let tx_id = record.id().to_string();
-            let desc_tokens: Vec<&str> = record.desc().unwrap().split(' ').collect();
-            let gene_tmp: Vec<&str> = desc_tokens[2].split(':').collect();
-            let gene_id = gene_tmp[1].to_string();
-            Ok((tx_id, gene_id))
-        },
-        _ => Err(failure::err_msg("Unknown fasta format in extract_tx_gene_id."))
+/// Whether `mate1` and `mate2` have the same id, and are not numbered as
+/// another mate than their own.
+fn are_mates(mate1: &fastq::Record, mate2: &fastq::Record) -> bool {
+    mate_id(mate1.id()) == mate_id(mate2.id())
+        && mate_number(mate1) != Some(2)
+        && mate_number(mate2) != Some(1)
+}
+
+/// Sequence of a FASTQ record.
+pub fn fastq_seq(record: &fastq::Record) -> DnaString {
+    DnaString::from_dna_string(str::from_utf8(record.seq()).unwrap())
+}
+
+/// Bytes read at a time by a `ThreadedReader`, and the number of such
+/// chunks it reads ahead.
+const READ_AHEAD_CHUNK: usize = 64 * 1024;
+const READ_AHEAD_CHUNKS: usize = 16;
+
+/// Reads `inner` on a thread of its own, ahead of the consumer, so that
+/// e.g. decompression overlaps with the work on what was already read.
+struct ThreadedReader {
+    chunks: mpsc::Receiver<io::Result<Vec<u8>>>,
+    chunk: Vec<u8>,
+    pos: usize,
+    done: bool,
+}
+
+impl ThreadedReader {
+    fn new<R: Read + Send + 'static>(mut inner: R) -> ThreadedReader {
+        let (tx, rx) = mpsc::sync_channel(READ_AHEAD_CHUNKS);
+        thread::spawn(move || loop {
+            let mut chunk = vec![0; READ_AHEAD_CHUNK];
+            match inner.read(&mut chunk) {
+                Ok(0) => break,
+                Ok(len) => {
+                    chunk.truncate(len);
+                    // the consumer hung up
+                    if tx.send(Ok(chunk)).is_err() {
+                        break;
+                    }
+                }
+                Err(ref err) if err.kind() == io::ErrorKind::Interrupted => continue,
+                Err(err) => {
+                    let _ = tx.send(Err(err));
+                    break;
+                }
+            }
+        });
+        ThreadedReader { chunks: rx, chunk: Vec::new(), pos: 0, done: false }
     }
 }
 
-pub fn get_next_record<R: io::Read>(
-    reader: &Arc<Mutex<fastq::Records<R>>>,
-) -> Option<Result<fastq::Record, io::Error>> {
-    let mut lock = reader.lock().unwrap();
-    lock.next()
+impl Read for ThreadedReader {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        if self.pos == self.chunk.len() {
+            if self.done {
+                return Ok(0);
+            }
+            match self.chunks.recv() {
+                Ok(Ok(chunk)) => {
+                    self.chunk = chunk;
+                    self.pos = 0;
+                }
+                Ok(Err(err)) => {
+                    self.done = true;
+                    return Err(err);
+                }
+                Err(_) => {
+                    self.done = true;
+                    return Ok(0);
+                }
+            }
+        }
+
+        let len = std::cmp::min(buf.len(), self.chunk.len() - self.pos);
+        buf[..len].copy_from_slice(&self.chunk[self.pos..self.pos + len]);
+        self.pos += len;
+        Ok(len)
+    }
 }
 
-pub fn open_file<P: AsRef<Path>>(
-    filename: &str, outdir: P
-) -> Result<File, Error> {
-    let out_fn = outdir.as_ref().join(filename);
-    let outfile = File::create(&out_fn)?;
-    Ok(outfile)
+/// Records of a plain or gzipped FASTQ file. Gzipped files are
+/// decompressed on a thread of their own, overlapping with the work on the
+/// records. Read errors, including corrupt gzip data, name the file.
+pub fn fastq_records(path: &Path) -> Result<impl Iterator<Item = Result<fastq::Record, Error>> + Send, Error> {
+    let reader = open_reads(path)?;
+    let path = path.to_path_buf();
+    Ok(fastq::Reader::new(reader)
+        .records()
+        .map(move |record| record.map_err(|err| failure::err_msg(format!("{}: {}", path.display(), err)))))
 }
 
-pub fn write_mappability_tsv<P: AsRef<Path>>(
-    records: Vec<MappabilityRecord>,
-    outdir: P
-) -> Result<(), Error> {
+/// Path of the reads that stands for stdin.
+const STDIN_PATH: &str = "-";
 
-    let mut outfile = open_file("tx_mappability.tsv", outdir)?;
+/// Plain or gzipped file of reads, or stdin for `-`, gzipped ones
+/// decompressed on a thread of their own.
+fn open_reads(path: &Path) -> Result<Box<dyn Read + Send>, Error> {
+    let (reader, is_gzip) = if path == Path::new(STDIN_PATH) {
+        detect_gz(io::stdin())?
+    } else {
+        open_detecting_gz(path)?
+    };
+    if is_gzip {
+        Ok(Box::new(ThreadedReader::new(reader)))
+    } else {
+        Ok(reader)
+    }
+}
 
-    outfile.write_all(MAPPABILITY_HEADER_STRING.as_bytes())?;
+/// Sequences of the plain or gzipped FASTQ reads piped to stdin. Stdin has
+/// no file name, so gzip is told by its magic bytes.
+pub fn fastq_reader_stdin() -> Result<impl Iterator<Item = Result<DnaString, Error>>, Error> {
+    fastq_reader_from(io::stdin())
+}
 
-    for record in records {
-        write!(outfile,
-               "{}\t{}\t{}\t{}\t{}\n",
-               record.tx_name,
-               record.gene_name,
-               record.total_kmer_count(),
-               record.fraction_unique_tx(),
-               record.fraction_unique_gene()
+/// Sequences of the plain or gzipped FASTQ reads of `reader`, read as
+/// `fastq_reader_stdin` reads stdin.
+pub fn fastq_reader_from<R: Read + Send + 'static>(
+    reader: R,
+) -> Result<impl Iterator<Item = Result<DnaString, Error>>, Error> {
+    let (reader, is_gzip) = detect_gz(reader)?;
+    let reader: Box<dyn Read + Send> = if is_gzip { Box::new(ThreadedReader::new(reader)) } else { reader };
+    Ok(fastq::Reader::new(reader).records().map(|record| match record {
+        Ok(record) => Ok(fastq_seq(&record)),
+        Err(err) => Err(failure::err_msg(format!("Error in reading reads: {}", err))),
+    }))
+}
 
-        )?;
+/// Format of a file of reads.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum ReadFormat {
+    /// FASTA if the file starts with `>`, FASTQ if it starts with `@`.
+    #[default]
+    Auto,
+    Fastq,
+    Fasta,
+}
+
+impl str::FromStr for ReadFormat {
+    type Err = Error;
+
+    fn from_str(s: &str) -> Result<Self, Error> {
+        match s {
+            "auto" => Ok(ReadFormat::Auto),
+            "fastq" => Ok(ReadFormat::Fastq),
+            "fasta" => Ok(ReadFormat::Fasta),
+            _ => Err(failure::err_msg(format!(
+                "Unknown read format '{}', expected auto, fastq or fasta", s
+            ))),
+        }
     }
+}
 
-    Ok(())
+/// Records of a plain or gzipped file of reads in `format`, like
+/// `fastq_records`. FASTA records come without qualities.
+pub fn read_records(
+    path: &Path,
+    format: ReadFormat,
+) -> Result<Box<dyn Iterator<Item = Result<fastq::Record, Error>> + Send>, Error> {
+    let mut reader = BufReader::new(open_reads(path)?);
+    let format = match format {
+        ReadFormat::Auto => {
+            let buf = reader.fill_buf().map_err(|err| failure::err_msg(format!("{}: {}", path.display(), err)))?;
+            match buf.iter().find(|c| !c.is_ascii_whitespace()) {
+                Some(b'>') => ReadFormat::Fasta,
+                Some(b'@') | None => ReadFormat::Fastq,
+                Some(_) => {
+                    return Err(failure::err_msg(format!(
+                        "{} is neither FASTA nor FASTQ: it does not start with > or @",
+                        path.display()
+                    )))
+                }
+            }
+        }
+        format => format,
+    };
+
+    let path = path.to_path_buf();
+    if format == ReadFormat::Fasta {
+        Ok(Box::new(fasta::Reader::new(reader).records().map(move |record| match record {
+            Ok(record) => Ok(fastq::Record::with_attrs(record.id(), record.desc(), record.seq(), &[])),
+            Err(err) => Err(failure::err_msg(format!("{}: {}", path.display(), err))),
+        })))
+    } else {
+        Ok(Box::new(fastq::Reader::new(reader).records().map(move |record| {
+            record.map_err(|err| failure::err_msg(format!("{}: {}", path.display(), err)))
+        })))
+    }
+}
+
+/// Open a plain or gzipped FASTA file.
+pub fn open_fasta(path: &Path) -> Result<fasta::Reader<Box<dyn Read + Send>>, Error> {
+    Ok(fasta::Reader::new(open_with_gz(path)?))
+}
+
+/// What to do when a transcript id occurs more than once in the reference.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub enum DuplicatePolicy {
+    /// Fail with an error naming the duplicated ids.
+    #[default]
+    Error,
+    /// Keep the first record with a given id and skip the later ones.
+    KeepFirst,
+    /// Keep every record, renaming later copies to `<id>_dup1`, `<id>_dup2`, ...
+    RenameWithSuffix,
+}
+
+impl str::FromStr for DuplicatePolicy {
+    type Err = Error;
+
+    fn from_str(s: &str) -> Result<Self, Error> {
+        match s {
+            "error" => Ok(DuplicatePolicy::Error),
+            "keep-first" => Ok(DuplicatePolicy::KeepFirst),
+            "rename" => Ok(DuplicatePolicy::RenameWithSuffix),
+            _ => Err(failure::err_msg(format!(
+                "Unknown duplicate policy '{}', expected error, keep-first or rename", s
+            ))),
+        }
+    }
+}
+
+/// What to do with indexed transcripts missing from a transcript to gene map.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub enum MissingGenePolicy {
+    /// Fail with an error naming a missing transcript.
+    #[default]
+    Error,
+    /// Assign them to the gene `pseudoaligner::UNKNOWN_GENE`.
+    Unknown,
+}
+
+impl str::FromStr for MissingGenePolicy {
+    type Err = Error;
+
+    fn from_str(s: &str) -> Result<Self, Error> {
+        match s {
+            "error" => Ok(MissingGenePolicy::Error),
+            "unknown" => Ok(MissingGenePolicy::Unknown),
+            _ => Err(failure::err_msg(format!(
+                "Unknown missing gene policy '{}', expected error or unknown", s
+            ))),
+        }
+    }
+}
+
+/// How reference FASTA records are turned into transcripts.
+#[derive(Clone, Debug, Default, Serialize, Deserialize)]
+pub struct ReferenceOptions {
+    /// What to do with repeated transcript ids.
+    pub duplicates: DuplicatePolicy,
+    /// Split sequences at runs of ambiguous bases rather than hashing them
+    /// to pseudo-random bases. Honoured by `build_index_from_fasta`, which
+    /// then reads the reference with the `*_segments` functions.
+    pub split_at_n: bool,
+    /// Skip records whose sequence is shorter than this.
+    pub min_seq_len: usize,
+    /// Only index these 0-based, half-open `(start, end)` spans of the
+    /// listed transcripts; other transcripts are indexed in full.
+    pub spans: Option<HashMap<String, Vec<(usize, usize)>>>,
+    /// Mask windows of `config::LOW_COMPLEXITY_WINDOW` bases whose
+    /// dinucleotide entropy is below this many bits, cutting sequences at
+    /// masked bases like `split_at_n` does at runs of N.
+    pub mask_entropy: Option<f64>,
+    /// Keep transcripts too short to have a k-mer as transcripts that no
+    /// read maps to, so that transcript ids line up with external metadata,
+    /// instead of dropping them.
+    pub keep_short: bool,
+    /// Treat soft-masked (lowercase) bases like runs of N under
+    /// `split_at_n`, cutting sequences around them, instead of indexing
+    /// them like uppercase bases.
+    pub honor_softmask: bool,
+    /// Drop the `.N` version suffix of transcript and gene ids parsed from
+    /// Gencode and Ensembl headers.
+    pub strip_versions: bool,
+    /// Drop the transcripts with these ids before building the graph,
+    /// numbering the others densely in their order.
+    #[serde(serialize_with = "serialize_sorted_set")]
+    pub exclude: HashSet<String>,
+    /// Where the build reports its progress
+    #[serde(skip)]
+    pub progress: Progress,
+}
+
+/// Write `set` in order, so that the hash of a build configuration does not
+/// depend on the iteration order of its hash sets.
+fn serialize_sorted_set<S: Serializer>(set: &HashSet<String>, serializer: S) -> Result<S::Ok, S::Error> {
+    set.iter().collect::<BTreeSet<_>>().serialize(serializer)
+}
+
+/// First `<tx_id>_dupN` that is not yet in `seen`.
+fn duplicate_suffix_id<V>(tx_id: &str, seen: &HashMap<String, V>) -> String {
+    let mut copy = 1;
+    loop {
+        let candidate = format!("{}_dup{}", tx_id, copy);
+        if !seen.contains_key(&candidate) {
+            return candidate;
+        }
+        copy += 1;
+    }
+}
+
+pub fn read_transcripts<R: Read>(
+    reader: fasta::Reader<R>,
+) -> Result<(Vec<DnaString>, Vec<String>, HashMap<String, String>), Error> {
+    read_transcripts_with_options(reader, &ReferenceOptions::default())
+}
+
+/// Read the transcripts of a FASTA file, skipping records shorter than
+/// `options.min_seq_len` and resolving repeated transcript ids according to
+/// `options.duplicates`. The returned vectors are always aligned.
+pub fn read_transcripts_with_options<R: Read>(
+    reader: fasta::Reader<R>,
+    options: &ReferenceOptions,
+) -> Result<(Vec<DnaString>, Vec<String>, HashMap<String, String>), Error> {
+    let (seqs, tx_ids, gene_ids, _) = read_fasta_records(reader, options, hashed_sequence, None, None)?;
+    let file_ids = vec![0; seqs.len()];
+    let (seqs, tx_ids, tx_to_gene_map, _) =
+        resolve_duplicates(seqs, tx_ids, gene_ids, file_ids, options.duplicates, &[])?;
+    Ok((seqs, tx_ids, tx_to_gene_map))
+}
+
+/// Read the transcripts of a FASTA file like `read_transcripts_with_options`,
+/// splitting every sequence at runs of ambiguous bases instead of replacing
+/// them with pseudo-random bases. Each transcript gets the list of its
+/// unambiguous segments, possibly empty.
+pub fn read_transcript_segments<R: Read>(
+    reader: fasta::Reader<R>,
+    options: &ReferenceOptions,
+) -> Result<(Vec<Vec<DnaString>>, Vec<String>, HashMap<String, String>), Error> {
+    let (seqs, tx_ids, gene_ids, _) = read_fasta_records(reader, options, split_sequence, None, None)?;
+    let file_ids = vec![0; seqs.len()];
+    let (seqs, tx_ids, tx_to_gene_map, _) =
+        resolve_duplicates(seqs, tx_ids, gene_ids, file_ids, options.duplicates, &[])?;
+    Ok((seqs, tx_ids, tx_to_gene_map))
+}
+
+/// Checksum of the records of a reference FASTA file.
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq)]
+pub struct ReferenceChecksum {
+    /// Hex SHA-256 over the id and sequence of every record, in file order
+    pub sha256: String,
+    pub num_records: usize,
+}
+
+/// Streaming computation of a `ReferenceChecksum`. Only ids and sequences
+/// are hashed, so compression and line wrapping do not change the checksum.
+#[derive(Default)]
+pub(crate) struct ReferenceDigest {
+    hasher: Sha256,
+    num_records: usize,
+}
+
+impl ReferenceDigest {
+    pub(crate) fn add(&mut self, record: &fasta::Record) {
+        self.add_line(record.id().as_bytes());
+        self.add_line(record.seq());
+        self.num_records += 1;
+    }
+
+    /// Hash `bytes` followed by a newline.
+    pub(crate) fn add_line(&mut self, bytes: &[u8]) {
+        self.hasher.update(bytes);
+        self.hasher.update(b"\n");
+    }
+
+    pub(crate) fn finish(self) -> ReferenceChecksum {
+        let sha256 = self
+            .hasher
+            .finalize()
+            .iter()
+            .map(|byte| format!("{:02x}", byte))
+            .collect();
+        ReferenceChecksum { sha256, num_records: self.num_records }
+    }
+
+    /// The first 8 bytes of the SHA-256, for checksums stored per transcript.
+    pub(crate) fn finish_short(self) -> u64 {
+        let mut bytes = [0u8; 8];
+        bytes.copy_from_slice(&self.hasher.finalize()[..8]);
+        u64::from_le_bytes(bytes)
+    }
+}
+
+/// Fraction of the bases of `seq` that are G or C, 0 for an empty sequence.
+pub fn gc_content(seq: &DnaString) -> f64 {
+    gc_fraction(seq.iter())
+}
+
+/// `gc_content` of the bases of a reference record, of either case, leaving
+/// out the ambiguous bases a build hashes or splits at.
+pub(crate) fn record_gc_content(record: &fasta::Record) -> f64 {
+    gc_fraction(record.seq().iter().filter_map(|base| match base.to_ascii_uppercase() {
+        b'A' => Some(0),
+        b'C' => Some(1),
+        b'G' => Some(2),
+        b'T' => Some(3),
+        _ => None,
+    }))
+}
+
+/// Fraction of the 2-bit `bases` that are G or C, 0 without bases.
+pub(crate) fn gc_fraction<I: Iterator<Item = u8>>(bases: I) -> f64 {
+    let (gc, total) = bases.fold((0, 0), |(gc, total), base| match base {
+        1 | 2 => (gc + 1, total + 1),
+        _ => (gc, total + 1),
+    });
+    if total == 0 {
+        return 0.0;
+    }
+    gc as f64 / total as f64
+}
+
+/// Checksum of the indexed sequence of a transcript: the `ReferenceDigest`
+/// of its segments, one per line, shortened to 8 bytes.
+pub fn transcript_checksum(segments: &[DnaString]) -> u64 {
+    let mut digest = ReferenceDigest::default();
+    for seq in segments {
+        let bases: Vec<u8> = seq.iter().map(|base| b"ACGT"[base as usize]).collect();
+        digest.add_line(&bases);
+    }
+    digest.finish_short()
+}
+
+/// Checksums of the reference FASTA files `paths`.
+pub fn reference_checksums(paths: &[PathBuf]) -> Result<Vec<ReferenceChecksum>, Error> {
+    let mut checksums = Vec::with_capacity(paths.len());
+    for path in paths {
+        let mut digest = ReferenceDigest::default();
+        for result in open_fasta(path)?.records() {
+            digest.add(&result?);
+        }
+        checksums.push(digest.finish());
+    }
+    Ok(checksums)
+}
+
+/// Outcome of `verify_reference`.
+#[derive(Debug, Clone)]
+pub struct ReferenceVerification {
+    /// Checksums recorded in the index, one per reference file
+    pub expected: Vec<ReferenceChecksum>,
+    /// Checksums of the given FASTA files
+    pub found: Vec<ReferenceChecksum>,
+}
+
+impl ReferenceVerification {
+    pub fn matches(&self) -> bool {
+        self.expected == self.found
+    }
+}
+
+impl fmt::Display for ReferenceVerification {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        if self.matches() {
+            let num_records: usize = self.found.iter().map(|c| c.num_records).sum();
+            return write!(f, "Reference matches the index ({} records)", num_records);
+        }
+
+        write!(f, "Reference does not match the index")?;
+        if self.expected.len() != self.found.len() {
+            write!(
+                f, "\n  index built from {} files, {} given",
+                self.expected.len(), self.found.len()
+            )?;
+        }
+        for (file_idx, (expected, found)) in self.expected.iter().zip(&self.found).enumerate() {
+            if expected == found {
+                continue;
+            }
+            write!(f, "\n  file {}: ", file_idx + 1)?;
+            if expected.num_records != found.num_records {
+                write!(
+                    f, "{} records in the index, {} in the FASTA ({:+}); ",
+                    expected.num_records, found.num_records,
+                    found.num_records as i64 - expected.num_records as i64
+                )?;
+            }
+            write!(f, "sha256 {} in the index, {} in the FASTA", expected.sha256, found.sha256)?;
+        }
+        Ok(())
+    }
+}
+
+/// Compare the reference checksums recorded in `index` with those of
+/// `fasta_paths`, which must be given in the order the index was built from.
+pub fn verify_reference<K: Kmer>(
+    index: &Pseudoaligner<K>,
+    fasta_paths: &[PathBuf],
+) -> Result<ReferenceVerification, Error> {
+    if index.reference_checksums.is_empty() {
+        return Err(failure::err_msg("The index does not record reference checksums"));
+    }
+    Ok(ReferenceVerification {
+        expected: index.reference_checksums.clone(),
+        found: reference_checksums(fasta_paths)?,
+    })
+}
+
+/// Summary of the records skipped while reading a FASTA file.
+#[derive(Clone, Debug, Default, PartialEq, Serialize, Deserialize)]
+pub struct SkippedRecords {
+    pub count: usize,
+    pub total_bases: usize,
+    /// Ids of the first few skipped records.
+    pub example_ids: Vec<String>,
+}
+
+impl SkippedRecords {
+    const MAX_EXAMPLES: usize = 5;
+
+    fn add(&mut self, record: &fasta::Record) {
+        self.count += 1;
+        self.total_bases += record.seq().len();
+        if self.example_ids.len() < Self::MAX_EXAMPLES {
+            self.example_ids.push(record.id().to_string());
+        }
+    }
+
+    /// Add the records skipped from another file.
+    fn merge(&mut self, other: SkippedRecords) {
+        self.count += other.count;
+        self.total_bases += other.total_bases;
+        let room = Self::MAX_EXAMPLES.saturating_sub(self.example_ids.len());
+        self.example_ids.extend(other.example_ids.into_iter().take(room));
+    }
+}
+
+impl fmt::Display for SkippedRecords {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{} records, {} bases", self.count, self.total_bases)?;
+        if !self.example_ids.is_empty() {
+            write!(f, " (e.g. {}", self.example_ids.join(", "))?;
+            if self.count > self.example_ids.len() {
+                write!(f, ", ...")?;
+            }
+            write!(f, ")")?;
+        }
+        Ok(())
+    }
+}
+
+/// Sequence of a record with ambiguous bases hashed to pseudo-random bases.
+pub(crate) fn hashed_sequence(record: &fasta::Record) -> DnaString {
+    DnaString::from_acgt_bytes_hashn(record.seq(), record.id().as_bytes())
+}
+
+/// Sequence of a record split at every run of ambiguous bases.
+pub(crate) fn split_sequence(record: &fasta::Record) -> Vec<DnaString> {
+    split_at_ambiguous(record.seq())
+}
+
+/// Sequence of a record split at soft-masked (lowercase) bases and at every
+/// run of ambiguous bases, with the number of soft-masked bases.
+pub(crate) fn softmask_split_sequence(record: &fasta::Record) -> (Vec<DnaString>, usize) {
+    let segments = record.seq()
+        .split(|base| !b"ACGT".contains(base))
+        .filter(|segment| !segment.is_empty())
+        .map(DnaString::from_acgt_bytes)
+        .collect();
+    (segments, softmasked_bases(record))
+}
+
+/// Sequence of a record split at soft-masked (lowercase) bases, with the
+/// remaining ambiguous bases hashed to pseudo-random bases, and the number
+/// of soft-masked bases.
+pub(crate) fn softmask_hashed_sequence(record: &fasta::Record) -> (Vec<DnaString>, usize) {
+    let segments = record.seq()
+        .split(|base| base.is_ascii_lowercase())
+        .filter(|segment| !segment.is_empty())
+        .map(|segment| DnaString::from_acgt_bytes_hashn(segment, record.id().as_bytes()))
+        .collect();
+    (segments, softmasked_bases(record))
+}
+
+/// Sequence of a record split at every run of ambiguous bases, none of
+/// them soft-masked.
+pub(crate) fn split_sequence_unmasked(record: &fasta::Record) -> (Vec<DnaString>, usize) {
+    (split_sequence(record), 0)
+}
+
+fn softmasked_bases(record: &fasta::Record) -> usize {
+    record.seq().iter().filter(|base| base.is_ascii_lowercase()).count()
+}
+
+/// Bases `start..end` of `s`. Panics if the range is out of bounds.
+pub fn subsequence(s: &DnaString, start: usize, end: usize) -> DnaString {
+    s.slice(start, end).to_owned()
+}
+
+/// Read transcript spans from a tab-separated `tx_id start end` file with
+/// 0-based, half-open coordinates. Empty lines and lines starting with `#`
+/// are ignored; a transcript may have several lines.
+pub fn read_spans<P: AsRef<Path>>(path: P) -> Result<HashMap<String, Vec<(usize, usize)>>, Error> {
+    let reader = BufReader::new(open_with_gz(&path)?);
+    let mut spans = HashMap::new();
+
+    for (line_no, line) in reader.lines().enumerate() {
+        let line = line?;
+        if line.is_empty() || line.starts_with('#') {
+            continue;
+        }
+
+        let fields: Vec<&str> = line.split('\t').collect();
+        let span = if fields.len() >= 3 {
+            match (fields[1].parse::<usize>(), fields[2].parse::<usize>()) {
+                (Ok(start), Ok(end)) if start < end => Some((start, end)),
+                _ => None,
+            }
+        } else {
+            None
+        };
+
+        match span {
+            Some(span) => spans.entry(fields[0].to_string()).or_insert_with(Vec::new).push(span),
+            None => return Err(failure::err_msg(format!(
+                "{}:{}: expected 'tx_id<TAB>start<TAB>end' with start < end, found '{}'",
+                path.as_ref().display(), line_no + 1, line
+            ))),
+        }
+    }
+
+    Ok(spans)
+}
+
+/// Read a kallisto-style transcript to gene map: a tab-separated file of
+/// `tx_id gene_id [gene_name]` lines. Returns the gene of every transcript
+/// and the names of the genes that have one. Empty lines and lines starting
+/// with `#` are ignored.
+pub fn read_t2g<P: AsRef<Path>>(path: P) -> Result<(HashMap<String, String>, HashMap<String, String>), Error> {
+    let reader = BufReader::new(open_with_gz(&path)?);
+    let mut tx_genes = HashMap::new();
+    let mut gene_names = HashMap::new();
+
+    for (line_no, line) in reader.lines().enumerate() {
+        let line = line?;
+        if line.is_empty() || line.starts_with('#') {
+            continue;
+        }
+
+        let fields: Vec<&str> = line.split('\t').collect();
+        if fields.len() < 2 || fields[1].is_empty() {
+            return Err(failure::err_msg(format!(
+                "{}:{}: expected 'tx_id<TAB>gene_id[<TAB>gene_name]', found '{}'",
+                path.as_ref().display(), line_no + 1, line
+            )));
+        }
+        if let Some(gene_id) = tx_genes.insert(fields[0].to_string(), fields[1].to_string()) {
+            if gene_id != fields[1] {
+                return Err(failure::err_msg(format!(
+                    "{}:{}: {} is in genes {} and {}",
+                    path.as_ref().display(), line_no + 1, fields[0], gene_id, fields[1]
+                )));
+            }
+        }
+        if let Some(name) = fields.get(2).filter(|name| !name.is_empty()) {
+            gene_names.insert(fields[1].to_string(), name.to_string());
+        }
+    }
+
+    Ok((tx_genes, gene_names))
+}
+
+/// Read a list of ids, one per line. Empty lines and lines starting with
+/// `#` are ignored.
+pub fn read_ids<P: AsRef<Path>>(path: P) -> Result<HashSet<String>, Error> {
+    let reader = BufReader::new(open_with_gz(&path)?);
+    let mut ids = HashSet::new();
+    for line in reader.lines() {
+        let line = line?;
+        let id = line.trim();
+        if !id.is_empty() && !id.starts_with('#') {
+            ids.insert(id.to_string());
+        }
+    }
+    Ok(ids)
+}
+
+/// Read transcript groups from a tab-separated `tx_id group` file. Empty
+/// lines and lines starting with `#` are ignored.
+pub fn read_groups<P: AsRef<Path>>(path: P) -> Result<HashMap<String, String>, Error> {
+    let reader = BufReader::new(open_with_gz(&path)?);
+    let mut groups = HashMap::new();
+
+    for (line_no, line) in reader.lines().enumerate() {
+        let line = line?;
+        if line.is_empty() || line.starts_with('#') {
+            continue;
+        }
+
+        let fields: Vec<&str> = line.split('\t').collect();
+        if fields.len() < 2 || fields[1].is_empty() {
+            return Err(failure::err_msg(format!(
+                "{}:{}: expected 'tx_id<TAB>group', found '{}'",
+                path.as_ref().display(), line_no + 1, line
+            )));
+        }
+        if let Some(group) = groups.insert(fields[0].to_string(), fields[1].to_string()) {
+            if group != fields[1] {
+                return Err(failure::err_msg(format!(
+                    "{}:{}: {} is in groups {} and {}",
+                    path.as_ref().display(), line_no + 1, fields[0], group, fields[1]
+                )));
+            }
+        }
+    }
+
+    Ok(groups)
+}
+
+/// A sample of a batch: its name, its reads and the mates of its reads,
+/// if paired.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Sample {
+    pub name: String,
+    pub reads: PathBuf,
+    pub mates: Option<PathBuf>,
+}
+
+/// Read samples from a tab-separated `name reads [mates]` sample sheet.
+/// Empty lines and lines starting with `#` are ignored. Names make the
+/// directory of the outputs of a sample, so they must be distinct and can
+/// not hold a `/`.
+pub fn read_sample_sheet<P: AsRef<Path>>(path: P) -> Result<Vec<Sample>, Error> {
+    let reader = BufReader::new(open_with_gz(&path)?);
+    let mut samples: Vec<Sample> = Vec::new();
+    let mut names = HashSet::new();
+
+    for (line_no, line) in reader.lines().enumerate() {
+        let line = line?;
+        if line.is_empty() || line.starts_with('#') {
+            continue;
+        }
+
+        let fields: Vec<&str> = line.split('\t').collect();
+        let valid_name = !fields[0].is_empty() && !fields[0].contains('/') && fields[0] != "." && fields[0] != "..";
+        if fields.len() < 2 || fields.len() > 3 || !valid_name || fields[1..].iter().any(|field| field.is_empty()) {
+            return Err(failure::err_msg(format!(
+                "{}:{}: expected 'name<TAB>reads[<TAB>mates]', found '{}'",
+                path.as_ref().display(), line_no + 1, line
+            )));
+        }
+        if !names.insert(fields[0].to_string()) {
+            return Err(failure::err_msg(format!(
+                "{}:{}: sample {} is listed twice",
+                path.as_ref().display(), line_no + 1, fields[0]
+            )));
+        }
+        samples.push(Sample {
+            name: fields[0].to_string(),
+            reads: PathBuf::from(fields[1]),
+            mates: fields.get(2).map(PathBuf::from),
+        });
+    }
+
+    Ok(samples)
+}
+
+/// Maximal runs of A, C, G and T (in either case) of `seq`, as `DnaString`s.
+pub fn split_at_ambiguous(seq: &[u8]) -> Vec<DnaString> {
+    seq.split(|base| !b"ACGTacgt".contains(base))
+    .filter(|segment| !segment.is_empty())
+    .map(DnaString::from_acgt_bytes)
+    .collect()
+}
+
+/// Cut the windows of `window` bases of `seq` whose dinucleotide entropy is
+/// below `min_entropy` bits (between 0 for a homopolymer and 4) out of it,
+/// returning the remaining segments and the number of bases masked.
+pub fn mask_low_complexity(seq: &DnaString, window: usize, min_entropy: f64) -> (Vec<DnaString>, usize) {
+    let len = seq.len();
+    if window < 2 || len < window {
+        return (vec![seq.clone()], 0);
+    }
+
+    let pair = |pos: usize| ((seq.get(pos) << 2) | seq.get(pos + 1)) as usize;
+    let num_pairs = (window - 1) as f64;
+    let mut counts = [0usize; 16];
+    for pos in 0..window - 1 {
+        counts[pair(pos)] += 1;
+    }
+
+    let mut masked = vec![false; len];
+    let mut masked_until = 0;
+    for start in 0..=len - window {
+        if start > 0 {
+            counts[pair(start - 1)] -= 1;
+            counts[pair(start + window - 2)] += 1;
+        }
+
+        let entropy: f64 = counts
+            .iter()
+            .filter(|&&count| count > 0)
+            .map(|&count| {
+                let p = count as f64 / num_pairs;
+                -p * p.log2()
+            })
+            .sum();
+        if entropy < min_entropy {
+            for is_masked in &mut masked[std::cmp::max(start, masked_until)..start + window] {
+                *is_masked = true;
+            }
+            masked_until = start + window;
+        }
+    }
+
+    let mut segments = Vec::new();
+    let mut segment_start = None;
+    for (pos, &is_masked) in masked.iter().enumerate() {
+        match (segment_start, is_masked) {
+            (None, false) => segment_start = Some(pos),
+            (Some(start), true) => {
+                segments.push(subsequence(seq, start, pos));
+                segment_start = None;
+            }
+            _ => (),
+        }
+    }
+    if let Some(start) = segment_start {
+        segments.push(subsequence(seq, start, len));
+    }
+
+    let num_masked = masked.iter().filter(|&&is_masked| is_masked).count();
+    (segments, num_masked)
+}
+
+/// Sequences, converted with `to_seq`, transcript ids and gene ids of every
+/// record of at least `options.min_seq_len` bases, in file order, and the
+/// names of the genes whose headers give one. Records of an unknown header
+/// format are their own gene, under their raw id. The shorter records are
+/// added to `skipped` if given.
+fn read_fasta_records<R: Read, S, F: Fn(&fasta::Record) -> S>(
+    reader: fasta::Reader<R>,
+    options: &ReferenceOptions,
+    to_seq: F,
+    mut digest: Option<&mut ReferenceDigest>,
+    skipped: Option<&mut SkippedRecords>,
+) -> Result<(Vec<S>, Vec<String>, Vec<String>, HashMap<String, String>), Error> {
+    let min_seq_len = options.min_seq_len;
+    let mut seqs = Vec::new();
+    let mut transcript_counter = 0;
+    let mut tx_ids = Vec::new();
+    let mut gene_ids = Vec::new();
+    let mut gene_names = HashMap::new();
+    let mut unknown_format = 0;
+    let mut skipped_counter = 0;
+    let mut short_records = SkippedRecords::default();
+
+    let mut fasta_format: Option<u8> = None;
+
+    info!("Starting reading the Fasta file\n");
+    options.progress.start(Phase::ReadSequences, None);
+    for result in reader.records() {
+        // obtain record or fail with error
+        let record = result?;
+        if let Some(ref mut digest) = digest {
+            digest.add(&record);
+        }
+
+        if record.seq().iter().all(|b| b.is_ascii_whitespace()) {
+            warn!("Skipping fasta record {} with an empty sequence", record.id());
+            skipped_counter += 1;
+            continue;
+        }
+
+        if record.seq().len() < min_seq_len {
+            short_records.add(&record);
+            continue;
+        }
+
+        // Sequence
+        seqs.push(to_seq(&record));
+
+        if let None = fasta_format {
+            fasta_format = detect_fasta_format(&record);
+        }
+
+        let (tx_id, gene_id) = match fasta_format {
+            Some(_) => extract_tx_gene_id(&record, fasta_format)?,
+            None => {
+                unknown_format += 1;
+                (record.id().to_string(), record.id().to_string())
+            }
+        };
+        let (tx_id, gene_id) = if options.strip_versions && fasta_format.is_some() {
+            (strip_version(&tx_id), strip_version(&gene_id))
+        } else {
+            (tx_id, gene_id)
+        };
+        if let Some(gene_name) = extract_gene_name(&record, fasta_format) {
+            gene_names.insert(gene_id.clone(), gene_name);
+        }
+
+        tx_ids.push(tx_id);
+        gene_ids.push(gene_id);
+
+        transcript_counter += 1;
+        if transcript_counter % 100 == 0 {
+            options.progress.update(Phase::ReadSequences, transcript_counter);
+        }
+    }
+    options.progress.finish(Phase::ReadSequences);
+
+    info!(
+        "Done reading the Fasta file; Found {} sequences, skipped {} empty records",
+        transcript_counter, skipped_counter
+    );
+    if short_records.count > 0 {
+        info!(
+            "Skipped sequences shorter than {} bases: {}",
+            min_seq_len, short_records
+        );
+    }
+    if let Some(skipped) = skipped {
+        skipped.merge(short_records);
+    }
+    if unknown_format > 0 {
+        warn!(
+            "{} records have neither Gencode nor Ensembl headers; using their ids as transcript and gene ids",
+            unknown_format
+        );
+    }
+
+    Ok((seqs, tx_ids, gene_ids, gene_names))
+}
+
+/// Apply `duplicates` to repeated transcript ids, dropping or renaming
+/// records in all the parallel vectors alike. `paths` names the file of
+/// every `file_ids` entry in error messages, if given.
+fn resolve_duplicates<S>(
+    seqs: Vec<S>,
+    tx_ids: Vec<String>,
+    gene_ids: Vec<String>,
+    file_ids: Vec<usize>,
+    duplicates: DuplicatePolicy,
+    paths: &[PathBuf],
+) -> Result<(Vec<S>, Vec<String>, HashMap<String, String>, Vec<usize>), Error> {
+    let mut kept_seqs = Vec::with_capacity(seqs.len());
+    let mut kept_tx_ids = Vec::with_capacity(tx_ids.len());
+    let mut kept_file_ids = Vec::with_capacity(file_ids.len());
+    // transcript id -> (gene id, index of the first file it was seen in)
+    let mut seen: HashMap<String, (String, usize)> = HashMap::new();
+    let mut collisions = Vec::new();
+
+    let records = seqs.into_iter().zip(tx_ids).zip(gene_ids).zip(file_ids);
+    for (((seq, tx_id), gene_id), file_id) in records {
+        let tx_id = match seen.get(&tx_id).map(|&(_, first_file_id)| first_file_id) {
+            None => tx_id,
+            Some(first_file_id) => match duplicates {
+                DuplicatePolicy::Error => {
+                    if paths.is_empty() {
+                        collisions.push(tx_id);
+                    } else {
+                        collisions.push(format!(
+                            "{} ({:?} and {:?})",
+                            tx_id, paths[first_file_id], paths[file_id]
+                        ));
+                    }
+                    continue;
+                }
+                DuplicatePolicy::KeepFirst => {
+                    warn!("Skipping duplicate transcript {}", tx_id);
+                    continue;
+                }
+                DuplicatePolicy::RenameWithSuffix => {
+                    let new_id = duplicate_suffix_id(&tx_id, &seen);
+                    warn!("Renaming duplicate transcript {} to {}", tx_id, new_id);
+                    new_id
+                }
+            },
+        };
+
+        seen.insert(tx_id.clone(), (gene_id, file_id));
+        kept_seqs.push(seq);
+        kept_tx_ids.push(tx_id);
+        kept_file_ids.push(file_id);
+    }
+
+    if !collisions.is_empty() {
+        return Err(PseudoalignerError::DuplicateTranscripts(collisions.join(", ")).into());
+    }
+
+    let tx_to_gene_map = seen
+        .into_iter()
+        .map(|(tx_id, (gene_id, _))| (tx_id, gene_id))
+        .collect();
+    Ok((kept_seqs, kept_tx_ids, tx_to_gene_map, kept_file_ids))
+}
+
+/// Read the transcripts of several FASTA files, in order, as if they were one file.
+/// The last returned vector holds, for every transcript, the index of the file it came from.
+pub fn read_multiple_fasta(
+    paths: &[PathBuf],
+) -> Result<(Vec<DnaString>, Vec<String>, Vec<usize>), Error> {
+    let (seqs, tx_ids, _, file_ids) = read_multiple_fasta_with_options(paths, &ReferenceOptions::default())?;
+    Ok((seqs, tx_ids, file_ids))
+}
+
+/// Like `read_multiple_fasta`, filtering records and resolving transcript
+/// ids repeated within or across files as `read_transcripts_with_options` does,
+/// and also returning the transcript to gene mapping.
+pub fn read_multiple_fasta_with_options(
+    paths: &[PathBuf],
+    options: &ReferenceOptions,
+) -> Result<(Vec<DnaString>, Vec<String>, HashMap<String, String>, Vec<usize>), Error> {
+    read_multiple_fasta_records(paths, options, hashed_sequence, None, None, None)
+}
+
+/// Like `read_multiple_fasta_with_options`, splitting sequences at runs of
+/// ambiguous bases as `read_transcript_segments` does.
+pub fn read_multiple_fasta_segments(
+    paths: &[PathBuf],
+    options: &ReferenceOptions,
+) -> Result<(Vec<Vec<DnaString>>, Vec<String>, HashMap<String, String>, Vec<usize>), Error> {
+    read_multiple_fasta_records(paths, options, split_sequence, None, None, None)
+}
+
+/// Records of `paths` converted with `to_seq`, as `read_multiple_fasta_with_options`
+/// returns them. The checksum of every file is pushed to `checksums` if
+/// given, the gene names found in the headers to `gene_names` and the
+/// records too short to keep to `skipped`.
+pub(crate) fn read_multiple_fasta_records<S, F: Fn(&fasta::Record) -> S>(
+    paths: &[PathBuf],
+    options: &ReferenceOptions,
+    to_seq: F,
+    mut checksums: Option<&mut Vec<ReferenceChecksum>>,
+    mut gene_names: Option<&mut HashMap<String, String>>,
+    mut skipped: Option<&mut SkippedRecords>,
+) -> Result<(Vec<S>, Vec<String>, HashMap<String, String>, Vec<usize>), Error> {
+    let mut seqs = Vec::new();
+    let mut tx_ids = Vec::new();
+    let mut gene_ids = Vec::new();
+    let mut file_ids = Vec::new();
+
+    for (file_id, path) in paths.iter().enumerate() {
+        info!("Reading transcripts from {:?}", path);
+        let reader = open_fasta(path)?;
+        let mut digest = ReferenceDigest::default();
+        let (file_seqs, file_tx_ids, file_gene_ids, file_gene_names) = read_fasta_records(
+            reader,
+            options,
+            &to_seq,
+            checksums.as_ref().map(|_| &mut digest),
+            skipped.as_deref_mut(),
+        )?;
+        if let Some(ref mut checksums) = checksums {
+            checksums.push(digest.finish());
+        }
+        if let Some(ref mut gene_names) = gene_names {
+            gene_names.extend(file_gene_names);
+        }
+
+        file_ids.extend(file_tx_ids.iter().map(|_| file_id));
+        seqs.extend(file_seqs);
+        tx_ids.extend(file_tx_ids);
+        gene_ids.extend(file_gene_ids);
+    }
+
+    resolve_duplicates(seqs, tx_ids, gene_ids, file_ids, options.duplicates, paths)
+}
+
+pub fn detect_fasta_format(record: &fasta::Record) -> Option<u8> {
+    let id_tokens: Vec<&str> = record.id().split('|').collect();
+    if id_tokens.len() == 9 {
+        return Some(config::FASTA_FORMAT_GENCODE)
+    }
+    let desc_tokens: Vec<&str> = record.desc().unwrap_or("").split(' ').collect();
+    if desc_tokens.len() == 5 || (desc_tokens.len() > 5 && desc_tokens[2].starts_with("gene:")) {
+        Some(config::FASTA_FORMAT_ENSEMBL)
+    } else {
+        None
+    }
+}
+
+pub fn extract_tx_gene_id(record: &fasta::Record, fasta_format: Option<u8>) -> Result<(String, String), Error>{
+    match fasta_format {
+        Some(config::FASTA_FORMAT_GENCODE) => {
+            let id_tokens: Vec<&str> = record.id().split('|').collect();
+            if id_tokens.len() < 2 {
+                return Err(PseudoalignerError::MalformedHeader(record.id().to_string()).into());
+            }
+            let tx_id = id_tokens[0].to_string();
+            let gene_id = id_tokens[1].to_string();
+            Ok((tx_id, gene_id))
+        },
+        Some(config::FASTA_FORMAT_ENSEMBL) => {
+            let tx_id = record.id().to_string();
+            let desc = record.desc()
+                .ok_or_else(|| PseudoalignerError::MissingDescription(tx_id.clone()))?;
+            let desc_tokens: Vec<&str> = desc.split(' ').collect();
+            let gene_tmp: Vec<&str> = match desc_tokens.get(2) {
+                Some(token) => token.split(':').collect(),
+                None => Vec::new(),
+            };
+            if gene_tmp.len() < 2 {
+                return Err(PseudoalignerError::MalformedHeader(tx_id).into());
+            }
+            let gene_id = gene_tmp[1].to_string();
+            Ok((tx_id, gene_id))
+        },
+        _ => Err(PseudoalignerError::UnknownFastaFormat(record.id().to_string()).into())
+    }
+}
+
+/// Name (symbol) of the gene of `record`: the sixth field of a Gencode id,
+/// or the `gene_symbol:` field of an Ensembl description.
+pub fn extract_gene_name(record: &fasta::Record, fasta_format: Option<u8>) -> Option<String> {
+    let gene_name = match fasta_format {
+        Some(config::FASTA_FORMAT_GENCODE) => record.id().split('|').nth(5),
+        Some(config::FASTA_FORMAT_ENSEMBL) => record
+            .desc()
+            .unwrap_or("")
+            .split(' ')
+            .find_map(|token| token.strip_prefix("gene_symbol:")),
+        _ => None,
+    };
+    gene_name.filter(|name| !name.is_empty()).map(|name| name.to_string())
+}
+
+/// `id` without its version, the digits after its last `.`: `ENST1.2`
+/// becomes `ENST1` and `ENST1.2_PAR_Y` becomes `ENST1_PAR_Y`. Ids without a
+/// version are returned as they are.
+pub fn strip_version(id: &str) -> String {
+    if let Some(dot) = id.rfind('.') {
+        let rest = &id[dot + 1..];
+        let num_digits = rest.bytes().take_while(|b| b.is_ascii_digit()).count();
+        if num_digits > 0 {
+            return format!("{}{}", &id[..dot], &rest[num_digits..]);
+        }
+    }
+    id.to_string()
+}
+
+/// A uniformly random subset of `min(target, reads.len())` reads, in their
+/// original order. The same `seed` always selects the same reads.
+pub fn downsample_reads<T>(reads: Vec<T>, target: usize, seed: u64) -> Vec<T> {
+    if target >= reads.len() {
+        return reads;
+    }
+
+    let mut rng = ChaCha8Rng::seed_from_u64(seed);
+    let mut keep = vec![false; reads.len()];
+    for idx in rand::seq::index::sample(&mut rng, reads.len(), target) {
+        keep[idx] = true;
+    }
+
+    reads
+        .into_iter()
+        .zip(keep)
+        .filter_map(|(read, keep)| if keep { Some(read) } else { None })
+        .collect()
+}
+
+/// Next item of an iterator shared between threads, such as the records of a
+/// `fastq::Reader`.
+pub fn get_next_record<I: Iterator>(reader: &Arc<Mutex<I>>) -> Option<I::Item> {
+    let mut lock = reader.lock().unwrap();
+    lock.next()
+}
+
+pub fn open_file<P: AsRef<Path>>(
+    filename: &str, outdir: P
+) -> Result<File, Error> {
+    let out_fn = outdir.as_ref().join(filename);
+    let outfile = File::create(&out_fn)?;
+    Ok(outfile)
+}
+
+pub fn write_mappability_tsv<P: AsRef<Path>>(
+    records: Vec<MappabilityRecord>,
+    outdir: P
+) -> Result<(), Error> {
+
+    let mut outfile = open_file("tx_mappability.tsv", outdir)?;
+
+    outfile.write_all(MAPPABILITY_HEADER_STRING.as_bytes())?;
+
+    for record in records {
+        write!(outfile,
+               "{}\t{}\t{}\t{}\t{}\n",
+               record.tx_name,
+               record.gene_name,
+               record.total_kmer_count(),
+               record.fraction_unique_tx(),
+               record.fraction_unique_gene()
+
+        )?;
+    }
+
+    Ok(())
+}
+
+/// Write the equivalence classes of `index` to `eq_classes.tsv`, one line
+/// per class in class id order: the id followed by the names of its
+/// transcripts, tab separated. The transcript index to name mapping goes to
+/// `transcripts.tsv`.
+pub fn write_eq_classes_tsv<K: Kmer + Sync + Send, P: AsRef<Path>>(
+    index: &Pseudoaligner<K>,
+    outdir: P,
+) -> Result<(), Error> {
+    let mut eq_class_file = BufWriter::new(open_file("eq_classes.tsv", &outdir)?);
+    for (eq_class_id, eq_class) in index.eq_classes() {
+        write!(eq_class_file, "{}", eq_class_id)?;
+        for &tx_id in eq_class {
+            write!(eq_class_file, "\t{}", index.tx_names[tx_id as usize])?;
+        }
+        writeln!(eq_class_file)?;
+    }
+    eq_class_file.flush()?;
+
+    let mut tx_file = BufWriter::new(open_file("transcripts.tsv", &outdir)?);
+    for (tx_id, tx_name) in index.tx_names.iter().enumerate() {
+        writeln!(tx_file, "{}\t{}", tx_id, tx_name)?;
+    }
+    tx_file.flush()?;
+
+    Ok(())
+}
+
+/// Write read counts per equivalence class, as from `count_reads`, as TSV
+/// of `transcripts count` lines, sorted by class.
+pub fn write_eq_class_counts_tsv<K: Kmer + Sync + Send, W: Write>(
+    writer: W,
+    index: &Pseudoaligner<K>,
+    eq_class_counts: &HashMap<Vec<u32>, u64>,
+) -> Result<(), Error> {
+    write_counts_matrix_tsv(writer, index, &[("count", eq_class_counts)])
+}
+
+/// Write the read counts per equivalence class of several samples as one
+/// TSV matrix: a header of `transcripts` and the sample names, then one
+/// row per class any sample has reads of, sorted by class, with its
+/// transcript names and the counts of every sample.
+pub fn write_counts_matrix_tsv<K: Kmer + Sync + Send, W: Write>(
+    mut writer: W,
+    index: &Pseudoaligner<K>,
+    samples: &[(&str, &HashMap<Vec<u32>, u64>)],
+) -> Result<(), Error> {
+    write!(writer, "transcripts")?;
+    for &(name, _) in samples {
+        write!(writer, "\t{}", name)?;
+    }
+    writeln!(writer)?;
+
+    let eq_classes: BTreeSet<&Vec<u32>> = samples.iter().flat_map(|&(_, counts)| counts.keys()).collect();
+    for eq_class in eq_classes {
+        let tx_names: Vec<&str> = eq_class.iter().map(|&tx_id| index.tx_names[tx_id as usize].as_str()).collect();
+        write!(writer, "{}", tx_names.join(","))?;
+        for &(_, counts) in samples {
+            write!(writer, "\t{}", counts.get(eq_class).cloned().unwrap_or(0))?;
+        }
+        writeln!(writer)?;
+    }
+    writer.flush()?;
+    Ok(())
+}
+
+/// Write the `confusion_matrix` of `index` as TSV: a header of the
+/// transcript names, then one row per transcript, its name followed by its
+/// counts.
+pub fn write_confusion_matrix_tsv<K: Kmer + Sync + Send, W: Write>(
+    mut writer: W,
+    index: &Pseudoaligner<K>,
+    matrix: &[Vec<u32>],
+) -> Result<(), Error> {
+    write!(writer, "transcript")?;
+    for tx_name in &index.tx_names {
+        write!(writer, "\t{}", tx_name)?;
+    }
+    writeln!(writer)?;
+
+    for (tx_name, row) in index.tx_names.iter().zip(matrix) {
+        write!(writer, "{}", tx_name)?;
+        for count in row {
+            write!(writer, "\t{}", count)?;
+        }
+        writeln!(writer)?;
+    }
+    writer.flush()?;
+    Ok(())
+}
+
+/// Write transcript abundances in salmon's `quant.sf` format: a
+/// `Name Length EffectiveLength TPM NumReads` header and one tab separated
+/// row per transcript. All the slices must have one entry per transcript.
+pub fn write_quant_sf<W: Write>(
+    mut writer: W,
+    tx_ids: &[String],
+    lengths: &[usize],
+    eff_lengths: &[f64],
+    tpm: &[f64],
+    num_reads: &[f64],
+) -> Result<(), Error> {
+    let num_tx = tx_ids.len();
+    if lengths.len() != num_tx || eff_lengths.len() != num_tx || tpm.len() != num_tx || num_reads.len() != num_tx {
+        return Err(failure::err_msg(format!(
+            "quant.sf columns differ in length: {} names, {} lengths, {} effective lengths, {} TPMs, {} read counts",
+            num_tx, lengths.len(), eff_lengths.len(), tpm.len(), num_reads.len()
+        )));
+    }
+
+    writeln!(writer, "Name\tLength\tEffectiveLength\tTPM\tNumReads")?;
+    for tx in 0..num_tx {
+        writeln!(
+            writer, "{}\t{}\t{:.3}\t{:.6}\t{:.3}",
+            tx_ids[tx], lengths[tx], eff_lengths[tx], tpm[tx], num_reads[tx]
+        )?;
+    }
+    Ok(())
+}
+
+/// Writes the mapping of every read as a line of TSV, with the columns `read
+/// status eq_class coverage read_length transcripts assigned sources strand
+/// matched_kmers valid_kmers kmer_ratio`. The status is `mapped` for reads
+/// with a non-empty equivalence class and enough coverage, `rescued` for those
+/// that only have enough by counting gaps between matches, `unmapped`
+/// otherwise, also for reads with too low a k-mer ratio; the class is given by
+/// its id when it is one of the index, and otherwise, when it only comes of
+/// intersecting the classes of several nodes, by an id past those of the
+/// index, numbered in the order such classes first occur in the run. Its
+/// transcript names are cut after `max_tx_names` with a count of the rest. The
+/// transcript the read was assigned to at random, by the seed of the run, is
+/// `assigned`, and the reference files of the class, as `eq_class_sources`
+/// gives them, are `sources`. The strand, `+` or `-`, is only known for reads
+/// that map, or would map reverse complemented. Missing values are `NA`.
+pub struct ReadTsvWriter {
+    writer: Box<dyn Write + Send>,
+    eq_class_ids: HashMap<Vec<u32>, EqClassIdType>,
+    next_eq_class_id: EqClassIdType,
+    max_tx_names: usize,
+}
+
+impl ReadTsvWriter {
+    /// Write to `writer`, starting with the header line.
+    pub fn new<W: Write + Send + 'static, K: Kmer + Sync + Send>(
+        writer: W,
+        index: &Pseudoaligner<K>,
+        max_tx_names: usize,
+    ) -> Result<ReadTsvWriter, Error> {
+        let mut writer: Box<dyn Write + Send> = Box::new(writer);
+        writeln!(
+            writer,
+            "read\tstatus\teq_class\tcoverage\tread_length\ttranscripts\tassigned\tsources\tstrand\tmatched_kmers\tvalid_kmers\tkmer_ratio"
+        )?;
+        let eq_class_ids = index.eq_classes().map(|(id, eq_class)| (eq_class.to_vec(), id)).collect();
+        let next_eq_class_id = index.eq_classes().count() as EqClassIdType;
+        Ok(ReadTsvWriter { writer, eq_class_ids, next_eq_class_id, max_tx_names })
+    }
+
+    /// Write to the file `path`, gzipped if its name ends in `.gz`.
+    pub fn create<P: AsRef<Path>, K: Kmer + Sync + Send>(
+        path: P,
+        index: &Pseudoaligner<K>,
+        max_tx_names: usize,
+    ) -> Result<ReadTsvWriter, Error> {
+        ReadTsvWriter::new(create_maybe_gz(path)?, index, max_tx_names)
+    }
+
+    /// Write the line of a read of `read_length` bases mapped to `eq_class`
+    /// with `coverage`, and the `detail` of the mapping.
+    pub fn write_read<K: Kmer + Sync + Send>(
+        &mut self,
+        index: &Pseudoaligner<K>,
+        read_id: &str,
+        read_length: usize,
+        eq_class: &[u32],
+        coverage: usize,
+        detail: ReadDetail,
+    ) -> Result<(), Error> {
+        let status = if detail.low_kmer_ratio {
+            "unmapped"
+        } else if detail.rescued {
+            "rescued"
+        } else if index.is_confident(eq_class, coverage) {
+            "mapped"
+        } else {
+            "unmapped"
+        };
+        let eq_class_id = if eq_class.is_empty() {
+            "NA".to_string()
+        } else {
+            match self.eq_class_ids.get(eq_class) {
+                Some(id) => id.to_string(),
+                None => {
+                    let id = self.next_eq_class_id;
+                    self.eq_class_ids.insert(eq_class.to_vec(), id);
+                    self.next_eq_class_id += 1;
+                    id.to_string()
+                }
+            }
+        };
+
+        let mut tx_names: Vec<&str> = eq_class
+            .iter()
+            .take(self.max_tx_names)
+            .map(|&tx| index.tx_names[tx as usize].as_str())
+            .collect();
+        let num_rest = eq_class.len() - tx_names.len();
+        let rest = format!("+{}", num_rest);
+        if num_rest > 0 {
+            tx_names.push(&rest);
+        }
+        let tx_names = if eq_class.is_empty() { "NA".to_string() } else { tx_names.join(",") };
+        let strand = detail.strand.map_or("NA", |strand| strand.symbol());
+        let kmer_ratio = detail.kmer_score.ratio().map_or("NA".to_string(), |ratio| format!("{:.4}", ratio));
+        let assigned = detail.assigned.map_or("NA", |tx_id| index.tx_names[tx_id as usize].as_str());
+        let sources = index.eq_class_sources(eq_class);
+        let sources = if sources.is_empty() { "NA".to_string() } else { sources.join(",") };
+
+        writeln!(
+            self.writer, "{}\t{}\t{}\t{}\t{}\t{}\t{}\t{}\t{}\t{}\t{}\t{}",
+            read_id, status, eq_class_id, coverage, read_length, tx_names, assigned, sources, strand,
+            detail.kmer_score.matched_kmers, detail.kmer_score.valid_kmers, kmer_ratio
+        )?;
+        Ok(())
+    }
+
+    /// Flush the lines written so far.
+    pub fn flush(&mut self) -> Result<(), Error> {
+        self.writer.flush()?;
+        Ok(())
+    }
+}
+
+/// Create the file `path` for writing, gzipped if its name ends in `.gz`.
+pub fn create_maybe_gz<P: AsRef<Path>>(path: P) -> Result<Box<dyn Write + Send>, Error> {
+    let path = path.as_ref();
+    let file = BufWriter::new(File::create(path)?);
+    if path.extension().is_some_and(|ext| ext == "gz") {
+        Ok(Box::new(GzEncoder::new(file, Compression::default())))
+    } else {
+        Ok(Box::new(file))
+    }
+}
+
+/// Writes reads that do not map as FASTQ, with their names, sequences and
+/// qualities as read, and their `UnmappedReason` code appended to the
+/// description as `unmapped=<code>`. Reads without qualities get `I` for
+/// every base. Both mates of a pair are written, to two files or
+/// interleaved in one.
+pub struct UnmappedWriter {
+    mates1: Box<dyn Write + Send>,
+    mates2: Option<Box<dyn Write + Send>>,
+}
+
+impl UnmappedWriter {
+    /// Write reads, or interleaved mates, to `path`, gzipped if its name
+    /// ends in `.gz`.
+    pub fn create<P: AsRef<Path>>(path: P) -> Result<UnmappedWriter, Error> {
+        Ok(UnmappedWriter { mates1: create_maybe_gz(path)?, mates2: None })
+    }
+
+    /// Write first mates to `path1` and second mates to `path2`.
+    pub fn create_paired<P: AsRef<Path>, Q: AsRef<Path>>(path1: P, path2: Q) -> Result<UnmappedWriter, Error> {
+        Ok(UnmappedWriter { mates1: create_maybe_gz(path1)?, mates2: Some(create_maybe_gz(path2)?) })
+    }
+
+    pub fn write_read(&mut self, record: &fastq::Record, reason: UnmappedReason) -> Result<(), Error> {
+        write_unmapped_record(&mut self.mates1, record, reason)
+    }
+
+    pub fn write_pair(&mut self, mate1: &fastq::Record, mate2: &fastq::Record, reason: UnmappedReason) -> Result<(), Error> {
+        write_unmapped_record(&mut self.mates1, mate1, reason)?;
+        match self.mates2 {
+            Some(ref mut mates2) => write_unmapped_record(mates2, mate2, reason),
+            None => write_unmapped_record(&mut self.mates1, mate2, reason),
+        }
+    }
+
+    pub fn flush(&mut self) -> Result<(), Error> {
+        self.mates1.flush()?;
+        if let Some(ref mut mates2) = self.mates2 {
+            mates2.flush()?;
+        }
+        Ok(())
+    }
+}
+
+pub(crate) fn write_unmapped_record<W: Write>(writer: &mut W, record: &fastq::Record, reason: UnmappedReason) -> Result<(), Error> {
+    match record.desc() {
+        Some(desc) => writeln!(writer, "@{} {} unmapped={}", record.id(), desc, reason.code())?,
+        None => writeln!(writer, "@{} unmapped={}", record.id(), reason.code())?,
+    }
+    writer.write_all(record.seq())?;
+    writeln!(writer, "\n+")?;
+    if record.qual().is_empty() {
+        writer.write_all(&vec![b'I'; record.seq().len()])?;
+    } else {
+        writer.write_all(record.qual())?;
+    }
+    writeln!(writer)?;
+    Ok(())
+}
+
+/// One line of `write_read_mappings_jsonl`; unmapped reads have no
+/// equivalence class and no coverage.
+#[derive(Serialize)]
+struct ReadMappingLine<'a> {
+    read: &'a str,
+    eq_class: Option<&'a [u32]>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    coverage: Option<usize>,
+}
+
+/// Write the mapping result of every read to `writer` as it comes, one JSON
+/// object per line: `{"read":id,"eq_class":[..],"coverage":n}` for mapped
+/// reads and `{"read":id,"eq_class":null}` otherwise. The writer is flushed
+/// every `JSONL_FLUSH_INTERVAL` lines, so output of any size takes constant
+/// memory.
+pub fn write_read_mappings_jsonl<W, I>(mut writer: W, results: I) -> Result<(), Error>
+where
+    W: Write,
+    I: Iterator<Item = (String, Option<(Vec<u32>, usize)>)>,
+{
+    for (line, (read_id, mapping)) in results.enumerate() {
+        let record = ReadMappingLine {
+            read: &read_id,
+            eq_class: mapping.as_ref().map(|(eq_class, _)| eq_class.as_slice()),
+            coverage: mapping.as_ref().map(|&(_, coverage)| coverage),
+        };
+        serde_json::to_writer(&mut writer, &record)?;
+        writeln!(writer)?;
+
+        if (line + 1) % config::JSONL_FLUSH_INTERVAL == 0 {
+            writer.flush()?;
+        }
+    }
+
+    writer.flush()?;
+    Ok(())
+}
+
+/// Write the de Bruijn graph of `index` in GFA1 format, one `S` line per node
+/// (named by its node id) and one `L` line per edge, streaming node by node.
+/// Each segment carries its length (`LN`), its equivalence class id (`EC`),
+/// and the ids (`CL`, the colors) and names (`TX`) of the transcripts
+/// containing it.
+pub fn write_gfa<W: Write, K: Kmer>(mut writer: W, index: &Pseudoaligner<K>) -> Result<(), Error> {
+    writeln!(writer, "H\tVN:Z:1.0")?;
+
+    let overlap = K::k() - 1;
+    for node in index.dbg.iter_nodes() {
+        let eq_class_id = *node.data();
+        let eq_class = &index.eq_classes[eq_class_id as usize];
+        let colors: Vec<String> = eq_class.iter().map(|tx| tx.to_string()).collect();
+        let tx_names: Vec<&str> = eq_class
+            .iter()
+            .map(|&tx| index.tx_names[tx as usize].as_str())
+            .collect();
+
+        writeln!(
+            writer,
+            "S\t{}\t{}\tLN:i:{}\tEC:i:{}\tCL:Z:{}\tTX:Z:{}",
+            node.node_id,
+            node.sequence().to_string(),
+            node.len(),
+            eq_class_id,
+            colors.join(","),
+            tx_names.join(",")
+        )?;
+
+        // every edge is stored on both of its nodes; write it from the lower id
+        for (target, dir, _) in node.l_edges() {
+            if target >= node.node_id {
+                write_gfa_link(&mut writer, node.node_id, "-", target, dir, overlap)?;
+            }
+        }
+
+        for (target, dir, _) in node.r_edges() {
+            if target > node.node_id {
+                write_gfa_link(&mut writer, node.node_id, "+", target, dir, overlap)?;
+            }
+        }
+    }
+
+    writer.flush()?;
+    Ok(())
+}
+
+/// Write the unitigs (graph nodes) of `index` as FASTA, one record per node
+/// in id order, streaming node by node. The header holds the node id, the
+/// equivalence class id (`ec`) and the names of the transcripts of the class
+/// (`tx`), cut after `max_tx_names` with a count of the rest.
+pub fn write_unitigs_fasta<W: Write, K: Kmer>(
+    mut writer: W,
+    index: &Pseudoaligner<K>,
+    max_tx_names: usize,
+) -> Result<(), Error> {
+    for node in index.dbg.iter_nodes() {
+        let eq_class_id = *node.data();
+        let eq_class = &index.eq_classes[eq_class_id as usize];
+        let tx_names: Vec<&str> = eq_class
+            .iter()
+            .take(max_tx_names)
+            .map(|&tx| index.tx_names[tx as usize].as_str())
+            .collect();
+
+        write!(writer, ">unitig_{} ec={} tx={}", node.node_id, eq_class_id, tx_names.join(","))?;
+        if eq_class.len() > tx_names.len() {
+            write!(writer, ",+{}", eq_class.len() - tx_names.len())?;
+        }
+        writeln!(writer)?;
+        writeln!(writer, "{}", node.sequence().to_string())?;
+    }
+
+    Ok(())
+}
+
+fn write_gfa_link<W: Write>(
+    writer: &mut W,
+    from: usize,
+    from_orient: &str,
+    to: usize,
+    to_side: Dir,
+    overlap: usize,
+) -> Result<(), Error> {
+    // entering the target by its left end means traversing it forwards
+    let to_orient = match to_side {
+        Dir::Left => "+",
+        Dir::Right => "-",
+    };
+    writeln!(writer, "L\t{}\t{}\t{}\t{}\t{}M", from, from_orient, to, to_orient, overlap)?;
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::fs;
+    use build_index::build_index_from_fasta;
+    use config::KmerType;
+    use test_utils::{build_test_index, build_unstranded_test_index, gencode_fasta, random_seq, TempDir};
+
+    /// Index of three unrelated transcripts.
+    fn small_index() -> Pseudoaligner<KmerType> {
+        build_test_index(&[
+            ("tx1", &random_seq(300, 1)),
+            ("tx2", &random_seq(300, 2)),
+            ("tx3", &random_seq(300, 3)),
+        ])
+    }
+
+    #[test]
+    fn read_multiple_fasta_concatenates_files_in_order() {
+        let dir = TempDir::new();
+        let (seq1, seq2, seq3) = (random_seq(60, 1), random_seq(70, 2), random_seq(80, 3));
+        let cdna = dir.write_fasta("cdna.fa", &[("tx1", &seq1), ("tx2", &seq2)]);
+        let ncrna = dir.write_fasta("ncrna.fa", &[("tx3", &seq3)]);
+
+        let (seqs, tx_ids, file_ids) = read_multiple_fasta(&[cdna, ncrna]).unwrap();
+        assert_eq!(tx_ids, vec!["tx1", "tx2", "tx3"]);
+        assert_eq!(file_ids, vec![0, 0, 1]);
+        let seqs: Vec<String> = seqs.iter().map(|seq| seq.to_string()).collect();
+        assert_eq!(seqs, vec![seq1, seq2, seq3]);
+    }
+
+    #[test]
+    fn read_multiple_fasta_reports_ids_repeated_across_files() {
+        let dir = TempDir::new();
+        let cdna = dir.write_fasta("cdna.fa", &[("tx1", &random_seq(60, 1)), ("tx2", &random_seq(60, 2))]);
+        let ncrna = dir.write_fasta("ncrna.fa", &[("tx2", &random_seq(60, 3)), ("tx3", &random_seq(60, 4))]);
+
+        let err = read_multiple_fasta(&[cdna, ncrna]).unwrap_err();
+        let message = err.to_string();
+        assert!(message.contains("tx2"), "{}", message);
+        assert!(message.contains("cdna.fa") && message.contains("ncrna.fa"), "{}", message);
+        assert!(!message.contains("tx1") && !message.contains("tx3"), "{}", message);
+    }
+
+    #[test]
+    fn index_round_trips_through_its_file() {
+        let dir = TempDir::new();
+        let index = small_index();
+        let path = dir.path().join("index.bin");
+        write_index(&index, &path).unwrap();
+
+        let metadata = read_index_metadata(&path).unwrap();
+        assert_eq!(metadata.kmer_length, KmerType::k());
+        assert_eq!(metadata.num_transcripts, 3);
+
+        let read: Pseudoaligner<KmerType> = read_index(&path).unwrap();
+        assert_eq!(read.tx_names, index.tx_names);
+        assert_eq!(read.eq_classes, index.eq_classes);
+        let read_seq = DnaString::from_dna_string(&random_seq(300, 2)[100..200]);
+        assert_eq!(read.map_read(&read_seq), index.map_read(&read_seq));
+        assert_eq!(read.map_read(&read_seq).unwrap().0, vec![1]);
+        assert_eq!(read.tx_node_starts, index.tx_node_starts);
+        assert_eq!(read.tx_gc_contents, index.tx_gc_contents);
+        assert_eq!(read.read_tx_position(&read_seq, 1), Some(100));
+    }
+
+    #[test]
+    fn an_unstranded_index_stays_unstranded_through_its_file() {
+        let dir = TempDir::new();
+        let tx1 = random_seq(300, 1);
+        let index = build_unstranded_test_index(&[("tx1", &tx1), ("tx2", &random_seq(300, 2))]);
+        let path = dir.path().join("index.bin");
+        write_index(&index, &path).unwrap();
+
+        let read: Pseudoaligner<KmerType> = read_index(&path).unwrap();
+        assert!(!read.stranded);
+        let read_seq = DnaString::from_dna_string(&tx1[100..200]);
+        assert_eq!(read.map_read(&read_seq.rc()), Some((vec![0], 100)));
+        assert_eq!(read.map_read(&read_seq.rc()), read.map_read(&read_seq));
+    }
+
+    #[test]
+    fn compressed_indices_read_back_identical() {
+        let dir = TempDir::new();
+        let index = small_index();
+        let plain_path = dir.path().join("index.bin");
+        write_index(&index, &plain_path).unwrap();
+        let plain = fs::read(&plain_path).unwrap();
+
+        let mut codecs = vec![IndexCompression::None, IndexCompression::Gzip];
+        if cfg!(feature = "zstd") {
+            codecs.push(IndexCompression::Zstd);
+        }
+        for codec in codecs {
+            let path = dir.path().join(format!("index.{:?}", codec));
+            write_index_with_compression(&index, &path, codec).unwrap();
+            if codec != IndexCompression::None {
+                assert!(fs::metadata(&path).unwrap().len() < plain.len() as u64, "{:?}", codec);
+            }
+
+            // the index read back writes the same bytes as the original
+            let read: Pseudoaligner<KmerType> = read_index(&path).unwrap();
+            let rewritten = dir.path().join(format!("rewritten.{:?}", codec));
+            write_index(&read, &rewritten).unwrap();
+            assert_eq!(fs::read(&rewritten).unwrap(), plain, "{:?}", codec);
+        }
+
+        #[cfg(not(feature = "zstd"))]
+        assert!(write_index_with_compression(&index, dir.path().join("index.zst"), IndexCompression::Zstd).is_err());
+    }
+
+    #[test]
+    fn file_with_wrong_magic_is_not_an_index() {
+        let dir = TempDir::new();
+        let path = dir.write("reads.fa", ">read1\nACGTACGTACGTACGTACGTACGTACGT\n");
+        let err = read_index::<KmerType, _>(&path).unwrap_err();
+        assert!(matches!(err, PseudoalignerError::NotAnIndex));
+    }
+
+    #[test]
+    fn missing_index_file_is_an_io_error() {
+        let dir = TempDir::new();
+        let err = read_index::<KmerType, _>(dir.path().join("missing.bin")).unwrap_err();
+        assert!(matches!(err, PseudoalignerError::Io(ref err) if err.kind() == io::ErrorKind::NotFound));
+    }
+
+    #[test]
+    fn truncated_index_is_an_error() {
+        let dir = TempDir::new();
+        let path = dir.path().join("index.bin");
+        write_index(&small_index(), &path).unwrap();
+        let bytes = fs::read(&path).unwrap();
+
+        // cut inside the magic bytes
+        fs::write(&path, &bytes[..4]).unwrap();
+        let err = read_index::<KmerType, _>(&path).unwrap_err();
+        assert!(matches!(err, PseudoalignerError::NotAnIndex));
+
+        // cut inside the payload
+        fs::write(&path, &bytes[..bytes.len() / 2]).unwrap();
+        assert!(read_index::<KmerType, _>(&path).is_err());
+    }
+
+    #[test]
+    fn index_of_a_newer_format_is_rejected() {
+        let dir = TempDir::new();
+        let path = dir.path().join("index.bin");
+        write_index(&small_index(), &path).unwrap();
+        let mut bytes = fs::read(&path).unwrap();
+        bytes[8..12].copy_from_slice(&(INDEX_FORMAT_VERSION + 1).to_le_bytes());
+        fs::write(&path, &bytes).unwrap();
+
+        let err = read_index::<KmerType, _>(&path).unwrap_err();
+        match err {
+            PseudoalignerError::IndexVersionMismatch { found, expected } => {
+                assert_eq!(found, INDEX_FORMAT_VERSION + 1);
+                assert_eq!(expected, INDEX_FORMAT_VERSION);
+            }
+            other => panic!("unexpected error {}", other),
+        }
+    }
+
+    #[test]
+    fn index_of_an_older_format_is_migrated() {
+        let dir = TempDir::new();
+        let index = small_index();
+        let path = dir.path().join("index.bin");
+        write_index(&index, &path).unwrap();
+
+        // version 12 had no GC contents, the last field of the payload
+        // before the k-mer positions section, and version 11 no compression
+        // codec byte
+        let mut bytes = fs::read(&path).unwrap();
+        let positions_start = bytes.len() - 8 - 8 * index.kmer_positions().len();
+        let gc_start = positions_start - 8 - 8 * index.tx_gc_contents.len();
+        bytes.drain(gc_start..positions_start);
+        assert_eq!(bytes[12], IndexCompression::None.code());
+        bytes.remove(12);
+        bytes[8..12].copy_from_slice(&11u32.to_le_bytes());
+        fs::write(&path, &bytes).unwrap();
+
+        let read: Pseudoaligner<KmerType> = read_index(&path).unwrap();
+        assert_eq!(read.tx_names, index.tx_names);
+        assert_eq!(read.tx_lengths, index.tx_lengths);
+        assert_eq!(read.tx_node_starts, index.tx_node_starts);
+        assert!(read.tx_gc_contents.is_empty());
+    }
+
+    #[test]
+    fn mmap_and_owned_loads_map_reads_the_same() {
+        let dir = TempDir::new();
+        let path = dir.path().join("index.bin");
+        write_index(&small_index(), &path).unwrap();
+        let owned: Pseudoaligner<KmerType> = read_index_with_mode(&path, IndexLoadMode::Owned).unwrap();
+        let mapped: Pseudoaligner<KmerType> = read_index_with_mode(&path, IndexLoadMode::Mmap).unwrap();
+        assert!(matches!(*mapped.kmer_positions(), KmerPositions::Mapped { .. }));
+
+        for seed in 1..=4 {
+            let tx = random_seq(300, seed);
+            for start in (0..250).step_by(25) {
+                let read_seq = DnaString::from_dna_string(&tx[start..start + 50]);
+                assert_eq!(mapped.map_read(&read_seq), owned.map_read(&read_seq));
+            }
+        }
+    }
+
+    #[test]
+    fn mmap_load_checks_the_positions_section_length() {
+        let dir = TempDir::new();
+        let index = small_index();
+        let path = dir.path().join("index.bin");
+        write_index(&index, &path).unwrap();
+        let bytes = fs::read(&path).unwrap();
+        let positions_start = bytes.len() - 8 - 8 * index.kmer_positions().len();
+
+        // a file truncated inside the positions section
+        fs::write(&path, &bytes[..bytes.len() - 4]).unwrap();
+        let err = read_index_with_mode::<KmerType, _>(&path, IndexLoadMode::Mmap).unwrap_err();
+        assert!(matches!(err, PseudoalignerError::TruncatedIndex { .. }));
+
+        // a length whose size in bytes overflows
+        let mut corrupt = bytes.clone();
+        corrupt[positions_start..positions_start + 8].copy_from_slice(&u64::MAX.to_le_bytes());
+        fs::write(&path, &corrupt).unwrap();
+        let err = read_index_with_mode::<KmerType, _>(&path, IndexLoadMode::Mmap).unwrap_err();
+        match err {
+            PseudoalignerError::TruncatedIndex { expected, found } => {
+                assert_eq!(expected, usize::MAX);
+                assert_eq!(found, corrupt.len());
+            }
+            other => panic!("unexpected error {}", other),
+        }
+    }
+
+    fn gzip(bytes: &[u8]) -> Vec<u8> {
+        let mut encoder = GzEncoder::new(Vec::new(), Compression::default());
+        encoder.write_all(bytes).unwrap();
+        encoder.finish().unwrap()
+    }
+
+    fn fasta_records(path: &Path) -> Vec<(String, Vec<u8>)> {
+        open_fasta(path)
+            .unwrap()
+            .records()
+            .map(|record| {
+                let record = record.unwrap();
+                (record.id().to_string(), record.seq().to_vec())
+            })
+            .collect()
+    }
+
+    #[test]
+    fn gzipped_fasta_reads_like_the_plain_file() {
+        let dir = TempDir::new();
+        let records = [("tx1", random_seq(100, 1)), ("tx2", random_seq(120, 2))];
+        let records: Vec<(&str, &str)> = records.iter().map(|(id, seq)| (*id, seq.as_str())).collect();
+        let fasta = gencode_fasta(&records);
+        let plain = dir.write("ref.fa", &fasta);
+        let gz = dir.path().join("ref.fa.gz");
+        fs::write(&gz, gzip(fasta.as_bytes())).unwrap();
+
+        assert_eq!(fasta_records(&gz), fasta_records(&plain));
+        assert_eq!(fasta_records(&plain).len(), 2);
+    }
+
+    #[test]
+    fn every_member_of_a_bgzipped_fasta_is_read() {
+        let dir = TempDir::new();
+        let (seq1, seq2) = (random_seq(100, 1), random_seq(120, 2));
+        let fasta1 = gencode_fasta(&[("tx1", &seq1)]);
+        let fasta2 = gencode_fasta(&[("tx2", &seq2)]);
+        let plain = dir.write("ref.fa", &(fasta1.clone() + &fasta2));
+        let bgz = dir.path().join("ref.fa.bgz");
+        fs::write(&bgz, [gzip(fasta1.as_bytes()), gzip(fasta2.as_bytes())].concat()).unwrap();
+
+        assert_eq!(fasta_records(&bgz), fasta_records(&plain));
+    }
+
+    #[test]
+    fn corrupt_gzip_error_names_the_file() {
+        let dir = TempDir::new();
+        let fasta = gencode_fasta(&[("tx1", &random_seq(2000, 1))]);
+        let mut bytes = gzip(fasta.as_bytes());
+        let middle = bytes.len() / 2;
+        for byte in &mut bytes[10..middle] {
+            *byte = !*byte;
+        }
+        let path = dir.path().join("corrupt.fa.gz");
+        fs::write(&path, &bytes).unwrap();
+
+        let err = read_multiple_fasta(std::slice::from_ref(&path)).unwrap_err();
+        assert!(err.to_string().contains("corrupt.fa.gz"), "{}", err);
+    }
+
+    #[test]
+    fn gzipped_fastq_maps_like_the_plain_file() {
+        let index = small_index();
+        let txs = [random_seq(300, 1), random_seq(300, 2), random_seq(300, 4)];
+        let fastq: String = (0..200)
+            .map(|read| {
+                let start = (read * 7) % 240;
+                format!("@read{}\n{}\n+\n{}\n", read, &txs[read % 3][start..start + 60], "I".repeat(60))
+            })
+            .collect();
+        let dir = TempDir::new();
+        let plain = dir.write("reads.fq", &fastq);
+        // two members, as basecallers write them
+        let split = fastq[..fastq.len() / 2].rfind('@').unwrap();
+        let mut bytes = gzip(&fastq.as_bytes()[..split]);
+        bytes.extend(gzip(&fastq.as_bytes()[split..]));
+        let gz = dir.path().join("reads.fq.gz");
+        fs::write(&gz, &bytes).unwrap();
+
+        let count = |path: &Path| {
+            let mut counts = HashMap::new();
+            for record in fastq_records(path).unwrap() {
+                let eq_class = index.map_read(&fastq_seq(&record.unwrap())).map(|(eq_class, _)| eq_class);
+                *counts.entry(eq_class).or_insert(0) += 1;
+            }
+            counts
+        };
+        let counts = count(&plain);
+        assert_eq!(counts.values().sum::<usize>(), 200);
+        assert_eq!(counts[&Some(vec![0])], 67);
+        assert_eq!(counts[&None], 66);
+        assert_eq!(count(&gz), counts);
+
+        let mut corrupt = gzip(fastq.as_bytes());
+        let middle = corrupt.len() / 2;
+        for byte in &mut corrupt[middle..middle + 64] {
+            *byte = !*byte;
+        }
+        let path = dir.path().join("corrupt.fq.gz");
+        fs::write(&path, &corrupt).unwrap();
+        let err = fastq_records(&path).unwrap().find_map(|record| record.err()).unwrap();
+        assert!(err.to_string().contains("corrupt.fq.gz"), "{}", err);
+    }
+
+    #[test]
+    fn gzipped_bytes_read_like_stdin_give_the_plain_sequences() {
+        let seqs = [random_seq(60, 1), random_seq(75, 2)];
+        let fastq: String = seqs
+            .iter()
+            .enumerate()
+            .map(|(read, seq)| format!("@read{}\n{}\n+\n{}\n", read, seq, "I".repeat(seq.len())))
+            .collect();
+
+        for bytes in [fastq.as_bytes().to_vec(), gzip(fastq.as_bytes())] {
+            let read: Vec<String> = fastq_reader_from(io::Cursor::new(bytes))
+                .unwrap()
+                .map(|seq| seq.unwrap().to_string())
+                .collect();
+            assert_eq!(read, seqs);
+        }
+    }
+
+    /// `gencode_fasta` of the records with their sequences wrapped every
+    /// `width` bases.
+    fn wrapped_fasta(records: &[(&str, &str)], width: usize) -> String {
+        records
+            .iter()
+            .map(|&(id, seq)| {
+                let fasta = gencode_fasta(&[(id, seq)]);
+                let header = fasta.lines().next().unwrap();
+                let lines: Vec<&str> = seq.as_bytes().chunks(width).map(|line| str::from_utf8(line).unwrap()).collect();
+                format!("{}\n{}\n", header, lines.join("\n"))
+            })
+            .collect()
+    }
+
+    #[test]
+    fn reference_checksum_ignores_wrapping_but_not_bases() {
+        let dir = TempDir::new();
+        let (seq1, seq2) = (random_seq(300, 1), random_seq(250, 2));
+        let path = dir.write("ref.fa", &wrapped_fasta(&[("tx1", &seq1), ("tx2", &seq2)], 1000));
+        let index: Pseudoaligner<KmerType> =
+            build_index_from_fasta(vec![path.clone()], &ReferenceOptions::default()).unwrap();
+
+        let rewrapped = dir.write("rewrapped.fa", &wrapped_fasta(&[("tx1", &seq1), ("tx2", &seq2)], 60));
+        let gz = dir.path().join("rewrapped.fa.gz");
+        fs::write(&gz, gzip(fs::read(&rewrapped).unwrap().as_slice())).unwrap();
+        for same in &[path, rewrapped, gz] {
+            let verification = verify_reference(&index, std::slice::from_ref(same)).unwrap();
+            assert!(verification.matches(), "{}", verification);
+        }
+
+        let mut changed = seq2.clone().into_bytes();
+        changed[100] = if changed[100] == b'A' { b'C' } else { b'A' };
+        let changed = dir.write(
+            "changed.fa",
+            &wrapped_fasta(&[("tx1", &seq1), ("tx2", str::from_utf8(&changed).unwrap())], 60),
+        );
+        let verification = verify_reference(&index, &[changed]).unwrap();
+        assert!(!verification.matches());
+        assert_eq!(verification.found[0].num_records, 2);
+        assert_ne!(verification.found[0].sha256, verification.expected[0].sha256);
+    }
+
+    #[test]
+    fn reference_checksums_are_stored_in_the_index_metadata() {
+        let dir = TempDir::new();
+        let path = dir.write("ref.fa", &wrapped_fasta(&[("tx1", &random_seq(300, 1))], 60));
+        let index: Pseudoaligner<KmerType> =
+            build_index_from_fasta(vec![path], &ReferenceOptions::default()).unwrap();
+        let index_path = dir.path().join("index.bin");
+        write_index(&index, &index_path).unwrap();
+
+        assert_eq!(index.reference_checksums.len(), 1);
+        assert_eq!(read_index_metadata(&index_path).unwrap().reference_checksums, index.reference_checksums);
+        let read: Pseudoaligner<KmerType> = read_index(&index_path).unwrap();
+        assert_eq!(read.reference_checksums, index.reference_checksums);
+    }
+
+    /// Index of transcripts `X + Y` and `Z + Y`, a graph of the nodes for
+    /// `X`, `Z` and the shared `Y`, with edges from the first two to `Y`.
+    fn shared_suffix_index() -> Pseudoaligner<KmerType> {
+        let shared = random_seq(200, 3);
+        build_test_index(&[
+            ("tx1", random_seq(199, 1) + "A" + &shared),
+            ("tx2", random_seq(199, 2) + "C" + &shared),
+        ])
+    }
+
+    fn gfa_lines(index: &Pseudoaligner<KmerType>) -> Vec<String> {
+        let mut gfa = Vec::new();
+        write_gfa(&mut gfa, index).unwrap();
+        String::from_utf8(gfa).unwrap().lines().map(|line| line.to_string()).collect()
+    }
+
+    #[test]
+    fn unitig_fasta_has_a_record_per_node_and_lists_both_transcripts_of_the_shared_one() {
+        let index = shared_suffix_index();
+        let mut fasta = Vec::new();
+        write_unitigs_fasta(&mut fasta, &index, 10).unwrap();
+        let fasta = String::from_utf8(fasta).unwrap();
+        let headers: Vec<&str> = fasta.lines().filter(|line| line.starts_with('>')).collect();
+        assert_eq!(headers.len(), index.dbg.len());
+        assert_eq!(fasta.lines().count(), 2 * index.dbg.len());
+
+        let shared = fasta
+            .lines()
+            .position(|line| line.ends_with(" tx=tx1,tx2"))
+            .expect("no unitig of both transcripts");
+        assert_eq!(fasta.lines().nth(shared + 1).unwrap().len(), 200);
+
+        let mut cut = Vec::new();
+        write_unitigs_fasta(&mut cut, &index, 1).unwrap();
+        assert!(String::from_utf8(cut).unwrap().contains(" tx=tx1,+1\n"));
+        let mut again = Vec::new();
+        write_unitigs_fasta(&mut again, &index, 10).unwrap();
+        assert_eq!(String::from_utf8(again).unwrap(), fasta);
+    }
+
+    #[test]
+    fn gfa_has_a_segment_per_node_and_a_link_per_edge() {
+        let index = shared_suffix_index();
+        let lines = gfa_lines(&index);
+        let num_edges: usize = index
+            .dbg
+            .iter_nodes()
+            .map(|node| node.l_edges().len() + node.r_edges().len())
+            .sum::<usize>() / 2;
+
+        assert_eq!(lines[0], "H\tVN:Z:1.0");
+        assert_eq!(index.dbg.len(), 3);
+        assert_eq!(num_edges, 2);
+        assert_eq!(lines.iter().filter(|line| line.starts_with("S\t")).count(), index.dbg.len());
+        assert_eq!(lines.iter().filter(|line| line.starts_with("L\t")).count(), num_edges);
+        for line in lines.iter().filter(|line| line.starts_with("L\t")) {
+            assert!(line.ends_with(&format!("\t{}M", KmerType::k() - 1)), "{}", line);
+        }
+    }
+
+    #[test]
+    fn gfa_segment_of_the_shared_unitig_carries_both_colors() {
+        let index = shared_suffix_index();
+        let shared = random_seq(200, 3);
+        let segments: Vec<Vec<String>> = gfa_lines(&index)
+            .iter()
+            .filter(|line| line.starts_with("S\t"))
+            .map(|line| line.split('\t').map(|field| field.to_string()).collect())
+            .collect();
+
+        let shared_segment = segments.iter().find(|fields| fields[2] == shared).expect("no shared segment");
+        assert_eq!(shared_segment[5], "CL:Z:0,1");
+        assert_eq!(shared_segment[6], "TX:Z:tx1,tx2");
+        for fields in segments.iter().filter(|fields| fields[2] != shared) {
+            assert!(fields[5] == "CL:Z:0" || fields[5] == "CL:Z:1", "{:?}", fields);
+        }
+    }
+
+    #[test]
+    fn eq_class_table_lists_both_transcripts_of_the_shared_class() {
+        let dir = TempDir::new();
+        let index = shared_suffix_index();
+        write_eq_classes_tsv(&index, dir.path()).unwrap();
+
+        let eq_classes = fs::read_to_string(dir.path().join("eq_classes.tsv")).unwrap();
+        let rows: Vec<&str> = eq_classes.lines().collect();
+        assert_eq!(rows.len(), 3);
+        let ids: Vec<usize> = rows.iter().map(|row| row.split('\t').next().unwrap().parse().unwrap()).collect();
+        assert!(ids.windows(2).all(|pair| pair[0] < pair[1]), "{:?}", ids);
+        assert_eq!(rows.iter().filter(|row| row.ends_with("\ttx1\ttx2")).count(), 1);
+
+        let transcripts = fs::read_to_string(dir.path().join("transcripts.tsv")).unwrap();
+        assert_eq!(transcripts, "0\ttx1\n1\ttx2\n");
+    }
+
+    /// Writer whose buffered bytes can not be written out.
+    struct UnflushableWriter;
+
+    impl Write for UnflushableWriter {
+        fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+            Ok(buf.len())
+        }
+
+        fn flush(&mut self) -> io::Result<()> {
+            Err(io::ErrorKind::WriteZero.into())
+        }
+    }
+
+    #[test]
+    fn gfa_write_errors_on_flush_are_reported() {
+        let err = write_gfa(UnflushableWriter, &shared_suffix_index()).unwrap_err();
+        assert_eq!(err.downcast_ref::<io::Error>().map(|err| err.kind()), Some(io::ErrorKind::WriteZero));
+    }
+
+    /// Transcripts of a FASTA with `tx1` twice, read under `duplicates`.
+    fn read_with_duplicates(
+        duplicates: DuplicatePolicy,
+    ) -> Result<(Vec<DnaString>, Vec<String>, HashMap<String, String>), Error> {
+        let fasta = gencode_fasta(&[
+            ("tx1", &random_seq(60, 1)),
+            ("tx2", &random_seq(60, 2)),
+            ("tx1", &random_seq(60, 3)),
+        ]);
+        let options = ReferenceOptions { duplicates, ..ReferenceOptions::default() };
+        read_transcripts_with_options(fasta::Reader::new(fasta.as_bytes()), &options)
+    }
+
+    #[test]
+    fn repeated_transcript_ids_are_an_error_by_default() {
+        let err = read_with_duplicates(DuplicatePolicy::default()).unwrap_err();
+        match err.downcast_ref::<PseudoalignerError>() {
+            Some(PseudoalignerError::DuplicateTranscripts(ids)) => assert_eq!(ids, "tx1"),
+            other => panic!("unexpected error {:?}", other),
+        }
+    }
+
+    #[test]
+    fn keep_first_drops_later_copies_of_a_transcript() {
+        let (seqs, tx_ids, tx_gene_map) = read_with_duplicates(DuplicatePolicy::KeepFirst).unwrap();
+        assert_eq!(tx_ids, vec!["tx1", "tx2"]);
+        assert_eq!(seqs.len(), tx_ids.len());
+        assert_eq!(seqs[0].to_string(), random_seq(60, 1));
+        assert_eq!(tx_gene_map.len(), 2);
+    }
+
+    #[test]
+    fn rename_keeps_every_copy_under_a_new_id() {
+        let (seqs, tx_ids, tx_gene_map) = read_with_duplicates(DuplicatePolicy::RenameWithSuffix).unwrap();
+        assert_eq!(tx_ids, vec!["tx1", "tx2", "tx1_dup1"]);
+        assert_eq!(seqs.len(), tx_ids.len());
+        assert_eq!(seqs[2].to_string(), random_seq(60, 3));
+        assert_eq!(tx_gene_map["tx1_dup1"], "Gtx1");
+    }
+
+    #[test]
+    fn headers_give_transcript_gene_and_name_per_format() {
+        let dir = TempDir::new();
+        let seq = random_seq(60, 1);
+        let gencode = dir.write("gencode.fa", &format!(
+            ">ENST00000456328.2|ENSG00000223972.5|OTTHUMG1.1|OTTHUMT1.1|DDX11L1-202|DDX11L1|1657|lncRNA|\n{}\n\
+             >ENST00000399012.6_PAR_Y|ENSG00000182378.14_PAR_Y|-|-|PLCXD1-201|PLCXD1|3285|protein_coding|\n{}\n",
+            seq, seq
+        ));
+        let ensembl = dir.write("ensembl.fa", &format!(
+            ">ENST00000632684.1 cdna chromosome:GRCh38:7:142786213:142786224:1 gene:ENSG00000282431.1 \
+             gene_biotype:TR_D_gene transcript_biotype:TR_D_gene gene_symbol:TRBD1 description:T cell receptor\n{}\n",
+            seq
+        ));
+        let plain = dir.write("plain.fa", &format!(">HLA:HLA00001.1 A*01:01:01:01\n{}\n", seq));
+        let paths = [gencode, ensembl, plain];
+
+        let read = |strip_versions: bool| {
+            let options = ReferenceOptions { strip_versions, ..ReferenceOptions::default() };
+            let mut gene_names = HashMap::new();
+            let (_, tx_ids, tx_gene_map, _) =
+                read_multiple_fasta_records(&paths, &options, hashed_sequence, None, Some(&mut gene_names), None)
+                    .unwrap();
+            (tx_ids, tx_gene_map, gene_names)
+        };
+
+        let (tx_ids, tx_gene_map, gene_names) = read(false);
+        assert_eq!(tx_ids, vec!["ENST00000456328.2", "ENST00000399012.6_PAR_Y", "ENST00000632684.1", "HLA:HLA00001.1"]);
+        assert_eq!(tx_gene_map["ENST00000456328.2"], "ENSG00000223972.5");
+        assert_eq!(tx_gene_map["ENST00000632684.1"], "ENSG00000282431.1");
+        assert_eq!(tx_gene_map["HLA:HLA00001.1"], "HLA:HLA00001.1");
+        assert_eq!(gene_names["ENSG00000223972.5"], "DDX11L1");
+        assert_eq!(gene_names["ENSG00000282431.1"], "TRBD1");
+        assert_eq!(gene_names.len(), 3);
+
+        let (tx_ids, tx_gene_map, gene_names) = read(true);
+        assert_eq!(tx_ids, vec!["ENST00000456328", "ENST00000399012_PAR_Y", "ENST00000632684", "HLA:HLA00001.1"]);
+        assert_eq!(tx_gene_map["ENST00000399012_PAR_Y"], "ENSG00000182378_PAR_Y");
+        assert_eq!(tx_gene_map["ENST00000632684"], "ENSG00000282431");
+        assert_eq!(tx_gene_map["HLA:HLA00001.1"], "HLA:HLA00001.1");
+        assert_eq!(gene_names["ENSG00000223972"], "DDX11L1");
+        assert_eq!(gene_names["ENSG00000282431"], "TRBD1");
+    }
+
+    #[test]
+    fn t2g_files_give_the_genes_and_names_of_transcripts() {
+        let dir = TempDir::new();
+        let path = dir.write("t2g.tsv", "# tx\tgene\tname\ntx1\tgene1\tA\ntx2\tgene1\n\ntx3\tgene2\tB\n");
+        let (tx_genes, gene_names) = read_t2g(&path).unwrap();
+        assert_eq!(tx_genes.len(), 3);
+        assert_eq!(tx_genes["tx2"], "gene1");
+        assert_eq!(tx_genes["tx3"], "gene2");
+        assert_eq!(gene_names.len(), 2);
+        assert_eq!(gene_names["gene1"], "A");
+
+        let conflicting = dir.write("conflicting.tsv", "tx1\tgene1\ntx1\tgene2\n");
+        assert!(read_t2g(&conflicting).unwrap_err().to_string().contains("tx1 is in genes gene1 and gene2"));
+        let malformed = dir.write("malformed.tsv", "tx1\n");
+        assert!(read_t2g(&malformed).unwrap_err().to_string().contains("malformed.tsv:1"));
+    }
+
+    #[test]
+    fn soft_masked_bases_split_sequences() {
+        let record = fasta::Record::with_attrs("tx1", None, b"ACGTacgtACNTTTnnGG");
+        let segments = |seqs: Vec<DnaString>| seqs.iter().map(|seq| seq.to_string()).collect::<Vec<_>>();
+        let (split, masked_bases) = softmask_split_sequence(&record);
+        assert_eq!(segments(split), vec!["ACGT", "AC", "TTT", "GG"]);
+        assert_eq!(masked_bases, 6);
+        // the N is hashed to a base instead
+        let (hashed, masked_bases) = softmask_hashed_sequence(&record);
+        let hashed = segments(hashed);
+        assert_eq!(masked_bases, 6);
+        assert_eq!(hashed.len(), 3);
+        assert_eq!(hashed[0], "ACGT");
+        assert!(hashed[1].starts_with("AC") && hashed[1].ends_with("TTT") && hashed[1].len() == 6);
+        assert_eq!(hashed[2], "GG");
+    }
+
+    #[test]
+    fn transcript_checksums_tell_segments_apart() {
+        let dna = DnaString::from_dna_string;
+        let whole = transcript_checksum(&[dna("ACGTACGT")]);
+        assert_eq!(transcript_checksum(&[dna("ACGTACGT")]), whole);
+        assert_ne!(transcript_checksum(&[dna("ACGT"), dna("ACGT")]), whole);
+        assert_ne!(transcript_checksum(&[dna("ACGTACGA")]), whole);
+    }
+
+    #[test]
+    fn read_mappings_are_written_one_json_object_per_line() {
+        let results = vec![
+            ("read1".to_string(), Some((vec![0, 2], 60))),
+            ("read2".to_string(), None),
+        ];
+        let mut out = Vec::new();
+        write_read_mappings_jsonl(&mut out, results.into_iter()).unwrap();
+        assert_eq!(
+            String::from_utf8(out).unwrap(),
+            "{\"read\":\"read1\",\"eq_class\":[0,2],\"coverage\":60}\n{\"read\":\"read2\",\"eq_class\":null}\n"
+        );
+    }
+
+    #[test]
+    fn interleaved_records_pair_up_two_by_two() {
+        let tx = random_seq(300, 1);
+        let index = small_index();
+        let fastq = |records: &[(&str, &str)]| -> String {
+            records
+                .iter()
+                .map(|(id, seq)| format!("@{}\n{}\n+\n{}\n", id, seq, "I".repeat(seq.len())))
+                .collect()
+        };
+        let dir = TempDir::new();
+        // the second mates are read from the other strand
+        let rc = |seq: &str| DnaString::from_dna_string(seq).rc().to_string();
+        let (mate2_r1, mate2_r2) = (rc(&tx[200..260]), rc(&tx[150..210]));
+        let pairs = [("r1/1", &tx[0..60]), ("r1/2", &mate2_r1[..]), ("r2/1", &tx[50..110]), ("r2/2", &mate2_r2[..])];
+        let path = dir.write("pairs.fq", &fastq(&pairs));
+
+        let mates: Vec<(DnaString, DnaString)> =
+            interleaved_fastq_reader(&path).unwrap().collect::<Result<_, _>>().unwrap();
+        assert_eq!(mates.len(), 2);
+        assert_eq!(mates[1].0.to_string(), &tx[50..110]);
+        assert_eq!(mates[1].1.to_string(), mate2_r2);
+        for (mate1, mate2) in &mates {
+            assert_eq!(index.map_read_pair(mate1, mate2).map(|(eq_class, _)| eq_class), Some(vec![0]));
+        }
+
+        let odd = dir.write("odd.fq", &fastq(&pairs[..3]));
+        let results: Vec<_> = interleaved_fastq_reader(&odd).unwrap().collect();
+        assert_eq!(results.len(), 2);
+        assert!(results[0].is_ok());
+        assert!(results[1].as_ref().unwrap_err().to_string().contains("odd number of records: r2/1 has no mate"));
+
+        let unpaired = dir.write("unpaired.fq", &fastq(&[pairs[0], pairs[3]]));
+        let err = interleaved_fastq_reader(&unpaired).unwrap().next().unwrap().unwrap_err();
+        assert!(err.to_string().contains("r1/1 and r2/2"));
+    }
+
+    #[test]
+    fn mate_files_pair_up_record_by_record() {
+        let fastq = |records: &[(&str, &str)]| -> String {
+            records
+                .iter()
+                .map(|(id, seq)| format!("@{}\n{}\n+\n{}\n", id, seq, "I".repeat(seq.len())))
+                .collect()
+        };
+        let dir = TempDir::new();
+        let path1 = dir.write("r1.fq", &fastq(&[("r1/1", "ACGTACGT"), ("r2/1", "GGGGCCCC")]));
+        let path2 = dir.write("r2.fq", &fastq(&[("r1/2", "TTTTAAAA"), ("r2/2", "CCCCGGGG")]));
+
+        let pairs: Vec<(fastq::Record, fastq::Record)> =
+            paired_fastq_records(&path1, &path2).unwrap().collect::<Result<_, _>>().unwrap();
+        assert_eq!(pairs.len(), 2);
+        assert_eq!((pairs[1].0.id(), pairs[1].1.id()), ("r2/1", "r2/2"));
+        assert_eq!(pairs[1].1.seq(), b"CCCCGGGG");
+
+        let other_ids = dir.write("other.fq", &fastq(&[("r1/2", "TTTTAAAA"), ("r3/2", "CCCCGGGG")]));
+        let results: Vec<_> = paired_fastq_records(&path1, &other_ids).unwrap().collect();
+        assert!(results[0].is_ok());
+        let err = results[1].as_ref().unwrap_err().to_string();
+        assert!(err.contains("r2/1") && err.contains("r3/2") && err.contains("different ids"), "{}", err);
+
+        let shorter = dir.write("shorter.fq", &fastq(&[("r1/2", "TTTTAAAA")]));
+        for (first, second) in &[(&path1, &shorter), (&shorter, &path1)] {
+            let results: Vec<_> = paired_fastq_records(first, second).unwrap().collect();
+            assert_eq!(results.len(), 2);
+            assert!(results[1].as_ref().unwrap_err().to_string().contains("different numbers of records"));
+        }
+    }
+
+    #[test]
+    fn quant_sf_has_salmon_header_and_rows() {
+        let tx_ids = vec!["tx1".to_string(), "tx2".to_string()];
+        let mut out = Vec::new();
+        write_quant_sf(&mut out, &tx_ids, &[1500, 300], &[1312.5, 112.25], &[987654.321, 12345.679], &[120.0, 2.5])
+            .unwrap();
+        assert_eq!(
+            String::from_utf8(out).unwrap(),
+            "Name\tLength\tEffectiveLength\tTPM\tNumReads\n\
+             tx1\t1500\t1312.500\t987654.321000\t120.000\n\
+             tx2\t300\t112.250\t12345.679000\t2.500\n"
+        );
+
+        let err = write_quant_sf(Vec::new(), &tx_ids, &[1500], &[1312.5, 112.25], &[1.0, 2.0], &[1.0, 2.0]).unwrap_err();
+        assert!(err.to_string().contains("2 names, 1 lengths"));
+    }
+
+    #[test]
+    fn casava_numbered_mates_pair_like_two_files() {
+        let tx = random_seq(300, 1);
+        let dir = TempDir::new();
+        let record = |id: &str, mate: usize, seq: &str| {
+            format!("@{} {}:N:0:ACGT\n{}\n+\n{}\n", id, mate, seq, "I".repeat(seq.len()))
+        };
+        let mates1 = [record("r1", 1, &tx[0..60]), record("r2", 1, &tx[40..100])];
+        let mates2 = [record("r1", 2, &tx[200..260]), record("r2", 2, &tx[220..280])];
+        let interleaved = dir.write("interleaved.fq", &format!("{}{}{}{}", mates1[0], mates2[0], mates1[1], mates2[1]));
+        let path1 = dir.write("reads_1.fq", &mates1.concat());
+        let path2 = dir.write("reads_2.fq", &mates2.concat());
+
+        let seqs = |pairs: Vec<Result<(fastq::Record, fastq::Record), Error>>| -> Vec<(Vec<u8>, Vec<u8>)> {
+            pairs.into_iter().map(|pair| pair.unwrap()).map(|(m1, m2)| (m1.seq().to_vec(), m2.seq().to_vec())).collect()
+        };
+        let from_one_file = seqs(interleaved_fastq_records(&interleaved).unwrap().collect());
+        assert_eq!(from_one_file.len(), 2);
+        assert_eq!(from_one_file, seqs(paired_fastq_records(&path1, &path2).unwrap().collect()));
+
+        // r1's second mate is missing, so r2's first would pair with r1's first
+        let dropped = dir.write("dropped.fq", &format!("{}{}{}", mates1[0], mates1[1], mates2[1]));
+        let err = interleaved_fastq_records(&dropped).unwrap().next().unwrap().unwrap_err();
+        assert!(err.to_string().contains("r1 and r2"), "{}", err);
+        let swapped = dir.write("swapped.fq", &format!("{}{}", mates2[0], mates1[0]));
+        assert!(interleaved_fastq_records(&swapped).unwrap().next().unwrap().is_err());
+    }
+
+    #[test]
+    fn fasta_reads_map_to_the_class_of_their_transcript() {
+        let index = small_index();
+        let tx2 = random_seq(300, 2);
+        let dir = TempDir::new();
+        // the first record is wrapped, the second from no transcript
+        let fasta = format!(">contig1 assembled\n{}\n{}\n>contig2\n{}\n", &tx2[0..60], &tx2[60..150], random_seq(100, 9));
+        let path = dir.write("contigs.fa", &fasta);
+
+        let records: Vec<fastq::Record> =
+            read_records(&path, ReadFormat::Auto).unwrap().map(|record| record.unwrap()).collect();
+        assert_eq!(records.len(), 2);
+        assert_eq!(records[0].id(), "contig1");
+        assert_eq!(records[0].seq(), &tx2.as_bytes()[0..150]);
+        assert_eq!(index.map_read(&fastq_seq(&records[0])).map(|(eq_class, _)| eq_class), Some(vec![1]));
+        assert_eq!(index.map_read(&fastq_seq(&records[1])), None);
+        assert_eq!(read_records(&path, ReadFormat::Fasta).unwrap().count(), 2);
+
+        let neither = dir.write("reads.txt", &tx2);
+        assert!(read_records(&neither, ReadFormat::Auto).is_err());
+    }
+
+    #[test]
+    fn gc_content_is_the_fraction_of_g_and_c_bases() {
+        assert_eq!(gc_content(&DnaString::from_dna_string("GCGC")), 1.0);
+        assert_eq!(gc_content(&DnaString::from_dna_string("ATAT")), 0.0);
+        assert_eq!(gc_content(&DnaString::from_dna_string("ACGTTA")), 1.0 / 3.0);
+        assert_eq!(gc_content(&DnaString::new()), 0.0);
+    }
+
+    #[test]
+    fn gc_contents_leave_out_the_run_of_n_whether_hashed_or_split_at() {
+        let dir = TempDir::new();
+        let (left, right) = (random_seq(150, 1), random_seq(150, 2));
+        let tx1 = format!("{}NNNNNNNNNN{}", left, right);
+        let tx2 = "GC".repeat(20) + &random_seq(200, 3);
+        let path = dir.write_fasta("ref.fa", &[("tx1", &tx1), ("tx2", &tx2)]);
+
+        let gc = |seq: &str| seq.bytes().filter(|&base| base == b'G' || base == b'C').count() as f64;
+        let expected = [(gc(&left) + gc(&right)) / 300.0, gc(&tx2) / 240.0];
+        for &split_at_n in &[false, true] {
+            let options = ReferenceOptions { split_at_n, ..ReferenceOptions::default() };
+            let index = build_index_from_fasta::<KmerType>(vec![path.clone()], &options).unwrap();
+            let gc_contents = index.gc_contents().unwrap();
+            assert_eq!(gc_contents.len(), 2);
+            for (found, expected) in gc_contents.iter().zip(&expected) {
+                assert!((found - expected).abs() < 1e-12, "{} {:?}", split_at_n, gc_contents);
+            }
+        }
+
+        let mut index = build_index_from_fasta::<KmerType>(vec![path], &ReferenceOptions::default()).unwrap();
+        index.tx_gc_contents.clear();
+        assert!(index.gc_contents().is_err());
+    }
+
+    #[test]
+    fn id_lists_skip_blank_and_comment_lines() {
+        let dir = TempDir::new();
+        let path = dir.write("ids.txt", "# kept\ntx1\n\n  tx2  \ntx1\n");
+        let ids = read_ids(&path).unwrap();
+        assert_eq!(ids.len(), 2);
+        assert!(ids.contains("tx1") && ids.contains("tx2"));
+    }
+
+    #[test]
+    fn subsequence_takes_a_half_open_range() {
+        let seq = DnaString::from_dna_string("ACGTTGCA");
+        assert_eq!(subsequence(&seq, 2, 6).to_string(), "GTTG");
+        assert_eq!(subsequence(&seq, 0, 8).to_string(), "ACGTTGCA");
+    }
+
+    #[test]
+    fn spans_are_read_per_transcript() {
+        let dir = TempDir::new();
+        let path = dir.write("spans.tsv", "# tx\tstart\tend\ntx1\t10\t20\n\ntx1\t30\t40\ntx2\t0\t5\n");
+        let spans = read_spans(&path).unwrap();
+        assert_eq!(spans.len(), 2);
+        assert_eq!(spans["tx1"], vec![(10, 20), (30, 40)]);
+        assert_eq!(spans["tx2"], vec![(0, 5)]);
+    }
+
+    #[test]
+    fn empty_records_are_skipped() {
+        let (seq1, seq3) = (random_seq(60, 1), random_seq(60, 3));
+        let fasta = gencode_fasta(&[("tx1", &seq1), ("tx2", ""), ("tx3", &seq3)]);
+        let (seqs, tx_ids, tx_gene_map) = read_transcripts(fasta::Reader::new(fasta.as_bytes())).unwrap();
+        assert_eq!(tx_ids, vec!["tx1", "tx3"]);
+        let seqs: Vec<String> = seqs.iter().map(|seq| seq.to_string()).collect();
+        assert_eq!(seqs, vec![seq1, seq3]);
+        assert!(!tx_gene_map.contains_key("tx2"));
+    }
+
+    #[test]
+    fn downsampling_is_reproducible_for_a_seed() {
+        let reads: Vec<usize> = (0..1000).collect();
+        let subset = downsample_reads(reads.clone(), 100, 7);
+        assert_eq!(subset.len(), 100);
+        assert!(subset.windows(2).all(|pair| pair[0] < pair[1]));
+        assert_eq!(downsample_reads(reads.clone(), 100, 7), subset);
+        assert_ne!(downsample_reads(reads.clone(), 100, 8), subset);
+
+        assert_eq!(downsample_reads(reads.clone(), 1000, 7), reads);
+        assert_eq!(downsample_reads(reads.clone(), 2000, 7), reads);
+    }
 }
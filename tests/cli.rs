@@ -0,0 +1,249 @@
+// Copyright (c) 2018 10x Genomics, Inc. All rights reserved.
+
+//! Runs of the `pseudoaligner` binary.
+
+extern crate flate2;
+
+use std::fs::{self, File};
+use std::io::Write;
+use std::path::{Path, PathBuf};
+use std::process::{self, Command, Output, Stdio};
+use std::thread;
+
+use flate2::write::GzEncoder;
+use flate2::Compression;
+
+const PSEUDOALIGNER: &str = env!("CARGO_BIN_EXE_pseudoaligner");
+
+/// A fresh directory under the system temp dir, removed when dropped.
+struct TempDir {
+    path: PathBuf,
+}
+
+impl TempDir {
+    fn new(name: &str) -> TempDir {
+        let path = std::env::temp_dir().join(format!("pseudoaligner-cli-{}-{}", process::id(), name));
+        fs::create_dir_all(&path).unwrap();
+        TempDir { path }
+    }
+
+    fn write(&self, name: &str, contents: &str) -> PathBuf {
+        let path = self.path.join(name);
+        File::create(&path).unwrap().write_all(contents.as_bytes()).unwrap();
+        path
+    }
+}
+
+impl Drop for TempDir {
+    fn drop(&mut self) {
+        let _ = fs::remove_dir_all(&self.path);
+    }
+}
+
+/// Pseudo-random sequence of `len` bases, the same for the same `seed`.
+fn random_seq(len: usize, seed: u64) -> String {
+    let mut state = seed.wrapping_mul(6364136223846793005).wrapping_add(1442695040888963407);
+    (0..len)
+        .map(|_| {
+            state = state.wrapping_mul(6364136223846793005).wrapping_add(1442695040888963407);
+            b"ACGT"[(state >> 62) as usize] as char
+        })
+        .collect()
+}
+
+/// Ten transcripts in pairs sharing their first 300 bases, with
+/// Gencode-style headers, and FASTQ reads of 60 bases spread over them.
+fn write_reference_and_reads(dir: &TempDir) -> (PathBuf, PathBuf) {
+    let mut fasta = String::new();
+    let mut seqs = Vec::new();
+    for i in 0..10u64 {
+        let seq = format!("{}{}", random_seq(300, 100 + i / 2), random_seq(300, 200 + i));
+        fasta.push_str(&format!(">tx{}|Gtx{}|-|-|tx{}-001|tx{}|{}|protein_coding|\n{}\n", i, i, i, i, seq.len(), seq));
+        seqs.push(seq);
+    }
+    let mut fastq = String::new();
+    for read in 0..400 {
+        let seq = &seqs[read % seqs.len()];
+        let start = (read * 37) % (seq.len() - 60);
+        fastq.push_str(&format!("@read{}\n{}\n+\n{}\n", read, &seq[start..start + 60], "I".repeat(60)));
+    }
+    (dir.write("ref.fa", &fasta), dir.write("reads.fq", &fastq))
+}
+
+fn run(args: &[&str]) -> Output {
+    let output = Command::new(PSEUDOALIGNER).args(args).output().unwrap();
+    assert!(output.status.success(), "{:?} failed: {}", args, String::from_utf8_lossy(&output.stderr));
+    output
+}
+
+fn path_str(path: &Path) -> &str {
+    path.to_str().unwrap()
+}
+
+/// The assigned transcript, the last field, of the stdout lines of the
+/// reads of the class of transcripts 0 and 1.
+fn shared_assignments(stdout: &[u8]) -> Vec<String> {
+    String::from_utf8_lossy(stdout)
+        .lines()
+        .filter(|line| line.contains("[0, 1]"))
+        .map(|line| {
+            let assigned = line.rsplitn(2, ", ").next().unwrap();
+            assigned[..assigned.len() - 1].to_string()
+        })
+        .collect()
+}
+
+#[test]
+fn seeded_mapping_output_does_not_depend_on_the_threads() {
+    let dir = TempDir::new("seeded-mapping");
+    let (fasta, reads) = write_reference_and_reads(&dir);
+    let index = dir.path.join("index");
+    run(&["index", "--quiet", "-i", path_str(&index), path_str(&fasta)]);
+
+    let map = |seed: &str, threads: &str| {
+        run(&[
+            "map", "--seed", seed, "--threads", threads, "--chunk-size", "7", "-i", path_str(&index), path_str(&reads),
+        ])
+        .stdout
+    };
+    let parallel = map("7", "4");
+    assert_eq!(String::from_utf8_lossy(&parallel).lines().count(), 400);
+    assert_eq!(map("7", "4"), parallel);
+    assert_eq!(map("7", "1"), parallel);
+
+    // reads of the shared halves are assigned one of their two transcripts
+    let assigned = shared_assignments(&parallel);
+    assert!(!assigned.is_empty());
+    for tx_id in &assigned {
+        assert!(tx_id == "Some(0)" || tx_id == "Some(1)", "{}", tx_id);
+    }
+    // and another seed assigns some of them differently
+    assert_ne!(shared_assignments(&map("8", "4")), assigned);
+}
+
+#[test]
+fn mapped_reads_are_flagged_on_stdout() {
+    let dir = TempDir::new("mapped-flag");
+    let (fasta, _) = write_reference_and_reads(&dir);
+    let index = dir.path.join("index");
+    run(&["index", "--quiet", "-i", path_str(&index), path_str(&fasta)]);
+
+    let tx0 = format!("{}{}", random_seq(300, 100), random_seq(300, 200));
+    let reads = format!(
+        "@mapped\n{}\n+\n{}\n@random\n{}\n+\n{}\n",
+        &tx0[400..460], "I".repeat(60), random_seq(60, 999), "I".repeat(60)
+    );
+    let reads = dir.write("flagged.fq", &reads);
+    let stdout = run(&["map", "--threads", "4", "-i", path_str(&index), path_str(&reads)]).stdout;
+    let stdout = String::from_utf8_lossy(&stdout);
+    let lines: Vec<&str> = stdout.lines().collect();
+    assert_eq!(lines.len(), 2);
+    // the tuple keeps its four fields
+    assert_eq!(lines, vec!["(true, \"mapped\", [0], 60)", "(false, \"random\", [], 0)"]);
+}
+
+#[test]
+fn a_batch_maps_every_sample_and_records_the_failed_one() {
+    let dir = TempDir::new("batch");
+    let (fasta, reads) = write_reference_and_reads(&dir);
+    let index = dir.path.join("index");
+    run(&["index", "--quiet", "-i", path_str(&index), path_str(&fasta)]);
+
+    let tx0 = format!("{}{}", random_seq(300, 100), random_seq(300, 200));
+    let few_reads = format!(
+        "@mapped\n{}\n+\n{}\n@random\n{}\n+\n{}\n",
+        &tx0[400..460], "I".repeat(60), random_seq(60, 999), "I".repeat(60)
+    );
+    let few_reads = dir.write("few.fq", &few_reads);
+    let missing = dir.path.join("missing.fq");
+    let sheet = dir.write(
+        "samples.tsv",
+        &format!("s1\t{}\nmissing\t{}\ns2\t{}\n", path_str(&reads), path_str(&missing), path_str(&few_reads)),
+    );
+    let outdir = dir.path.join("batch");
+    let output = Command::new(PSEUDOALIGNER)
+        .args(&["batch", "--threads", "2", "-i", path_str(&index), "-o", path_str(&outdir), path_str(&sheet)])
+        .output()
+        .unwrap();
+    assert!(!output.status.success());
+    assert!(String::from_utf8_lossy(&output.stderr).contains("1 of 3 samples failed"));
+
+    for sample in &["s1", "s2"] {
+        let summary = fs::read_to_string(outdir.join(sample).join("summary.json")).unwrap();
+        let total_reads = if *sample == "s1" { 400 } else { 2 };
+        assert!(summary.contains(&format!("\"total_reads\": {},", total_reads)), "{}", summary);
+        let counts = fs::read_to_string(outdir.join(sample).join("counts.tsv")).unwrap();
+        assert!(counts.starts_with("transcripts\tcount\n"));
+    }
+
+    let status = fs::read_to_string(outdir.join("samples.tsv")).unwrap();
+    let status: Vec<Vec<&str>> = status.lines().map(|line| line.split('\t').collect()).collect();
+    assert_eq!(status.len(), 4);
+    assert_eq!(status[1][..3], ["s1", "ok", "400"]);
+    assert_eq!(status[2][..2], ["missing", "failed"]);
+    assert_eq!(status[3], ["s2", "ok", "2", "1", "NA"]);
+
+    // one column per sample that succeeded, summing to its mapped reads
+    let matrix = fs::read_to_string(outdir.join("counts_matrix.tsv")).unwrap();
+    let mut lines = matrix.lines();
+    assert_eq!(lines.next(), Some("transcripts\ts1\ts2"));
+    let mut column_sums = [0u64; 2];
+    for line in lines {
+        let fields: Vec<&str> = line.split('\t').collect();
+        assert_eq!(fields.len(), 3, "{}", line);
+        for (sum, count) in column_sums.iter_mut().zip(&fields[1..]) {
+            *sum += count.parse::<u64>().unwrap();
+        }
+    }
+    assert_eq!(column_sums, [status[1][3].parse().unwrap(), 1]);
+}
+
+/// Run `args` with `stdin` piped in, from another thread so that the
+/// output can not fill up before the input is written. A run that stops
+/// early may leave the input unread.
+fn run_with_stdin(args: &[&str], stdin: &[u8]) -> Output {
+    let mut child = Command::new(PSEUDOALIGNER)
+        .args(args)
+        .stdin(Stdio::piped())
+        .stdout(Stdio::piped())
+        .stderr(Stdio::piped())
+        .spawn()
+        .unwrap();
+    let mut child_stdin = child.stdin.take().unwrap();
+    let stdin = stdin.to_vec();
+    let writer = thread::spawn(move || {
+        let _ = child_stdin.write_all(&stdin);
+    });
+    let output = child.wait_with_output().unwrap();
+    writer.join().unwrap();
+    output
+}
+
+fn gzip(bytes: &[u8]) -> Vec<u8> {
+    let mut encoder = GzEncoder::new(Vec::new(), Compression::default());
+    encoder.write_all(bytes).unwrap();
+    encoder.finish().unwrap()
+}
+
+#[test]
+fn reads_piped_to_stdin_map_like_the_file() {
+    let dir = TempDir::new("stdin");
+    let (fasta, reads) = write_reference_and_reads(&dir);
+    let index = dir.path.join("index");
+    run(&["index", "--quiet", "-i", path_str(&index), path_str(&fasta)]);
+
+    let map_args = ["map", "--threads", "2", "-i", path_str(&index)];
+    let from_file = run(&[&map_args[..], &[path_str(&reads)][..]].concat()).stdout;
+    assert_eq!(String::from_utf8_lossy(&from_file).lines().count(), 400);
+    let fastq = fs::read(&reads).unwrap();
+    for stdin in &[fastq.clone(), gzip(&fastq)] {
+        let output = run_with_stdin(&[&map_args[..], &["-"][..]].concat(), stdin);
+        assert!(output.status.success(), "{}", String::from_utf8_lossy(&output.stderr));
+        assert_eq!(output.stdout, from_file);
+    }
+
+    // mates piped to stdin have to be interleaved
+    let output = run_with_stdin(&[&map_args[..], &["-", path_str(&reads)][..]].concat(), &fastq);
+    assert!(!output.status.success());
+    assert!(String::from_utf8_lossy(&output.stderr).contains("need to be interleaved"));
+}